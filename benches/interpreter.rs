@@ -0,0 +1,139 @@
+//! Interpreter benchmarks. `cargo bench` runs them all; `cargo bench -- <name>`
+//! runs the groups whose name contains `<name>`, e.g. `cargo bench -- draw`.
+
+use chip8::asm;
+use chip8::audio::SilentBackend;
+use chip8::constants::{DISPLAY_HEIGHT, DISPLAY_WIDTH, FONT_START_ADDRESS, RGBA_PIXEL_SIZE};
+use chip8::cpu::CPU;
+use chip8::gif_recorder::{self, Palette};
+use chip8::opcodes::Opcode;
+use chip8::settings::{DisplaySettings, QuirkSettings, Settings};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::hint::black_box;
+
+const TICKS_PER_ITERATION: u64 = 1000;
+
+/// A loop of arithmetic, memory and drawing instructions for `tick`
+const TICK_PROGRAM: &str = "
+    LD I, 0x300
+loop:
+    ADD V4, 1
+    LD V5, V4
+    SHR V5, V5
+    XOR V6, V5
+    ADD V7, V6
+    LD B, V7
+    LD V2, [I]
+    DRW V4, V5, 3
+    SE V4, 0
+    JP loop
+    CLS
+    JP loop
+";
+
+fn cpu_with_quirks(quirks: QuirkSettings) -> CPU {
+    let settings = Settings { quirks, ..Settings::default() };
+    CPU::with_audio_backend(settings, Box::new(SilentBackend))
+}
+
+fn cpu() -> CPU {
+    cpu_with_quirks(QuirkSettings::default())
+}
+
+/// A CPU whose display has some lit pixels
+fn cpu_with_picture() -> CPU {
+    let mut cpu = cpu();
+    cpu.set_address_register(FONT_START_ADDRESS);
+    for (x, y) in [(0, 0), (10, 5), (30, 12), (50, 20), (60, 28)] {
+        cpu.draw_sprite(x, y, 15);
+    }
+    cpu
+}
+
+fn decode(c: &mut Criterion) {
+    let words: Vec<u16> = (0..=u16::MAX).collect();
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Elements(words.len() as u64));
+    group.bench_function("all words", |b| {
+        b.iter(|| words.iter().filter_map(|&word| Opcode::decode(black_box(word))).count())
+    });
+    group.finish();
+}
+
+fn execute(c: &mut Criterion) {
+    let mut group = c.benchmark_group("execute");
+    let instructions = [
+        ("LD VA, 0x42", 0x6A42),
+        ("ADD VA, VB", 0x8AB4),
+        ("SHR VA, VB", 0x8AB6),
+        ("LD B, VA", 0xFA33),
+        ("LD [I], VF", 0xFF55),
+        ("LD VF, [I]", 0xFF65),
+        ("DRW VA, VB, 15", 0xDABF),
+    ];
+    for (name, word) in instructions {
+        let opcode = Opcode::decode(word).unwrap();
+        let mut cpu = cpu();
+        cpu.set_address_register(0x300);
+        group.bench_function(name, |b| b.iter(|| black_box(&opcode).execute(&mut cpu).unwrap()));
+    }
+    group.finish();
+}
+
+fn tick(c: &mut Criterion) {
+    let program = asm::assemble(TICK_PROGRAM).unwrap();
+    let mut cpu = cpu();
+    cpu.load_program(&program);
+    let mut group = c.benchmark_group("tick");
+    group.throughput(Throughput::Elements(TICKS_PER_ITERATION));
+    group.bench_function("mixed loop", |b| {
+        b.iter(|| {
+            for _ in 0..TICKS_PER_ITERATION {
+                cpu.tick().unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+fn draw_sprite(c: &mut Criterion) {
+    let mut group = c.benchmark_group("draw_sprite");
+    let profiles = [("wrap", QuirkSettings::default()), ("clip", QuirkSettings::profile("chip8").unwrap())];
+    for (name, quirks) in profiles {
+        for (position, x, y) in [("middle", 20, 8), ("corner", 60, 28)] {
+            let mut cpu = cpu_with_quirks(quirks);
+            cpu.set_address_register(FONT_START_ADDRESS);
+            group.bench_function(BenchmarkId::new(name, position), |b| {
+                b.iter(|| cpu.draw_sprite(black_box(x), black_box(y), 15))
+            });
+        }
+    }
+    group.finish();
+}
+
+fn render_to_buffer(c: &mut Criterion) {
+    let mut cpu = cpu_with_picture();
+    let mut buffer = vec![0u8; DISPLAY_WIDTH * DISPLAY_HEIGHT * RGBA_PIXEL_SIZE];
+    let mut group = c.benchmark_group("render_to_buffer");
+    group.throughput(Throughput::Bytes(buffer.len() as u64));
+    group.bench_function("phosphor", |b| b.iter(|| cpu.render_to_buffer(black_box(&mut buffer))));
+    group.finish();
+}
+
+/// Palette lookup and scaling of a frame, as the GIF recorder does for each one it writes
+fn gif_frame(c: &mut Criterion) {
+    let mut buffer = vec![0u8; DISPLAY_WIDTH * DISPLAY_HEIGHT * RGBA_PIXEL_SIZE];
+    cpu_with_picture().render_to_buffer(&mut buffer);
+    let palette = Palette::from_colors(&DisplaySettings::default().color);
+
+    let mut group = c.benchmark_group("gif_frame");
+    for scale in [1u16, 4, 8] {
+        group.bench_with_input(BenchmarkId::new("index and scale", scale), &scale, |b, &scale| {
+            b.iter(|| gif_recorder::scale_frame(&palette.index_frame(black_box(&buffer)), scale))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, decode, execute, tick, draw_sprite, render_to_buffer, gif_frame);
+criterion_main!(benches);
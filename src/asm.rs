@@ -0,0 +1,224 @@
+//! A small two-pass assembler for the "Cowgod" CHIP-8 mnemonic syntax.
+//!
+//! Supported syntax, one statement per line:
+//!   - instructions as printed by `disasm`, e.g. `LD V0, 0x12` or `DRW V0, V1, 5`
+//!   - labels (`loop:`) usable anywhere an address is expected
+//!   - `DB` and `DW` for raw bytes and words
+//!   - comments starting with `;` or `#`
+//!
+//! Numbers may be decimal, `0x`/`$` hexadecimal or `0b` binary.
+
+use crate::constants::PROGRAM_START_ADDRESS;
+use crate::opcodes::Opcode;
+use std::collections::HashMap;
+use std::fmt;
+
+const ADDRESS_MAX: u32 = 0xFFF;
+const BYTE_MAX: u32 = 0xFF;
+const NIBBLE_MAX: u32 = 0xF;
+
+#[derive(Debug)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+struct Statement<'a> {
+    line: usize,
+    mnemonic: String,
+    operands: Vec<&'a str>,
+}
+
+/// Assemble source text into a program to be loaded at 0x200
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    // Pass 1: collect labels and statements, working out where each one lands
+    let mut labels = HashMap::new();
+    let mut statements = Vec::new();
+    let mut address = PROGRAM_START_ADDRESS as u32;
+
+    for (index, raw_line) in source.lines().enumerate() {
+        let line = index + 1;
+        let mut text = raw_line.split([';', '#']).next().unwrap_or("").trim();
+
+        while let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if label.is_empty() || !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                break;
+            }
+            if labels.insert(label.to_ascii_lowercase(), address).is_some() {
+                return Err(AsmError { line, message: format!("duplicate label '{}'", label) });
+            }
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let operands: Vec<&str> = rest.split(',').map(str::trim).filter(|s| !s.is_empty()).collect();
+        let mnemonic = mnemonic.to_ascii_uppercase();
+
+        address += match mnemonic.as_str() {
+            "DB" => operands.len() as u32,
+            "DW" => operands.len() as u32 * 2,
+            _ => 2,
+        };
+        statements.push(Statement { line, mnemonic, operands });
+    }
+
+    // Pass 2: encode with every label known
+    let mut program = Vec::new();
+    for statement in &statements {
+        let assembler = LineAssembler { labels: &labels, line: statement.line };
+        match statement.mnemonic.as_str() {
+            "DB" => {
+                for operand in &statement.operands {
+                    program.push(assembler.number(operand, BYTE_MAX)? as u8);
+                }
+            }
+            "DW" => {
+                for operand in &statement.operands {
+                    program.extend_from_slice(&(assembler.number(operand, u16::MAX as u32)? as u16).to_be_bytes());
+                }
+            }
+            _ => {
+                let opcode = assembler.instruction(&statement.mnemonic, &statement.operands)?;
+                program.extend_from_slice(&opcode.encode().to_be_bytes());
+            }
+        }
+    }
+
+    Ok(program)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operand {
+    V(u8),
+    I,
+    IndirectI,
+    DelayTimer,
+    SoundTimer,
+    Key,
+    Font,
+    Bcd,
+    Value(u32),
+}
+
+struct LineAssembler<'a> {
+    labels: &'a HashMap<String, u32>,
+    line: usize,
+}
+
+impl LineAssembler<'_> {
+    fn error<T>(&self, message: String) -> Result<T, AsmError> {
+        Err(AsmError { line: self.line, message })
+    }
+
+    fn operand(&self, text: &str) -> Result<Operand, AsmError> {
+        let upper = text.to_ascii_uppercase();
+        let operand = match upper.as_str() {
+            "I" => Operand::I,
+            "[I]" => Operand::IndirectI,
+            "DT" => Operand::DelayTimer,
+            "ST" => Operand::SoundTimer,
+            "K" => Operand::Key,
+            "F" => Operand::Font,
+            "B" => Operand::Bcd,
+            _ if upper.len() == 2 && upper.starts_with('V') => match u8::from_str_radix(&upper[1..], 16) {
+                Ok(register) => Operand::V(register),
+                Err(_) => return self.error(format!("invalid register '{}'", text)),
+            },
+            _ => Operand::Value(self.number(text, u32::MAX)?),
+        };
+        Ok(operand)
+    }
+
+    fn number(&self, text: &str, max: u32) -> Result<u32, AsmError> {
+        let lower = text.to_ascii_lowercase();
+        let parsed = if let Some(hex) = lower.strip_prefix("0x").or_else(|| lower.strip_prefix('$')) {
+            u32::from_str_radix(hex, 16).ok()
+        } else if let Some(binary) = lower.strip_prefix("0b") {
+            u32::from_str_radix(binary, 2).ok()
+        } else if lower.starts_with(|c: char| c.is_ascii_digit()) {
+            lower.parse().ok()
+        } else {
+            self.labels.get(&lower).copied()
+        };
+
+        match parsed {
+            Some(value) if value <= max => Ok(value),
+            Some(value) => self.error(format!("value {:#X} out of range (max {:#X})", value, max)),
+            None if lower.starts_with(|c: char| c.is_ascii_digit() || c == '$') => {
+                self.error(format!("invalid number '{}'", text))
+            }
+            None => self.error(format!("unknown label '{}'", text)),
+        }
+    }
+
+    fn instruction(&self, mnemonic: &str, operands: &[&str]) -> Result<Opcode, AsmError> {
+        use Operand::*;
+
+        let operands = operands.iter().map(|text| self.operand(text)).collect::<Result<Vec<_>, _>>()?;
+        let address = |value: u32| self.check(value, ADDRESS_MAX).map(|v| v as u16);
+        let byte = |value: u32| self.check(value, BYTE_MAX).map(|v| v as u8);
+        let nibble = |value: u32| self.check(value, NIBBLE_MAX).map(|v| v as u8);
+
+        let opcode = match (mnemonic, operands.as_slice()) {
+            ("CLS", []) => Opcode::ClearDisplay {},
+            ("RET", []) => Opcode::Return {},
+            ("SYS", [Value(a)]) => Opcode::CallRoutine { address: address(*a)? },
+            ("JP", [Value(a)]) => Opcode::Goto { address: address(*a)? },
+            ("JP", [V(0), Value(a)]) => Opcode::JumpWithOffset { address: address(*a)? },
+            ("CALL", [Value(a)]) => Opcode::CallSubroutine { address: address(*a)? },
+            ("SE", [V(x), Value(n)]) => Opcode::SkipIfEqual { register: *x, value: byte(*n)? },
+            ("SE", [V(x), V(y)]) => Opcode::SkipIfRegistersEqual { reg_x: *x, reg_y: *y },
+            ("SNE", [V(x), Value(n)]) => Opcode::SkipIfNotEqual { register: *x, value: byte(*n)? },
+            ("SNE", [V(x), V(y)]) => Opcode::SkipIfRegNotEqual { reg_x: *x, reg_y: *y },
+            ("LD", [V(x), Value(n)]) => Opcode::SetRegister { register: *x, value: byte(*n)? },
+            ("LD", [V(x), V(y)]) => Opcode::AssignRegister { reg_x: *x, reg_y: *y },
+            ("LD", [I, Value(a)]) => Opcode::SetAddress { address: address(*a)? },
+            ("LD", [V(x), DelayTimer]) => Opcode::GetDelayTimer { register: *x },
+            ("LD", [V(x), Key]) => Opcode::WaitForKey { register: *x },
+            ("LD", [DelayTimer, V(x)]) => Opcode::SetDelayTimer { register: *x },
+            ("LD", [SoundTimer, V(x)]) => Opcode::SetSoundTimer { register: *x },
+            ("LD", [Font, V(x)]) => Opcode::SetSpriteAddress { register: *x },
+            ("LD", [Bcd, V(x)]) => Opcode::StoreBCD { register: *x },
+            ("LD", [IndirectI, V(x)]) => Opcode::StoreRegisters { reg_x: *x },
+            ("LD", [V(x), IndirectI]) => Opcode::LoadRegisters { reg_x: *x },
+            ("ADD", [V(x), Value(n)]) => Opcode::AddToRegister { register: *x, value: byte(*n)? },
+            ("ADD", [V(x), V(y)]) => Opcode::AddRegisters { reg_x: *x, reg_y: *y },
+            ("ADD", [I, V(x)]) => Opcode::AddToAddress { register: *x },
+            ("OR", [V(x), V(y)]) => Opcode::BitwiseOr { reg_x: *x, reg_y: *y },
+            ("AND", [V(x), V(y)]) => Opcode::BitwiseAnd { reg_x: *x, reg_y: *y },
+            ("XOR", [V(x), V(y)]) => Opcode::BitwiseXor { reg_x: *x, reg_y: *y },
+            ("SUB", [V(x), V(y)]) => Opcode::SubtractRegisters { reg_x: *x, reg_y: *y },
+            ("SUBN", [V(x), V(y)]) => Opcode::SubtractReverse { reg_x: *x, reg_y: *y },
+            ("SHR", [V(x)]) => Opcode::ShiftRight { reg_x: *x, reg_y: *x },
+            ("SHR", [V(x), V(y)]) => Opcode::ShiftRight { reg_x: *x, reg_y: *y },
+            ("SHL", [V(x)]) => Opcode::ShiftLeft { reg_x: *x, reg_y: *x },
+            ("SHL", [V(x), V(y)]) => Opcode::ShiftLeft { reg_x: *x, reg_y: *y },
+            ("RND", [V(x), Value(n)]) => Opcode::Random { register: *x, value: byte(*n)? },
+            ("DRW", [V(x), V(y), Value(n)]) => Opcode::Draw { reg_x: *x, reg_y: *y, height: nibble(*n)? },
+            ("SKP", [V(x)]) => Opcode::SkipIfKeyPressed { register: *x },
+            ("SKNP", [V(x)]) => Opcode::SkipIfKeyNotPressed { register: *x },
+            _ => return self.error(format!("invalid instruction '{}' with {} operand(s)", mnemonic, operands.len())),
+        };
+        Ok(opcode)
+    }
+
+    fn check(&self, value: u32, max: u32) -> Result<u32, AsmError> {
+        if value <= max {
+            Ok(value)
+        } else {
+            self.error(format!("value {:#X} out of range (max {:#X})", value, max))
+        }
+    }
+}
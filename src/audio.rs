@@ -0,0 +1,321 @@
+//! Audio output backends for the sound timer.
+//!
+//! `Timers` only decides *when* the beeper is on; a backend decides what that
+//! sounds like. Keeping the device behind a trait lets the emulator run on
+//! machines without a sound card and lets tests observe the beeper directly.
+
+use rodio::{OutputStreamBuilder, Sink, Source};
+use crate::settings::{AudioSettings, Shared, Waveform};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Synthesis constants that don't change
+pub const SAMPLE_RATE: u32 = 44_100;         // Samples per second for live and recorded audio
+const NOISE_SEED: u32 = 0x1D87_2B41;        // Any non-zero xorshift seed works
+const MS_PER_SECOND: f32 = 1000.0;
+const SETTINGS_POLL_SAMPLES: u32 = 1024;     // How often live playback picks up changed settings (~23ms)
+
+/// Sample-by-sample beep synthesizer. The oscillator runs continuously and the
+/// gate only moves the envelope, so starts and stops land on exact samples
+/// and never cut the waveform mid-cycle.
+pub struct ToneGenerator {
+    waveform: Waveform,
+    phase_step: f32,
+    volume: f32,
+    attack_step: f32,
+    release_step: f32,
+    phase: f32,
+    envelope: f32,
+    noise_state: u32,
+    noise_value: f32,
+}
+
+impl ToneGenerator {
+    pub fn new(settings: &AudioSettings, sample_rate: u32) -> Self {
+        let mut generator = Self {
+            waveform: settings.waveform,
+            phase_step: 0.0,
+            volume: 0.0,
+            attack_step: 0.0,
+            release_step: 0.0,
+            phase: 0.0,
+            envelope: 0.0,
+            noise_state: NOISE_SEED,
+            noise_value: 0.0,
+        };
+        generator.apply_settings(settings, sample_rate);
+        generator
+    }
+
+    /// Switch to new settings without resetting the phase or envelope, so a
+    /// change while the beep is playing doesn't click
+    pub fn apply_settings(&mut self, settings: &AudioSettings, sample_rate: u32) {
+        self.waveform = settings.waveform;
+        self.phase_step = settings.beep_frequency_hz / sample_rate as f32;
+        self.volume = settings.beep_volume.clamp(0.0, 1.0);
+        self.attack_step = envelope_step(settings.attack_ms, sample_rate);
+        self.release_step = envelope_step(settings.release_ms, sample_rate);
+    }
+
+    /// Produce the next sample in the range -1.0..=1.0 with the gate open or closed
+    pub fn next_sample(&mut self, gate: bool) -> f32 {
+        self.envelope = if gate {
+            (self.envelope + self.attack_step).min(1.0)
+        } else {
+            (self.envelope - self.release_step).max(0.0)
+        };
+
+        let value = match self.waveform {
+            Waveform::Sine => (self.phase * std::f32::consts::TAU).sin(),
+            Waveform::Square => if self.phase < 0.5 { 1.0 } else { -1.0 },
+            Waveform::Triangle => 1.0 - 4.0 * (self.phase - 0.5).abs(),
+            Waveform::Sawtooth => 2.0 * self.phase - 1.0,
+            Waveform::Noise => self.noise_value,
+        };
+
+        self.phase += self.phase_step;
+        if self.phase >= 1.0 {
+            self.phase = self.phase.fract();
+            // Noise is sample-and-hold at the beep frequency, so it keeps a sense of pitch
+            self.noise_state ^= self.noise_state << 13;
+            self.noise_state ^= self.noise_state >> 17;
+            self.noise_state ^= self.noise_state << 5;
+            self.noise_value = self.noise_state as f32 / u32::MAX as f32 * 2.0 - 1.0;
+        }
+
+        value * self.envelope * self.volume
+    }
+}
+
+/// Per-sample envelope change for a fade of `ms` milliseconds (0 = instant)
+fn envelope_step(ms: f32, sample_rate: u32) -> f32 {
+    let samples = ms / MS_PER_SECOND * sample_rate as f32;
+    if samples <= 1.0 { 1.0 } else { 1.0 / samples }
+}
+
+/// Endless rodio source that plays a `ToneGenerator` whenever the shared gate is open
+struct GatedTone {
+    generator: ToneGenerator,
+    gate: Arc<AtomicBool>,
+    settings: Shared<AudioSettings>,
+    samples_until_poll: u32,
+}
+
+impl Iterator for GatedTone {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.samples_until_poll == 0 {
+            self.samples_until_poll = SETTINGS_POLL_SAMPLES;
+            // Never block the audio thread; a reload in progress is picked up next time
+            if let Ok(settings) = self.settings.try_read() {
+                self.generator.apply_settings(&settings, SAMPLE_RATE);
+            }
+        }
+        self.samples_until_poll -= 1;
+        Some(self.generator.next_sample(self.gate.load(Ordering::Relaxed)))
+    }
+}
+
+impl Source for GatedTone {
+    fn current_span_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> rodio::ChannelCount {
+        1
+    }
+
+    fn sample_rate(&self) -> rodio::SampleRate {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+pub trait AudioBackend {
+    /// Called on every timer tick with whether the sound timer is running
+    fn set_tone(&mut self, active: bool);
+}
+
+/// Plays the beep on the default output device through rodio
+pub struct RodioBackend {
+    _stream: rodio::OutputStream,
+    _sink: Sink,
+    gate: Arc<AtomicBool>,
+}
+
+impl RodioBackend {
+    pub fn open(settings: Shared<AudioSettings>) -> Result<Self, rodio::StreamError> {
+        let _stream = OutputStreamBuilder::open_default_stream()?;
+        let sink = Sink::connect_new(_stream.mixer());
+
+        // The generator runs for the lifetime of the stream; the sound timer only opens the gate
+        let gate = Arc::new(AtomicBool::new(false));
+        let generator = ToneGenerator::new(&settings.read().unwrap(), SAMPLE_RATE);
+        sink.append(GatedTone {
+            generator,
+            gate: gate.clone(),
+            settings,
+            samples_until_poll: SETTINGS_POLL_SAMPLES,
+        });
+        sink.play();
+
+        Ok(Self { _stream, _sink: sink, gate })
+    }
+}
+
+impl AudioBackend for RodioBackend {
+    fn set_tone(&mut self, active: bool) {
+        self.gate.store(active, Ordering::Relaxed);
+    }
+}
+
+/// Discards all sound; used when no output device is available
+pub struct SilentBackend;
+
+impl AudioBackend for SilentBackend {
+    fn set_tone(&mut self, _active: bool) {}
+}
+
+/// A change in beeper state, on the `tick`th timer tick since the backend was
+/// created, counting from 0. Ticks are emulated time, so tests don't depend on
+/// how fast they run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ToneEvent {
+    pub tick: u64,
+    pub active: bool,
+}
+
+/// Records on/off transitions instead of playing them, so tests can assert on the beeper
+pub struct RecordingBackend {
+    ticks: u64,
+    active: bool,
+    events: Arc<Mutex<Vec<ToneEvent>>>,
+}
+
+impl Default for RecordingBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RecordingBackend {
+    pub fn new() -> Self {
+        Self {
+            ticks: 0,
+            active: false,
+            events: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Shared handle to the event log that stays readable after the backend is boxed
+    pub fn events(&self) -> Arc<Mutex<Vec<ToneEvent>>> {
+        self.events.clone()
+    }
+}
+
+impl AudioBackend for RecordingBackend {
+    fn set_tone(&mut self, active: bool) {
+        if active != self.active {
+            self.active = active;
+            self.events.lock().unwrap().push(ToneEvent { tick: self.ticks, active });
+        }
+        self.ticks += 1;
+    }
+}
+
+/// Open the default output device, falling back to silence if there isn't one
+pub fn open_default_backend(settings: Shared<AudioSettings>) -> Box<dyn AudioBackend> {
+    match RodioBackend::open(settings) {
+        Ok(backend) => Box::new(backend),
+        Err(e) => {
+            eprintln!("Warning: no audio output device available ({}), sound is disabled", e);
+            Box::new(SilentBackend)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 125 Hz at 1000 samples per second is 8 samples a cycle, with exact phase steps
+    const TEST_SAMPLE_RATE: u32 = 1000;
+    const CYCLE: usize = 8;
+
+    fn settings(waveform: Waveform, attack_ms: f32, release_ms: f32) -> AudioSettings {
+        AudioSettings { beep_frequency_hz: 125.0, beep_volume: 1.0, waveform, attack_ms, release_ms }
+    }
+
+    fn samples(generator: &mut ToneGenerator, gate: bool, count: usize) -> Vec<f32> {
+        (0..count).map(|_| generator.next_sample(gate)).collect()
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (index, (a, e)) in actual.iter().zip(expected).enumerate() {
+            assert!((a - e).abs() < 1e-5, "sample {}: {} != {} in {:?}", index, a, e, actual);
+        }
+    }
+
+    fn first_cycle(waveform: Waveform) -> Vec<f32> {
+        samples(&mut ToneGenerator::new(&settings(waveform, 0.0, 0.0), TEST_SAMPLE_RATE), true, CYCLE)
+    }
+
+    #[test]
+    fn waveform_shapes() {
+        assert_close(&first_cycle(Waveform::Square), &[1.0, 1.0, 1.0, 1.0, -1.0, -1.0, -1.0, -1.0]);
+        assert_close(&first_cycle(Waveform::Sawtooth), &[-1.0, -0.75, -0.5, -0.25, 0.0, 0.25, 0.5, 0.75]);
+        assert_close(&first_cycle(Waveform::Triangle), &[-1.0, -0.5, 0.0, 0.5, 1.0, 0.5, 0.0, -0.5]);
+        let half = std::f32::consts::FRAC_1_SQRT_2;
+        assert_close(&first_cycle(Waveform::Sine), &[0.0, half, 1.0, half, 0.0, -half, -1.0, -half]);
+    }
+
+    #[test]
+    fn noise_holds_each_value_for_a_cycle() {
+        let mut generator = ToneGenerator::new(&settings(Waveform::Noise, 0.0, 0.0), TEST_SAMPLE_RATE);
+        let noise = samples(&mut generator, true, 3 * CYCLE);
+        let cycles: Vec<&[f32]> = noise.chunks(CYCLE).collect();
+        for cycle in &cycles {
+            assert!(cycle.iter().all(|sample| sample == &cycle[0] && (-1.0..=1.0).contains(sample)), "{:?}", cycle);
+        }
+        assert_ne!(cycles[1][0], cycles[2][0]);
+    }
+
+    #[test]
+    fn gate_ramps_up_over_the_attack_and_down_over_the_release() {
+        // A square wave is always at full amplitude, so its size is the envelope
+        let mut generator = ToneGenerator::new(&settings(Waveform::Square, 10.0, 20.0), TEST_SAMPLE_RATE);
+        let levels = |samples: Vec<f32>| samples.iter().map(|sample| sample.abs()).collect::<Vec<_>>();
+
+        assert_close(&samples(&mut generator, false, 4), &[0.0; 4]);
+        let attack: Vec<f32> = (1..=10).map(|step| step as f32 / 10.0).collect();
+        assert_close(&levels(samples(&mut generator, true, 10)), &attack);
+        assert_close(&levels(samples(&mut generator, true, 4)), &[1.0; 4]);
+
+        let release: Vec<f32> = (0..20).map(|step| 0.95 - step as f32 * 0.05).collect();
+        assert_close(&levels(samples(&mut generator, false, 20)), &release);
+        assert_close(&samples(&mut generator, false, 4), &[0.0; 4]);
+    }
+
+    #[test]
+    fn zero_length_fades_are_instant() {
+        let mut generator = ToneGenerator::new(&settings(Waveform::Square, 0.0, 0.0), TEST_SAMPLE_RATE);
+        assert_eq!(generator.next_sample(true), 1.0);
+        assert_eq!(generator.next_sample(false), 0.0);
+    }
+
+    #[test]
+    fn volume_scales_and_new_settings_keep_the_envelope() {
+        let mut generator = ToneGenerator::new(&settings(Waveform::Square, 0.0, 0.0), TEST_SAMPLE_RATE);
+        generator.next_sample(true);
+        let quieter = AudioSettings { beep_volume: 0.25, ..settings(Waveform::Square, 100.0, 0.0) };
+        generator.apply_settings(&quieter, TEST_SAMPLE_RATE);
+        // Already fully open, so the longer attack doesn't start the fade again
+        assert_close(&samples(&mut generator, true, 2), &[0.25, 0.25]);
+    }
+}
@@ -0,0 +1,260 @@
+//! Octo cartridges: programs shared as GIF images.
+//!
+//! Octo hides a JSON payload, `{"program": "<Octo source>", "options": {...}}`,
+//! in the low two bits of each pixel's palette index, most significant pair
+//! first, four pixels to a byte, running on through every frame. The payload is
+//! preceded by its length as a 32-bit big-endian number.
+//!
+//! The source is compiled with `octo`. Options with a counterpart in `Settings`
+//! (tick rate, colors and quirks) become a settings layer; the rest, such as
+//! the XO-CHIP plane colors or screen rotation, have nothing to apply to.
+
+use crate::octo;
+use serde_json::{Map, Value};
+
+const GIF_SIGNATURE: &[u8] = b"GIF8";
+const LENGTH_PREFIX_SIZE: usize = 4;
+const BITS_PER_PIXEL: usize = 2;
+const PIXELS_PER_BYTE: usize = 8 / BITS_PER_PIXEL;
+
+pub struct Cartridge {
+    pub program: Vec<u8>,
+    /// The cartridge's options as a partial settings table
+    pub settings: toml::Table,
+}
+
+pub fn is_cartridge(data: &[u8]) -> bool {
+    data.starts_with(GIF_SIGNATURE)
+}
+
+/// Extract and compile the program in an Octo cartridge
+pub fn decode(data: &[u8]) -> Result<Cartridge, String> {
+    let payload = read_payload(data)?;
+    let payload: Value = serde_json::from_slice(&payload)
+        .map_err(|e| format!("not an Octo cartridge (the embedded data isn't valid JSON: {})", e))?;
+
+    let source = payload
+        .get("program")
+        .and_then(Value::as_str)
+        .ok_or("not an Octo cartridge (no program in the embedded data)")?;
+    let program = octo::compile(source).map_err(|e| format!("can't compile the cartridge's program: {}", e))?;
+
+    let options = payload.get("options").and_then(Value::as_object);
+    let settings = options.map(settings_layer).unwrap_or_default();
+    Ok(Cartridge { program, settings })
+}
+
+/// The bytes carried in the pixels of every frame, without the length prefix
+fn read_payload(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(data).map_err(|e| format!("can't read the GIF: {}", e))?;
+
+    let mut bytes = Vec::new();
+    let mut byte = 0u8;
+    let mut pixels = 0;
+    while let Some(frame) = decoder.read_next_frame().map_err(|e| format!("can't read the GIF: {}", e))? {
+        for &index in frame.buffer.iter() {
+            byte = (byte << BITS_PER_PIXEL) | (index & 0b11);
+            pixels += 1;
+            if pixels == PIXELS_PER_BYTE {
+                bytes.push(byte);
+                byte = 0;
+                pixels = 0;
+            }
+        }
+    }
+
+    let Some(prefix) = bytes.first_chunk::<LENGTH_PREFIX_SIZE>() else {
+        return Err("not an Octo cartridge (the GIF is too small)".to_string());
+    };
+    let length = u32::from_be_bytes(*prefix) as usize;
+    let end = LENGTH_PREFIX_SIZE.saturating_add(length);
+    if end > bytes.len() {
+        return Err("not an Octo cartridge (the embedded data is cut short)".to_string());
+    }
+    Ok(bytes[LENGTH_PREFIX_SIZE..end].to_vec())
+}
+
+/// Translate Octo's options into the settings they correspond to
+fn settings_layer(options: &Map<String, Value>) -> toml::Table {
+    let mut settings = toml::Table::new();
+    let mut set = |section: &str, key: &str, value: toml::Value| {
+        let mut table = &mut settings;
+        for part in section.split('.') {
+            table = table
+                .entry(part)
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                .as_table_mut()
+                .expect("sections are always tables");
+        }
+        table.insert(key.to_string(), value);
+    };
+
+    if let Some(tickrate) = options.get("tickrate").and_then(Value::as_u64) {
+        set("cpu", "cycles_per_frame", toml::Value::Integer(tickrate.clamp(1, i64::MAX as u64) as i64));
+    }
+
+    // `shiftQuirks` and `loadStoreQuirk` turn on the opposite of what our
+    // settings turn on (shifting VX in place, leaving I alone), so they are
+    // inverted; the other three turn on the same behaviour as their settings
+    let quirks = [
+        ("shiftQuirks", "shift_uses_vy", true),
+        ("loadStoreQuirk", "load_store_increment_i", true),
+        ("jumpQuirks", "jump_uses_vx", false),
+        ("logicQuirks", "vf_reset", false),
+        ("clipQuirks", "clip_sprites", false),
+    ];
+    for (option, setting, inverted) in quirks {
+        if let Some(enabled) = options.get(option).and_then(Value::as_bool) {
+            set("quirks", setting, toml::Value::Boolean(enabled != inverted));
+        }
+    }
+
+    // The display only tints lit pixels through per-channel divisors, so the
+    // fill color is matched as closely as those allow
+    if let Some([r, g, b]) = options.get("fillColor").and_then(Value::as_str).and_then(parse_color) {
+        for (key, channel) in [("red_divisor", r), ("green_divisor", g), ("blue_divisor", b)] {
+            let divisor = if channel == 0 { u8::MAX } else { (255.0 / channel as f32).round() as u8 };
+            set("display.color", key, toml::Value::Integer(divisor as i64));
+        }
+    }
+    if let Some([r, g, b]) = options.get("backgroundColor").and_then(Value::as_str).and_then(parse_color) {
+        let rgba = [r, g, b, u8::MAX].map(|channel| toml::Value::Integer(channel as i64));
+        set("display.color", "background", toml::Value::Array(rgba.to_vec()));
+    }
+    settings
+}
+
+/// An HTML color, `#RRGGBB`
+fn parse_color(text: &str) -> Option<[u8; 3]> {
+    let hex = text.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use gif::{Encoder, Frame};
+    use serde_json::json;
+
+    const FRAME_WIDTH: u16 = 16;
+    const FRAME_HEIGHT: u16 = 8;
+
+    /// A GIF whose frames, `width` by `height`, hold `pixels` as palette
+    /// indices, padded to fill the last frame
+    fn gif(pixels: &[u8], width: u16, height: u16) -> Vec<u8> {
+        let frame_size = width as usize * height as usize;
+        let mut pixels = pixels.to_vec();
+        pixels.resize(pixels.len().div_ceil(frame_size).max(1) * frame_size, 0);
+
+        let palette = [0, 0, 0, 85, 85, 85, 170, 170, 170, 255, 255, 255];
+        let mut data = Vec::new();
+        let mut encoder = Encoder::new(&mut data, width, height, &palette).unwrap();
+        for buffer in pixels.chunks(frame_size) {
+            let frame = Frame { width, height, buffer: buffer.into(), ..Frame::default() };
+            encoder.write_frame(&frame).unwrap();
+        }
+        drop(encoder);
+        data
+    }
+
+    /// Octo's encoding of `bytes`, two bits to a pixel, most significant first
+    fn pixels(bytes: &[u8]) -> Vec<u8> {
+        bytes
+            .iter()
+            .flat_map(|byte| (0..8).step_by(BITS_PER_PIXEL).rev().map(move |shift| (byte >> shift) & 0b11))
+            .collect()
+    }
+
+    /// A cartridge carrying `payload` behind its length prefix
+    fn cartridge(payload: &[u8]) -> Vec<u8> {
+        let mut bytes = (payload.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(payload);
+        gif(&pixels(&bytes), FRAME_WIDTH, FRAME_HEIGHT)
+    }
+
+    /// A cartridge carrying `payload` as JSON, also used by the command-line tests
+    pub(crate) fn json_cartridge(payload: Value) -> Vec<u8> {
+        cartridge(payload.to_string().as_bytes())
+    }
+
+    #[test]
+    fn payload_is_read_across_frames_up_to_its_length() {
+        // 32 bytes fit in a frame, so this needs two, and the rest of the second is padding
+        let payload = b"a payload long enough to need a second frame";
+        let data = cartridge(payload);
+        assert!(is_cartridge(&data));
+        assert_eq!(read_payload(&data).unwrap(), payload);
+    }
+
+    #[test]
+    fn decode_compiles_the_program() {
+        let data = json_cartridge(json!({ "program": ": main clear loop again" }));
+        let cartridge = decode(&data).unwrap();
+        assert_eq!(cartridge.program, octo::compile(": main clear loop again").unwrap());
+        assert!(cartridge.settings.is_empty());
+    }
+
+    #[test]
+    fn options_become_settings() {
+        let data = json_cartridge(json!({
+            "program": ": main",
+            "options": {
+                "tickrate": 20,
+                "shiftQuirks": true,
+                "loadStoreQuirk": false,
+                "jumpQuirks": true,
+                "logicQuirks": true,
+                "clipQuirks": false,
+                "fillColor": "#FF8000",
+                "backgroundColor": "#102030",
+                "rotation": 90,
+            },
+        }));
+        let expected: toml::Table = toml::from_str(
+            "
+            cpu.cycles_per_frame = 20
+            quirks.shift_uses_vy = false
+            quirks.load_store_increment_i = true
+            quirks.jump_uses_vx = true
+            quirks.vf_reset = true
+            quirks.clip_sprites = false
+            display.color.red_divisor = 1
+            display.color.green_divisor = 2
+            display.color.blue_divisor = 255
+            display.color.background = [16, 32, 48, 255]
+            ",
+        )
+        .unwrap();
+        assert_eq!(decode(&data).unwrap().settings, expected);
+    }
+
+    #[test]
+    fn truncated_payloads_are_rejected() {
+        // Three bytes, too few for the length prefix
+        let data = gif(&pixels(&[0, 0, 0]), 12, 1);
+        assert_eq!(read_payload(&data).err().unwrap(), "not an Octo cartridge (the GIF is too small)");
+
+        // A length past the end of the frames
+        let data = gif(&pixels(&[0, 0, 0x10, 0, b'{']), FRAME_WIDTH, FRAME_HEIGHT);
+        assert_eq!(read_payload(&data).err().unwrap(), "not an Octo cartridge (the embedded data is cut short)");
+    }
+
+    #[test]
+    fn bad_payloads_are_rejected() {
+        assert!(decode(b"GIF89a").err().unwrap().starts_with("can't read the GIF"));
+        assert!(decode(&cartridge(b"{")).err().unwrap().contains("isn't valid JSON"));
+        let no_program = json_cartridge(json!({ "options": {} }));
+        assert_eq!(decode(&no_program).err().unwrap(), "not an Octo cartridge (no program in the embedded data)");
+        let bad_program = json_cartridge(json!({ "program": "clear" }));
+        assert_eq!(
+            decode(&bad_program).err().unwrap(),
+            "can't compile the cartridge's program: line 1: the program has no ': main' label"
+        );
+    }
+}
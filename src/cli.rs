@@ -0,0 +1,510 @@
+//! Command-line parsing.
+//!
+//! Every subcommand that runs a ROM accepts the same settings options: a config
+//! file, a quirk profile, an RNG seed, and `--section.setting value` overrides
+//! for any field in `Settings` (e.g. `--cpu.cycles-per-frame 20`). These are the
+//! last layer on top of the config files described in `config_layers`.
+
+use crate::config_layers::{self, LayeredSettings};
+use crate::coverage;
+use crate::gdb;
+use crate::rom::{RomFile, RomSource};
+use crate::settings::{ConfigError, Settings};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+const DEFAULT_CONFIG_FILE: &str = "chip8_config.toml";
+const DEFAULT_HEADLESS_FRAMES: u32 = 600;   // 10 seconds at 60 FPS
+const DEFAULT_SPEED_SECONDS: f64 = 5.0;     // Long enough for a steady instructions per second
+
+pub enum Command {
+    Run { rom: String, settings: SettingsArgs },
+    Headless { rom: String, frames: u32, settings: SettingsArgs },
+    Record { rom: String, frames: u32, output: Option<String>, settings: SettingsArgs },
+    Speed { rom: String, duration: Duration, settings: SettingsArgs },
+    Gdb { rom: String, port: u16, settings: SettingsArgs },
+    Coverage { files: Vec<String>, output: Option<String> },
+    Disasm { rom: String, output: Option<String> },
+    Asm { source: String, output: Option<String> },
+    Info { rom: String },
+    CheckConfig { settings: SettingsArgs },
+    PrintConfig { output: Option<String>, settings: SettingsArgs },
+    Help { topic: Option<String> },
+}
+
+/// Options that decide which `Settings` a run uses
+#[derive(Clone, Default)]
+pub struct SettingsArgs {
+    /// Change with `set_rom`, which forgets the cartridge read from the old ROM
+    pub rom: Option<String>,
+    pub config: Option<String>,
+    pub quirks: Option<String>,
+    /// Always within the i64 range, as the seed is applied as a TOML setting
+    pub seed: Option<u64>,
+    pub overrides: Vec<(String, String)>,
+    /// The ROM's Octo cartridge settings, read on the first load only so that
+    /// reloading the config files doesn't decode the ROM again
+    cartridge: OnceLock<Option<toml::Table>>,
+}
+
+#[derive(Debug)]
+pub struct CliError(pub String);
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CliError {}
+
+const SUBCOMMANDS: [&str; 9] = ["run", "headless", "record", "speed", "gdb", "coverage", "disasm", "asm", "info"];
+
+/// Parse the arguments after the program name
+pub fn parse(args: &[String]) -> Result<Command, CliError> {
+    let Some(first) = args.first() else {
+        return Ok(Command::Help { topic: None });
+    };
+
+    match first.as_str() {
+        "help" | "-h" | "--help" => Ok(Command::Help { topic: args.get(1).cloned() }),
+        "--check-config" => parse_subcommand("check-config", &args[1..]),
+        "--print-config" => parse_subcommand("print-config", &args[1..]),
+        name if SUBCOMMANDS.contains(&name) => parse_subcommand(name, &args[1..]),
+        // A bare ROM path is shorthand for `run`
+        _ => parse_subcommand("run", args),
+    }
+}
+
+fn parse_subcommand(name: &str, args: &[String]) -> Result<Command, CliError> {
+    let takes_settings = matches!(name, "run" | "headless" | "record" | "speed" | "gdb" | "check-config" | "print-config");
+    let takes_frames = matches!(name, "headless" | "record");
+    let takes_output = matches!(name, "record" | "coverage" | "disasm" | "asm" | "print-config");
+
+    let mut settings = SettingsArgs::default();
+    let mut frames = DEFAULT_HEADLESS_FRAMES;
+    let mut output = None;
+    let mut port = gdb::DEFAULT_PORT;
+    let mut duration = Duration::from_secs_f64(DEFAULT_SPEED_SECONDS);
+    let mut positional: Option<String> = None;
+    let mut more_files = Vec::new(); // `coverage` takes any number of files
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Ok(Command::Help { topic: Some(name.to_string()) });
+        }
+
+        let Some(option) = arg.strip_prefix("--").or_else(|| (arg == "-o").then_some("output")) else {
+            if arg.starts_with('-') && arg != "-" {
+                return Err(CliError(format!("unknown option '{}' for '{}'", arg, name)));
+            }
+            if name == "coverage" && positional.is_some() {
+                more_files.push(arg.clone());
+            } else if positional.replace(arg.clone()).is_some() {
+                return Err(CliError(format!("unexpected argument '{}' for '{}'", arg, name)));
+            }
+            continue;
+        };
+
+        // Accept both `--option value` and `--option=value`
+        let (option, inline_value) = match option.split_once('=') {
+            Some((option, value)) => (option, Some(value.to_string())),
+            None => (option, None),
+        };
+        let mut value = || {
+            inline_value
+                .clone()
+                .or_else(|| args.next().cloned())
+                .ok_or_else(|| CliError(format!("option '--{}' needs a value", option)))
+        };
+
+        match option {
+            "config" if takes_settings => settings.config = Some(value()?),
+            "quirks" if takes_settings => settings.quirks = Some(value()?),
+            "seed" if takes_settings => {
+                // The seed is applied as a config setting, and TOML integers are i64
+                let seed: u64 = parse_number(option, &value()?)?;
+                if seed > i64::MAX as u64 {
+                    return Err(CliError(format!("'--seed' must be at most {}", i64::MAX)));
+                }
+                settings.seed = Some(seed);
+            }
+            "frames" if takes_frames => frames = parse_number(option, &value()?)?,
+            "output" if takes_output => output = Some(value()?),
+            "port" if name == "gdb" => port = parse_number(option, &value()?)?,
+            "seconds" if name == "speed" => {
+                let seconds: f64 = parse_number(option, &value()?)?;
+                // `Duration` rejects negative, NaN and too large values; zero is rejected here
+                duration = match Duration::try_from_secs_f64(seconds) {
+                    Ok(duration) if !duration.is_zero() => duration,
+                    _ => {
+                        let max = Duration::MAX.as_secs_f64();
+                        return Err(CliError(format!("'--seconds' must be a positive number up to {:e}", max)));
+                    }
+                };
+            }
+            setting if takes_settings && setting.contains('.') => {
+                settings.overrides.push((setting.to_string(), value()?));
+            }
+            _ => return Err(CliError(format!("unknown option '--{}' for '{}'", option, name))),
+        }
+    }
+
+    if name == "check-config" {
+        // `--check-config [file]` is shorthand for `--check-config --config file`
+        if let Some(config) = positional {
+            settings.config.get_or_insert(config);
+        }
+        return Ok(Command::CheckConfig { settings });
+    }
+    if name == "print-config" {
+        // The ROM is optional and only decides which `<rom>.toml` applies
+        settings.rom = positional;
+        return Ok(Command::PrintConfig { output, settings });
+    }
+
+    // `run` without a ROM opens the launcher
+    if name == "run" && positional.is_none() {
+        return Ok(Command::Run { rom: String::new(), settings });
+    }
+
+    let Some(path) = positional else {
+        let what = match name {
+            "asm" => "source file",
+            "coverage" => "coverage file",
+            _ => "ROM file",
+        };
+        return Err(CliError(format!("'{}' needs a {}", name, what)));
+    };
+
+    if takes_settings {
+        settings.rom = Some(path.clone());
+    }
+
+    Ok(match name {
+        "run" => Command::Run { rom: path, settings },
+        "headless" => Command::Headless { rom: path, frames, settings },
+        "record" => Command::Record { rom: path, frames, output, settings },
+        "speed" => Command::Speed { rom: path, duration, settings },
+        "gdb" => Command::Gdb { rom: path, port, settings },
+        "coverage" => Command::Coverage { files: std::iter::once(path).chain(more_files).collect(), output },
+        "disasm" => Command::Disasm { rom: path, output },
+        "asm" => Command::Asm { source: path, output },
+        "info" => Command::Info { rom: path },
+        _ => unreachable!(),
+    })
+}
+
+fn parse_number<T: std::str::FromStr>(option: &str, value: &str) -> Result<T, CliError> {
+    value
+        .parse()
+        .map_err(|_| CliError(format!("invalid number '{}' for '--{}'", value, option)))
+}
+
+impl SettingsArgs {
+    /// Every config file that may contribute settings, lowest priority first,
+    /// whether or not it currently exists
+    pub fn config_files(&self) -> Vec<PathBuf> {
+        let mut files = self.shared_config_files();
+        files.extend(self.rom_config_file());
+        files
+    }
+
+    /// The user and project config files, which apply to every ROM
+    fn shared_config_files(&self) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        files.extend(config_layers::user_config_file());
+        files.push(PathBuf::from(self.config.as_deref().unwrap_or(DEFAULT_CONFIG_FILE)));
+        files
+    }
+
+    /// Run a different ROM, with its own cartridge settings and config file
+    pub fn set_rom(&mut self, rom: &str) {
+        self.rom = Some(rom.to_string());
+        self.cartridge = OnceLock::new();
+    }
+
+    fn rom_config_file(&self) -> Option<PathBuf> {
+        self.rom.as_deref().and_then(config_layers::rom_config_file)
+    }
+
+    /// Build the settings for a run
+    pub fn load(&self) -> Result<Settings, Box<dyn std::error::Error>> {
+        Ok(self.load_layered()?.into_settings())
+    }
+
+    /// Merge every config file that exists, then the quirk profile, seed and
+    /// individual overrides on top. Invalid settings are an error rather than
+    /// silently replaced by defaults.
+    pub fn load_layered(&self) -> Result<LayeredSettings, Box<dyn std::error::Error>> {
+        // An explicit --config must exist; the other layers are optional
+        if let Some(config) = &self.config
+            && !Path::new(config).is_file()
+        {
+            return Err(CliError(format!("config file '{}' not found", config)).into());
+        }
+
+        let mut layered = LayeredSettings::new();
+        let mut loaded_any = false;
+        let mut apply_file = |layered: &mut LayeredSettings, file: PathBuf| -> Result<(), ConfigError> {
+            eprintln!("Loading config from: {}", file.display());
+            for warning in layered.apply_file(&file)? {
+                eprintln!("warning: {}: {}", file.display(), warning);
+            }
+            loaded_any = true;
+            Ok(())
+        };
+        for file in self.shared_config_files().into_iter().filter(|file| file.is_file()) {
+            apply_file(&mut layered, file)?;
+        }
+        self.apply_cartridge(&mut layered)?;
+        if let Some(file) = self.rom_config_file().filter(|file| file.is_file()) {
+            apply_file(&mut layered, file)?;
+        }
+        if !loaded_any {
+            eprintln!("No config file found. Using default settings.");
+        }
+
+        if let Some(profile) = &self.quirks {
+            layered.apply_quirk_profile(profile).map_err(CliError)?;
+        }
+        if let Some(seed) = self.seed {
+            layered.set("cpu.seed", &seed.to_string(), "--seed")?;
+        }
+        for (path, value) in &self.overrides {
+            layered
+                .set(path, value, &format!("--{}", path))
+                .map_err(|e| CliError(format!("--{}: {}", path, e)))?;
+        }
+
+        layered.check()?;
+        Ok(layered)
+    }
+
+    /// Apply the options of an Octo cartridge ROM
+    fn apply_cartridge(&self, layered: &mut LayeredSettings) -> Result<(), ConfigError> {
+        if let (Some(rom), Some(settings)) = (&self.rom, self.cartridge_settings()) {
+            eprintln!("Using the settings in the Octo cartridge {}", rom);
+            layered.apply_table(settings.clone(), &format!("cartridge {}", rom))?;
+        }
+        Ok(())
+    }
+
+    /// The settings in the ROM if it is an Octo cartridge, read once per ROM.
+    /// Other read errors are reported when the ROM is loaded, but a `.gif` that
+    /// fails to decode is warned about here, as its settings would otherwise go
+    /// missing without a word. Stdin can only be read once and is left for the
+    /// ROM itself.
+    fn cartridge_settings(&self) -> Option<&toml::Table> {
+        self.cartridge
+            .get_or_init(|| {
+                let rom = self.rom.as_deref().filter(|rom| !rom.is_empty() && *rom != "-")?;
+                match RomFile::read(rom) {
+                    Ok(file) => file.cartridge_settings,
+                    Err(e) => {
+                        let extension = RomSource::parse(rom).name_path().extension().unwrap_or_default();
+                        if extension.eq_ignore_ascii_case("gif") {
+                            eprintln!("warning: can't read the Octo cartridge settings: {}", e);
+                        }
+                        None
+                    }
+                }
+            })
+            .as_ref()
+    }
+}
+
+const SETTINGS_HELP: &str = "\
+Settings options:
+  --config <file>          Load settings from this file instead of chip8_config.toml
+  --quirks <profile>       Use the quirks of a platform: default, chip8, schip or xo-chip
+  --seed <n>               Seed the random number generator (0 = random)
+  --<section>.<setting> <value>
+                           Override any config file setting, e.g. --cpu.cycles-per-frame 20
+                           or --display.color.red-divisor 2
+
+Settings are merged from, lowest priority first: the built-in defaults, the user
+config file, chip8_config.toml (or --config), the options in an Octo cartridge,
+<rom>.toml next to the ROM, and the options above. Use --print-config to see where each setting came from.";
+
+/// Help text for the whole program, or for one subcommand
+pub fn usage(program: &str, topic: Option<&str>) -> Result<String, CliError> {
+    let text = match topic {
+        None => format!(
+            "CHIP-8 Emulator
+
+Usage: {program} <command> [options] <file>
+       {program} <rom_file> [options]      (same as 'run')
+       {program} --check-config [file]     (validate a config file and exit)
+       {program} --print-config [rom]      (show the effective settings and exit)
+
+Commands:
+  run       Play a ROM in a window, or pick one in the launcher
+  headless  Run a ROM without a window and print the final screen
+  record    Run a ROM without a window and save a GIF (and WAV) of it
+  speed     Run a ROM as fast as possible and report instructions per second
+  gdb       Debug a ROM from GDB or another remote protocol frontend
+  coverage  Merge coverage files and report what ran
+  disasm    Disassemble a ROM
+  asm       Assemble a source file into a ROM
+  info      Show information about a ROM
+  help      Show help for a command
+
+A ROM can be a file, a ZIP archive holding one ROM, an entry in an archive
+(games.zip:PONG.ch8), or - to read it from standard input. Octo cartridge
+GIFs are compiled and run with the settings they carry.
+
+Run '{program} help <command>' for the options of a command.
+
+Example: {program} PONG.ch8
+Example: {program} run PONG.ch8 --config chip8_config.toml --quirks schip"
+        ),
+        Some("run") => format!(
+            "Usage: {program} run [rom_file] [options]\n\n\
+             Play a ROM in a window. Without a ROM, the launcher opens to pick one. Edits to the config file are applied while running,\n\
+             except the window scale and RNG seed.\n\n{SETTINGS_HELP}"
+        ),
+        Some("headless") => format!(
+            "Usage: {program} headless <rom_file> [options]\n\n\
+             Run a ROM without a window or sound device and print the final screen.\n\n\
+             Options:\n  --frames <n>             Number of frames to run (default: {DEFAULT_HEADLESS_FRAMES})\n\n{SETTINGS_HELP}"
+        ),
+        Some("record") => format!(
+            "Usage: {program} record <rom_file> [options]\n\n\
+             Run a ROM without a window and record it to a GIF, plus a WAV if recording.record_audio is set.\n\n\
+             Options:\n  --frames <n>             Number of frames to record (default: {DEFAULT_HEADLESS_FRAMES})\n  \
+             -o, --output <file>      GIF to write (default: named after the ROM in recording.output_dir)\n\n{SETTINGS_HELP}"
+        ),
+        Some("speed") => format!(
+            "Usage: {program} speed <rom_file> [options]\n\n\
+             Run a ROM without a window or frame pacing for a while and report how many instructions\n\
+             per second the interpreter managed. Timers still tick once every cpu.cycles_per_frame instructions.\n\n\
+             Options:\n  --seconds <n>            How long to run (default: {DEFAULT_SPEED_SECONDS})\n\n{SETTINGS_HELP}"
+        ),
+        Some("gdb") => format!(
+            "Usage: {program} gdb <rom_file> [options]\n\n\
+             Run a ROM without a window under the control of a debugger speaking the GDB remote\n\
+             serial protocol. The server listens on localhost; connect with 'target remote :<port>'.\n\
+             Registers: v0-vf, i, pc, sp and the timers dt and st. Memory is the 4K address space.\n\
+             'monitor screen' prints the display and 'monitor reset' restarts the ROM. 'monitor help' lists\n\
+             the register watches, memory watches and conditional breakpoints that can be set.\n\n\
+             Options:\n  --port <n>               TCP port to listen on (default: {})\n\n{SETTINGS_HELP}",
+            gdb::DEFAULT_PORT
+        ),
+        Some("coverage") => format!(
+            "Usage: {program} coverage <file.cov>... [options]\n\n\
+             Merge the coverage recorded by runs with --coverage.enabled true, all of the same ROM, and\n\
+             write the result as a .cov file, an annotated disassembly (.asm) and a heatmap of memory (.png).\n\n\
+             Options:\n  -o, --output <path>      Path of the merged files without extension (default: {})",
+            coverage::DEFAULT_MERGED_OUTPUT
+        ),
+        Some("disasm") => format!(
+            "Usage: {program} disasm <rom_file> [options]\n\n\
+             Disassemble a ROM. The listing can be reassembled with 'asm'.\n\n\
+             Options:\n  -o, --output <file>      Write the listing to a file instead of stdout"
+        ),
+        Some("asm") => format!(
+            "Usage: {program} asm <source_file> [options]\n\n\
+             Assemble a source file into a ROM.\n\n\
+             Options:\n  -o, --output <file>      ROM to write (default: the source file with a .ch8 extension)"
+        ),
+        Some("info") => format!(
+            "Usage: {program} info <rom_file>\n\n\
+             Show a ROM's size, CRC32, the platform its instructions need and how often each\n\
+             instruction is used, with any warnings that apply before running it."
+        ),
+        Some("check-config") | Some("--check-config") => format!(
+            "Usage: {program} --check-config [file] [options]\n\n\
+             Validate the config layers, with [file] in place of chip8_config.toml, together\n\
+             with any overrides. Reports every invalid setting and unknown key, then exits.\n\n{SETTINGS_HELP}"
+        ),
+        Some("print-config") | Some("--print-config") => format!(
+            "Usage: {program} --print-config [rom_file] [options]\n\n\
+             Show the effective settings, each with the layer it came from, then exit.\n\
+             Give a ROM to include its <rom>.toml.\n\n\
+             Options:\n  -o, --output <file>      Also save the merged settings as a config file\n\n{SETTINGS_HELP}"
+        ),
+        Some(other) => return Err(CliError(format!("unknown command '{}'", other))),
+    };
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &str) -> Result<Command, CliError> {
+        parse(&args.split_whitespace().map(str::to_string).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn speed_takes_a_duration() {
+        let Ok(Command::Speed { duration, .. }) = parse_args("speed game.ch8 --seconds 2.5") else {
+            panic!("expected a speed command");
+        };
+        assert_eq!(duration, Duration::from_millis(2500));
+    }
+
+    #[test]
+    fn speed_rejects_durations_out_of_range() {
+        for seconds in ["0", "-1", "NaN", "inf", "1e20"] {
+            let result = parse_args(&format!("speed game.ch8 --seconds {}", seconds));
+            assert!(result.is_err(), "--seconds {} was accepted", seconds);
+        }
+    }
+
+    #[test]
+    fn seed_must_fit_in_a_config_file() {
+        let seed = |value: &str| match parse_args(&format!("headless game.ch8 --seed {}", value)) {
+            Ok(Command::Headless { settings, .. }) => Ok(settings.seed),
+            Ok(_) => panic!("expected a headless command"),
+            Err(e) => Err(e.0),
+        };
+        let max = i64::MAX as u64;
+        assert_eq!(seed(&max.to_string()), Ok(Some(max)));
+        assert_eq!(seed(&(max + 1).to_string()), Err(format!("'--seed' must be at most {}", max)));
+        assert!(seed("-1").is_err());
+
+        let settings = parse_args(&format!("--check-config --seed {}", max));
+        let Ok(Command::CheckConfig { settings }) = settings else {
+            panic!("expected a check-config command");
+        };
+        assert_eq!(settings.load().unwrap().cpu.seed, max);
+    }
+
+    #[test]
+    fn cartridge_settings_are_read_once_per_rom() {
+        let dir = tempfile::tempdir().unwrap();
+        let cartridge = |name: &str, tickrate: u32| {
+            let path = dir.path().join(name);
+            let data = crate::cartridge::tests::json_cartridge(serde_json::json!({
+                "program": ": main",
+                "options": { "tickrate": tickrate },
+            }));
+            std::fs::write(&path, data).unwrap();
+            path.to_string_lossy().into_owned()
+        };
+        let first = cartridge("first.gif", 20);
+        let second = cartridge("second.gif", 30);
+
+        let mut args = SettingsArgs::default();
+        args.set_rom(&first);
+        assert_eq!(args.load().unwrap().cpu.cycles_per_frame, 20);
+        // Reloads, e.g. after a config file changes, don't read the ROM again
+        std::fs::remove_file(&first).unwrap();
+        assert_eq!(args.load().unwrap().cpu.cycles_per_frame, 20);
+
+        args.set_rom(&second);
+        assert_eq!(args.load().unwrap().cpu.cycles_per_frame, 30);
+
+        // A broken cartridge is warned about and its settings skipped
+        let broken = dir.path().join("broken.gif");
+        std::fs::write(&broken, b"GIF89a").unwrap();
+        args.set_rom(&broken.to_string_lossy());
+        assert!(args.cartridge_settings().is_none());
+        assert!(args.load().is_ok());
+    }
+}
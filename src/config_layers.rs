@@ -0,0 +1,338 @@
+//! Settings assembled from several config files and the command line.
+//!
+//! Layers are applied in order, each overriding only the settings it mentions:
+//!   1. built-in defaults
+//!   2. the user config file (e.g. `~/.config/chip8/config.toml`)
+//!   3. the project file, `chip8_config.toml` in the current directory, or the
+//!      file given with `--config` instead
+//!   4. the options embedded in an Octo cartridge ROM
+//!   5. `<rom>.toml` next to the ROM, for per-game settings
+//!   6. command-line options
+//!
+//! The layer that set each value is remembered so `--print-config` can show
+//! where every effective setting came from.
+
+use crate::rom::{self, RomSource};
+use crate::settings::{ConfigError, ConfigIssue, QuirkSettings, Settings};
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+const APP_DIR_NAME: &str = "chip8";
+const USER_CONFIG_FILE: &str = "config.toml";
+const DEFAULT_ORIGIN: &str = "default";
+const ORIGIN_COLUMN: usize = 40;   // Line up the origin comments in `describe`
+
+/// The per-user configuration directory, following each platform's convention
+pub fn user_config_dir() -> Option<PathBuf> {
+    user_config_dir_from(|name| env::var_os(name))
+}
+
+/// `user_config_dir`, reading environment variables through `var`
+fn user_config_dir_from(var: impl Fn(&str) -> Option<OsString>) -> Option<PathBuf> {
+    let base = if cfg!(windows) {
+        var("APPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        var("HOME").map(|home| PathBuf::from(home).join("Library/Application Support"))
+    } else {
+        var("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| var("HOME").map(|home| PathBuf::from(home).join(".config")))
+    };
+    base.map(|dir| dir.join(APP_DIR_NAME))
+}
+
+pub fn user_config_file() -> Option<PathBuf> {
+    user_config_dir().map(|dir| dir.join(USER_CONFIG_FILE))
+}
+
+/// The per-ROM config file, `<rom>.toml` next to the ROM. For a ROM inside an
+/// archive it sits next to the archive, named after the entry; an archive
+/// given without an entry uses the name of the one ROM it holds.
+pub fn rom_config_file(rom: &str) -> Option<PathBuf> {
+    match RomSource::parse(rom) {
+        RomSource::Stdin => None,
+        RomSource::File(path) => Some(path.with_extension("toml")),
+        RomSource::Archive { archive, entry } => {
+            let entry = match entry {
+                Some(entry) => entry.to_string(),
+                None => rom::archive_rom(archive).ok()?,
+            };
+            // Only the extension goes; `with_extension` would also cut at a dot in the name
+            let stem = Path::new(&entry).file_stem()?;
+            Some(archive.with_file_name(format!("{}.toml", stem.to_string_lossy())))
+        }
+    }
+}
+
+/// Effective settings plus the layer each one came from
+pub struct LayeredSettings {
+    settings: Settings,
+    origins: BTreeMap<String, String>,
+}
+
+impl Default for LayeredSettings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LayeredSettings {
+    pub fn new() -> Self {
+        Self {
+            settings: Settings::default(),
+            origins: BTreeMap::new(),
+        }
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn into_settings(self) -> Settings {
+        self.settings
+    }
+
+    /// Where the value at `path` came from
+    pub fn origin(&self, path: &str) -> &str {
+        self.origins.get(path).map(String::as_str).unwrap_or(DEFAULT_ORIGIN)
+    }
+
+    /// Merge a config file over the current settings, returning its warnings
+    pub fn apply_file(&mut self, path: &Path) -> Result<Vec<ConfigIssue>, ConfigError> {
+        let (layer, warnings) = Settings::read_layer(path)?;
+        self.apply_table(layer, &path.display().to_string())?;
+        Ok(warnings)
+    }
+
+    /// Merge a partial settings table over the current settings
+    pub fn apply_table(&mut self, layer: toml::Table, source: &str) -> Result<(), ConfigError> {
+        let mut merged = self.to_table();
+        merge(&mut merged, layer, "", source, &mut self.origins);
+
+        // Callers pass type-checked values, so this only fails on a bug
+        self.settings = toml::Value::Table(merged).try_into().map_err(|e: toml::de::Error| ConfigError {
+            file: source.to_string(),
+            issues: vec![ConfigIssue { path: String::new(), message: e.message().to_string() }],
+        })?;
+        Ok(())
+    }
+
+    /// Replace every quirk with those of a named profile
+    pub fn apply_quirk_profile(&mut self, profile: &str) -> Result<(), String> {
+        self.settings.quirks = QuirkSettings::profile(profile).ok_or_else(|| {
+            format!(
+                "unknown quirk profile '{}' (expected one of: {})",
+                profile,
+                QuirkSettings::PROFILES.join(", ")
+            )
+        })?;
+
+        let source = format!("--quirks {}", profile);
+        if let Ok(toml::Value::Table(quirks)) = toml::Value::try_from(self.settings.quirks) {
+            for key in quirks.keys() {
+                self.origins.insert(format!("quirks.{}", key), source.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Override one setting by its dotted path (see `Settings::set_by_path`)
+    pub fn set(&mut self, path: &str, value: &str, source: &str) -> Result<(), String> {
+        self.settings.set_by_path(path, value)?;
+        self.origins.insert(path.replace('-', "_"), source.to_string());
+        Ok(())
+    }
+
+    /// Range-check the merged settings, naming the layer behind each bad value
+    pub fn check(&self) -> Result<(), ConfigError> {
+        self.settings.check().map_err(|mut error| {
+            for issue in &mut error.issues {
+                let origin = self.origin(&issue.path);
+                if origin != DEFAULT_ORIGIN {
+                    issue.message = format!("{} (set by {})", issue.message, origin);
+                }
+            }
+            error
+        })
+    }
+
+    /// The effective settings as TOML, each value commented with its origin
+    pub fn describe(&self) -> String {
+        let mut text = String::new();
+        describe_table(&self.to_table(), "", &self.origins, &mut text);
+        text.trim_start().to_string()
+    }
+
+    fn to_table(&self) -> toml::Table {
+        match toml::Value::try_from(&self.settings) {
+            Ok(toml::Value::Table(table)) => table,
+            _ => unreachable!("Settings always serializes to a table"),
+        }
+    }
+}
+
+/// Copy the known settings in `layer` over `base`, recording `source` for each one
+fn merge(base: &mut toml::Table, layer: toml::Table, prefix: &str, source: &str, origins: &mut BTreeMap<String, String>) {
+    for (key, value) in layer {
+        let path = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+        // Unknown keys were already reported as warnings
+        let Some(slot) = base.get_mut(&key) else { continue };
+        match (slot, value) {
+            (toml::Value::Table(inner), toml::Value::Table(layer_inner)) => {
+                merge(inner, layer_inner, &path, source, origins)
+            }
+            (slot, value) => {
+                *slot = value;
+                origins.insert(path, source.to_string());
+            }
+        }
+    }
+}
+
+fn describe_table(table: &toml::Table, prefix: &str, origins: &BTreeMap<String, String>, text: &mut String) {
+    if !prefix.is_empty() {
+        let _ = writeln!(text, "\n[{}]", prefix);
+    }
+    // TOML needs a table's own values before its sub-tables
+    for (key, value) in table.iter().filter(|(_, value)| !value.is_table()) {
+        let path = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+        let origin = origins.get(&path).map(String::as_str).unwrap_or(DEFAULT_ORIGIN);
+        let line = match value {
+            // Settings floats are f32; printing the widened f64 would show noise digits
+            toml::Value::Float(float) => format!("{} = {:?}", key, *float as f32),
+            _ => format!("{} = {}", key, value),
+        };
+        let _ = writeln!(text, "{:<width$} # {}", line, origin, width = ORIGIN_COLUMN);
+    }
+    for (key, value) in table {
+        if let toml::Value::Table(inner) = value {
+            let path = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+            describe_table(inner, &path, origins, text);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// The value and origin comment `describe` printed for `key` in `[section]`
+    fn described<'a>(text: &'a str, section: &str, key: &str) -> (&'a str, &'a str) {
+        let header = format!("[{}]", section);
+        let line = text
+            .lines()
+            .skip_while(|line| *line != header)
+            .find(|line| line.starts_with(&format!("{} = ", key)))
+            .unwrap_or_else(|| panic!("{}.{} not described in\n{}", section, key, text));
+        let (setting, origin) = line.split_once(" # ").unwrap();
+        (setting.split_once(" = ").unwrap().1.trim(), origin)
+    }
+
+    #[test]
+    fn later_layers_win_and_are_named_as_the_origin() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, text: &str| {
+            let path = dir.path().join(name);
+            fs::write(&path, text).unwrap();
+            path
+        };
+        let user = write("user.toml", "[cpu]\ncycles_per_frame = 11\ntarget_fps = 50\n[audio]\nbeep_volume = 0.5\n");
+        let project = write("chip8_config.toml", "[cpu]\ncycles_per_frame = 12\n[quirks]\nvf_reset = false\n");
+        let rom = write("PONG.toml", "[cpu]\ncycles_per_frame = 13\n[quirks]\nclip_sprites = false\n");
+
+        let mut layered = LayeredSettings::new();
+        for file in [&user, &project, &rom] {
+            assert!(layered.apply_file(file).unwrap().is_empty());
+        }
+        layered.set("cpu.cycles-per-frame", "14", "--cpu.cycles-per-frame").unwrap();
+
+        let settings = layered.settings();
+        assert_eq!(settings.cpu.cycles_per_frame, 14);
+        assert_eq!(settings.cpu.target_fps, 50);
+        assert_eq!(settings.audio.beep_volume, 0.5);
+        assert!(!settings.quirks.vf_reset);
+        assert!(!settings.quirks.clip_sprites);
+
+        let text = layered.describe();
+        let origin = |path: &Path| path.display().to_string();
+        assert_eq!(described(&text, "cpu", "cycles_per_frame"), ("14", "--cpu.cycles-per-frame"));
+        assert_eq!(described(&text, "cpu", "target_fps"), ("50", origin(&user).as_str()));
+        assert_eq!(described(&text, "audio", "beep_volume"), ("0.5", origin(&user).as_str()));
+        assert_eq!(described(&text, "quirks", "vf_reset"), ("false", origin(&project).as_str()));
+        assert_eq!(described(&text, "quirks", "clip_sprites"), ("false", origin(&rom).as_str()));
+        assert_eq!(described(&text, "cpu", "seed"), ("0", DEFAULT_ORIGIN));
+        assert_eq!(layered.origin("cpu.cycles_per_frame"), "--cpu.cycles-per-frame");
+        assert_eq!(layered.origin("quirks.clip_sprites"), origin(&rom));
+    }
+
+    #[test]
+    fn quirk_profile_replaces_every_quirk() {
+        let mut layered = LayeredSettings::new();
+        layered.set("quirks.vf_reset", "false", "rom.toml").unwrap();
+        layered.apply_quirk_profile("chip8").unwrap();
+        assert_eq!(layered.settings().quirks, QuirkSettings::profile("chip8").unwrap());
+        assert_eq!(layered.origin("quirks.vf_reset"), "--quirks chip8");
+        assert_eq!(layered.origin("quirks.shift_uses_vy"), "--quirks chip8");
+
+        // An override after the profile still wins
+        layered.set("quirks.vf_reset", "false", "--quirks.vf-reset").unwrap();
+        assert!(!layered.settings().quirks.vf_reset);
+        assert_eq!(layered.origin("quirks.vf_reset"), "--quirks.vf-reset");
+
+        assert!(layered.apply_quirk_profile("nope").unwrap_err().starts_with("unknown quirk profile 'nope'"));
+    }
+
+    #[test]
+    fn unknown_keys_are_warnings_and_bad_values_name_their_layer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, "[cpu]\ncycles_per_frame = 0\ncycles_per_fame = 10\n").unwrap();
+
+        let mut layered = LayeredSettings::new();
+        let warnings = layered.apply_file(&path).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].path, "cpu.cycles_per_fame");
+
+        let error = layered.check().unwrap_err();
+        assert_eq!(error.issues.len(), 1);
+        assert_eq!(error.issues[0].path, "cpu.cycles_per_frame");
+        assert!(error.issues[0].message.ends_with(&format!("(set by {})", path.display())), "{}", error.issues[0].message);
+    }
+
+    #[test]
+    fn describe_output_reads_back_as_the_same_settings() {
+        let mut layered = LayeredSettings::new();
+        layered.set("display.color.background", "[1, 2, 3, 255]", "--display.color.background").unwrap();
+        layered.set("audio.beep_volume", "0.3", "--audio.beep-volume").unwrap();
+        let read: Settings = toml::from_str(&layered.describe()).unwrap();
+        assert_eq!(toml::to_string(&read).unwrap(), toml::to_string(layered.settings()).unwrap());
+    }
+
+    #[test]
+    fn user_config_dir_follows_the_platform_convention() {
+        let vars = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| vars.iter().find(|(var, _)| *var == name).map(|(_, value)| OsString::from(value))
+        };
+        let dir = user_config_dir_from(vars(&[("HOME", "/home/ann"), ("XDG_CONFIG_HOME", "/xdg"), ("APPDATA", "C:/AppData")]));
+        let expected = if cfg!(windows) {
+            "C:/AppData/chip8"
+        } else if cfg!(target_os = "macos") {
+            "/home/ann/Library/Application Support/chip8"
+        } else {
+            "/xdg/chip8"
+        };
+        assert_eq!(dir, Some(PathBuf::from(expected)));
+        assert_eq!(user_config_dir_from(vars(&[])), None);
+
+        if cfg!(all(unix, not(target_os = "macos"))) {
+            // An empty XDG_CONFIG_HOME counts as unset
+            let dir = user_config_dir_from(vars(&[("HOME", "/home/ann"), ("XDG_CONFIG_HOME", "")]));
+            assert_eq!(dir, Some(PathBuf::from("/home/ann/.config/chip8")));
+        }
+    }
+}
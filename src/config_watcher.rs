@@ -0,0 +1,156 @@
+//! Reloads the config files while the emulator is running.
+//!
+//! Each file's modification time is polled rather than using OS notifications,
+//! which keeps this dependency-free and works the same everywhere. A reload
+//! goes through the same loader as startup, so command-line overrides still
+//! win and an invalid edit is reported and ignored instead of applied.
+
+use crate::cli::SettingsArgs;
+use crate::settings::Settings;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);   // How often the files are checked
+
+pub struct ConfigWatcher {
+    args: SettingsArgs,
+    paths: Vec<PathBuf>,
+    modified: Vec<Option<SystemTime>>,
+    last_poll: Instant,
+}
+
+impl ConfigWatcher {
+    /// Watch every config file `args` loads from. Files that don't exist yet
+    /// are picked up when created.
+    pub fn new(args: SettingsArgs) -> Self {
+        let mut watcher = Self {
+            args,
+            paths: Vec::new(),
+            modified: Vec::new(),
+            last_poll: Instant::now(),
+        };
+        watcher.refresh_paths();
+        watcher
+    }
+
+    fn refresh_paths(&mut self) {
+        self.paths = self.args.config_files();
+        self.modified = self.paths.iter().map(|path| modified_time(path)).collect();
+    }
+
+    /// Returns the new settings if a file changed since the last poll and they still load
+    pub fn poll(&mut self) -> Option<Settings> {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return None;
+        }
+        self.last_poll = Instant::now();
+
+        let modified: Vec<_> = self.paths.iter().map(|path| modified_time(path)).collect();
+        let changed = (0..modified.len()).find(|&i| modified[i] != self.modified[i])?;
+        self.modified = modified;
+
+        println!("{} changed, reloading...", self.paths[changed].display());
+        match self.args.load() {
+            Ok(settings) => Some(settings),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                eprintln!("Keeping the previous settings.");
+                None
+            }
+        }
+    }
+
+    /// Load the settings for a different ROM, whose own `<rom>.toml` replaces
+    /// the previous one's, and watch its files from now on
+    pub fn switch_rom(&mut self, rom: &str) -> Result<Settings, Box<dyn std::error::Error>> {
+        let mut args = self.args.clone();
+        args.set_rom(rom);
+        let settings = args.load()?;
+        self.args = args;
+        self.refresh_paths();
+        Ok(settings)
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `contents` to `path` and give it a modification time `age` seconds
+    /// from now, so changes show whatever the file system's time resolution
+    fn touch(path: &Path, contents: &str, age: u64) {
+        fs::write(path, contents).unwrap();
+        let file = fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(age)).unwrap();
+    }
+
+    /// Poll as if the interval has passed
+    fn poll_now(watcher: &mut ConfigWatcher) -> Option<Settings> {
+        watcher.last_poll = Instant::now() - POLL_INTERVAL;
+        watcher.poll()
+    }
+
+    fn args(config: &Path, rom: &Path) -> SettingsArgs {
+        let mut args = SettingsArgs::default();
+        args.config = Some(config.to_string_lossy().into_owned());
+        args.set_rom(&rom.to_string_lossy());
+        args
+    }
+
+    #[test]
+    fn reloads_when_a_file_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (config, rom) = (dir.path().join("config.toml"), dir.path().join("game.ch8"));
+        touch(&config, "[cpu]\ncycles_per_frame = 11\n", 0);
+        touch(&rom, "\u{12}\u{00}", 0);
+        let mut watcher = ConfigWatcher::new(args(&config, &rom));
+        assert!(poll_now(&mut watcher).is_none(), "nothing changed");
+
+        touch(&config, "[cpu]\ncycles_per_frame = 12\n", 10);
+        assert!(watcher.poll().is_none(), "not checked again before the interval");
+        assert_eq!(poll_now(&mut watcher).unwrap().cpu.cycles_per_frame, 12);
+        assert!(poll_now(&mut watcher).is_none(), "the change is only reported once");
+
+        // A file created later is picked up, on top of the other layers
+        touch(&dir.path().join("game.toml"), "[cpu]\ntarget_fps = 30\n", 20);
+        let settings = poll_now(&mut watcher).unwrap();
+        assert_eq!((settings.cpu.cycles_per_frame, settings.cpu.target_fps), (12, 30));
+
+        // An invalid edit is reported and the caller keeps its settings
+        touch(&config, "[cpu]\ncycles_per_frame = 0\n", 30);
+        assert!(poll_now(&mut watcher).is_none());
+    }
+
+    #[test]
+    fn switching_rom_watches_the_new_roms_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("config.toml");
+        let (first, second) = (dir.path().join("first.ch8"), dir.path().join("second.ch8"));
+        touch(&config, "[cpu]\ncycles_per_frame = 11\n", 0);
+        touch(&first, "\u{12}\u{00}", 0);
+        touch(&second, "\u{12}\u{00}", 0);
+        touch(&dir.path().join("first.toml"), "[cpu]\ncycles_per_frame = 20\n", 0);
+        touch(&dir.path().join("second.toml"), "[cpu]\ncycles_per_frame = 30\n", 0);
+
+        let mut watcher = ConfigWatcher::new(args(&config, &first));
+        assert!(watcher.paths.contains(&dir.path().join("first.toml")));
+        let settings = watcher.switch_rom(&second.to_string_lossy()).unwrap();
+        assert_eq!(settings.cpu.cycles_per_frame, 30);
+        assert!(!watcher.paths.contains(&dir.path().join("first.toml")));
+
+        touch(&dir.path().join("first.toml"), "[cpu]\ncycles_per_frame = 21\n", 10);
+        assert!(poll_now(&mut watcher).is_none(), "the old ROM's config is no longer watched");
+        touch(&dir.path().join("second.toml"), "[cpu]\ncycles_per_frame = 31\n", 10);
+        assert_eq!(poll_now(&mut watcher).unwrap().cpu.cycles_per_frame, 31);
+
+        // A ROM whose settings don't load is refused and the current one kept
+        touch(&dir.path().join("first.toml"), "[cpu]\ncycles_per_frame = 0\n", 20);
+        assert!(watcher.switch_rom(&first.to_string_lossy()).is_err());
+        assert!(watcher.paths.contains(&dir.path().join("second.toml")));
+    }
+}
@@ -1,7 +1,10 @@
 //! Shared architectural constants for the CHIP-8 emulator.
 //! 
 //! These constants define the fundamental aspects of the CHIP-8 architecture
-//! that are shared across multiple modules and should not be changed.
+//! that are shared across multiple modules and should not be changed, along
+//! with the frame format and timing shared by the host-side modules.
+
+use std::time::Duration;
 
 /// Display dimensions - CHIP-8 has a fixed 64x32 monochrome display
 pub const DISPLAY_WIDTH: usize = 64;
@@ -10,4 +13,14 @@ pub const DISPLAY_HEIGHT: usize = 32;
 /// Memory layout constants
 pub const PROGRAM_START_ADDRESS: u16 = 0x200;  // Programs are loaded starting at 0x200
 pub const FONT_START_ADDRESS: u16 = 0x50;      // Font data is stored starting at 0x50
-pub const INSTRUCTION_SIZE: u16 = 2;           // Each instruction is 2 bytes
\ No newline at end of file
+pub const INSTRUCTION_SIZE: u16 = 2;           // Each instruction is 2 bytes
+pub const ADDRESS_MAX: u16 = 0xFFF;            // Highest address in the 4K of memory
+
+/// Host-side frame format and timing
+pub const RGBA_PIXEL_SIZE: usize = 4;          // Bytes per RGBA pixel in rendered frames
+const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
+
+/// How long one frame lasts at `fps` frames per second
+pub fn frame_duration(fps: u32) -> Duration {
+    Duration::from_nanos(NANOSECONDS_PER_SECOND / fps as u64)
+}
@@ -0,0 +1,357 @@
+//! Code coverage of the 4K address space.
+//!
+//! With `coverage.enabled`, the CPU marks every byte it executes as an
+//! instruction, reads as data or writes. When the CPU is dropped the marks are
+//! written next to `coverage.output`:
+//!
+//! - `.cov`, the raw marks and the program they belong to, which `chip8
+//!   coverage` merges across runs
+//! - `.asm`, the program's disassembly with each word annotated, which still
+//!   assembles back into the ROM
+//! - `.png`, a heatmap of memory, one 8x8 cell per byte in 64 rows of 64
+//!
+//! With `coverage.merge`, a run adds its marks to an existing `.cov` for the
+//! same program, so coverage builds up over several runs with different input.
+
+use crate::constants::PROGRAM_START_ADDRESS;
+use crate::disasm;
+use crate::hex;
+use crate::memory::MEMORY_SIZE;
+use crate::settings::CoverageSettings;
+use image::{Rgb, RgbImage};
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+pub const EXECUTED: u8 = 0b001;
+pub const READ: u8 = 0b010;
+pub const WRITTEN: u8 = 0b100;
+
+pub const DEFAULT_MERGED_OUTPUT: &str = "chip8_coverage_merged";
+const FILE_VERSION: u32 = 1;
+const HEATMAP_COLUMNS: usize = 64;          // Bytes per heatmap row
+const HEATMAP_CELL_SIZE: u32 = 8;           // Pixels per byte in the heatmap
+
+// Heatmap colors
+const EXECUTED_COLOR: Rgb<u8> = Rgb([64, 200, 64]);
+const READ_COLOR: Rgb<u8> = Rgb([64, 128, 255]);
+const WRITTEN_COLOR: Rgb<u8> = Rgb([230, 64, 64]);
+const READ_WRITTEN_COLOR: Rgb<u8> = Rgb([200, 64, 220]);
+const UNTOUCHED_PROGRAM_COLOR: Rgb<u8> = Rgb([110, 110, 110]);  // Part of the ROM, never used
+const UNTOUCHED_COLOR: Rgb<u8> = Rgb([30, 30, 30]);
+
+/// What happened to each byte of memory during one or more runs
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoverageMap {
+    /// The program that was loaded at 0x200
+    pub program: Vec<u8>,
+    /// `EXECUTED`, `READ` and `WRITTEN` bits for each address
+    pub flags: Vec<u8>,
+}
+
+/// The `.cov` file: the program and the flags as hex strings
+#[derive(Serialize, Deserialize)]
+struct CoverageFile {
+    version: u32,
+    program: String,
+    flags: String,
+}
+
+impl CoverageMap {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let file: CoverageFile = serde_json::from_str(&text)
+            .map_err(|e| format!("{}: not a coverage file ({})", path.display(), e))?;
+        if file.version != FILE_VERSION {
+            return Err(format!("{}: unsupported coverage file version {}", path.display(), file.version));
+        }
+        let program = hex::decode(&file.program);
+        let flags = hex::decode(&file.flags);
+        match (program, flags) {
+            (Some(program), Some(flags)) if flags.len() == MEMORY_SIZE => Ok(Self { program, flags }),
+            _ => Err(format!("{}: the coverage data is damaged", path.display())),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let file = CoverageFile {
+            version: FILE_VERSION,
+            program: hex::encode(&self.program),
+            flags: hex::encode(&self.flags),
+        };
+        let text = serde_json::to_string(&file).map_err(|e| e.to_string())?;
+        fs::write(path, text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Add another run's marks. Both must be of the same program.
+    pub fn merge(&mut self, other: &CoverageMap) -> Result<(), String> {
+        if other.program != self.program {
+            return Err("the coverage is of a different program".to_string());
+        }
+        for (flags, other) in self.flags.iter_mut().zip(&other.flags) {
+            *flags |= other;
+        }
+        Ok(())
+    }
+
+    fn program_range(&self) -> std::ops::Range<usize> {
+        let start = PROGRAM_START_ADDRESS as usize;
+        start..(start + self.program.len()).min(MEMORY_SIZE)
+    }
+
+    /// How many of the program's bytes ran as code and were touched at all
+    pub fn summary(&self) -> String {
+        let range = self.program_range();
+        let program = &self.flags[range.clone()];
+        let executed = program.iter().filter(|&&flags| flags & EXECUTED != 0).count();
+        let used = program.iter().filter(|&&flags| flags != 0).count();
+        let percent = |count: usize| if range.is_empty() { 0.0 } else { count as f64 * 100.0 / range.len() as f64 };
+        format!(
+            "Coverage: {} of {} program bytes executed ({:.1}%), {} used as code or data ({:.1}%)",
+            executed,
+            range.len(),
+            percent(executed),
+            used,
+            percent(used)
+        )
+    }
+
+    /// The program's disassembly, each word annotated with what happened to it
+    pub fn annotated_disassembly(&self) -> String {
+        let mut listing = String::new();
+        let _ = writeln!(listing, "; {}", self.summary());
+        let _ = writeln!(listing, ";");
+        let range = self.program_range();
+        listing.push_str(&disasm::disassemble_annotated(&self.program, PROGRAM_START_ADDRESS, |address, size| {
+            let flags = (address..address + size)
+                .filter(|address| range.contains(address))
+                .fold(0, |flags, address| flags | self.flags[address]);
+            describe(flags)
+        }));
+        listing
+    }
+
+    pub fn heatmap(&self) -> RgbImage {
+        let rows = MEMORY_SIZE.div_ceil(HEATMAP_COLUMNS);
+        let range = self.program_range();
+        let mut image = RgbImage::new(
+            HEATMAP_COLUMNS as u32 * HEATMAP_CELL_SIZE,
+            rows as u32 * HEATMAP_CELL_SIZE,
+        );
+        for (address, &flags) in self.flags.iter().enumerate() {
+            let color = if flags & EXECUTED != 0 {
+                EXECUTED_COLOR
+            } else if flags & (READ | WRITTEN) == READ | WRITTEN {
+                READ_WRITTEN_COLOR
+            } else if flags & WRITTEN != 0 {
+                WRITTEN_COLOR
+            } else if flags & READ != 0 {
+                READ_COLOR
+            } else if range.contains(&address) {
+                UNTOUCHED_PROGRAM_COLOR
+            } else {
+                UNTOUCHED_COLOR
+            };
+            let x = (address % HEATMAP_COLUMNS) as u32 * HEATMAP_CELL_SIZE;
+            let y = (address / HEATMAP_COLUMNS) as u32 * HEATMAP_CELL_SIZE;
+            for dy in 0..HEATMAP_CELL_SIZE {
+                for dx in 0..HEATMAP_CELL_SIZE {
+                    image.put_pixel(x + dx, y + dy, color);
+                }
+            }
+        }
+        image
+    }
+
+    /// Write `<base>.cov`, `<base>.asm` and `<base>.png`
+    pub fn write_reports(&self, base: &str) -> Result<(), String> {
+        self.save(Path::new(&format!("{}.cov", base)))?;
+        let listing = format!("{}.asm", base);
+        fs::write(&listing, self.annotated_disassembly()).map_err(|e| format!("{}: {}", listing, e))?;
+        let heatmap = format!("{}.png", base);
+        self.heatmap().save(&heatmap).map_err(|e| format!("{}: {}", heatmap, e))?;
+        println!("{}", self.summary());
+        println!("Wrote coverage to {0}.cov, {0}.asm and {0}.png", base);
+        Ok(())
+    }
+}
+
+fn describe(flags: u8) -> String {
+    if flags == 0 {
+        return "untouched".to_string();
+    }
+    let marks = [(EXECUTED, "executed"), (READ, "read"), (WRITTEN, "written")];
+    let names: Vec<&str> = marks.iter().filter(|(bit, _)| flags & bit != 0).map(|(_, name)| *name).collect();
+    names.join(", ")
+}
+
+/// Marks memory as the CPU uses it. Reads happen through `&CPU`, so the flags
+/// are cells.
+pub struct CoverageRecorder {
+    settings: CoverageSettings,
+    program: Vec<u8>,
+    flags: Vec<Cell<u8>>,
+}
+
+impl CoverageRecorder {
+    /// A recorder for `settings`, or `None` when coverage is off
+    pub fn new(settings: &CoverageSettings) -> Option<Self> {
+        settings.enabled.then(|| Self {
+            settings: settings.clone(),
+            program: Vec::new(),
+            flags: vec![Cell::new(0); MEMORY_SIZE],
+        })
+    }
+
+    pub fn settings(&self) -> &CoverageSettings {
+        &self.settings
+    }
+
+    pub fn program(&self) -> &[u8] {
+        &self.program
+    }
+
+    /// Note the program loaded at 0x200, which the reports are about
+    pub fn set_program(&mut self, program: &[u8]) {
+        self.program = program.to_vec();
+    }
+
+    pub fn mark(&self, address: u16, flag: u8) {
+        if let Some(flags) = self.flags.get(address as usize) {
+            flags.set(flags.get() | flag);
+        }
+    }
+
+    fn map(&self) -> CoverageMap {
+        CoverageMap {
+            program: self.program.clone(),
+            flags: self.flags.iter().map(Cell::get).collect(),
+        }
+    }
+
+    /// Write the reports, merged with the existing `.cov` if asked to
+    pub fn write_reports(&self) -> Result<(), String> {
+        let base = &self.settings.output;
+        let mut map = self.map();
+        let previous = Path::new(&format!("{}.cov", base)).to_path_buf();
+        if self.settings.merge && previous.is_file() {
+            let previous = CoverageMap::load(&previous)?;
+            match map.merge(&previous) {
+                Ok(()) => println!("Merged with the coverage in {}.cov", base),
+                Err(e) => eprintln!("Not merging with {}.cov: {}", base, e),
+            }
+        }
+        map.write_reports(base)
+    }
+}
+
+impl Drop for CoverageRecorder {
+    fn drop(&mut self) {
+        // A CPU that never had a program, like the launcher's, has nothing to report
+        if self.program.is_empty() {
+            return;
+        }
+        if let Err(e) = self.write_reports() {
+            eprintln!("Failed to write the coverage: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    const PROGRAM: [u8; 5] = [0x00, 0xE0, 0x12, 0x00, 0xAB]; // CLS, JP 0x200, a data byte
+
+    fn map_with(marks: &[(usize, u8)]) -> CoverageMap {
+        let mut flags = vec![0; MEMORY_SIZE];
+        for &(address, flag) in marks {
+            flags[address] |= flag;
+        }
+        CoverageMap { program: PROGRAM.to_vec(), flags }
+    }
+
+    #[test]
+    fn merge_combines_runs_of_the_same_program() {
+        let mut map = map_with(&[(0x200, EXECUTED), (0x300, WRITTEN)]);
+        map.merge(&map_with(&[(0x200, READ), (0x204, READ)])).unwrap();
+        assert_eq!(map, map_with(&[(0x200, EXECUTED | READ), (0x204, READ), (0x300, WRITTEN)]));
+
+        let mut other = map_with(&[(0x202, EXECUTED)]);
+        other.program[0] = 0xFF;
+        assert_eq!(map.merge(&other), Err("the coverage is of a different program".to_string()));
+        assert_eq!(map.flags[0x202], 0, "a refused merge changes nothing");
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run.cov");
+        let map = map_with(&[(0x200, EXECUTED), (0x201, EXECUTED), (0xFFF, WRITTEN)]);
+        map.save(&path).unwrap();
+        assert_eq!(CoverageMap::load(&path).unwrap(), map);
+
+        for damaged in [
+            r#"{"version": 2, "program": "", "flags": ""}"#,
+            r#"{"version": 1, "program": "00e0", "flags": "00"}"#,
+            r#"{"version": 1, "program": "0", "flags": ""}"#,
+            "not json",
+        ] {
+            fs::write(&path, damaged).unwrap();
+            assert!(CoverageMap::load(&path).is_err(), "{} was loaded", damaged);
+        }
+    }
+
+    #[test]
+    fn recorder_merges_with_the_previous_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("coverage").to_string_lossy().into_owned();
+        let settings = CoverageSettings { enabled: true, output: output.clone(), merge: true };
+        for address in [0x200, 0x202] {
+            let mut recorder = CoverageRecorder::new(&settings).unwrap();
+            recorder.set_program(&PROGRAM);
+            recorder.mark(address, EXECUTED);
+        }
+        let merged = CoverageMap::load(Path::new(&format!("{}.cov", output))).unwrap();
+        assert_eq!(merged, map_with(&[(0x200, EXECUTED), (0x202, EXECUTED)]));
+        assert!(Path::new(&format!("{}.asm", output)).is_file());
+        assert!(Path::new(&format!("{}.png", output)).is_file());
+    }
+
+    #[test]
+    fn annotated_disassembly_still_assembles() {
+        let map = map_with(&[(0x200, EXECUTED), (0x201, EXECUTED), (0x204, READ | WRITTEN)]);
+        let listing = map.annotated_disassembly();
+        let lines: Vec<&str> = listing.lines().filter(|line| !line.starts_with(';')).collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with("  executed"), "{}", lines[0]);
+        assert!(lines[1].ends_with("  untouched"), "{}", lines[1]);
+        assert!(lines[2].ends_with("  read, written"), "{}", lines[2]);
+        assert!(listing.starts_with("; Coverage: 2 of 5 program bytes executed (40.0%), 3 used as code or data (60.0%)"));
+        assert_eq!(asm::assemble(&listing).unwrap(), PROGRAM);
+    }
+
+    #[test]
+    fn heatmap_colors() {
+        let map = map_with(&[(0x200, EXECUTED | WRITTEN), (0x201, READ), (0x202, WRITTEN), (0x203, READ | WRITTEN)]);
+        let image = map.heatmap();
+        let cell = |address: usize| {
+            let x = (address % HEATMAP_COLUMNS) as u32 * HEATMAP_CELL_SIZE;
+            let y = (address / HEATMAP_COLUMNS) as u32 * HEATMAP_CELL_SIZE;
+            // Every pixel of the cell has the same color
+            let color = *image.get_pixel(x, y);
+            assert_eq!(*image.get_pixel(x + HEATMAP_CELL_SIZE - 1, y + HEATMAP_CELL_SIZE - 1), color);
+            color
+        };
+        assert_eq!(cell(0x200), EXECUTED_COLOR, "execution wins");
+        assert_eq!(cell(0x201), READ_COLOR);
+        assert_eq!(cell(0x202), WRITTEN_COLOR);
+        assert_eq!(cell(0x203), READ_WRITTEN_COLOR);
+        assert_eq!(cell(0x204), UNTOUCHED_PROGRAM_COLOR);
+        assert_eq!(cell(0x205), UNTOUCHED_COLOR);
+        assert_eq!(cell(0x000), UNTOUCHED_COLOR);
+    }
+}
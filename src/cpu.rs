@@ -1,69 +1,316 @@
 
 use crate::memory::Memory;
-use crate::memory::Stack;
+use crate::decode_cache::{DecodeCache, Decoded};
+use crate::memory::{Stack, STACK_SIZE};
 use crate::reg::Registers;
 use crate::timer::Timers;
-use crate::display::{Display};
-use crate::opcodes::Opcode;
+use crate::display::{Display, DisplaySnapshot};
+use crate::opcodes::VF_REGISTER_INDEX;
 use crate::input::InputState;
 use crate::font::FONT_SET;
+use crate::rom::MAX_PROGRAM_SIZE;
 use crate::constants::{PROGRAM_START_ADDRESS, INSTRUCTION_SIZE, FONT_START_ADDRESS,
                         DISPLAY_WIDTH, DISPLAY_HEIGHT};
-use crate::settings::Settings;
-use std::sync::Arc;
+use crate::memory::MEMORY_SIZE;
+use crate::settings::{self, DisplaySettings, LiveSettings, QuirkSettings, Settings, CoverageSettings, ProfileSettings, Shared, TraceSettings};
+use crate::audio::{self, AudioBackend};
+use crate::coverage::{self, CoverageRecorder};
+use crate::profile::Profiler;
+use crate::trace::{TraceEntry, Tracer};
+use crate::watch::{Access, Register, Watches};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt;
 
 // CPU-specific constants
 const SPRITE_WIDTH: u8 = 8;                 // Standard sprite width
 const PIXEL_BIT_SHIFT: u8 = 7;              // Bit shift for pixel extraction
 
+/// Something a program did that the machine can't carry out. The CPU stops
+/// rather than guessing, so the fault can be reported along with the trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuFault {
+    /// The word at `address` isn't a CHIP-8 instruction
+    UnknownOpcode { address: u16, opcode: u16 },
+    /// 0NNN, a call into the original interpreter's machine code
+    MachineCodeCall { address: u16, opcode: u16 },
+    StackOverflow { address: u16 },
+    StackUnderflow { address: u16 },
+    /// An instruction reached `target`, past the end of memory
+    MemoryOutOfRange { address: u16, target: u32 },
+    /// Execution ran off the end of memory
+    ProgramCounterOutOfRange { address: u16 },
+}
+
+impl fmt::Display for CpuFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            CpuFault::UnknownOpcode { address, opcode } => {
+                write!(f, "unknown opcode {:04X} at {:#05X}", opcode, address)
+            }
+            CpuFault::MachineCodeCall { address, opcode } => {
+                write!(f, "machine code call {:04X} at {:#05X} (0NNN is not supported)", opcode, address)
+            }
+            CpuFault::StackOverflow { address } => {
+                write!(f, "stack overflow at {:#05X} (more than {} nested calls)", address, STACK_SIZE)
+            }
+            CpuFault::StackUnderflow { address } => write!(f, "return with an empty stack at {:#05X}", address),
+            CpuFault::MemoryOutOfRange { address, target } => {
+                write!(f, "instruction at {:#05X} reached address {:#X}, past the end of memory", address, target)
+            }
+            CpuFault::ProgramCounterOutOfRange { address } => {
+                write!(f, "program counter left memory at {:#X}", address)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CpuFault {}
+
 #[allow(clippy::upper_case_acronyms)]
 pub struct CPU {
     registers: Registers,
     memory: Memory,
+    decoded: DecodeCache, // Must be invalidated on every write to memory
     stack: Stack,
     timers: Timers,
     display: Display,
     input: InputState,
     program_counter: u16,
+    quirks: QuirkSettings,
+    rng: StdRng,
+    seed: u64, // 0 = random; kept so a hard reset replays the same random numbers
+    cycles: u64, // Instructions executed since power-on
+    instruction_address: u16, // Address of the instruction being executed
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    coverage: Option<CoverageRecorder>,
+    watches: Watches, // Set by the debugger
 }
 
 impl CPU {
-    #[allow(dead_code)]
+    /// A CPU with the default settings, playing sound on the default output
+    /// device. Opening a device is more than a `Default` impl should do.
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         Self::with_settings(Settings::default())
     }
     
     pub fn with_settings(settings: Settings) -> Self {
+        let audio = audio::open_default_backend(settings::shared(settings.audio.clone()));
+        Self::with_audio_backend(settings, audio)
+    }
+
+    /// Create a CPU whose display and sound follow `live`, so a config reload
+    /// changes them without restarting
+    pub fn with_live_settings(settings: &Settings, live: &LiveSettings) -> Self {
+        let audio = audio::open_default_backend(live.audio.clone());
+        Self::build(settings, live.display.clone(), audio)
+    }
+
+    /// Create a CPU that plays sound through `audio` instead of the default output device
+    pub fn with_audio_backend(settings: Settings, audio: Box<dyn AudioBackend>) -> Self {
+        Self::build(&settings, settings::shared(settings.display.clone()), audio)
+    }
+
+    fn build(settings: &Settings, display: Shared<DisplaySettings>, audio: Box<dyn AudioBackend>) -> Self {
         let mut cpu = Self {
             registers: Registers::new(),
             memory: Memory::new(),
+            decoded: DecodeCache::new(),
             stack: Stack::new(),
-            timers: Timers::with_settings(Arc::new(settings.audio)),
-            display: Display::with_settings(Arc::new(settings.display)),
+            timers: Timers::with_backend(audio),
+            display: Display::with_settings(display),
             input: InputState::new(),
             program_counter: PROGRAM_START_ADDRESS,
+            quirks: settings.quirks,
+            rng: new_rng(settings.cpu.seed),
+            seed: settings.cpu.seed,
+            cycles: 0,
+            instruction_address: PROGRAM_START_ADDRESS,
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            watches: Watches::default(),
         };
-        
+        cpu.load_font();
+        cpu.set_trace_settings(&settings.trace);
+        cpu.set_profile_settings(&settings.profile);
+        cpu.set_coverage_settings(&settings.coverage);
+        cpu
+    }
+
+    fn load_font(&mut self) {
         // Load font data into memory starting at 0x50
         for (i, &byte) in FONT_SET.iter().enumerate() {
-            cpu.memory.write(FONT_START_ADDRESS + i as u16, byte);
+            self.memory.write(FONT_START_ADDRESS + i as u16, byte);
         }
-        
-        cpu
     }
 
-    pub fn tick(&mut self) {
-        // fetch
-        let raw_opcode = self.memory.read_u16(self.program_counter);
+    /// Reset the processor as if the reset line were pulled: registers, stack,
+    /// timers, display and program counter start over, but memory keeps
+    /// whatever the program has written to it
+    pub fn soft_reset(&mut self) {
+        self.registers = Registers::new();
+        self.stack = Stack::new();
+        self.timers.set_delay(0);
+        self.timers.set_sound(0);
+        self.display.clear();
+        self.input.cancel_wait();
+        self.program_counter = PROGRAM_START_ADDRESS;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.reset_stack();
+        }
+    }
+
+    /// Power-cycle the machine: memory is wiped and re-seeded with the font and
+    /// `program`, and the random number generator restarts from its seed
+    pub fn hard_reset(&mut self, program: &[u8]) {
+        self.memory = Memory::new();
+        self.decoded.clear();
+        self.cycles = 0;
+        self.load_font();
+        self.rng = new_rng(self.seed);
+        self.soft_reset();
+        self.load_program(program);
+    }
+
+    /// Copy a program into memory at 0x200 and point the program counter at it.
+    /// Anything past the end of memory is dropped; `RomFile` refuses such ROMs first.
+    pub fn load_program(&mut self, program: &[u8]) {
+        for (i, byte) in program.iter().take(MAX_PROGRAM_SIZE).enumerate() {
+            self.memory.write(PROGRAM_START_ADDRESS + i as u16, *byte);
+        }
+        self.decoded.clear();
+        if let Some(coverage) = &mut self.coverage {
+            coverage.set_program(&program[..program.len().min(MAX_PROGRAM_SIZE)]);
+        }
+        self.set_program_counter(PROGRAM_START_ADDRESS);
+    }
+
+    pub fn tick(&mut self) -> Result<(), CpuFault> {
+        let address = self.program_counter;
+        if address as usize + 1 >= MEMORY_SIZE {
+            return self.fault(CpuFault::ProgramCounterOutOfRange { address });
+        }
+        self.instruction_address = address;
+
+        // fetch and decode, or find the instruction already decoded
+        let Decoded { raw: raw_opcode, opcode } = self.decoded.fetch(&self.memory, address);
 
         // increment program counter
         self.program_counter += INSTRUCTION_SIZE;
 
-        // decode
-        let opcode: Opcode = Opcode::from_raw(raw_opcode);
+        let Some(opcode) = opcode else {
+            return self.fault(CpuFault::UnknownOpcode { address, opcode: raw_opcode });
+        };
+        self.cycles += 1;
+        if let Some(coverage) = &self.coverage {
+            coverage.mark(address, coverage::EXECUTED);
+            coverage.mark(address + 1, coverage::EXECUTED);
+        }
 
         // execute
-        opcode.execute(self);
+        let traced = self.tracer.as_ref().is_some_and(|tracer| tracer.wants(address, &opcode));
+        let before = self.registers.data();
+        let result = opcode.execute(self);
+        if traced {
+            let entry = TraceEntry {
+                cycle: self.cycles,
+                address,
+                opcode: raw_opcode,
+                before,
+                after: self.registers.data(),
+                i: self.registers.get_i(),
+                delay_timer: self.timers.get_delay(),
+                sound_timer: self.timers.get_sound(),
+            };
+            if let Some(tracer) = &mut self.tracer {
+                tracer.record(entry);
+            }
+        }
+        self.watches.check_register(Register::Pc, address, self);
+        if let Some(profiler) = &mut self.profiler
+            && result.is_ok()
+        {
+            profiler.record(address, &opcode, self.registers.get_v(VF_REGISTER_INDEX));
+        }
+        result.or_else(|fault| self.fault(fault))
+    }
+
+    /// Report a fault to the tracer, which dumps its ring buffer
+    fn fault(&mut self, fault: CpuFault) -> Result<(), CpuFault> {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.dump(&fault);
+        }
+        Err(fault)
+    }
+
+    /// Run one frame's worth of instructions followed by a 60 Hz timer tick.
+    /// Returns whether the beeper sounded during the frame, or the fault that
+    /// stopped it.
+    pub fn run_frame(&mut self, cycles: u32) -> Result<bool, CpuFault> {
+        // Always execute at least one cycle per frame, even when waiting for key
+        // This allows the wait_for_key instruction to check if a key was pressed
+        for _ in 0..cycles {
+            self.tick()?;
+        }
+        let sound_on = self.is_sound_active();
+        self.update_timers();
+        Ok(sound_on)
+    }
+
+    /// Start, stop or reconfigure tracing. The ring buffer is kept unless the
+    /// settings changed.
+    pub fn set_trace_settings(&mut self, settings: &TraceSettings) {
+        if self.tracer.as_ref().map(Tracer::settings) == Some(settings) {
+            return;
+        }
+        self.tracer = None; // Flushes the previous output before the file is reopened
+        self.tracer = Tracer::new(settings).unwrap_or_else(|e| {
+            eprintln!("Failed to open trace output {}: {}", settings.output, e);
+            None
+        });
+    }
+
+    /// Start or stop profiling. Stopping, or changing the output, writes the
+    /// report so far.
+    pub fn set_profile_settings(&mut self, settings: &ProfileSettings) {
+        if self.profiler.as_ref().map(Profiler::settings) != Some(settings) {
+            self.profiler = None;
+            self.profiler = Profiler::new(settings);
+        }
+    }
+
+    /// Start or stop recording coverage. Stopping, or changing the output,
+    /// writes the coverage so far.
+    pub fn set_coverage_settings(&mut self, settings: &CoverageSettings) {
+        if self.coverage.as_ref().map(CoverageRecorder::settings) != Some(settings) {
+            let program = self.coverage.take().map(|coverage| coverage.program().to_vec()).unwrap_or_default();
+            self.coverage = CoverageRecorder::new(settings);
+            if let Some(coverage) = &mut self.coverage {
+                coverage.set_program(&program);
+            }
+        }
+    }
+
+    /// Instructions executed since power-on
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Address of the instruction being executed, for faults raised by it
+    pub fn instruction_address(&self) -> u16 {
+        self.instruction_address
+    }
+
+    /// Fail unless `length` bytes from `start` are all inside memory
+    pub fn check_memory_range(&self, start: u16, length: u16) -> Result<(), CpuFault> {
+        let end = start as u32 + length as u32;
+        if length > 0 && end > MEMORY_SIZE as u32 {
+            return Err(CpuFault::MemoryOutOfRange { address: self.instruction_address, target: end - 1 });
+        }
+        Ok(())
     }
 
     pub fn get_address_register(&self) -> u16 {
@@ -71,7 +318,9 @@ impl CPU {
     }
 
     pub fn set_address_register(&mut self, value: u16) {
+        let old = self.registers.get_i();
         self.registers.set_i(value);
+        self.watches.check_register(Register::I, old, self);
     }
 
     pub fn get_register(&self, index: u8) -> u8 {
@@ -79,22 +328,68 @@ impl CPU {
     }
 
     pub fn set_register(&mut self, index: u8, value: u8) {
+        let old = self.registers.get_v(index);
         self.registers.set_v(index, value);
+        self.watches.check_register(Register::V(index), old as u16, self);
     }
 
     pub fn read_memory(&self, address: u16) -> u8 {
-        self.memory.read(address)
+        let value = self.memory.read(address);
+        self.watches.check_memory(address, Access::Read, value, value);
+        if let Some(coverage) = &self.coverage {
+            coverage.mark(address, coverage::READ);
+        }
+        value
     }
 
     pub fn write_memory(&mut self, address: u16, value: u8) {
+        let old = self.memory.read(address);
+        self.memory.write(address, value);
+        self.decoded.invalidate(address);
+        self.watches.check_memory(address, Access::Write, old, value);
+        if let Some(coverage) = &self.coverage {
+            coverage.mark(address, coverage::WRITTEN);
+        }
+    }
+
+    /// Read memory for the debugger, without triggering watches
+    pub fn peek_memory(&self, address: u16) -> Option<u8> {
+        ((address as usize) < MEMORY_SIZE).then(|| self.memory.read(address))
+    }
+
+    /// Write memory for the debugger, without triggering watches
+    pub fn poke_memory(&mut self, address: u16, value: u8) {
         self.memory.write(address, value);
+        self.decoded.invalidate(address);
+    }
+
+    pub fn watches(&self) -> &Watches {
+        &self.watches
+    }
+
+    pub fn watches_mut(&mut self) -> &mut Watches {
+        &mut self.watches
     }
 
     pub fn pop_stack(&mut self) -> Option<u16> {
-        self.stack.pop()
+        let old = self.stack_depth();
+        let value = self.stack.pop();
+        self.watches.check_register(Register::Sp, old as u16, self);
+        value
+    }
+
+    pub fn push_stack(&mut self, value: u16) -> Result<(), CpuFault> {
+        let old = self.stack_depth();
+        self.stack
+            .push(value)
+            .map_err(|_| CpuFault::StackOverflow { address: self.instruction_address })?;
+        self.watches.check_register(Register::Sp, old as u16, self);
+        Ok(())
     }
-    pub fn push_stack(&mut self, value: u16) {
-        let _ = self.stack.push(value);
+
+    /// Number of return addresses on the stack, the stack pointer of the original interpreter
+    pub fn stack_depth(&self) -> u8 {
+        self.stack.depth() as u8
     }
 
     pub fn get_program_counter(&self) -> u16 {
@@ -113,15 +408,35 @@ impl CPU {
         self.display.clear();
     }
 
+    pub fn quirks(&self) -> QuirkSettings {
+        self.quirks
+    }
+
+    pub fn set_quirks(&mut self, quirks: QuirkSettings) {
+        self.quirks = quirks;
+    }
+
+    pub fn random_byte(&mut self) -> u8 {
+        self.rng.random()
+    }
+
     pub fn draw_sprite(&mut self, x: u8, y: u8, height: u8) -> bool {
         let mut collision = false;
         let addr = self.registers.get_i();
+        // The starting position always wraps; whether the sprite body does depends on the quirk
+        let (x, y) = (x % DISPLAY_WIDTH as u8, y % DISPLAY_HEIGHT as u8);
         
         for row in 0..height {
-            let sprite_byte = self.memory.read(addr + row as u16);
+            let sprite_byte = self.read_memory(addr + row as u16);
+            if self.quirks.clip_sprites && (y + row) as usize >= DISPLAY_HEIGHT {
+                break;
+            }
             let y_pos = (y + row) % (DISPLAY_HEIGHT as u8);
             
             for col in 0..SPRITE_WIDTH {
+                if self.quirks.clip_sprites && (x + col) as usize >= DISPLAY_WIDTH {
+                    break;
+                }
                 let x_pos = (x + col) % (DISPLAY_WIDTH as u8);
                 let pixel = (sprite_byte >> (PIXEL_BIT_SHIFT - col)) & 1;
                 
@@ -143,7 +458,7 @@ impl CPU {
 
     pub fn wait_for_key(&mut self, register: u8) -> bool {
         if let Some(key) = self.input.wait_for_key() {
-            self.registers.set_v(register, key);
+            self.set_register(register, key);
             true // Key was pressed, continue execution
         } else {
             false // Still waiting for key, don't advance PC
@@ -155,27 +470,58 @@ impl CPU {
     }
 
     pub fn set_delay_timer(&mut self, value: u8) {
+        let old = self.timers.get_delay();
         self.timers.set_delay(value);
+        self.watches.check_register(Register::Dt, old as u16, self);
+    }
+
+    pub fn get_sound_timer(&self) -> u8 {
+        self.timers.get_sound()
     }
 
     pub fn set_sound_timer(&mut self, value: u8) {
+        let old = self.timers.get_sound();
         self.timers.set_sound(value);
+        self.watches.check_register(Register::St, old as u16, self);
+    }
+
+    pub fn is_sound_active(&self) -> bool {
+        self.timers.is_sound_active()
     }
 
     pub fn render_to_buffer(&mut self, buffer: &mut [u8]) {
         self.display.render_to_buffer(buffer);
     }
 
+    pub fn display_snapshot(&self) -> DisplaySnapshot {
+        self.display.snapshot()
+    }
+
+    /// The 60 Hz tick that ends a frame
     pub fn update_timers(&mut self) {
+        let (delay, sound) = (self.timers.get_delay(), self.timers.get_sound());
         self.timers.tick();
+        self.watches.check_register(Register::Dt, delay as u16, self);
+        self.watches.check_register(Register::St, sound as u16, self);
+        if let Some(profiler) = &mut self.profiler {
+            profiler.end_frame();
+        }
     }
 
-    pub fn handle_key_press(&mut self, key: winit::keyboard::KeyCode) {
-        self.input.handle_key_press(key);
+    /// Press a key of the CHIP-8 keypad by its value
+    pub fn press_key(&mut self, key: u8) {
+        self.input.press(key);
     }
 
-    pub fn handle_key_release(&mut self, key: winit::keyboard::KeyCode) {
-        self.input.handle_key_release(key);
+    pub fn release_key(&mut self, key: u8) {
+        self.input.release(key);
     }
 
-}
\ No newline at end of file
+}
+
+fn new_rng(seed: u64) -> StdRng {
+    match seed {
+        0 => StdRng::from_os_rng(),
+        seed => StdRng::seed_from_u64(seed),
+    }
+}
@@ -0,0 +1,145 @@
+//! Decoded instructions, kept by address.
+//!
+//! `CPU::tick` fetches and decodes an instruction the first time it runs and
+//! reuses the result after that, so loops skip both. A write to memory has to
+//! drop the instructions covering the written byte, the one starting there and
+//! the one starting a byte before it, or self-modifying code would run stale
+//! instructions. The CPU does that in `write_memory` and `poke_memory`, and
+//! clears the cache when it loads a program or wipes memory.
+//!
+//! Instructions still run one `tick` at a time rather than in basic blocks:
+//! tracing, coverage, profiling, watches and the cycles-per-frame budget all
+//! work per instruction, and with decoding gone the dispatch is cheap.
+
+use crate::memory::{Memory, MEMORY_SIZE};
+use crate::opcodes::Opcode;
+
+/// An instruction word and what it decodes to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decoded {
+    pub raw: u16,
+    /// `None` when the word isn't a CHIP-8 instruction
+    pub opcode: Option<Opcode>,
+}
+
+pub struct DecodeCache {
+    slots: Box<[Option<Decoded>]>, // One per address an instruction can start at
+}
+
+impl Default for DecodeCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        Self { slots: vec![None; MEMORY_SIZE - 1].into_boxed_slice() }
+    }
+
+    /// The instruction at `address`, decoded from `memory` unless it is
+    /// already cached. `address` must leave room for both bytes.
+    pub fn fetch(&mut self, memory: &Memory, address: u16) -> Decoded {
+        let slot = &mut self.slots[address as usize];
+        *slot.get_or_insert_with(|| {
+            let raw = memory.read_u16(address);
+            Decoded { raw, opcode: Opcode::decode(raw) }
+        })
+    }
+
+    /// Forget the instructions that include the byte at `address`
+    pub fn invalidate(&mut self, address: u16) {
+        let address = address as usize;
+        for start in address.saturating_sub(1)..=address.min(self.slots.len() - 1) {
+            self.slots[start] = None;
+        }
+    }
+
+    /// Forget every instruction
+    pub fn clear(&mut self) {
+        self.slots.fill(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::SilentBackend;
+    use crate::cpu::CPU;
+    use crate::settings::Settings;
+
+    fn memory_with(address: u16, bytes: &[u8]) -> Memory {
+        let mut memory = Memory::new();
+        for (i, &byte) in bytes.iter().enumerate() {
+            memory.write(address + i as u16, byte);
+        }
+        memory
+    }
+
+    #[test]
+    fn fetch_decodes_once_and_keeps_the_result() {
+        let mut cache = DecodeCache::new();
+        let memory = memory_with(0x200, &[0x6A, 0x42]);
+        let decoded = cache.fetch(&memory, 0x200);
+        assert_eq!(decoded, Decoded { raw: 0x6A42, opcode: Opcode::decode(0x6A42) });
+
+        // Memory changed behind the cache's back, so the stale word is returned
+        let changed = memory_with(0x200, &[0x6B, 0x42]);
+        assert_eq!(cache.fetch(&changed, 0x200), decoded);
+    }
+
+    #[test]
+    fn invalidate_drops_both_instructions_covering_a_byte() {
+        let mut cache = DecodeCache::new();
+        let memory = memory_with(0x200, &[0x6A, 0x42, 0x6B]);
+        cache.fetch(&memory, 0x200);
+        cache.fetch(&memory, 0x201);
+        cache.fetch(&memory, 0x202);
+
+        let changed = memory_with(0x200, &[0x6A, 0x43, 0x6B]);
+        cache.invalidate(0x201);
+        assert_eq!(cache.fetch(&changed, 0x200).raw, 0x6A43);
+        assert_eq!(cache.fetch(&changed, 0x201).raw, 0x436B);
+        // The instruction at 0x202 doesn't include 0x201
+        assert_eq!(cache.fetch(&changed, 0x202).raw, 0x6B00);
+    }
+
+    #[test]
+    fn invalidate_handles_both_ends_of_memory() {
+        let mut cache = DecodeCache::new();
+        cache.invalidate(0x000);
+        cache.invalidate((MEMORY_SIZE - 1) as u16);
+    }
+
+    #[test]
+    fn unknown_words_are_cached_as_such() {
+        let mut cache = DecodeCache::new();
+        let memory = memory_with(0x200, &[0xFF, 0xFF]);
+        assert_eq!(cache.fetch(&memory, 0x200), Decoded { raw: 0xFFFF, opcode: None });
+    }
+
+    #[test]
+    fn self_modifying_code_runs_the_new_instruction() {
+        // Run the instruction at 0x206 once, then overwrite it with FX55 and run it again
+        let program = [
+            0x60, 0x62, // 0x200: LD V0, 0x62    high byte of LD V2, 0x22
+            0x61, 0x22, // 0x202: LD V1, 0x22    its low byte
+            0xA2, 0x06, // 0x204: LD I, 0x206
+            0x62, 0x11, // 0x206: LD V2, 0x11    becomes LD V2, 0x22
+            0xF1, 0x55, // 0x208: LD [I], V1
+            0x12, 0x06, // 0x20A: JP 0x206
+        ];
+        let mut cpu = CPU::with_audio_backend(Settings::default(), Box::new(SilentBackend));
+        cpu.load_program(&program);
+        for _ in 0..5 {
+            cpu.tick().unwrap();
+        }
+        assert_eq!(cpu.get_register(2), 0x11);
+        for _ in 0..2 {
+            cpu.tick().unwrap();
+        }
+        assert_eq!(cpu.get_program_counter(), 0x208);
+        assert_eq!(cpu.get_register(2), 0x22);
+        assert_eq!(cpu.peek_memory(0x207), Some(0x22));
+    }
+}
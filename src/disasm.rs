@@ -0,0 +1,45 @@
+//! Disassembly listings for CHIP-8 programs.
+//!
+//! The listing is valid input for `asm`, so a ROM can be disassembled, edited
+//! and reassembled. Words that don't decode to an instruction (sprite data,
+//! usually) are emitted as `DW`, and a trailing odd byte as `DB`.
+
+use crate::opcodes::Opcode;
+use std::fmt::Write;
+
+const MNEMONIC_COLUMN_WIDTH: usize = 24;   // Pad mnemonics so the address comments line up
+
+pub fn disassemble(program: &[u8], origin: u16) -> String {
+    disassemble_annotated(program, origin, |_, _| String::new())
+}
+
+/// A listing with a note after each line's address comment. `annotate` gets
+/// the address and size of each word or byte.
+pub fn disassemble_annotated(program: &[u8], origin: u16, annotate: impl Fn(usize, usize) -> String) -> String {
+    let mut listing = String::new();
+
+    for (i, chunk) in program.chunks(2).enumerate() {
+        let address = origin as usize + i * 2;
+        let (text, raw) = match *chunk {
+            [hi, lo] => {
+                let raw = u16::from_be_bytes([hi, lo]);
+                let text = match Opcode::decode(raw) {
+                    Some(opcode) => opcode.to_string(),
+                    None => format!("DW {:#06X}", raw),
+                };
+                (text, format!("{:04X}", raw))
+            }
+            [byte] => (format!("DB {:#04X}", byte), format!("{:02X}", byte)),
+            _ => unreachable!(),
+        };
+        let note = annotate(address, chunk.len());
+        let _ = write!(listing, "    {:<width$}; {:#05X}: {}", text, address, raw, width = MNEMONIC_COLUMN_WIDTH);
+        if note.is_empty() {
+            listing.push('\n');
+        } else {
+            let _ = writeln!(listing, "  {}", note);
+        }
+    }
+
+    listing
+}
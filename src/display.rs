@@ -8,32 +8,42 @@ The carry flag (VF) is set to 1 if any screen pixels are flipped from set to uns
 This is used for collision detection. 
  */
 
-use crate::constants::{DISPLAY_WIDTH, DISPLAY_HEIGHT};
-use crate::settings::DisplaySettings;
-use std::sync::Arc;
+use crate::constants::{DISPLAY_WIDTH, DISPLAY_HEIGHT, RGBA_PIXEL_SIZE};
+use crate::settings::{self, DisplaySettings, Shared};
 
 // Display-specific constants that don't change
-const RGBA_PIXEL_SIZE: usize = 4;          // Bytes per RGBA pixel
+
+/// Packed copy of the 1-bit framebuffer, one `u64` per row (bit 63 is the leftmost pixel).
+pub type DisplaySnapshot = [u64; DISPLAY_HEIGHT];
 
 pub struct Display {
     display: [[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT], // 64x32 pixels, 1 bit per pixel
     phosphor: [[u8; DISPLAY_WIDTH]; DISPLAY_HEIGHT], // Phosphor decay values (0-255)
-    settings: Arc<DisplaySettings>,
+    settings: Shared<DisplaySettings>,
+    max_phosphor_value: u8, // Copied from the settings each frame, so drawing doesn't take the lock
 }
 
 
 
+impl Default for Display {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Display {
     #[allow(dead_code)]
     pub fn new() -> Self {
-        Self::with_settings(Arc::new(DisplaySettings::default()))
+        Self::with_settings(settings::shared(DisplaySettings::default()))
     }
     
-    pub fn with_settings(settings: Arc<DisplaySettings>) -> Self {
+    pub fn with_settings(settings: Shared<DisplaySettings>) -> Self {
+        let max_phosphor_value = settings.read().unwrap().max_phosphor_value;
         Self { 
             display: [[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
             phosphor: [[0; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
             settings,
+            max_phosphor_value,
         }
     }
 
@@ -47,6 +57,8 @@ impl Display {
     pub fn render_to_buffer(&mut self, buffer: &mut [u8]) {
         // Convert 64x32 boolean display to RGBA pixel buffer with phosphor simulation
         // Each pixel is 4 bytes (RGBA) // How fast phosphor decays
+        let settings = self.settings.read().unwrap();
+        self.max_phosphor_value = settings.max_phosphor_value;
         
         for y in 0..DISPLAY_HEIGHT {
             for x in 0..DISPLAY_WIDTH {
@@ -54,17 +66,45 @@ impl Display {
                 
                 // Update phosphor decay
                 if !self.display[y][x] && self.phosphor[y][x] > 0 {
-                    self.phosphor[y][x] = self.phosphor[y][x].saturating_sub(self.settings.phosphor_decay_rate);
+                    self.phosphor[y][x] = self.phosphor[y][x].saturating_sub(settings.phosphor_decay_rate);
                 }
                 
                 // Render based on phosphor value (not just on/off)
                 let brightness = self.phosphor[y][x];
                 
                 // Classic green phosphor color with brightness
-                buffer[pixel_index] = brightness / self.settings.color.red_divisor;     // R (slight red)
-                buffer[pixel_index + 1] = brightness / self.settings.color.green_divisor;          // G (full green)
-                buffer[pixel_index + 2] = brightness / self.settings.color.blue_divisor; // B (very slight blue)
-                buffer[pixel_index + 3] = 255;                                                      // A (always opaque)
+                let [r, g, b] = settings.color.phosphor_color(brightness);
+                buffer[pixel_index] = r;
+                buffer[pixel_index + 1] = g;
+                buffer[pixel_index + 2] = b;
+                buffer[pixel_index + 3] = 255; // A (always opaque)
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> DisplaySnapshot {
+        let mut snapshot = [0u64; DISPLAY_HEIGHT];
+        for (y, row) in self.display.iter().enumerate() {
+            for (x, &pixel) in row.iter().enumerate() {
+                if pixel {
+                    snapshot[y] |= 1 << (DISPLAY_WIDTH - 1 - x);
+                }
+            }
+        }
+        snapshot
+    }
+
+    /// Replace the framebuffer with a snapshot. Newly lit pixels get full phosphor,
+    /// so feeding consecutive snapshots reproduces the decay seen on screen.
+    pub fn load_snapshot(&mut self, snapshot: &DisplaySnapshot) {
+        let max_phosphor_value = self.settings.read().unwrap().max_phosphor_value;
+        for (y, &row) in snapshot.iter().enumerate() {
+            for x in 0..DISPLAY_WIDTH {
+                let pixel = (row >> (DISPLAY_WIDTH - 1 - x)) & 1 == 1;
+                if pixel && !self.display[y][x] {
+                    self.phosphor[y][x] = max_phosphor_value;
+                }
+                self.display[y][x] = pixel;
             }
         }
     }
@@ -84,7 +124,7 @@ impl Display {
         
         // If pixel is now on, set phosphor to max
         if self.display[y][x] {
-            self.phosphor[y][x] = self.settings.max_phosphor_value;
+            self.phosphor[y][x] = self.max_phosphor_value;
         }
         // If pixel turned off, phosphor will decay naturally
     }
@@ -0,0 +1,325 @@
+//! The CPU on a thread of its own, so a window drag or a vsync stall in the
+//! event loop doesn't stop the game.
+//!
+//! The window sends `Command`s, such as key presses, over a channel. For every
+//! emulated frame the thread sends back an `Event::Frame` with the beeper state
+//! and display contents, which the WAV recorder and instant replay need one of
+//! per frame. The rendered pixels go through a triple buffer instead: the
+//! thread always has a buffer to draw into, the window always has a finished
+//! frame to show, and a frame the window was too slow for is simply replaced.
+
+use crate::constants::{self, DISPLAY_HEIGHT, DISPLAY_WIDTH, RGBA_PIXEL_SIZE};
+use crate::cpu::{CpuFault, CPU};
+use crate::display::DisplaySnapshot;
+use crate::settings::{LiveSettings, Settings};
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender, TryIter};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+
+/// Requests from the window to the emulation thread
+pub enum Command {
+    KeyDown(u8),
+    KeyUp(u8),
+    /// Start `program`, the ROM called `name`, on a new CPU built from the
+    /// current settings
+    Load { program: Vec<u8>, name: String },
+    Reset { hard: bool },
+    ApplySettings(Box<Settings>),
+    /// Stop running frames, e.g. while the launcher is open
+    SetPaused(bool),
+    Shutdown,
+}
+
+/// News from the emulation thread
+// Nearly every event is a frame, so boxing it would only add an allocation per frame
+#[allow(clippy::large_enum_variant)]
+pub enum Event {
+    /// A frame finished at `emulated_time`
+    Frame { emulated_time: Duration, sound_on: bool, snapshot: DisplaySnapshot },
+    /// The program faulted; no more frames run until a reset or another ROM
+    Fault(CpuFault),
+}
+
+/// A rendered display, as RGBA pixels
+pub struct Frame {
+    pub pixels: Vec<u8>,
+    pub emulated_time: Duration,
+}
+
+impl Frame {
+    fn new() -> Self {
+        Self { pixels: vec![0; DISPLAY_WIDTH * DISPLAY_HEIGHT * RGBA_PIXEL_SIZE], emulated_time: Duration::ZERO }
+    }
+}
+
+/// The buffer between the writer's and the reader's, and whether the writer
+/// has put a frame there the reader hasn't taken yet
+struct Middle {
+    frame: Frame,
+    fresh: bool,
+}
+
+/// The emulation thread's side of the triple buffer
+struct FrameWriter {
+    back: Frame,
+    middle: Arc<Mutex<Middle>>,
+}
+
+impl FrameWriter {
+    /// Hand the back buffer over to the reader and take the middle one to draw into next
+    fn publish(&mut self) {
+        let mut middle = self.middle.lock().unwrap();
+        std::mem::swap(&mut self.back, &mut middle.frame);
+        middle.fresh = true;
+    }
+}
+
+/// The window's side of the triple buffer
+pub struct FrameReader {
+    front: Frame,
+    middle: Arc<Mutex<Middle>>,
+}
+
+impl FrameReader {
+    /// The newest finished frame, and whether it is new since the last call
+    pub fn latest(&mut self) -> (&Frame, bool) {
+        let mut middle = self.middle.lock().unwrap();
+        let fresh = std::mem::take(&mut middle.fresh);
+        if fresh {
+            std::mem::swap(&mut self.front, &mut middle.frame);
+        }
+        (&self.front, fresh)
+    }
+}
+
+fn triple_buffer() -> (FrameWriter, FrameReader) {
+    let middle = Arc::new(Mutex::new(Middle { frame: Frame::new(), fresh: false }));
+    let writer = FrameWriter { back: Frame::new(), middle: middle.clone() };
+    (writer, FrameReader { front: Frame::new(), middle })
+}
+
+/// Handle to the emulation thread. Dropping it stops the thread and waits for
+/// it, so the CPU gets to write its trace, profile and coverage files.
+pub struct EmulationThread {
+    commands: Sender<Command>,
+    events: Receiver<Event>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl EmulationThread {
+    /// Start the thread, paused with no program until a `Command::Load`.
+    /// Returns the frame reader separately so the window can hold on to a
+    /// frame while sending commands.
+    pub fn spawn(settings: &Settings, live: &LiveSettings) -> std::io::Result<(Self, FrameReader)> {
+        let (commands, command_receiver) = unbounded();
+        let (event_sender, events) = unbounded();
+        let (frames, reader) = triple_buffer();
+        let (settings, live) = (settings.clone(), live.clone());
+        // The CPU is built on the thread; its audio device can't move between threads
+        let thread = thread::Builder::new().name("emulation".to_string()).spawn(move || {
+            let emulation = Emulation {
+                settings,
+                live,
+                cpu: None,
+                program: Vec::new(),
+                rom_name: String::new(),
+                paused: false,
+                faulted: false,
+                emulated_time: Duration::ZERO,
+                next_frame: Instant::now(),
+                events: event_sender,
+                frames,
+            };
+            emulation.run(command_receiver);
+        })?;
+        Ok((Self { commands, events, thread: Some(thread) }, reader))
+    }
+
+    pub fn send(&self, command: Command) {
+        // Fails only if the thread has died, which `drop` reports
+        let _ = self.commands.send(command);
+    }
+
+    /// Events sent since the last call, without waiting for more
+    pub fn events(&self) -> TryIter<'_, Event> {
+        self.events.try_iter()
+    }
+}
+
+impl Drop for EmulationThread {
+    fn drop(&mut self) {
+        self.send(Command::Shutdown);
+        if let Some(thread) = self.thread.take()
+            && thread.join().is_err()
+        {
+            eprintln!("The emulation thread panicked");
+        }
+    }
+}
+
+/// `settings` for a CPU running the ROM called `rom_name`. Each ROM opened in
+/// the window gets its own profile, named after it, instead of overwriting
+/// the profile of the ROM before.
+fn for_rom(settings: &Settings, rom_name: &str) -> Settings {
+    let mut settings = settings.clone();
+    settings.profile.output = format!("{}_{}", settings.profile.output, rom_name);
+    settings
+}
+
+/// State owned by the emulation thread
+struct Emulation {
+    settings: Settings,
+    live: LiveSettings,
+    cpu: Option<CPU>, // None until a program is loaded
+    program: Vec<u8>, // The running ROM, kept for hard resets
+    rom_name: String,
+    paused: bool,
+    faulted: bool,
+    emulated_time: Duration, // Time elapsed on the emulated machine, used to timestamp recordings
+    next_frame: Instant,
+    events: Sender<Event>,
+    frames: FrameWriter,
+}
+
+impl Emulation {
+    fn run(mut self, commands: Receiver<Command>) {
+        loop {
+            let running = self.cpu.is_some() && !self.paused && !self.faulted;
+            let command = if running {
+                match commands.recv_deadline(self.next_frame) {
+                    Ok(command) => Some(command),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            } else {
+                match commands.recv() {
+                    Ok(command) => Some(command),
+                    Err(_) => return,
+                }
+            };
+            match command {
+                Some(Command::Shutdown) => return,
+                Some(command) => self.handle(command),
+                None => self.run_frame(),
+            }
+        }
+    }
+
+    fn handle(&mut self, command: Command) {
+        match command {
+            Command::KeyDown(key) => {
+                if let Some(cpu) = &mut self.cpu {
+                    cpu.press_key(key);
+                }
+            }
+            Command::KeyUp(key) => {
+                if let Some(cpu) = &mut self.cpu {
+                    cpu.release_key(key);
+                }
+            }
+            Command::Load { program, name } => {
+                // Drop the old CPU first so its audio device and output files are released
+                self.cpu = None;
+                self.rom_name = name;
+                let mut cpu = CPU::with_live_settings(&for_rom(&self.settings, &self.rom_name), &self.live);
+                cpu.load_program(&program);
+                self.cpu = Some(cpu);
+                self.program = program;
+                self.restart();
+            }
+            Command::Reset { hard } => {
+                if let Some(cpu) = &mut self.cpu {
+                    if hard {
+                        cpu.hard_reset(&self.program);
+                    } else {
+                        cpu.soft_reset();
+                    }
+                    self.restart();
+                }
+            }
+            Command::ApplySettings(settings) => {
+                if let Some(cpu) = &mut self.cpu {
+                    cpu.set_quirks(settings.quirks);
+                    cpu.set_trace_settings(&settings.trace);
+                    cpu.set_profile_settings(&for_rom(&settings, &self.rom_name).profile);
+                    cpu.set_coverage_settings(&settings.coverage);
+                }
+                self.settings = *settings;
+            }
+            Command::SetPaused(paused) => {
+                self.paused = paused;
+                self.next_frame = Instant::now();
+            }
+            Command::Shutdown => {}
+        }
+    }
+
+    /// Clear a fault and run the next frame straight away
+    fn restart(&mut self) {
+        self.faulted = false;
+        self.next_frame = Instant::now();
+    }
+
+    fn run_frame(&mut self) {
+        let Some(cpu) = &mut self.cpu else {
+            return;
+        };
+        let frame_duration = constants::frame_duration(self.settings.cpu.target_fps);
+        let sound_on = match cpu.run_frame(self.settings.cpu.cycles_per_frame) {
+            Ok(sound_on) => sound_on,
+            Err(fault) => {
+                self.faulted = true;
+                let _ = self.events.send(Event::Fault(fault));
+                false
+            }
+        };
+        self.emulated_time += frame_duration;
+
+        cpu.render_to_buffer(&mut self.frames.back.pixels);
+        self.frames.back.emulated_time = self.emulated_time;
+        self.frames.publish();
+        let _ = self.events.send(Event::Frame {
+            emulated_time: self.emulated_time,
+            sound_on,
+            snapshot: cpu.display_snapshot(),
+        });
+
+        // Keep to the frame rate, but don't rush to catch up after falling behind
+        let now = Instant::now();
+        self.next_frame = (self.next_frame + frame_duration).max(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn triple_buffer_hands_over_the_newest_frame() {
+        let (mut writer, mut reader) = triple_buffer();
+        assert!(!reader.latest().1, "nothing published yet");
+
+        for second in 1..=2 {
+            writer.back.emulated_time = Duration::from_secs(second);
+            writer.publish();
+        }
+        let (frame, fresh) = reader.latest();
+        assert!(fresh);
+        assert_eq!(frame.emulated_time, Duration::from_secs(2), "the older frame was replaced");
+
+        let (frame, fresh) = reader.latest();
+        assert!(!fresh);
+        assert_eq!(frame.emulated_time, Duration::from_secs(2), "the frame stays until a new one");
+    }
+
+    #[test]
+    fn each_rom_gets_its_own_profile() {
+        let mut settings = Settings::default();
+        settings.profile.output = "pong".to_string();
+        assert_eq!(for_rom(&settings, "PONG").profile.output, "pong_PONG");
+        assert_eq!(for_rom(&settings, "Maze").profile.output, "pong_Maze");
+        assert_eq!(for_rom(&settings, "Maze").trace, settings.trace);
+    }
+}
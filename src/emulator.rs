@@ -1,15 +1,18 @@
-use crate::cpu::CPU;
+use crate::config_watcher::ConfigWatcher;
+use crate::emulation_thread::{Command, EmulationThread, Event, FrameReader};
 use crate::gif_recorder::GifRecorder;
-use crate::constants::PROGRAM_START_ADDRESS;
-use crate::settings::Settings;
+use crate::input::InputState;
+use crate::launcher::{self, Launcher, LauncherAction, LAUNCHER_WIDTH, LAUNCHER_HEIGHT};
+use crate::rom::{RomFile, RomSource};
+use crate::replay::ReplayBuffer;
+use crate::wav_recorder::WavRecorder;
+use crate::settings::{LiveSettings, Settings};
 use pixels::{Pixels, SurfaceTexture};
 use winit::event::{KeyEvent, ElementState};
 use winit::keyboard::{KeyCode, PhysicalKey, ModifiersState};
 use winit::window::Window;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
-
-const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
+use std::time::Duration;
 
 #[derive(Clone, Default)]
 pub struct EmulatorConfig {
@@ -20,72 +23,194 @@ pub struct EmulatorConfig {
 
 
 pub struct Emulator {
-    cpu: CPU,
+    emulation: EmulationThread, // Runs the CPU
+    frames: FrameReader, // Frames rendered by the emulation thread
     pixels: Pixels<'static>,
+    window: Arc<Window>,
     config: EmulatorConfig,
-    last_update: Instant,
+    live: LiveSettings, // Settings shared with the display, audio and recorders
+    config_watcher: Option<ConfigWatcher>,
+    emulated_time: Duration, // Time elapsed on the emulated machine, as of the latest frame
     gif_recorder: GifRecorder,
+    wav_recorder: WavRecorder,
+    replay_buffer: ReplayBuffer,
     rom_name: String, // Store ROM name for filename generation
+    launcher: Launcher,
+    launcher_open: bool, // The launcher replaces the game display and pauses it
 }
 
 impl Emulator {
     pub fn new(window: Arc<Window>, config: EmulatorConfig) -> Result<Self, Box<dyn std::error::Error>> {
         let window_size = window.inner_size();
-        let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, window);
+        let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, window.clone());
         let (display_width, display_height) = crate::display::Display::get_dimensions();
         
         let pixels = pixels::PixelsBuilder::new(display_width, display_height, surface_texture)
             .enable_vsync(true)
             .build()?;
 
-        let mut cpu = CPU::with_settings(config.settings.clone());
-        let rom_name = Self::extract_rom_name(&config.rom_path);
-        
-        // Load ROM only if a path is provided
-        if !config.rom_path.is_empty() {
-            match crate::memory::RomFile::load_from_file(&config.rom_path) {
-                Ok(rom) => {
-                    println!("Loaded {} successfully!", config.rom_path);
-                    for (i, byte) in rom.data.iter().enumerate() {
-                        cpu.write_memory(PROGRAM_START_ADDRESS + i as u16, *byte);
-                    }
-                    cpu.set_program_counter(PROGRAM_START_ADDRESS);
-                }
-                Err(e) => {
-                    eprintln!("Failed to load {}: {}", config.rom_path, e);
-                    return Err(Box::new(std::io::Error::new(
-                        std::io::ErrorKind::NotFound,
-                        format!("ROM file not found: {}", config.rom_path)
-                    )));
-                }
-            }
+        // Read the ROM first so a bad path fails before any devices are opened
+        let program = if config.rom_path.is_empty() {
+            None
         } else {
-            eprintln!("No ROM file specified");
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                "No ROM file specified"
-            )));
-        }
+            let rom = RomFile::load_from_file(&config.rom_path)?;
+            rom.print_warnings(&config.rom_path);
+            Some(rom.data)
+        };
+
+        let live = LiveSettings::new(&config.settings);
+        let (emulation, frames) = EmulationThread::spawn(&config.settings, &live)?;
 
         // Initialize GIF recorder with settings
-        let gif_recorder = GifRecorder::with_settings(Arc::new(config.settings.recording.clone()));
+        let gif_recorder = GifRecorder::with_settings(live.recording.clone(), live.display.clone());
+        let wav_recorder = WavRecorder::with_settings(live.audio.clone());
+        let replay_capacity = Self::replay_capacity(&config.settings);
+        let rom_path = config.rom_path.clone();
         
-        Ok(Self {
-            cpu,
+        let mut emulator = Self {
+            emulation,
+            frames,
             pixels,
+            window,
             config,
-            last_update: Instant::now(),
+            live,
+            config_watcher: None,
+            emulated_time: Duration::ZERO,
             gif_recorder,
-            rom_name,
-        })
+            wav_recorder,
+            replay_buffer: ReplayBuffer::with_capacity(replay_capacity),
+            rom_name: Self::extract_rom_name(""),
+            launcher: Launcher::new(),
+            launcher_open: false,
+        };
+
+        // Without a ROM, start in the launcher
+        match program {
+            Some(program) => emulator.start_program(&rom_path, program),
+            None => emulator.open_launcher(),
+        }
+        Ok(emulator)
+    }
+
+    /// Start `program` on a new CPU and make `rom_path` the running ROM
+    fn start_program(&mut self, rom_path: &str, program: Vec<u8>) {
+        self.rom_name = Self::extract_rom_name(rom_path);
+        self.emulation.send(Command::Load { program, name: self.rom_name.clone() });
+        println!("Loaded {} successfully!", rom_path);
+
+        self.config.rom_path = rom_path.to_string();
+        self.window.set_title(&format!("CHIP-8 Emulator - {}", self.rom_name));
+        launcher::remember_recent(rom_path);
+    }
+
+    /// Replace the running game with another ROM, using that ROM's settings
+    pub fn switch_rom(&mut self, rom_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        // Read it first so a bad file leaves the current game untouched
+        let rom = RomFile::load_from_file(rom_path).map_err(|e| format!("can't load {}", e))?;
+        rom.print_warnings(rom_path);
+        let program = rom.data;
+        if let Some(watcher) = &mut self.config_watcher {
+            let settings = watcher.switch_rom(rom_path)?;
+            self.apply_settings(settings);
+        }
+
+        if self.gif_recorder.is_recording() {
+            self.toggle_recording()?;
+        }
+        self.replay_buffer.clear();
+        self.start_program(rom_path, program);
+        Ok(())
+    }
+
+    /// Switch to a ROM picked in the launcher or dropped on the window
+    pub fn open_rom(&mut self, rom_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.switch_rom(rom_path)?;
+        if self.launcher_open {
+            self.close_launcher();
+        }
+        Ok(())
+    }
+
+    /// Restart the running ROM. A soft reset keeps memory, so programs that
+    /// modify themselves carry on from their current state; a hard reset
+    /// reloads the ROM and font as if the machine were switched off and on.
+    pub fn reset(&mut self, hard: bool) {
+        if self.config.rom_path.is_empty() {
+            return;
+        }
+        self.emulation.send(Command::Reset { hard });
+        println!("{} reset", if hard { "Hard" } else { "Soft" });
+        self.replay_buffer.clear();
+    }
+
+    pub fn open_launcher(&mut self) {
+        self.launcher.open(&self.config.settings.launcher.rom_dir);
+        self.launcher_open = true;
+        self.emulation.send(Command::SetPaused(true));
+        if let Err(e) = self.pixels.resize_buffer(LAUNCHER_WIDTH, LAUNCHER_HEIGHT) {
+            eprintln!("Failed to resize display for the launcher: {}", e);
+        }
+    }
+
+    fn close_launcher(&mut self) {
+        let (display_width, display_height) = crate::display::Display::get_dimensions();
+        self.launcher_open = false;
+        self.emulation.send(Command::SetPaused(false));
+        if let Err(e) = self.pixels.resize_buffer(display_width, display_height) {
+            eprintln!("Failed to resize display: {}", e);
+        }
+    }
+
+    /// Escape backs out of the launcher into the running game. Returns false
+    /// when there is nothing to back out of and the app should quit instead.
+    pub fn handle_escape(&mut self) -> bool {
+        if self.launcher_open && !self.config.rom_path.is_empty() {
+            self.close_launcher();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn replay_capacity(settings: &Settings) -> usize {
+        (settings.recording.replay_seconds as usize).saturating_mul(settings.cpu.target_fps as usize)
+    }
+
+    /// Reload settings from the config file whenever `watcher` sees it change
+    pub fn watch_config(&mut self, watcher: ConfigWatcher) {
+        self.config_watcher = Some(watcher);
+    }
+
+    /// Switch to new settings without restarting. Recordings in progress keep
+    /// the settings they started with.
+    fn apply_settings(&mut self, settings: Settings) {
+        self.live.apply(&settings);
+        self.emulation.send(Command::ApplySettings(Box::new(settings.clone())));
+        self.replay_buffer.set_capacity(Self::replay_capacity(&settings));
+        self.config.settings = settings;
     }
 
-    fn extract_rom_name(rom_path: &str) -> String {
+    /// Apply settings reloaded from an edited config file. The window scale and
+    /// RNG seed only take effect on the next launch.
+    fn reload_settings(&mut self, settings: Settings) {
+        if settings.display.default_scale_factor != self.config.settings.display.default_scale_factor {
+            println!("Note: display.default_scale_factor takes effect after a restart");
+        }
+        if settings.cpu.seed != self.config.settings.cpu.seed {
+            println!("Note: cpu.seed takes effect after a restart");
+        }
+        self.apply_settings(settings);
+        println!("Settings reloaded.");
+    }
+
+    pub fn extract_rom_name(rom_path: &str) -> String {
         if rom_path.is_empty() {
             return "no_rom".to_string();
         }
         
-        std::path::Path::new(rom_path)
+        // Named after the ROM itself, not the archive holding it
+        RomSource::parse(rom_path)
+            .name_path()
             .file_stem()
             .and_then(|s| s.to_str())
             .unwrap_or("unknown")
@@ -96,6 +221,7 @@ impl Emulator {
     pub fn toggle_recording(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
         if self.gif_recorder.is_recording() {
             self.gif_recorder.stop_recording()?;
+            self.wav_recorder.stop_recording()?;
             println!("GIF recording stopped");
             Ok(false)
         } else {
@@ -106,53 +232,116 @@ impl Emulator {
             );
             self.gif_recorder.start_recording(&filename)?;
             println!("Started GIF recording: {}", filename);
+            
+            if self.config.settings.recording.record_audio {
+                let wav_filename = std::path::Path::new(&filename).with_extension("wav");
+                self.wav_recorder.start_recording(&wav_filename.to_string_lossy(), self.emulated_time)?;
+                println!("Started audio recording: {}", wav_filename.display());
+            }
             Ok(true)
         }
     }
 
+    pub fn save_replay(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.replay_buffer.is_empty() {
+            return Err("Instant replay is disabled or has no frames yet".into());
+        }
+
+        let frames = self.replay_buffer.render_frames(self.live.display.clone());
+        let filename = GifRecorder::generate_filename(
+            &self.rom_name,
+            &self.config.settings.recording.output_dir,
+            "chip8_{rom_name}_replay_{timestamp}"
+        );
+        self.gif_recorder.save_frames(&filename, frames)?;
+        println!("Saving instant replay: {}", filename);
+
+        if self.config.settings.recording.record_audio {
+            let wav_filename = std::path::Path::new(&filename).with_extension("wav");
+            self.wav_recorder.save_frames(&wav_filename.to_string_lossy(), &self.replay_buffer.sound())?;
+        }
+        Ok(())
+    }
+
     pub fn handle_keyboard_input(&mut self, event: &KeyEvent, modifiers: &ModifiersState) {
+        if self.launcher_open {
+            if let LauncherAction::Load(path) = self.launcher.handle_key(event)
+                && let Err(e) = self.open_rom(&path.to_string_lossy())
+            {
+                self.launcher.set_message(format!("error: {}", e));
+            }
+            return;
+        }
+
         if let PhysicalKey::Code(keycode) = event.physical_key {
             match event.state {
                 ElementState::Pressed => {
                     match keycode {
+                        KeyCode::KeyR if modifiers.control_key() && modifiers.shift_key() => {
+                            if let Err(e) = self.save_replay() {
+                                eprintln!("Replay error: {}", e);
+                            }
+                        }
                         KeyCode::KeyR if modifiers.control_key() => {
                             if let Err(e) = self.toggle_recording() {
                                 eprintln!("Recording error: {}", e);
                             }
                         }
-                        _ => self.cpu.handle_key_press(keycode),
+                        KeyCode::KeyO if modifiers.control_key() => self.open_launcher(),
+                        KeyCode::F5 => self.reset(modifiers.shift_key()),
+                        _ => {
+                            if let Some(key) = InputState::map_key_to_chip8(keycode) {
+                                self.emulation.send(Command::KeyDown(key));
+                            }
+                        }
+                    }
+                }
+                ElementState::Released => {
+                    if let Some(key) = InputState::map_key_to_chip8(keycode) {
+                        self.emulation.send(Command::KeyUp(key));
                     }
                 }
-                ElementState::Released => self.cpu.handle_key_release(keycode),
             }
         }
     }
 
 
+    /// Pick up reloaded settings and whatever the emulation thread has done since the last call
     pub fn update(&mut self) {
-        let now = Instant::now();
-        let elapsed = now.duration_since(self.last_update);
-        let frame_duration = Duration::from_nanos(NANOSECONDS_PER_SECOND / self.config.settings.cpu.target_fps as u64);
-        
-        if elapsed >= frame_duration {
-            // Always execute at least one cycle per frame, even when waiting for key
-            // This allows the wait_for_key instruction to check if a key was pressed
-            for _ in 0..self.config.settings.cpu.cycles_per_frame {
-                self.cpu.tick();
+        if let Some(settings) = self.config_watcher.as_mut().and_then(ConfigWatcher::poll) {
+            self.reload_settings(settings);
+        }
+
+        for event in self.emulation.events() {
+            match event {
+                Event::Frame { emulated_time, sound_on, snapshot } => {
+                    self.emulated_time = emulated_time;
+                    self.replay_buffer.push(emulated_time, sound_on, snapshot);
+                    if let Err(e) = self.wav_recorder.add_frame(emulated_time, sound_on) {
+                        eprintln!("Failed to add audio to WAV: {}", e);
+                    }
+                }
+                Event::Fault(fault) => eprintln!("CPU fault: {}. Press F5 to restart the ROM.", fault),
             }
-            
-            self.cpu.update_timers();
-            self.last_update = now;
         }
     }
 
     pub fn render(&mut self) -> Result<(), pixels::Error> {
         let frame = self.pixels.frame_mut();
-        self.cpu.render_to_buffer(frame);
-        
+        if self.launcher_open {
+            let display = self.live.display.read().unwrap();
+            self.launcher.render(frame, &display.color);
+            drop(display);
+            return self.pixels.render();
+        }
+
+        let (latest, fresh) = self.frames.latest();
+        frame.copy_from_slice(&latest.pixels);
+
         // Record frame if GIF recording is active
-        if self.gif_recorder.is_recording() {
-            if let Err(e) = self.gif_recorder.add_frame(frame) {
+        #[allow(clippy::collapsible_if)] // Newer lint than this code; left as written
+        if fresh && self.gif_recorder.is_recording() {
+            if let Err(e) = self.gif_recorder.add_frame(frame, latest.emulated_time) {
                 eprintln!("Failed to add frame to GIF: {}", e);
             }
         }
@@ -0,0 +1,856 @@
+//! A GDB remote serial protocol server, so a debugger frontend can drive the CPU.
+//!
+//! `chip8 gdb <rom>` listens on localhost and runs the ROM without a window,
+//! one `CPU::tick` at a time as the debugger asks. After connecting with
+//! `target remote :1234`, the debugger sees this register file, in order:
+//!
+//! | Registers | Size    | Notes                                      |
+//! |-----------|---------|--------------------------------------------|
+//! | v0 - vf   | 8 bits  |                                            |
+//! | i         | 16 bits | little-endian, like every multi-byte value |
+//! | pc        | 16 bits |                                            |
+//! | sp        | 8 bits  | return addresses on the stack; read-only   |
+//! | dt, st    | 8 bits  | the delay and sound timers                 |
+//!
+//! The address space is the 4K of `Memory`. Breakpoints are kept here rather
+//! than patched into memory, so a ROM reading its own code never sees them.
+//! While running, the timers tick every `cpu.cycles_per_frame` instructions
+//! and frames are paced at `cpu.target_fps`; Ctrl+C in the debugger stops it.
+//! `monitor screen` prints the display and `monitor reset` restarts the ROM.
+//!
+//! GDB's memory watchpoints map onto the CPU's `Watches`; register watches and
+//! conditional breakpoints, which GDB has no packets for, are set with
+//! `monitor watch` and `monitor break` (see `watch` for the conditions).
+
+use crate::audio::SilentBackend;
+use crate::constants::{self, ADDRESS_MAX};
+use crate::cpu::{CpuFault, CPU};
+use crate::headless;
+use crate::hex;
+use crate::memory::MEMORY_SIZE;
+use crate::settings::Settings;
+use crate::watch::{self, Access, Expr, MemoryWatch, Register, RegisterWatch, WatchHit};
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_PORT: u16 = 1234;
+const READ_CHUNK_SIZE: usize = 1024;
+const INTERRUPT: u8 = 0x03;                 // Sent by the debugger to stop a running target
+const PACKET_SIZE: usize = 0x4000;          // Largest packet we accept, advertised in qSupported
+
+const MONITOR_HELP: &str = "\
+Monitor commands:
+  screen                              Print the display
+  reset                               Restart the ROM
+  break <address> if <condition>      Stop at an address when a condition holds
+  watch <register> [if <condition>]   Stop when a register changes, or when the condition becomes true
+  watch read|write|access <start>[..<end>]
+                                      Stop when an instruction touches memory in the range
+  watches                             List the watches and conditional breakpoints
+  delete                              Delete them all
+Conditions use v0-vf, i, pc, sp, dt, st, [address] for memory, numbers,
++ - & | ^ ! == != < <= > >= && || and 'x in start..end'.
+";
+
+// Signals reported in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+// Register numbers, in the order of the `g` packet
+const REGISTER_I: usize = 16;
+const REGISTER_PC: usize = 17;
+const REGISTER_SP: usize = 18;
+const REGISTER_DT: usize = 19;
+const REGISTER_ST: usize = 20;
+const REGISTER_COUNT: usize = 21;
+
+/// Wait for a debugger on `port` and serve it until it detaches or kills the
+/// target, then print the screen as the ROM left it
+pub fn serve(settings: Settings, program: &[u8], port: u16) -> Result<(), Box<dyn std::error::Error>> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+    println!("Waiting for a debugger on {} (in GDB: target remote :{})", listener.local_addr()?, port);
+    let (stream, peer) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    println!("Debugger connected from {}", peer);
+
+    let mut session = Session::new(Connection::new(stream), settings, program);
+    let result = session.run();
+    print!("{}", headless::screen_text(&session.cpu.display_snapshot()));
+    match result {
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => println!("Debugger disconnected"),
+        result => result?,
+    }
+    Ok(())
+}
+
+/// The packet layer: framing, checksums and acknowledgements
+struct Connection {
+    stream: TcpStream,
+    pending: VecDeque<u8>, // Bytes received but not yet consumed
+    no_ack: bool,          // Set once the debugger agrees to QStartNoAckMode
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            pending: VecDeque::new(),
+            no_ack: false,
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        loop {
+            if let Some(byte) = self.pending.pop_front() {
+                return Ok(byte);
+            }
+            let mut buffer = [0u8; READ_CHUNK_SIZE];
+            let count = self.stream.read(&mut buffer)?;
+            if count == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            self.pending.extend(&buffer[..count]);
+        }
+    }
+
+    /// Check, without waiting, whether the debugger has asked to stop
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut buffer = [0u8; READ_CHUNK_SIZE];
+        let result = self.stream.read(&mut buffer);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(count) => self.pending.extend(&buffer[..count]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+        match self.pending.iter().position(|&byte| byte == INTERRUPT) {
+            Some(index) => {
+                self.pending.remove(index);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// The next packet's contents, or `None` for an interrupt
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Skip acknowledgements and anything else between packets
+            loop {
+                match self.read_byte()? {
+                    b'$' => break,
+                    INTERRUPT => return Ok(None),
+                    _ => {}
+                }
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    byte if data.len() < PACKET_SIZE => data.push(byte),
+                    _ => {}
+                }
+            }
+            let checksum_digits = [self.read_byte()?, self.read_byte()?];
+            let expected = std::str::from_utf8(&checksum_digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+            if !self.no_ack {
+                if expected != Some(checksum(&data)) {
+                    self.stream.write_all(b"-")?;
+                    continue;
+                }
+                self.stream.write_all(b"+")?;
+            }
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+
+    /// Send a packet, resending it until the debugger acknowledges it
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            if self.no_ack {
+                return Ok(());
+            }
+            loop {
+                match self.read_byte()? {
+                    b'+' => return Ok(()),
+                    b'-' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// What to do after handling a packet
+enum Action {
+    Reply(String),
+    Resume { step: bool },
+    /// End the session, sending the reply first if there is one
+    Quit(Option<String>),
+}
+
+struct Session {
+    connection: Connection,
+    cpu: CPU,
+    program: Vec<u8>, // Kept for `monitor reset`
+    breakpoints: BTreeSet<u16>,
+    conditional_breakpoints: Vec<(u16, Expr)>, // Set with `monitor break`
+    cycles_per_frame: u32,
+    frame_duration: Duration,
+    frame_cycles: u32, // Instructions run since the timers last ticked
+    last_stop: Stop,
+}
+
+/// Why the program stopped
+#[derive(Clone, Copy)]
+enum Stop {
+    Signal(u8),
+    Watch(WatchHit),
+}
+
+impl Session {
+    fn new(connection: Connection, settings: Settings, program: &[u8]) -> Self {
+        let cycles_per_frame = settings.cpu.cycles_per_frame;
+        let frame_duration = constants::frame_duration(settings.cpu.target_fps);
+        let mut cpu = CPU::with_audio_backend(settings, Box::new(SilentBackend));
+        cpu.load_program(program);
+        Self {
+            connection,
+            cpu,
+            program: program.to_vec(),
+            breakpoints: BTreeSet::new(),
+            conditional_breakpoints: Vec::new(),
+            cycles_per_frame,
+            frame_duration,
+            frame_cycles: 0,
+            last_stop: Stop::Signal(SIGTRAP),
+        }
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        loop {
+            let Some(packet) = self.connection.read_packet()? else {
+                // Already stopped; report where
+                self.connection.send(&stop_reply(Stop::Signal(SIGINT)))?;
+                continue;
+            };
+            match self.handle(&packet) {
+                Action::Reply(reply) => self.connection.send(&reply)?,
+                Action::Resume { step } => {
+                    let stop = self.resume(step)?;
+                    self.last_stop = stop;
+                    if let Stop::Watch(hit) = stop {
+                        let message = format!("Watch hit: {}\n", hit);
+                        print!("{}", message);
+                        // GDB reports memory watchpoints itself, but knows nothing of register watches
+                        if let WatchHit::Register { .. } = hit {
+                            self.connection.send(&format!("O{}", hex::encode(message.as_bytes())))?;
+                        }
+                    }
+                    self.connection.send(&stop_reply(stop))?;
+                }
+                Action::Quit(reply) => {
+                    if let Some(reply) = reply {
+                        self.connection.send(&reply)?;
+                    }
+                    println!("Debugger detached");
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, packet: &str) -> Action {
+        let reply = |text: &str| Action::Reply(text.to_string());
+        let Some(command) = packet.chars().next() else {
+            return reply("");
+        };
+        let args = &packet[command.len_utf8()..];
+        match command {
+            '?' => Action::Reply(stop_reply(self.last_stop)),
+            'g' => Action::Reply(self.read_registers()),
+            'G' => reply(if self.write_registers(args).is_some() { "OK" } else { "E01" }),
+            'p' => match usize::from_str_radix(args, 16).ok().and_then(|n| self.read_register(n)) {
+                Some(value) => Action::Reply(value),
+                None => reply("E01"),
+            },
+            'P' => reply(if self.write_register_packet(args).is_some() { "OK" } else { "E01" }),
+            'm' => match self.read_memory(args) {
+                Some(data) => Action::Reply(data),
+                None => reply("E01"),
+            },
+            'M' => reply(if self.write_memory(args).is_some() { "OK" } else { "E01" }),
+            'c' | 's' => {
+                if let Ok(address) = u16::from_str_radix(args, 16) {
+                    self.cpu.set_program_counter(address);
+                }
+                Action::Resume { step: command == 's' }
+            }
+            'Z' | 'z' => match self.set_breakpoint(args, command == 'Z') {
+                Some(true) => reply("OK"),
+                Some(false) => reply(""),
+                None => reply("E01"),
+            },
+            'H' | 'T' => reply("OK"),
+            'D' => Action::Quit(Some("OK".to_string())),
+            'k' => Action::Quit(None),
+            'q' | 'Q' => Action::Reply(self.query(packet)),
+            // Everything else, including vCont and binary X writes, is
+            // unsupported; an empty reply makes GDB fall back to the basics
+            _ => reply(""),
+        }
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+", PACKET_SIZE);
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return read_chunk(&target_description(), args).unwrap_or_else(|| "E01".to_string());
+        }
+        if let Some(command) = packet.strip_prefix("qRcmd,") {
+            return self.monitor(command);
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                self.connection.no_ack = true;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    /// Commands sent with `monitor`, hex-encoded both ways
+    fn monitor(&mut self, command: &str) -> String {
+        let command = hex::decode(command).map(|bytes| String::from_utf8_lossy(&bytes).trim().to_string());
+        let command = command.unwrap_or_default();
+        let (name, args) = command.split_once(' ').unwrap_or((&command, ""));
+        let output = match name {
+            "screen" => headless::screen_text(&self.cpu.display_snapshot()),
+            "reset" => {
+                self.cpu.hard_reset(&self.program);
+                self.frame_cycles = 0;
+                "ROM restarted\n".to_string()
+            }
+            "break" => self.add_conditional_breakpoint(args).unwrap_or_else(|e| format!("{}\n", e)),
+            "watch" => self.add_watch(args).unwrap_or_else(|e| format!("{}\n", e)),
+            "watches" => self.describe_watches(),
+            "delete" => {
+                self.conditional_breakpoints.clear();
+                self.cpu.watches_mut().clear();
+                "Deleted all watches and conditional breakpoints\n".to_string()
+            }
+            _ => MONITOR_HELP.to_string(),
+        };
+        hex::encode(output.as_bytes())
+    }
+
+    /// `break <address> if <condition>`
+    fn add_conditional_breakpoint(&mut self, args: &str) -> Result<String, String> {
+        let (address, condition) = args.split_once(" if ").ok_or("usage: break <address> if <condition>")?;
+        let address = parse_address(address.trim())?;
+        let condition = Expr::parse(condition)?;
+        let message = format!("Breakpoint at {:#05X} if {}\n", address, condition);
+        self.conditional_breakpoints.push((address, condition));
+        Ok(message)
+    }
+
+    /// `watch <register> [if <condition>]` or `watch read|write|access <start>[..<end>]`
+    fn add_watch(&mut self, args: &str) -> Result<String, String> {
+        let (target, rest) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+        let access = match target {
+            "read" => Some(Access::Read),
+            "write" => Some(Access::Write),
+            "access" => Some(Access::Any),
+            _ => None,
+        };
+        if let Some(access) = access {
+            let (start, end) = match rest.trim().split_once("..") {
+                Some((start, end)) => (parse_address(start)?, parse_range_end(end)?),
+                None => {
+                    let address = parse_address(rest.trim())?;
+                    (address, address + 1)
+                }
+            };
+            if start >= end {
+                return Err(format!("empty range {:#05X}..{:#05X}", start, end));
+            }
+            self.cpu.watches_mut().add_memory(MemoryWatch { start, end, access });
+            return Ok(format!("Watching {}s of {:#05X}..{:#05X}\n", access, start, end));
+        }
+
+        let register = Register::parse(target).ok_or_else(|| format!("'{}' is not a register or read, write or access", target))?;
+        let condition = match rest.trim().strip_prefix("if ") {
+            Some(condition) => Some(Expr::parse(condition)?),
+            None if rest.trim().is_empty() => None,
+            None => return Err("usage: watch <register> [if <condition>]".to_string()),
+        };
+        let watch = RegisterWatch { register, condition };
+        let message = format!("Watching {}\n", describe_register_watch(&watch));
+        self.cpu.watches_mut().add_register(watch);
+        Ok(message)
+    }
+
+    fn describe_watches(&self) -> String {
+        let mut text = String::new();
+        for (address, condition) in &self.conditional_breakpoints {
+            text.push_str(&format!("break {:#05X} if {}\n", address, condition));
+        }
+        for watch in self.cpu.watches().memory_watches() {
+            text.push_str(&format!("watch {} {:#05X}..{:#05X}\n", watch.access, watch.start, watch.end));
+        }
+        for watch in self.cpu.watches().register_watches() {
+            text.push_str(&format!("watch {}\n", describe_register_watch(watch)));
+        }
+        if text.is_empty() {
+            text.push_str("No watches or conditional breakpoints\n");
+        }
+        text
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        let pc = self.cpu.get_program_counter();
+        self.breakpoints.contains(&pc)
+            || self
+                .conditional_breakpoints
+                .iter()
+                .any(|(address, condition)| *address == pc && condition.evaluate(&self.cpu))
+    }
+
+    /// Run one instruction, or until a breakpoint, watch, fault or interrupt,
+    /// and return why it stopped
+    fn resume(&mut self, step: bool) -> io::Result<Stop> {
+        let mut next_frame = Instant::now() + self.frame_duration;
+        // Forget anything the debugger's own register writes set off
+        self.cpu.watches().take_hit();
+        loop {
+            if let Err(fault) = self.tick() {
+                println!("CPU fault: {}", fault);
+                // Leave the PC on the instruction that faulted
+                self.cpu.set_program_counter(fault_address(&fault));
+                return Ok(Stop::Signal(fault_signal(&fault)));
+            }
+            if let Some(hit) = self.cpu.watches().take_hit() {
+                return Ok(Stop::Watch(hit));
+            }
+            if step || self.at_breakpoint() {
+                return Ok(Stop::Signal(SIGTRAP));
+            }
+            if self.frame_cycles == 0 {
+                // A frame ended: let the debugger interrupt, then keep real-time pace
+                if self.connection.interrupted()? {
+                    return Ok(Stop::Signal(SIGINT));
+                }
+                let now = Instant::now();
+                if next_frame > now {
+                    std::thread::sleep(next_frame - now);
+                    next_frame += self.frame_duration;
+                } else {
+                    next_frame = now + self.frame_duration;
+                }
+            }
+        }
+    }
+
+    /// Execute one instruction, ticking the timers at the end of each frame
+    fn tick(&mut self) -> Result<(), CpuFault> {
+        self.cpu.tick()?;
+        self.frame_cycles += 1;
+        if self.frame_cycles >= self.cycles_per_frame {
+            self.cpu.update_timers();
+            self.frame_cycles = 0;
+        }
+        Ok(())
+    }
+
+    /// `Z`/`z` arguments: type,address,kind, where kind is the length for
+    /// watchpoints. Returns whether the type is supported.
+    fn set_breakpoint(&mut self, args: &str, insert: bool) -> Option<bool> {
+        let mut fields = args.split(',');
+        let kind = fields.next()?;
+        let address = u16::from_str_radix(fields.next()?, 16).ok()?;
+        let length = u16::from_str_radix(fields.next()?, 16).ok()?;
+        let access = match kind {
+            // Software and hardware breakpoints work the same way here
+            "0" | "1" => {
+                if insert {
+                    self.breakpoints.insert(address);
+                } else {
+                    self.breakpoints.remove(&address);
+                }
+                return Some(true);
+            }
+            "2" => Access::Write,
+            "3" => Access::Read,
+            "4" => Access::Any,
+            _ => return Some(false),
+        };
+        let watch = MemoryWatch { start: address, end: address.checked_add(length.max(1))?, access };
+        if insert {
+            self.cpu.watches_mut().add_memory(watch);
+        } else {
+            self.cpu.watches_mut().remove_memory(watch);
+        }
+        Some(true)
+    }
+
+    fn read_register(&self, number: usize) -> Option<String> {
+        let cpu = &self.cpu;
+        Some(match number {
+            0..=15 => hex::encode(&[cpu.get_register(number as u8)]),
+            REGISTER_I => hex::encode(&cpu.get_address_register().to_le_bytes()),
+            REGISTER_PC => hex::encode(&cpu.get_program_counter().to_le_bytes()),
+            REGISTER_SP => hex::encode(&[cpu.stack_depth()]),
+            REGISTER_DT => hex::encode(&[cpu.get_delay_timer()]),
+            REGISTER_ST => hex::encode(&[cpu.get_sound_timer()]),
+            _ => return None,
+        })
+    }
+
+    fn read_registers(&self) -> String {
+        (0..REGISTER_COUNT).filter_map(|number| self.read_register(number)).collect()
+    }
+
+    fn write_register(&mut self, number: usize, bytes: &[u8]) -> Option<()> {
+        let word = || Some(u16::from_le_bytes(bytes.try_into().ok()?));
+        let byte = || match bytes {
+            [value] => Some(*value),
+            _ => None,
+        };
+        match number {
+            0..=15 => self.cpu.set_register(number as u8, byte()?),
+            // I is 12 bits wide
+            REGISTER_I => self.cpu.set_address_register(word().filter(|&i| i <= ADDRESS_MAX)?),
+            REGISTER_PC => self.cpu.set_program_counter(word()?),
+            // The stack pointer can't be moved without inventing return addresses
+            REGISTER_SP => {
+                byte()?;
+            }
+            REGISTER_DT => self.cpu.set_delay_timer(byte()?),
+            REGISTER_ST => self.cpu.set_sound_timer(byte()?),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn write_registers(&mut self, args: &str) -> Option<()> {
+        let bytes = hex::decode(args)?;
+        let mut rest = bytes.as_slice();
+        for number in 0..REGISTER_COUNT {
+            let size = register_size(number);
+            let (value, remaining) = rest.split_at_checked(size)?;
+            self.write_register(number, value)?;
+            rest = remaining;
+        }
+        Some(())
+    }
+
+    /// `P` arguments: number=value
+    fn write_register_packet(&mut self, args: &str) -> Option<()> {
+        let (number, value) = args.split_once('=')?;
+        let number = usize::from_str_radix(number, 16).ok()?;
+        self.write_register(number, &hex::decode(value)?)
+    }
+
+    /// `m` arguments: address,length. Reads stop at the end of memory.
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (address, length) = parse_range(args)?;
+        if address >= MEMORY_SIZE {
+            return None;
+        }
+        let end = address.saturating_add(length).min(MEMORY_SIZE);
+        let bytes: Vec<u8> = (address..end).filter_map(|a| self.cpu.peek_memory(a as u16)).collect();
+        Some(hex::encode(&bytes))
+    }
+
+    /// `M` arguments: address,length:data
+    fn write_memory(&mut self, args: &str) -> Option<()> {
+        let (range, data) = args.split_once(':')?;
+        let (address, length) = parse_range(range)?;
+        let bytes = hex::decode(data)?;
+        if bytes.len() != length || address.saturating_add(length) > MEMORY_SIZE {
+            return None;
+        }
+        for (offset, byte) in bytes.into_iter().enumerate() {
+            self.cpu.poke_memory((address + offset) as u16, byte);
+        }
+        Some(())
+    }
+}
+
+fn stop_reply(stop: Stop) -> String {
+    match stop {
+        Stop::Signal(signal) => format!("S{:02x}", signal),
+        Stop::Watch(WatchHit::Memory { watch, address, .. }) => {
+            let kind = match watch.access {
+                Access::Write => "watch",
+                Access::Read => "rwatch",
+                Access::Any => "awatch",
+            };
+            format!("T{:02x}{}:{:x};", SIGTRAP, kind, address)
+        }
+        Stop::Watch(WatchHit::Register { .. }) => format!("S{:02x}", SIGTRAP),
+    }
+}
+
+fn describe_register_watch(watch: &RegisterWatch) -> String {
+    match &watch.condition {
+        Some(condition) => format!("{} if {}", watch.register, condition),
+        None => watch.register.to_string(),
+    }
+}
+
+/// A decimal or `0x` hexadecimal address in memory
+fn parse_address(text: &str) -> Result<u16, String> {
+    parse_bounded_address(text, MEMORY_SIZE - 1)
+}
+
+/// The exclusive end of an address range, which may be the end of memory
+fn parse_range_end(text: &str) -> Result<u16, String> {
+    parse_bounded_address(text, MEMORY_SIZE)
+}
+
+fn parse_bounded_address(text: &str, max: usize) -> Result<u16, String> {
+    watch::parse_number(text.trim())
+        .and_then(|value| u16::try_from(value).ok())
+        .filter(|&value| value as usize <= max)
+        .ok_or_else(|| format!("'{}' is not an address", text.trim()))
+}
+
+fn fault_address(fault: &CpuFault) -> u16 {
+    match *fault {
+        CpuFault::UnknownOpcode { address, .. }
+        | CpuFault::MachineCodeCall { address, .. }
+        | CpuFault::StackOverflow { address }
+        | CpuFault::StackUnderflow { address }
+        | CpuFault::MemoryOutOfRange { address, .. }
+        | CpuFault::ProgramCounterOutOfRange { address } => address,
+    }
+}
+
+fn fault_signal(fault: &CpuFault) -> u8 {
+    match fault {
+        CpuFault::UnknownOpcode { .. } | CpuFault::MachineCodeCall { .. } => SIGILL,
+        _ => SIGSEGV,
+    }
+}
+
+fn register_size(number: usize) -> usize {
+    match number {
+        REGISTER_I | REGISTER_PC => 2,
+        _ => 1,
+    }
+}
+
+/// The register file described for GDB, which has no CHIP-8 architecture of its own
+fn target_description() -> String {
+    let mut registers: Vec<(String, usize, &str)> =
+        (0..16).map(|n| (format!("v{:x}", n), 8, "uint8")).collect();
+    registers.push(("i".to_string(), 16, "data_ptr"));
+    registers.push(("pc".to_string(), 16, "code_ptr"));
+    for name in ["sp", "dt", "st"] {
+        registers.push((name.to_string(), 8, "uint8"));
+    }
+
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n  <feature name=\"org.chip8.core\">\n",
+    );
+    for (number, (name, bits, kind)) in registers.iter().enumerate() {
+        xml.push_str(&format!(
+            "    <reg name=\"{}\" bitsize=\"{}\" type=\"{}\" regnum=\"{}\"/>\n",
+            name, bits, kind, number
+        ));
+    }
+    xml.push_str("  </feature>\n</target>\n");
+    xml
+}
+
+/// Part of a `qXfer` object: offset,length, answered `m` when more follows and `l` at the end
+fn read_chunk(object: &str, args: &str) -> Option<String> {
+    let (offset, length) = parse_range(args)?;
+    let bytes = object.as_bytes();
+    let start = offset.min(bytes.len());
+    let end = start.saturating_add(length).min(bytes.len());
+    let marker = if end < bytes.len() { 'm' } else { 'l' };
+    Some(format!("{}{}", marker, String::from_utf8_lossy(&bytes[start..end])))
+}
+
+/// address,length in hex
+fn parse_range(args: &str) -> Option<(usize, usize)> {
+    let (address, length) = args.split_once(',')?;
+    Some((usize::from_str_radix(address, 16).ok()?, usize::from_str_radix(length, 16).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A session serving one end of a loopback connection, and the other end
+    fn session(program: &[u8]) -> (Session, TcpStream) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (Session::new(Connection::new(server), Settings::default(), program), client)
+    }
+
+    fn reply(session: &mut Session, packet: &str) -> String {
+        match session.handle(packet) {
+            Action::Reply(reply) => reply,
+            _ => panic!("'{}' didn't get a reply", packet),
+        }
+    }
+
+    /// Read exactly `count` bytes the server sent
+    fn received(client: &mut TcpStream, count: usize) -> String {
+        let mut buffer = vec![0; count];
+        client.read_exact(&mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn packets_are_acknowledged_when_their_checksum_matches() {
+        let (mut session, mut client) = session(&[]);
+        client.write_all(b"+$g#67").unwrap();
+        assert_eq!(session.connection.read_packet().unwrap().as_deref(), Some("g"));
+        assert_eq!(received(&mut client, 1), "+");
+
+        // A bad checksum is refused, and the resent packet accepted
+        client.write_all(b"$m200,2#00$m200,2#5d").unwrap();
+        assert_eq!(session.connection.read_packet().unwrap().as_deref(), Some("m200,2"));
+        assert_eq!(received(&mut client, 2), "-+");
+
+        session.connection.no_ack = true;
+        client.write_all(b"$g#00$?#3f").unwrap();
+        assert_eq!(session.connection.read_packet().unwrap().as_deref(), Some("g"));
+        assert_eq!(session.connection.read_packet().unwrap().as_deref(), Some("?"));
+    }
+
+    #[test]
+    fn interrupt_between_packets() {
+        let (mut session, mut client) = session(&[]);
+        client.write_all(&[INTERRUPT]).unwrap();
+        assert_eq!(session.connection.read_packet().unwrap(), None);
+
+        client.write_all(&[b'+', INTERRUPT]).unwrap();
+        client.flush().unwrap();
+        // Poll until the byte arrives, as `resume` does once a frame
+        while !session.connection.interrupted().unwrap() {}
+        client.write_all(b"$?#3f").unwrap();
+        assert_eq!(session.connection.read_packet().unwrap().as_deref(), Some("?"));
+    }
+
+    #[test]
+    fn sent_packets_are_resent_until_acknowledged() {
+        let (mut session, mut client) = session(&[]);
+        client.write_all(b"-+").unwrap();
+        session.connection.send("OK").unwrap();
+        assert_eq!(received(&mut client, 12), "$OK#9a$OK#9a");
+    }
+
+    #[test]
+    fn registers_read_and_write() {
+        let (mut session, _client) = session(&[]);
+        session.cpu.set_register(0xA, 0x42);
+        session.cpu.set_address_register(0x234);
+        let registers = reply(&mut session, "g");
+        // v0-vf, then i and pc little-endian, then sp, dt and st
+        assert_eq!(registers, format!("{}42{}34020002000000", "00".repeat(10), "00".repeat(5)));
+
+        let written = format!("{}{}", (0..16).map(|n| format!("{:02x}", n)).collect::<String>(), "cd0b1002000506");
+        assert_eq!(reply(&mut session, &format!("G{}", written)), "OK");
+        assert_eq!(session.cpu.get_register(0xF), 0x0F);
+        assert_eq!(session.cpu.get_address_register(), 0xBCD);
+        assert_eq!(session.cpu.get_program_counter(), 0x210);
+        assert_eq!((session.cpu.get_delay_timer(), session.cpu.get_sound_timer()), (5, 6));
+        assert_eq!(reply(&mut session, "g"), written);
+        assert_eq!(reply(&mut session, "G00"), "E01", "too short");
+
+        assert_eq!(reply(&mut session, "p10"), "cd0b");
+        assert_eq!(reply(&mut session, "P10=ffff"), "E01", "I is 12 bits wide");
+        assert_eq!(reply(&mut session, "P3=7f"), "OK");
+        assert_eq!(reply(&mut session, "p3"), "7f");
+        assert_eq!(reply(&mut session, "P11=0003"), "OK");
+        assert_eq!(session.cpu.get_program_counter(), 0x300);
+        assert_eq!(reply(&mut session, "P3=7f7f"), "E01", "v3 is one byte");
+        assert_eq!(reply(&mut session, "p15"), "E01", "there is no register 0x15");
+    }
+
+    #[test]
+    fn memory_read_and_write() {
+        let (mut session, _client) = session(&[0x00, 0xE0, 0x12, 0x00]);
+        assert_eq!(reply(&mut session, "m200,4"), "00e01200");
+        assert_eq!(reply(&mut session, "mffe,10"), "0000", "reads stop at the end of memory");
+        assert_eq!(reply(&mut session, "m1000,1"), "E01");
+
+        assert_eq!(reply(&mut session, "M300,2:abcd"), "OK");
+        assert_eq!(reply(&mut session, "m300,2"), "abcd");
+        assert_eq!(reply(&mut session, "M300,2:ab"), "E01", "length mismatch");
+        assert_eq!(reply(&mut session, "Mfff,2:abcd"), "E01", "past the end of memory");
+        assert_eq!(reply(&mut session, "M300,1:zz"), "E01");
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints() {
+        let (mut session, _client) = session(&[]);
+        assert_eq!(reply(&mut session, "Z0,204,2"), "OK");
+        assert_eq!(reply(&mut session, "Z1,206,2"), "OK");
+        assert_eq!(session.breakpoints, BTreeSet::from([0x204, 0x206]));
+        assert_eq!(reply(&mut session, "z0,204,2"), "OK");
+        assert_eq!(session.breakpoints, BTreeSet::from([0x206]));
+
+        assert_eq!(reply(&mut session, "Z2,300,4"), "OK");
+        assert_eq!(reply(&mut session, "Z3,310,1"), "OK");
+        let watches: Vec<_> = session.cpu.watches().memory_watches().to_vec();
+        assert_eq!(watches, [
+            MemoryWatch { start: 0x300, end: 0x304, access: Access::Write },
+            MemoryWatch { start: 0x310, end: 0x311, access: Access::Read },
+        ]);
+        assert_eq!(reply(&mut session, "z2,300,4"), "OK");
+        assert_eq!(session.cpu.watches().memory_watches().len(), 1);
+
+        assert_eq!(reply(&mut session, "Z5,300,4"), "", "unsupported type");
+        assert_eq!(reply(&mut session, "Z0,xyz,2"), "E01");
+    }
+
+    #[test]
+    fn addresses_stop_at_the_end_of_memory() {
+        assert_eq!(parse_address("0xFFF"), Ok(0xFFF));
+        assert!(parse_address("0x1000").is_err());
+        assert_eq!(parse_range_end("0x1000"), Ok(0x1000));
+
+        let (mut session, _client) = session(&[]);
+        let monitor = |session: &mut Session, command: &str| {
+            let output = session.monitor(&hex::encode(command.as_bytes()));
+            String::from_utf8(hex::decode(&output).unwrap()).unwrap()
+        };
+        assert_eq!(monitor(&mut session, "break 0x1000 if v0 == 1"), "'0x1000' is not an address\n");
+        assert_eq!(monitor(&mut session, "watch write 0xFF0..0x1000"), "Watching writes of 0xFF0..0x1000\n");
+    }
+
+    #[test]
+    fn chunks_of_the_target_description() {
+        assert_eq!(read_chunk("abcdef", "0,4"), Some("mabcd".to_string()));
+        assert_eq!(read_chunk("abcdef", "4,4"), Some("lef".to_string()));
+        assert_eq!(read_chunk("abcdef", "10,4"), Some("l".to_string()));
+        assert_eq!(read_chunk("abcdef", "4"), None);
+        assert_eq!(read_chunk("abcdef", "x,4"), None);
+    }
+}
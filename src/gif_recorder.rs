@@ -1,52 +1,147 @@
 use gif::{Encoder, Frame, Repeat};
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::BufWriter;
 use std::thread;
+use std::time::Duration;
 use crossbeam_channel::{Sender, Receiver, bounded};
-use crate::constants::{DISPLAY_WIDTH, DISPLAY_HEIGHT};
-use crate::settings::RecordingSettings;
-use std::sync::Arc;
+use crate::constants::{DISPLAY_WIDTH, DISPLAY_HEIGHT, RGBA_PIXEL_SIZE};
+use crate::settings::{self, ColorSettings, DisplaySettings, RecordingSettings, Shared};
 
-// Buffer size constant that doesn't change
-const RECORDING_BUFFER_SIZE: usize = 30; // Buffer up to 30 frames
+// GIF encoding constants that don't change
+const PALETTE_SIZE: usize = 256;             // One palette entry per phosphor brightness level
+const MIN_FRAME_DELAY: u64 = 2;              // Browsers clamp delays below 2cs to 10cs, so never emit them
+const RECORDING_BUFFER_SIZE: usize = 30;     // Buffer up to 30 frames
 
 pub struct GifRecorder {
     sender: Option<Sender<RecordCommand>>,
     thread_handle: Option<thread::JoinHandle<()>>,
+    replay_handles: Vec<thread::JoinHandle<()>>,
     frame_count: u32,
-    settings: Arc<RecordingSettings>,
+    settings: Shared<RecordingSettings>,
+    display: Shared<DisplaySettings>,
 }
 
 enum RecordCommand {
-    AddFrame(Vec<u8>),
+    AddFrame { rgba: Vec<u8>, timestamp: Duration },
     Stop,
 }
 
+/// Fixed GIF palette derived from the phosphor colours, so frames never need quantizing
+pub struct Palette {
+    rgb: Vec<u8>,
+    lookup: HashMap<[u8; 3], u8>,
+}
+
+impl Palette {
+    pub fn from_colors(colors: &ColorSettings) -> Self {
+        let mut rgb = Vec::with_capacity(PALETTE_SIZE * 3);
+        let mut lookup = HashMap::new();
+        for brightness in 0..PALETTE_SIZE {
+            let color = colors.phosphor_color(brightness as u8);
+            rgb.extend_from_slice(&color);
+            lookup.entry(color).or_insert(brightness as u8);
+        }
+        Self { rgb, lookup }
+    }
+
+    fn index_of(&self, color: [u8; 3]) -> u8 {
+        if let Some(&index) = self.lookup.get(&color) {
+            return index;
+        }
+        // Not a phosphor colour; fall back to the closest palette entry
+        self.rgb
+            .chunks_exact(3)
+            .enumerate()
+            .min_by_key(|(_, entry)| {
+                entry.iter().zip(color).map(|(&a, b)| (a as i32 - b as i32).pow(2)).sum::<i32>()
+            })
+            .map(|(index, _)| index as u8)
+            .unwrap_or(0)
+    }
+
+    /// Convert a 64x32 RGBA frame into palette indices
+    pub fn index_frame(&self, rgba_buffer: &[u8]) -> Vec<u8> {
+        rgba_buffer
+            .chunks_exact(RGBA_PIXEL_SIZE)
+            .map(|pixel| self.index_of([pixel[0], pixel[1], pixel[2]]))
+            .collect()
+    }
+}
+
+/// Bounding box of the pixels that changed between two frames, in display coordinates
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Region {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Region {
+    const FULL: Region = Region { x: 0, y: 0, width: DISPLAY_WIDTH, height: DISPLAY_HEIGHT };
+
+    fn changed_between(previous: &[u8], current: &[u8]) -> Option<Region> {
+        let (mut min_x, mut min_y) = (DISPLAY_WIDTH, DISPLAY_HEIGHT);
+        let (mut max_x, mut max_y) = (0, 0);
+        for (i, (a, b)) in previous.iter().zip(current).enumerate() {
+            if a != b {
+                let (x, y) = (i % DISPLAY_WIDTH, i / DISPLAY_WIDTH);
+                min_x = min_x.min(x);
+                min_y = min_y.min(y);
+                max_x = max_x.max(x);
+                max_y = max_y.max(y);
+            }
+        }
+        (min_x <= max_x).then(|| Region {
+            x: min_x,
+            y: min_y,
+            width: max_x - min_x + 1,
+            height: max_y - min_y + 1,
+        })
+    }
+}
+
+impl Default for GifRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl GifRecorder {
     #[allow(dead_code)]
     pub fn new() -> Self {
-        Self::with_settings(Arc::new(RecordingSettings::default()))
+        Self::with_settings(settings::shared(RecordingSettings::default()), settings::shared(DisplaySettings::default()))
     }
-    
-    pub fn with_settings(settings: Arc<RecordingSettings>) -> Self {
+
+    pub fn with_settings(settings: Shared<RecordingSettings>, display: Shared<DisplaySettings>) -> Self {
         Self {
             sender: None,
             thread_handle: None,
+            replay_handles: Vec::new(),
             frame_count: 0,
             settings,
+            display,
         }
     }
 
+    /// The recording settings and palette to encode with, fixed for the whole GIF
+    fn encoding_settings(&self) -> (RecordingSettings, Palette) {
+        let palette = Palette::from_colors(&self.display.read().unwrap().color);
+        (self.settings.read().unwrap().clone(), palette)
+    }
+
     pub fn generate_filename(rom_name: &str, output_dir: &str, pattern: &str) -> String {
         let timestamp = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap_or_default()
             .as_secs();
-        
+
         let filename = pattern
             .replace("{rom_name}", rom_name)
             .replace("{timestamp}", &timestamp.to_string());
-        
+
         format!("{}/{}.gif", output_dir, filename)
     }
 
@@ -55,14 +150,17 @@ impl GifRecorder {
             return Err("Already recording".into());
         }
 
-        let (sender, receiver) = bounded(RECORDING_BUFFER_SIZE); // Buffer up to 30 frames
+        // When the buffer is full the window waits for the encoder instead of dropping
+        // a frame, whose change would be missing from the GIF. The game itself keeps
+        // running on the emulation thread.
+        let (sender, receiver) = bounded(RECORDING_BUFFER_SIZE);
         self.sender = Some(sender);
         self.frame_count = 0;
 
         let filename = filename.to_string();
-        let settings = self.settings.clone();
+        let (settings, palette) = self.encoding_settings();
         let thread_handle = thread::spawn(move || {
-            if let Err(e) = recording_thread(receiver, filename, settings) {
+            if let Err(e) = recording_thread(receiver, filename, settings, palette) {
                 eprintln!("Recording thread error: {}", e);
             }
         });
@@ -72,27 +170,18 @@ impl GifRecorder {
         Ok(())
     }
 
-    pub fn add_frame(&mut self, rgba_buffer: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    /// Queue a frame captured at `timestamp` of emulated time
+    pub fn add_frame(&mut self, rgba_buffer: &[u8], timestamp: Duration) -> Result<(), Box<dyn std::error::Error>> {
         if let Some(ref sender) = self.sender {
             self.frame_count += 1;
-            
+
             // Skip frames to reduce load
-            if self.frame_count % (self.settings.gif_frame_skip + 1) != 0 {
+            #[allow(clippy::manual_is_multiple_of)] // Newer lint than this code; left as written
+            if self.frame_count % (self.settings.read().unwrap().gif_frame_skip + 1) != 0 {
                 return Ok(());
             }
 
-            // Clone the buffer for the recording thread
-            let buffer = rgba_buffer.to_vec();
-            
-            // Try to send, but don't block if the buffer is full
-            match sender.try_send(RecordCommand::AddFrame(buffer)) {
-                Ok(_) => {},
-                Err(crossbeam_channel::TrySendError::Full(_)) => {
-                    // Skip this frame if buffer is full
-                    println!("Recording buffer full, skipping frame");
-                }
-                Err(e) => return Err(Box::new(e)),
-            }
+            sender.send(RecordCommand::AddFrame { rgba: rgba_buffer.to_vec(), timestamp })?;
         }
         Ok(())
     }
@@ -100,11 +189,11 @@ impl GifRecorder {
     pub fn stop_recording(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         if let Some(sender) = self.sender.take() {
             sender.send(RecordCommand::Stop)?;
-            
+
             if let Some(handle) = self.thread_handle.take() {
                 handle.join().map_err(|_| "Recording thread panicked")?;
             }
-            
+
             println!("GIF recording stopped and saved");
         }
         Ok(())
@@ -114,6 +203,35 @@ impl GifRecorder {
         self.sender.is_some()
     }
 
+    /// Encode an already captured sequence of timestamped RGBA frames (e.g. an instant
+    /// replay) on its own recording thread, independent of any live recording.
+    pub fn save_frames(&mut self, filename: &str, frames: Vec<(Duration, Vec<u8>)>) -> Result<(), Box<dyn std::error::Error>> {
+        if frames.is_empty() {
+            return Err("No frames to save".into());
+        }
+
+        // The frames are already in memory, so room for all of them costs nothing
+        // and queueing them never blocks the window
+        let (sender, receiver) = bounded(frames.len() + 1);
+        let (settings, palette) = self.encoding_settings();
+        let step = settings.gif_frame_skip as usize + 1;
+        for (timestamp, rgba) in frames.into_iter().step_by(step) {
+            sender.send(RecordCommand::AddFrame { rgba, timestamp })?;
+        }
+        sender.send(RecordCommand::Stop)?;
+
+        // Reap replays that have already finished writing
+        self.replay_handles.retain(|handle| !handle.is_finished());
+
+        let filename = filename.to_string();
+        self.replay_handles.push(thread::spawn(move || {
+            if let Err(e) = recording_thread(receiver, filename, settings, palette) {
+                eprintln!("Replay recording error: {}", e);
+            }
+        }));
+        Ok(())
+    }
+
 }
 
 impl Drop for GifRecorder {
@@ -121,30 +239,64 @@ impl Drop for GifRecorder {
         if self.is_recording() {
             let _ = self.stop_recording();
         }
+        for handle in self.replay_handles.drain(..) {
+            let _ = handle.join();
+        }
     }
 }
 
-fn recording_thread(receiver: Receiver<RecordCommand>, filename: String, settings: Arc<RecordingSettings>) -> Result<(), Box<dyn std::error::Error>> {
+/// A frame that has been received but not yet written, because its delay is only
+/// known once the next differing frame arrives.
+struct PendingFrame {
+    start: u64, // centiseconds of emulated time
+    last_seen: u64,
+}
+
+fn recording_thread(receiver: Receiver<RecordCommand>, filename: String, settings: RecordingSettings, palette: Palette) -> Result<(), Box<dyn std::error::Error>> {
     let file = File::create(&filename)?;
     let writer = BufWriter::new(file);
-    
+
     let scale_factor = settings.gif_scale_factor;
     let scaled_width = (DISPLAY_WIDTH * scale_factor as usize) as u16;
     let scaled_height = (DISPLAY_HEIGHT * scale_factor as usize) as u16;
-    
-    let mut encoder = Encoder::new(writer, scaled_width, scaled_height, &[])?;
+
+    let mut encoder = Encoder::new(writer, scaled_width, scaled_height, &palette.rgb)?;
     encoder.set_repeat(Repeat::Infinite)?;
-    
-    let frame_delay = settings.gif_frame_delay; // Delay in centiseconds
 
-    while let Ok(RecordCommand::AddFrame(rgba_buffer)) = receiver.recv() {
-        // Convert and scale in the background thread
-        let scaled_rgb = scale_and_convert_buffer(&rgba_buffer, scale_factor);
-        
-        let mut frame = Frame::from_rgb(scaled_width, scaled_height, &scaled_rgb);
-        frame.delay = frame_delay;
-        
-        encoder.write_frame(&frame)?;
+    // `written` is the canvas as of the last emitted frame, `current` the newest frame received.
+    // Emitting only the region where they differ keeps each GIF frame small.
+    let mut written: Option<Vec<u8>> = None;
+    let mut current: Vec<u8> = Vec::new();
+    let mut pending: Option<PendingFrame> = None;
+
+    while let Ok(RecordCommand::AddFrame { rgba, timestamp }) = receiver.recv() {
+        let indexed = palette.index_frame(&rgba);
+        let now = timestamp.as_millis() as u64 / 10;
+
+        match pending.as_mut() {
+            None => pending = Some(PendingFrame { start: now, last_seen: now }),
+            Some(frame) => {
+                frame.last_seen = now;
+                if indexed == current {
+                    // Identical frames just extend the delay of the pending one
+                    continue;
+                }
+                if now.saturating_sub(frame.start) >= MIN_FRAME_DELAY {
+                    let delay = now - frame.start;
+                    write_frame(&mut encoder, written.as_deref(), &current, scale_factor, delay)?;
+                    written = Some(std::mem::take(&mut current));
+                    pending = Some(PendingFrame { start: now, last_seen: now });
+                }
+                // Otherwise the change is too quick to show on its own and is folded
+                // into the pending frame, which will now show the newer content
+            }
+        }
+        current = indexed;
+    }
+
+    if let Some(frame) = pending {
+        let delay = (frame.last_seen - frame.start + settings.gif_frame_delay as u64).max(MIN_FRAME_DELAY);
+        write_frame(&mut encoder, written.as_deref(), &current, scale_factor, delay)?;
     }
 
     drop(encoder); // Finalize the GIF
@@ -152,39 +304,161 @@ fn recording_thread(receiver: Receiver<RecordCommand>, filename: String, setting
     Ok(())
 }
 
-fn scale_and_convert_buffer(rgba_buffer: &[u8], scale_factor: u16) -> Vec<u8> {
-    let scaled_width = DISPLAY_WIDTH * scale_factor as usize;
-    let scaled_height = DISPLAY_HEIGHT * scale_factor as usize;
-    let mut scaled_rgb = vec![0u8; scaled_width * scaled_height * 3];
-
-    // Use a more efficient scaling approach
-    for y in 0..DISPLAY_HEIGHT {
-        for x in 0..DISPLAY_WIDTH {
-            let src_idx = (y * DISPLAY_WIDTH + x) * 4;
-            let r = rgba_buffer[src_idx];
-            let g = rgba_buffer[src_idx + 1];
-            let b = rgba_buffer[src_idx + 2];
-
-            // Calculate the starting position for this pixel in the scaled buffer
-            let scaled_y = y * scale_factor as usize;
-            let scaled_x = x * scale_factor as usize;
-
-            // Fill the scaled area with the same color
-            for sy in 0..scale_factor as usize {
-                let dst_y = scaled_y + sy;
-                let row_start = dst_y * scaled_width * 3;
-                
-                for sx in 0..scale_factor as usize {
-                    let dst_x = scaled_x + sx;
-                    let dst_idx = row_start + dst_x * 3;
-                    
-                    scaled_rgb[dst_idx] = r;
-                    scaled_rgb[dst_idx + 1] = g;
-                    scaled_rgb[dst_idx + 2] = b;
-                }
+fn write_frame<W: std::io::Write>(
+    encoder: &mut Encoder<W>,
+    previous: Option<&[u8]>,
+    current: &[u8],
+    scale_factor: u16,
+    delay: u64,
+) -> Result<(), gif::EncodingError> {
+    let region = match previous {
+        None => Region::FULL,
+        // Changes that cancelled out still need a frame to carry the delay
+        Some(previous) => Region::changed_between(previous, current)
+            .unwrap_or(Region { x: 0, y: 0, width: 1, height: 1 }),
+    };
+
+    let scale = scale_factor as usize;
+    let frame = Frame {
+        left: (region.x * scale) as u16,
+        top: (region.y * scale) as u16,
+        width: (region.width * scale) as u16,
+        height: (region.height * scale) as u16,
+        delay: delay.min(u16::MAX as u64) as u16,
+        buffer: Cow::Owned(scale_region(current, region, scale_factor)),
+        ..Frame::default()
+    };
+    encoder.write_frame(&frame)
+}
+
+/// Scale a whole indexed 64x32 frame up by `scale_factor`
+pub fn scale_frame(indexed: &[u8], scale_factor: u16) -> Vec<u8> {
+    scale_region(indexed, Region::FULL, scale_factor)
+}
+
+/// Cut `region` out of an indexed 64x32 frame and scale it up by `scale_factor`
+fn scale_region(indexed: &[u8], region: Region, scale_factor: u16) -> Vec<u8> {
+    let scale = scale_factor as usize;
+    let scaled_width = region.width * scale;
+    let mut scaled = Vec::with_capacity(scaled_width * region.height * scale);
+
+    for y in region.y..region.y + region.height {
+        let row_start = y * DISPLAY_WIDTH + region.x;
+        let row = &indexed[row_start..row_start + region.width];
+        let scaled_row_start = scaled.len();
+        for &index in row {
+            scaled.extend(std::iter::repeat_n(index, scale));
+        }
+        // Repeat the scaled row for the remaining scanlines of this pixel row
+        for _ in 1..scale {
+            scaled.extend_from_within(scaled_row_start..scaled_row_start + scaled_width);
+        }
+    }
+
+    scaled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIT: u8 = 255;
+
+    /// An RGBA frame with the pixels at `lit` at full brightness
+    fn rgba(colors: &ColorSettings, lit: &[(usize, usize)]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(DISPLAY_WIDTH * DISPLAY_HEIGHT * RGBA_PIXEL_SIZE);
+        for y in 0..DISPLAY_HEIGHT {
+            for x in 0..DISPLAY_WIDTH {
+                let brightness = if lit.contains(&(x, y)) { LIT } else { 0 };
+                frame.extend(colors.phosphor_color(brightness));
+                frame.push(255);
             }
         }
+        frame
+    }
+
+    fn indexed(lit: &[(usize, usize)]) -> Vec<u8> {
+        let palette = Palette::from_colors(&ColorSettings::default());
+        palette.index_frame(&rgba(&ColorSettings::default(), lit))
+    }
+
+    #[test]
+    fn palette_maps_phosphor_colors_to_their_brightness() {
+        let colors = ColorSettings::default();
+        let palette = Palette::from_colors(&colors);
+        assert_eq!(palette.rgb.len(), PALETTE_SIZE * 3);
+        for brightness in [0, 1, 128, 255] {
+            let index = palette.index_of(colors.phosphor_color(brightness));
+            // Brightnesses that share a colour share the first index with it
+            assert_eq!(palette.rgb[index as usize * 3..][..3], colors.phosphor_color(brightness));
+            assert!(index <= brightness);
+        }
+
+        // Colours outside the palette take the closest entry
+        let white = palette.index_of([255, 255, 255]);
+        let brightest = palette.index_of(colors.phosphor_color(LIT));
+        assert_eq!(palette.rgb[white as usize * 3..][..3], palette.rgb[brightest as usize * 3..][..3]);
+    }
+
+    #[test]
+    fn changed_region_bounds_the_differing_pixels() {
+        let blank = indexed(&[]);
+        assert_eq!(Region::changed_between(&blank, &blank), None);
+        assert_eq!(
+            Region::changed_between(&blank, &indexed(&[(5, 7)])),
+            Some(Region { x: 5, y: 7, width: 1, height: 1 })
+        );
+        assert_eq!(
+            Region::changed_between(&indexed(&[(10, 2)]), &indexed(&[(3, 20)])),
+            Some(Region { x: 3, y: 2, width: 8, height: 19 })
+        );
+    }
+
+    type WrittenFrame = (u16, u16, u16, u16, u16); // Left, top, width, height, delay
+
+    /// Encode `frames`, given as lit pixels at a time in milliseconds, and read
+    /// back each GIF frame's position, size and delay
+    fn encode(frames: &[(u64, &[(usize, usize)])]) -> (RecordingSettings, Vec<WrittenFrame>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.gif");
+        let settings = RecordingSettings { gif_scale_factor: 1, ..RecordingSettings::default() };
+        let colors = ColorSettings::default();
+
+        let (sender, receiver) = bounded(frames.len() + 1);
+        for &(millis, lit) in frames {
+            sender.send(RecordCommand::AddFrame { rgba: rgba(&colors, lit), timestamp: Duration::from_millis(millis) }).unwrap();
+        }
+        sender.send(RecordCommand::Stop).unwrap();
+        recording_thread(receiver, path.to_string_lossy().into_owned(), settings.clone(), Palette::from_colors(&colors)).unwrap();
+
+        let mut decoder = gif::DecodeOptions::new().read_info(File::open(&path).unwrap()).unwrap();
+        let mut written = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            written.push((frame.left, frame.top, frame.width, frame.height, frame.delay));
+        }
+        (settings, written)
     }
 
-    scaled_rgb
-}
\ No newline at end of file
+    #[test]
+    fn unchanged_frames_extend_the_delay() {
+        let (settings, frames) = encode(&[(0, &[]), (100, &[]), (200, &[]), (300, &[])]);
+        let full = (DISPLAY_WIDTH as u16, DISPLAY_HEIGHT as u16);
+        assert_eq!(frames, [(0, 0, full.0, full.1, 30 + settings.gif_frame_delay)]);
+    }
+
+    #[test]
+    fn a_changed_pixel_is_written_as_its_own_region() {
+        let (settings, frames) = encode(&[(0, &[]), (100, &[]), (200, &[(9, 4)]), (300, &[(9, 4)])]);
+        let full = (DISPLAY_WIDTH as u16, DISPLAY_HEIGHT as u16);
+        assert_eq!(frames, [(0, 0, full.0, full.1, 20), (9, 4, 1, 1, 10 + settings.gif_frame_delay)]);
+    }
+
+    #[test]
+    fn changes_quicker_than_the_minimum_delay_are_folded_together() {
+        // The pixel lights 1cs in, too soon to show the blank frame on its own, so
+        // a single frame covers the whole 10cs
+        let (settings, frames) = encode(&[(0, &[]), (10, &[(1, 1)]), (100, &[(1, 1)])]);
+        let full = (DISPLAY_WIDTH as u16, DISPLAY_HEIGHT as u16);
+        assert_eq!(frames, [(0, 0, full.0, full.1, 10 + settings.gif_frame_delay)]);
+    }
+}
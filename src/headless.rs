@@ -0,0 +1,156 @@
+//! Running the emulator without a window or sound device.
+//!
+//! Frames are executed back to back with no pacing, while emulated time still
+//! advances at the configured frame rate so recordings play back at the right
+//! speed. `Headless::run_max_speed` does the same against the clock to measure
+//! how fast the interpreter is.
+
+use crate::audio::SilentBackend;
+use crate::constants::{self, DISPLAY_WIDTH, DISPLAY_HEIGHT, RGBA_PIXEL_SIZE};
+use crate::cpu::{CpuFault, CPU};
+use crate::display::DisplaySnapshot;
+use crate::gif_recorder::GifRecorder;
+use crate::settings::{self, Settings};
+use crate::wav_recorder::WavRecorder;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const FRAMES_PER_CLOCK_CHECK: u32 = 64;     // Keeps reading the clock out of the measurement
+
+/// What a max-speed run got through
+pub struct SpeedReport {
+    pub instructions: u64,
+    pub frames: u64,
+    pub elapsed: Duration,
+    /// The fault that ended the run early, if any
+    pub fault: Option<CpuFault>,
+}
+
+impl SpeedReport {
+    pub fn instructions_per_second(&self) -> f64 {
+        self.instructions as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+pub struct Headless {
+    cpu: CPU,
+    settings: Settings,
+    emulated_time: Duration,
+}
+
+impl Headless {
+    pub fn new(settings: Settings, program: &[u8]) -> Self {
+        let mut cpu = CPU::with_audio_backend(settings.clone(), Box::new(SilentBackend));
+        cpu.load_program(program);
+        Self {
+            cpu,
+            settings,
+            emulated_time: Duration::ZERO,
+        }
+    }
+
+    /// Execute one frame, returning whether the beeper sounded during it
+    pub fn run_frame(&mut self) -> Result<bool, CpuFault> {
+        let sound_on = self.cpu.run_frame(self.settings.cpu.cycles_per_frame)?;
+        self.emulated_time += constants::frame_duration(self.settings.cpu.target_fps);
+        Ok(sound_on)
+    }
+
+    /// Execute frames back to back for `duration` of real time, as fast as
+    /// the host allows. Timers still tick once per frame.
+    pub fn run_max_speed(&mut self, duration: Duration) -> SpeedReport {
+        let start_instructions = self.cpu.cycles();
+        let start = Instant::now();
+        let mut frames = 0;
+        let mut fault = None;
+        'run: while start.elapsed() < duration {
+            for _ in 0..FRAMES_PER_CLOCK_CHECK {
+                if let Err(e) = self.run_frame() {
+                    fault = Some(e);
+                    break 'run;
+                }
+                frames += 1;
+            }
+        }
+        SpeedReport {
+            instructions: self.cpu.cycles() - start_instructions,
+            frames,
+            elapsed: start.elapsed(),
+            fault,
+        }
+    }
+
+    pub fn emulated_time(&self) -> Duration {
+        self.emulated_time
+    }
+
+    pub fn press_key(&mut self, key: u8) {
+        self.cpu.press_key(key);
+    }
+
+    pub fn release_key(&mut self, key: u8) {
+        self.cpu.release_key(key);
+    }
+
+    pub fn display_snapshot(&self) -> DisplaySnapshot {
+        self.cpu.display_snapshot()
+    }
+
+    pub fn render_to_buffer(&mut self, buffer: &mut [u8]) {
+        self.cpu.render_to_buffer(buffer);
+    }
+
+    /// The current display as text, `#` for lit pixels and `.` for dark ones
+    pub fn screen_text(&self) -> String {
+        screen_text(&self.display_snapshot())
+    }
+}
+
+/// A display snapshot as text, `#` for lit pixels and `.` for dark ones
+pub fn screen_text(snapshot: &DisplaySnapshot) -> String {
+    let mut text = String::with_capacity((DISPLAY_WIDTH + 1) * DISPLAY_HEIGHT);
+    for row in snapshot {
+        for x in 0..DISPLAY_WIDTH {
+            let lit = (row >> (DISPLAY_WIDTH - 1 - x)) & 1 == 1;
+            text.push(if lit { '#' } else { '.' });
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// Run `frames` frames headlessly, writing a GIF (and a WAV, if enabled) to `output`
+pub fn record(settings: Settings, program: &[u8], frames: u32, output: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut gif_recorder = GifRecorder::with_settings(settings::shared(settings.recording.clone()), settings::shared(settings.display.clone()));
+    let mut wav_recorder = WavRecorder::with_settings(settings::shared(settings.audio.clone()));
+    let mut headless = Headless::new(settings.clone(), program);
+    let mut buffer = vec![0u8; DISPLAY_WIDTH * DISPLAY_HEIGHT * RGBA_PIXEL_SIZE];
+
+    gif_recorder.start_recording(output)?;
+    if settings.recording.record_audio {
+        let wav_filename = Path::new(output).with_extension("wav");
+        wav_recorder.start_recording(&wav_filename.to_string_lossy(), Duration::ZERO)?;
+    }
+
+    // A fault ends the recording early; what was recorded up to it is kept
+    let mut fault = None;
+    for _ in 0..frames {
+        let sound_on = match headless.run_frame() {
+            Ok(sound_on) => sound_on,
+            Err(e) => {
+                fault = Some(e);
+                break;
+            }
+        };
+        headless.render_to_buffer(&mut buffer);
+        gif_recorder.add_frame(&buffer, headless.emulated_time())?;
+        wav_recorder.add_frame(headless.emulated_time(), sound_on)?;
+    }
+
+    gif_recorder.stop_recording()?;
+    wav_recorder.stop_recording()?;
+    match fault {
+        Some(fault) => Err(format!("CPU fault: {}", fault).into()),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,40 @@
+//! Bytes as lowercase hex text, two digits each, as GDB packets and coverage
+//! files store them.
+
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The bytes in `text`, or `None` unless it is pairs of hex digits
+pub fn decode(text: &str) -> Option<Vec<u8>> {
+    // `from_str_radix` alone would also take a sign, as in "+1"
+    if !text.len().is_multiple_of(2) || !text.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let bytes: Vec<u8> = (0..=u8::MAX).collect();
+        assert_eq!(decode(&encode(&bytes)), Some(bytes));
+        assert_eq!(encode(&[0x00, 0xAB]), "00ab");
+    }
+
+    #[test]
+    fn decode_rejects_odd_or_invalid_input() {
+        assert_eq!(decode("00ff7A"), Some(vec![0x00, 0xFF, 0x7A]));
+        assert_eq!(decode(""), Some(Vec::new()));
+        assert_eq!(decode("abc"), None);
+        assert_eq!(decode("zz"), None);
+        assert_eq!(decode("+1"), None);
+        assert_eq!(decode("é"), None);
+    }
+}
@@ -8,6 +8,12 @@ pub struct InputState {
     key_for_wait: Option<u8>,
 }
 
+impl Default for InputState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl InputState {
     pub fn new() -> Self {
         Self {
@@ -19,27 +25,42 @@ impl InputState {
     }
 
     pub fn handle_key_press(&mut self, key: KeyCode) {
-        if let Some(chip8_key) = self.map_key_to_chip8(key) {
-            self.pressed_keys.insert(chip8_key);
-            self.last_key_pressed = Some(chip8_key);
-            
-            // If we're waiting for a key, store it
-            if self.waiting_for_key && self.key_for_wait.is_none() {
-                self.key_for_wait = Some(chip8_key);
-            }
+        if let Some(chip8_key) = Self::map_key_to_chip8(key) {
+            self.press(chip8_key);
         }
     }
 
     pub fn handle_key_release(&mut self, key: KeyCode) {
-        if let Some(chip8_key) = self.map_key_to_chip8(key) {
-            self.pressed_keys.remove(&chip8_key);
+        if let Some(chip8_key) = Self::map_key_to_chip8(key) {
+            self.release(chip8_key);
+        }
+    }
+
+    /// Press a key of the CHIP-8 keypad, 0x0 to 0xF
+    pub fn press(&mut self, chip8_key: u8) {
+        self.pressed_keys.insert(chip8_key);
+        self.last_key_pressed = Some(chip8_key);
+        
+        // If we're waiting for a key, store it
+        if self.waiting_for_key && self.key_for_wait.is_none() {
+            self.key_for_wait = Some(chip8_key);
         }
     }
 
+    pub fn release(&mut self, chip8_key: u8) {
+        self.pressed_keys.remove(&chip8_key);
+    }
+
     pub fn is_key_pressed(&self, key: u8) -> bool {
         self.pressed_keys.contains(&key)
     }
 
+    /// Abandon an FX0A wait in progress, e.g. when the CPU is reset
+    pub fn cancel_wait(&mut self) {
+        self.waiting_for_key = false;
+        self.key_for_wait = None;
+    }
+
     pub fn wait_for_key(&mut self) -> Option<u8> {
         if !self.waiting_for_key {
             // Start waiting for a key
@@ -64,7 +85,8 @@ impl InputState {
     }
 
 
-    fn map_key_to_chip8(&self, key: KeyCode) -> Option<u8> {
+    /// The keypad key a keyboard key stands for, if any
+    pub fn map_key_to_chip8(key: KeyCode) -> Option<u8> {
         match key {
             // CHIP-8 Keypad Layout:
             // 1 2 3 C
@@ -100,4 +122,63 @@ impl InputState {
             _ => None,
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn qwerty_keys_map_to_the_keypad() {
+        let mut input = InputState::new();
+        input.handle_key_press(KeyCode::KeyX);
+        input.handle_key_press(KeyCode::KeyV);
+        assert!(input.is_key_pressed(0x0));
+        assert!(input.is_key_pressed(0xF));
+        input.handle_key_release(KeyCode::KeyX);
+        assert!(!input.is_key_pressed(0x0));
+        input.handle_key_press(KeyCode::KeyP);
+        assert_eq!(input.pressed_keys.len(), 1, "unmapped keys are ignored");
+    }
+
+    #[test]
+    fn wait_returns_the_key_once_released() {
+        let mut input = InputState::new();
+        assert_eq!(input.wait_for_key(), None);
+        input.press(0x5);
+        assert_eq!(input.wait_for_key(), None, "the key is still held");
+        input.release(0x5);
+        assert_eq!(input.wait_for_key(), Some(0x5));
+        assert_eq!(input.wait_for_key(), None, "a new wait starts over");
+    }
+
+    #[test]
+    fn wait_ignores_keys_held_before_it_started() {
+        let mut input = InputState::new();
+        input.press(0x1);
+        assert_eq!(input.wait_for_key(), None);
+        input.release(0x1);
+        assert_eq!(input.wait_for_key(), None);
+    }
+
+    #[test]
+    fn wait_takes_the_first_key_pressed() {
+        let mut input = InputState::new();
+        input.wait_for_key();
+        input.press(0x2);
+        input.press(0x3);
+        input.release(0x3);
+        assert_eq!(input.wait_for_key(), None, "0x2 is still held");
+        input.release(0x2);
+        assert_eq!(input.wait_for_key(), Some(0x2));
+    }
+
+    #[test]
+    fn cancelled_wait_forgets_the_key() {
+        let mut input = InputState::new();
+        input.wait_for_key();
+        input.press(0x4);
+        input.release(0x4);
+        input.cancel_wait();
+        assert_eq!(input.wait_for_key(), None);
+    }
+}
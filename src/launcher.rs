@@ -0,0 +1,424 @@
+//! In-window ROM launcher.
+//!
+//! Drawn with the 3x5 menu font into a buffer larger than the CHIP-8 display,
+//! so a game can be picked, switched or restarted without going back to the
+//! shell. It lists the ROMs in `launcher.rom_dir` and recently played files,
+//! filters them as you type, and shows the selected ROM's metadata. ZIP
+//! archives are listed too; one holding a single ROM loads like a ROM file.
+
+use crate::config_layers;
+use crate::constants::RGBA_PIXEL_SIZE;
+use crate::font::{menu_glyph, MENU_GLYPH_WIDTH, MENU_GLYPH_HEIGHT};
+use crate::rom::{self, RomInfo, RomSource};
+use crate::settings::ColorSettings;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use winit::event::{ElementState, KeyEvent};
+use winit::keyboard::{KeyCode, PhysicalKey};
+
+// Layout constants; the buffer is 2.5x the CHIP-8 display so the window keeps its shape
+pub const LAUNCHER_WIDTH: u32 = 160;
+pub const LAUNCHER_HEIGHT: u32 = 80;
+const CELL_WIDTH: usize = MENU_GLYPH_WIDTH + 1;          // One pixel between characters
+const LINE_HEIGHT: usize = MENU_GLYPH_HEIGHT + 1;        // One pixel between lines
+const COLUMNS: usize = LAUNCHER_WIDTH as usize / CELL_WIDTH;
+const LIST_TOP_LINE: usize = 2;
+const LIST_LINES: usize = 7;
+const INFO_LINE: usize = LIST_TOP_LINE + LIST_LINES + 1;
+const STATUS_LINE: usize = INFO_LINE + 2;
+const DIM_BRIGHTNESS: u8 = 110;                           // Phosphor level for secondary text
+
+const RECENT_FILE: &str = "recent_roms.txt";
+const RECENT_LIMIT: usize = 10;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Tab {
+    Browse,
+    Recent,
+}
+
+pub enum LauncherAction {
+    None,
+    Load(PathBuf),
+}
+
+pub struct Launcher {
+    tab: Tab,
+    rom_dir: PathBuf,
+    roms: Vec<PathBuf>,
+    recent: Vec<PathBuf>,
+    query: String,
+    selected: usize,
+    scroll: usize,
+    info_cache: HashMap<PathBuf, Option<RomInfo>>,
+    message: Option<String>,
+}
+
+impl Default for Launcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Launcher {
+    pub fn new() -> Self {
+        Self {
+            tab: Tab::Browse,
+            rom_dir: PathBuf::new(),
+            roms: Vec::new(),
+            recent: Vec::new(),
+            query: String::new(),
+            selected: 0,
+            scroll: 0,
+            info_cache: HashMap::new(),
+            message: None,
+        }
+    }
+
+    /// Rescan `rom_dir` and the recent list; called every time the launcher is shown
+    pub fn open(&mut self, rom_dir: &str) {
+        self.rom_dir = PathBuf::from(rom_dir);
+        self.roms = rom::list_roms(&self.rom_dir).unwrap_or_else(|e| {
+            self.message = Some(format!("can't read {}: {}", rom_dir, e));
+            Vec::new()
+        });
+        self.recent = load_recent();
+        self.tab = if self.roms.is_empty() && !self.recent.is_empty() { Tab::Recent } else { Tab::Browse };
+        self.query.clear();
+        self.selected = 0;
+        self.scroll = 0;
+        self.info_cache.clear();
+    }
+
+    /// Show a message (e.g. a load error) on the status line until the next key press
+    pub fn set_message(&mut self, message: String) {
+        self.message = Some(message);
+    }
+
+    /// The ROMs in the current tab that match the search
+    fn entries(&self) -> Vec<&PathBuf> {
+        let query = self.query.to_ascii_lowercase();
+        let source = match self.tab {
+            Tab::Browse => &self.roms,
+            Tab::Recent => &self.recent,
+        };
+        source
+            .iter()
+            .filter(|path| file_name(path).to_ascii_lowercase().contains(&query))
+            .collect()
+    }
+
+    pub fn handle_key(&mut self, event: &KeyEvent) -> LauncherAction {
+        if event.state != ElementState::Pressed {
+            return LauncherAction::None;
+        }
+        self.message = None;
+
+        let count = self.entries().len();
+        let PhysicalKey::Code(keycode) = event.physical_key else {
+            return LauncherAction::None;
+        };
+        match keycode {
+            KeyCode::Enter | KeyCode::NumpadEnter => {
+                if let Some(path) = self.entries().get(self.selected) {
+                    return LauncherAction::Load((*path).clone());
+                }
+            }
+            KeyCode::ArrowUp => self.selected = self.selected.saturating_sub(1),
+            KeyCode::ArrowDown => self.selected = (self.selected + 1).min(count.saturating_sub(1)),
+            KeyCode::PageUp => self.selected = self.selected.saturating_sub(LIST_LINES),
+            KeyCode::PageDown => self.selected = (self.selected + LIST_LINES).min(count.saturating_sub(1)),
+            KeyCode::Home => self.selected = 0,
+            KeyCode::End => self.selected = count.saturating_sub(1),
+            KeyCode::Tab => {
+                self.tab = match self.tab {
+                    Tab::Browse => Tab::Recent,
+                    Tab::Recent => Tab::Browse,
+                };
+                self.selected = 0;
+            }
+            KeyCode::Backspace => {
+                self.query.pop();
+                self.selected = 0;
+            }
+            _ => {
+                // Anything printable goes into the search
+                let typed = event.text.as_deref().unwrap_or("");
+                let printable: String = typed.chars().filter(|c| c.is_ascii_graphic() || *c == ' ').collect();
+                if !printable.is_empty() {
+                    self.query.push_str(&printable);
+                    self.selected = 0;
+                }
+            }
+        }
+
+        // Keep the selection inside the visible part of the list
+        if self.selected < self.scroll {
+            self.scroll = self.selected;
+        } else if self.selected >= self.scroll + LIST_LINES {
+            self.scroll = self.selected + 1 - LIST_LINES;
+        }
+        LauncherAction::None
+    }
+
+    /// Draw the launcher into an RGBA buffer of `LAUNCHER_WIDTH` x `LAUNCHER_HEIGHT`
+    pub fn render(&mut self, frame: &mut [u8], colors: &ColorSettings) {
+        let mut canvas = Canvas {
+            frame,
+            foreground: colors.phosphor_color(u8::MAX),
+            dim: colors.phosphor_color(DIM_BRIGHTNESS),
+            background: colors.background,
+        };
+        canvas.clear();
+
+        // Tabs, with the current one highlighted
+        let tabs = [(Tab::Browse, " ROMS "), (Tab::Recent, " RECENT ")];
+        let mut column = 0;
+        for (tab, label) in tabs {
+            canvas.text(column, 0, label, tab == self.tab);
+            column += label.len() + 1;
+        }
+        canvas.dim_text(COLUMNS - 7, 0, "TAB <->");
+        canvas.text(0, 1, &format!("FIND: {}_", self.query), false);
+
+        let entries: Vec<PathBuf> = self.entries().into_iter().cloned().collect();
+        if entries.is_empty() {
+            let empty = match self.tab {
+                Tab::Browse if self.query.is_empty() => format!("no ROMs in {}", self.rom_dir.display()),
+                Tab::Recent if self.query.is_empty() => "nothing played yet".to_string(),
+                _ => "no matches".to_string(),
+            };
+            canvas.dim_text(1, LIST_TOP_LINE, &empty);
+        }
+        for (row, path) in entries.iter().enumerate().skip(self.scroll).take(LIST_LINES) {
+            canvas.text(0, LIST_TOP_LINE + row - self.scroll, &format!(" {}", file_name(path)), row == self.selected);
+        }
+        if self.scroll + LIST_LINES < entries.len() {
+            canvas.dim_text(COLUMNS - 1, LIST_TOP_LINE + LIST_LINES - 1, "+");
+        }
+
+        canvas.separator(INFO_LINE);
+        if let Some(path) = entries.get(self.selected) {
+            let info = self
+                .info_cache
+                .entry(path.clone())
+                .or_insert_with(|| RomInfo::from_file(path).ok());
+            match info {
+                Some(info) => {
+                    canvas.text(0, INFO_LINE, &info.title, false);
+                    canvas.dim_text(
+                        0,
+                        INFO_LINE + 1,
+                        &format!("{} B  CRC {:08X}  {}", info.size, info.crc32, info.platform),
+                    );
+                }
+                None => canvas.dim_text(0, INFO_LINE, "can't read this file"),
+            }
+        }
+
+        match &self.message {
+            Some(message) => canvas.text(0, STATUS_LINE, message, false),
+            None => canvas.dim_text(0, STATUS_LINE, "ENTER PLAY  ESC BACK  TYPE TO SEARCH"),
+        }
+    }
+}
+
+/// Text drawing into the launcher buffer, in character cells
+struct Canvas<'a> {
+    frame: &'a mut [u8],
+    foreground: [u8; 3],
+    dim: [u8; 3],
+    background: [u8; 4],
+}
+
+impl Canvas<'_> {
+    fn clear(&mut self) {
+        for pixel in self.frame.chunks_exact_mut(RGBA_PIXEL_SIZE) {
+            pixel.copy_from_slice(&self.background);
+        }
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, [r, g, b]: [u8; 3]) {
+        if x < LAUNCHER_WIDTH as usize && y < LAUNCHER_HEIGHT as usize {
+            let index = (y * LAUNCHER_WIDTH as usize + x) * RGBA_PIXEL_SIZE;
+            self.frame[index..index + RGBA_PIXEL_SIZE].copy_from_slice(&[r, g, b, u8::MAX]);
+        }
+    }
+
+    /// Draw `text` at a character cell, inverted if `highlight` is set
+    fn text(&mut self, column: usize, line: usize, text: &str, highlight: bool) {
+        let color = self.foreground;
+        if highlight {
+            let width = text.chars().count().min(COLUMNS.saturating_sub(column)) * CELL_WIDTH;
+            for y in 0..LINE_HEIGHT {
+                for x in 0..width {
+                    self.set_pixel(column * CELL_WIDTH + x, line * LINE_HEIGHT + y, color);
+                }
+            }
+            let [r, g, b, _] = self.background;
+            self.glyphs(column, line, text, [r, g, b]);
+        } else {
+            self.glyphs(column, line, text, color);
+        }
+    }
+
+    fn dim_text(&mut self, column: usize, line: usize, text: &str) {
+        let color = self.dim;
+        self.glyphs(column, line, text, color);
+    }
+
+    fn glyphs(&mut self, column: usize, line: usize, text: &str, color: [u8; 3]) {
+        for (i, c) in text.chars().take(COLUMNS.saturating_sub(column)).enumerate() {
+            let left = (column + i) * CELL_WIDTH;
+            let top = line * LINE_HEIGHT + 1;
+            for (y, row) in menu_glyph(c).iter().enumerate() {
+                for x in 0..MENU_GLYPH_WIDTH {
+                    if row >> (MENU_GLYPH_WIDTH - 1 - x) & 1 == 1 {
+                        self.set_pixel(left + x, top + y, color);
+                    }
+                }
+            }
+        }
+    }
+
+    /// A dotted rule just above `line`
+    fn separator(&mut self, line: usize) {
+        let color = self.dim;
+        for x in (0..LAUNCHER_WIDTH as usize).step_by(2) {
+            self.set_pixel(x, line * LINE_HEIGHT - 1, color);
+        }
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn recent_file() -> Option<PathBuf> {
+    config_layers::user_config_dir().map(|dir| dir.join(RECENT_FILE))
+}
+
+/// Recently played ROMs, most recent first, skipping any that no longer exist
+fn load_recent() -> Vec<PathBuf> {
+    recent_file().map(|file| read_recent(&file)).unwrap_or_default()
+}
+
+fn read_recent(file: &Path) -> Vec<PathBuf> {
+    let Ok(contents) = fs::read_to_string(file) else {
+        return Vec::new();
+    };
+    contents.lines().filter(|line| rom::source_exists(line)).map(PathBuf::from).collect()
+}
+
+/// Put `rom` at the top of the recent list. ROMs piped in on stdin can't be reopened.
+pub fn remember_recent(rom_path: &str) {
+    if let Some(file) = recent_file() {
+        add_recent(&file, rom_path);
+    }
+}
+
+fn add_recent(file: &Path, rom_path: &str) {
+    let rom = match RomSource::parse(rom_path) {
+        RomSource::Stdin => return,
+        RomSource::File(path) | RomSource::Archive { archive: path, entry: None } => absolute(path),
+        RomSource::Archive { archive, entry: Some(entry) } => {
+            PathBuf::from(format!("{}:{}", absolute(archive).display(), entry))
+        }
+    };
+
+    let mut recent = read_recent(file);
+    recent.retain(|path| *path != rom);
+    recent.insert(0, rom);
+    recent.truncate(RECENT_LIMIT);
+
+    let contents: String = recent.iter().map(|path| format!("{}\n", path.display())).collect();
+    let result = file.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| fs::write(file, contents));
+    if let Err(e) = result {
+        eprintln!("Warning: could not update {}: {}", file.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create(dir: &Path, names: &[&str]) {
+        for name in names {
+            fs::write(dir.join(name), [0x12, 0x00]).unwrap();
+        }
+    }
+
+    #[test]
+    fn search_filters_the_listing_by_file_name() {
+        let dir = tempfile::tempdir().unwrap();
+        create(dir.path(), &["Pong.ch8", "pong2.c8", "Tetris.ch8", "readme.txt"]);
+        let mut launcher = Launcher::new();
+        launcher.open(&dir.path().to_string_lossy());
+        launcher.tab = Tab::Browse;
+        let names = |launcher: &Launcher| launcher.entries().iter().map(|path| file_name(path)).collect::<Vec<_>>();
+        assert_eq!(names(&launcher), ["Pong.ch8", "pong2.c8", "Tetris.ch8"]);
+
+        launcher.query = "PONG".to_string();
+        assert_eq!(names(&launcher), ["Pong.ch8", "pong2.c8"]);
+        launcher.query = "2.".to_string();
+        assert_eq!(names(&launcher), ["pong2.c8"]);
+        launcher.query = "zzz".to_string();
+        assert!(names(&launcher).is_empty());
+    }
+
+    #[test]
+    fn unreadable_rom_dir_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut launcher = Launcher::new();
+        launcher.open(&dir.path().join("missing").to_string_lossy());
+        assert!(launcher.roms.is_empty());
+        assert!(launcher.message.as_deref().is_some_and(|message| message.starts_with("can't read")));
+    }
+
+    #[test]
+    fn recent_list_puts_the_latest_first_without_duplicates() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("config").join(RECENT_FILE);
+        let roms: Vec<String> = (0..RECENT_LIMIT + 2).map(|index| format!("game{}.ch8", index)).collect();
+        for rom in &roms {
+            create(dir.path(), &[rom]);
+        }
+        let path = |index: usize| absolute(&dir.path().join(&roms[index]));
+
+        add_recent(&file, &path(0).to_string_lossy());
+        add_recent(&file, &path(1).to_string_lossy());
+        add_recent(&file, &path(0).to_string_lossy());
+        assert_eq!(read_recent(&file), [path(0), path(1)]);
+
+        // Stdin can't be reopened, and ROMs that have gone are skipped
+        add_recent(&file, "-");
+        fs::remove_file(path(1)).unwrap();
+        assert_eq!(read_recent(&file), [path(0)]);
+
+        for index in 2..roms.len() {
+            add_recent(&file, &path(index).to_string_lossy());
+        }
+        let recent = read_recent(&file);
+        assert_eq!(recent.len(), RECENT_LIMIT);
+        assert_eq!(recent[0], path(roms.len() - 1));
+    }
+
+    #[test]
+    fn recent_archive_entries_keep_their_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join(RECENT_FILE);
+        let archive = dir.path().join("games.zip");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&archive).unwrap());
+        zip.start_file("PONG.ch8", zip::write::SimpleFileOptions::default()).unwrap();
+        zip.finish().unwrap();
+
+        add_recent(&file, &format!("{}:PONG.ch8", archive.display()));
+        let expected = PathBuf::from(format!("{}:PONG.ch8", absolute(&archive).display()));
+        assert_eq!(read_recent(&file), [expected]);
+    }
+}
@@ -0,0 +1,36 @@
+//! The emulator as a library, used by the `Chip8` binary and the integration
+//! tests in `tests/`.
+
+pub mod constants;
+pub mod opcodes;
+pub mod display;
+pub mod timer;
+pub mod audio;
+pub mod cpu;
+pub mod memory;
+pub mod decode_cache;
+pub mod reg;
+pub mod font;
+pub mod input;
+pub mod emulator;
+pub mod emulation_thread;
+pub mod gif_recorder;
+pub mod replay;
+pub mod wav_recorder;
+pub mod settings;
+pub mod cli;
+pub mod headless;
+pub mod gdb;
+pub mod hex;
+pub mod disasm;
+pub mod asm;
+pub mod rom;
+pub mod cartridge;
+pub mod octo;
+pub mod trace;
+pub mod profile;
+pub mod coverage;
+pub mod watch;
+pub mod launcher;
+pub mod config_watcher;
+pub mod config_layers;
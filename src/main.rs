@@ -1,18 +1,12 @@
-mod constants;
-mod opcodes;
-mod display;
-mod timer;
-mod cpu;
-mod memory;
-mod reg;
-mod font;
-mod input;
-mod emulator;
-mod gif_recorder;
-mod settings;
-
+use chip8::{asm, cli, config_watcher, constants, coverage, disasm, emulator, gdb, gif_recorder, headless, rom, trace};
+use cli::{Command, SettingsArgs};
+use config_watcher::ConfigWatcher;
+use constants::PROGRAM_START_ADDRESS;
+use coverage::CoverageMap;
 use emulator::{Emulator, EmulatorConfig};
-use settings::Settings;
+use gif_recorder::GifRecorder;
+use headless::Headless;
+use rom::RomFile;
 use winit::application::ApplicationHandler;
 use winit::dpi::LogicalSize;
 use winit::event::{WindowEvent, ElementState};
@@ -21,11 +15,14 @@ use winit::window::{Window, WindowId};
 use std::sync::Arc;
 use winit::keyboard::{KeyCode, PhysicalKey, ModifiersState};
 use std::env;
+use std::path::Path;
+use std::process::ExitCode;
 
 struct Chip8App {
     window: Option<Arc<Window>>,
     emulator: Option<Emulator>,
     config: EmulatorConfig,
+    config_watcher: Option<ConfigWatcher>,
     modifiers: ModifiersState,
 }
 
@@ -41,7 +38,10 @@ impl ApplicationHandler for Chip8App {
         ).unwrap());
 
         match Emulator::new(window.clone(), self.config.clone()) {
-            Ok(emulator) => {
+            Ok(mut emulator) => {
+                if let Some(watcher) = self.config_watcher.take() {
+                    emulator.watch_config(watcher);
+                }
                 self.emulator = Some(emulator);
                 self.window = Some(window);
             }
@@ -66,11 +66,21 @@ impl ApplicationHandler for Chip8App {
                     (event.state, event.physical_key),
                     (ElementState::Pressed, PhysicalKey::Code(KeyCode::Escape))
                 ) {
-                    event_loop.exit();
+                    // Escape leaves the launcher first, and quits from the game
+                    if !self.emulator.as_mut().is_some_and(Emulator::handle_escape) {
+                        event_loop.exit();
+                    }
                 } else if let Some(emulator) = &mut self.emulator {
                     emulator.handle_keyboard_input(&event, &self.modifiers);
                 }
             }
+            WindowEvent::DroppedFile(path) => {
+                if let Some(emulator) = &mut self.emulator
+                    && let Err(e) = emulator.open_rom(&path.to_string_lossy())
+                {
+                    eprintln!("Failed to load dropped file: {}", e);
+                }
+            }
             WindowEvent::RedrawRequested => {
                 if let Some(emulator) = &mut self.emulator {
                     emulator.update();
@@ -92,73 +102,164 @@ impl ApplicationHandler for Chip8App {
     }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init();
+fn main() -> ExitCode {
+    // Trace lines go through the logger unless trace.output names a file
+    let filter = format!("error,{}=trace", trace::TRACE_TARGET);
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(filter)).init();
     
     let args: Vec<String> = env::args().collect();
+    let program = args.first()
+        .and_then(|arg| Path::new(arg).file_stem())
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("chip8")
+        .to_string();
     
-    if args.len() < 2 {
-        println!("CHIP-8 Emulator");
-        println!("Usage: {} <rom_file> [config_file]", args[0]);
-        println!("  rom_file:    Path to CHIP-8 ROM file (.ch8)");
-        println!("  config_file: Optional path to config file (.toml)");
-        println!();
-        println!("Example: {} PONG.ch8", args[0]);
-        println!("Example: {} PONG.ch8 chip8_config.toml", args[0]);
-        return Ok(());
-    }
-    
-    let rom_path = args[1].clone();
+    let command = match cli::parse(&args[1..]) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            eprintln!();
+            eprintln!("Run '{} help' for usage.", program);
+            return ExitCode::from(2);
+        }
+    };
     
-    // Load settings from config file if provided
-    let settings = if args.len() >= 3 {
-        let config_file = &args[2];
-        println!("Loading config from: {}", config_file);
-        match Settings::load_from_file(config_file) {
-            Ok(s) => {
-                println!("Config loaded successfully.");
-                s
-            },
-            Err(e) => {
-                eprintln!("Failed to load config file: {}. Using defaults.", e);
-                Settings::default()
+    match run_command(command, &program) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run_command(command: Command, program: &str) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::Help { topic } => println!("{}", cli::usage(program, topic.as_deref())?),
+        Command::Run { rom, settings } => run_window(rom, &settings)?,
+        Command::Headless { rom, frames, settings } => {
+            let rom_file = RomFile::load_from_file(&rom)?;
+            rom_file.print_warnings(&rom);
+            let data = rom_file.data;
+            let mut headless = Headless::new(settings.load()?, &data);
+            // On a fault, show the screen as the program left it
+            let result = (0..frames).try_for_each(|_| headless.run_frame().map(|_| ()));
+            print!("{}", headless.screen_text());
+            result.map_err(|fault| format!("CPU fault: {}", fault))?;
+        }
+        Command::Record { rom, frames, output, settings } => {
+            let settings = settings.load()?;
+            let rom_file = RomFile::load_from_file(&rom)?;
+            rom_file.print_warnings(&rom);
+            let data = rom_file.data;
+            let output = output.unwrap_or_else(|| GifRecorder::generate_filename(
+                &Emulator::extract_rom_name(&rom),
+                &settings.recording.output_dir,
+                "chip8_{rom_name}_{timestamp}"
+            ));
+            headless::record(settings, &data, frames, &output)?;
+        }
+        Command::Speed { rom, duration, settings } => {
+            let settings = settings.load()?;
+            let rom_file = RomFile::load_from_file(&rom)?;
+            rom_file.print_warnings(&rom);
+            let configured = settings.cpu.cycles_per_frame as f64 * settings.cpu.target_fps as f64;
+            let mut headless = Headless::new(settings, &rom_file.data);
+            let report = headless.run_max_speed(duration);
+            let per_second = report.instructions_per_second();
+            println!(
+                "Ran {} instructions ({} frames) in {:.2} s",
+                report.instructions,
+                report.frames,
+                report.elapsed.as_secs_f64()
+            );
+            println!(
+                "{:.2} million instructions per second, {:.0}x the configured {} per second",
+                per_second / 1_000_000.0,
+                per_second / configured,
+                configured
+            );
+            if let Some(fault) = report.fault {
+                return Err(format!("CPU fault: {}", fault).into());
             }
         }
-    } else {
-        // Check if default config file exists
-        if std::path::Path::new("chip8_config.toml").exists() {
-            println!("Found chip8_config.toml, loading...");
-            match Settings::load_from_file("chip8_config.toml") {
-                Ok(s) => {
-                    println!("Config loaded successfully.");
-                    s
-                },
-                Err(e) => {
-                    eprintln!("Failed to load default config: {}. Using defaults.", e);
-                    Settings::default()
-                }
+        Command::Gdb { rom, port, settings } => {
+            let settings = settings.load()?;
+            let rom_file = RomFile::load_from_file(&rom)?;
+            rom_file.print_warnings(&rom);
+            gdb::serve(settings, &rom_file.data, port)?;
+        }
+        Command::Coverage { files, output } => {
+            let mut merged = CoverageMap::load(Path::new(&files[0]))?;
+            for file in &files[1..] {
+                merged.merge(&CoverageMap::load(Path::new(file))?).map_err(|e| format!("{}: {}", file, e))?;
             }
-        } else {
-            println!("No config file specified. Using default settings.");
-            Settings::default()
+            merged.write_reports(output.as_deref().unwrap_or(coverage::DEFAULT_MERGED_OUTPUT))?;
         }
-    };
-    
-    println!("Starting CHIP-8 emulator with ROM: {}", rom_path);
+        Command::Disasm { rom, output } => {
+            // Any file can be disassembled, even one too large to run
+            let data = RomFile::read(&rom)?.data;
+            let listing = disasm::disassemble(&data, PROGRAM_START_ADDRESS);
+            match output {
+                Some(path) => std::fs::write(path, listing)?,
+                None => print!("{}", listing),
+            }
+        }
+        Command::Asm { source, output } => {
+            let text = std::fs::read_to_string(&source)?;
+            let program = asm::assemble(&text).map_err(|e| format!("{}: {}", source, e))?;
+            let output = output.unwrap_or_else(|| Path::new(&source).with_extension("ch8").to_string_lossy().into_owned());
+            std::fs::write(&output, &program)?;
+            println!("Assembled {} bytes to {}", program.len(), output);
+        }
+        Command::CheckConfig { settings } => {
+            settings.load()?;
+            println!("Settings are valid.");
+        }
+        Command::PrintConfig { output, settings } => {
+            let layered = settings.load_layered()?;
+            print!("{}", layered.describe());
+            if let Some(path) = output {
+                layered.settings().save_to_file(&path)?;
+                eprintln!("Saved settings to {}", path);
+            }
+        }
+        Command::Info { rom } => {
+            print!("{}", rom::report(&rom, &RomFile::read(&rom)?));
+        }
+    }
+    Ok(())
+}
+
+fn run_window(rom_path: String, settings_args: &SettingsArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let settings = settings_args.load()?;
+
+    // Re-run the same loading steps when a config file changes, so overrides still apply
+    let config_watcher = ConfigWatcher::new(settings_args.clone());
+
+    if rom_path.is_empty() {
+        println!("Starting CHIP-8 emulator in the ROM launcher");
+    } else {
+        println!("Starting CHIP-8 emulator with ROM: {}", rom_path);
+    }
     println!("Controls: Press 'Ctrl+R' to start/stop GIF recording");
+    println!("          Press 'Ctrl+Shift+R' to save an instant replay");
+    println!("          Press 'Ctrl+O' to pick another ROM, or drop one on the window");
+    println!("          Press 'F5' to soft reset, 'Shift+F5' to hard reset");
     
     let config = EmulatorConfig {
         rom_path,
         settings,
     };
 
-    let event_loop = EventLoop::new().unwrap();
+    let event_loop = EventLoop::new()?;
     let mut app = Chip8App {
         window: None,
         emulator: None,
         config,
+        config_watcher: Some(config_watcher),
         modifiers: ModifiersState::empty(),
     };
 
     event_loop.run_app(&mut app).map_err(|e| Box::new(e) as Box<dyn std::error::Error>)
-}
\ No newline at end of file
+}
@@ -1,5 +1,5 @@
 // CHIP-8 memory layout constants
-const MEMORY_SIZE: usize = 4096;           // Total memory size (4KB)
+pub const MEMORY_SIZE: usize = 4096;       // Total memory size (4KB)
 const MEMORY_SIZE_HEX: u16 = 0x1000;      // Memory size as hex  
 const MEMORY_MAX_ADDRESS: u16 = 0xFFF;    // Maximum valid memory address
 
@@ -15,6 +15,12 @@ pub struct Memory {
     memory: [u8; MEMORY_SIZE],
 }
 
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Memory {
     pub fn new() -> Self {
         Self { memory: [0; MEMORY_SIZE] } // initialize memory to 0!
@@ -49,17 +55,6 @@ impl Memory {
 }
 
 
-pub struct RomFile {
-    pub data: Vec<u8>,
-}
-
-impl RomFile {
-    pub fn load_from_file(file_path: &str) -> Result<Self, std::io::Error> {
-        let data = std::fs::read(file_path)?;
-        Ok(Self { data })
-    }
-}
-
 // im pretty sure an array like this is the best way to do it. There might be something about how 
 // programs interface that we might need to consider, but that is for sure a future us problem.
 
@@ -74,13 +69,19 @@ modern implementations usually have more.[22][23]
 // a pointer in this space is 16 bits wide
 
 
-const STACK_SIZE: usize = 12;
+pub const STACK_SIZE: usize = 12;
 
 pub struct Stack {
     stack: [Option<u16>; STACK_SIZE], // 48 bytes total, 13 bits to store a pointer so we need 16 bits total. We are going to emulate 12 for nesting tho.
 
 }
 
+impl Default for Stack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Stack {
     pub fn new() -> Self {
         Self { stack: [None; STACK_SIZE] } // Use None for empty slots
@@ -96,6 +97,11 @@ impl Stack {
         None
     }
 
+    /// How many return addresses are on the stack
+    pub fn depth(&self) -> usize {
+        self.stack.iter().filter(|slot| slot.is_some()).count()
+    }
+
     pub fn push(&mut self, value: u16) -> Result<(), &'static str> {
         for i in 0..STACK_SIZE {
             if self.stack[i].is_none() {
@@ -105,4 +111,75 @@ impl Stack {
         }
         Err("Stack overflow")
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_starts_zeroed_and_keeps_writes() {
+        let mut memory = Memory::new();
+        assert!((0..MEMORY_SIZE_HEX).all(|address| memory.read(address) == 0));
+        memory.write(0x000, 0x12);
+        memory.write(MEMORY_MAX_ADDRESS, 0x34);
+        assert_eq!(memory.read(0x000), 0x12);
+        assert_eq!(memory.read(MEMORY_MAX_ADDRESS), 0x34);
+    }
+
+    #[test]
+    fn read_u16_is_big_endian() {
+        let mut memory = Memory::new();
+        memory.write(0xFFD, 0xAB);
+        memory.write(0xFFE, 0xCD);
+        assert_eq!(memory.read_u16(0xFFD), 0xABCD);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn read_past_the_end_panics() {
+        Memory::new().read(MEMORY_SIZE_HEX);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn read_u16_of_the_last_byte_panics() {
+        Memory::new().read_u16(MEMORY_MAX_ADDRESS);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn write_past_the_end_panics() {
+        Memory::new().write(MEMORY_SIZE_HEX, 0);
+    }
+
+    #[test]
+    fn stack_is_last_in_first_out() {
+        let mut stack = Stack::new();
+        stack.push(0x200).unwrap();
+        stack.push(0x300).unwrap();
+        assert_eq!(stack.depth(), 2);
+        assert_eq!(stack.pop(), Some(0x300));
+        assert_eq!(stack.pop(), Some(0x200));
+        assert_eq!(stack.depth(), 0);
+    }
+
+    #[test]
+    fn stack_overflows_past_its_size() {
+        let mut stack = Stack::new();
+        for level in 0..STACK_SIZE {
+            stack.push(level as u16).unwrap();
+        }
+        assert!(stack.push(0xFFF).is_err());
+        assert_eq!(stack.depth(), STACK_SIZE);
+        assert_eq!(stack.pop(), Some(STACK_SIZE as u16 - 1), "the overflowing push is dropped");
+    }
+
+    #[test]
+    fn stack_underflow_returns_none() {
+        let mut stack = Stack::new();
+        assert_eq!(stack.pop(), None);
+        stack.push(0x200).unwrap();
+        stack.pop();
+        assert_eq!(stack.pop(), None);
+    }
+}
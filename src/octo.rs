@@ -0,0 +1,787 @@
+//! A compiler for the CHIP-8 subset of Octo, the language Octo cartridges carry.
+//!
+//! Supported:
+//!   - labels (`: name`), `:const`, `:alias`, `:unpack`, `:next`, `:org`, `:byte`
+//!   - every CHIP-8 statement: `clear`, `return`/`;`, `jump`, `jump0`, `native`,
+//!     `sprite`, `bcd`, `save`, `load`, `i := ...`, `i += vx`, `delay`/`buzzer :=`
+//!     and `vx` assignments and arithmetic
+//!   - `if ... then`, `if ... begin ... else ... end`, `loop ... while ... again`,
+//!     including the `<`, `>`, `<=` and `>=` comparisons (which clobber vf)
+//!   - calls by naming a label, and bare numbers as data bytes
+//!
+//! Macros, `:calc`, `:stringmode` and the SUPER-CHIP/XO-CHIP statements are
+//! reported as errors rather than compiled wrongly. Execution starts at
+//! `: main` through a jump at 0x200, as in Octo.
+
+use crate::constants::PROGRAM_START_ADDRESS;
+use std::collections::HashMap;
+use std::fmt;
+
+const ADDRESS_MAX: i32 = 0xFFF;
+const BYTE_MIN: i32 = -128;             // Negative bytes wrap, e.g. `v0 += -1`
+const BYTE_MAX: i32 = 0xFF;
+const NIBBLE_MAX: i32 = 0xF;
+const FLAG_REGISTER: u8 = 0xF;
+
+/// Statements of the later platforms, which this emulator doesn't run
+const EXTENDED_STATEMENTS: [&str; 14] = [
+    "hires", "lores", "scroll-down", "scroll-up", "scroll-left", "scroll-right", "exit",
+    "saveflags", "loadflags", "plane", "audio", "pitch", "long", "bighex",
+];
+const UNSUPPORTED_DIRECTIVES: [&str; 6] = [":macro", ":calc", ":stringmode", ":assert", ":call", ":pointer"];
+
+#[derive(Debug)]
+pub struct OctoError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for OctoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for OctoError {}
+
+#[derive(Clone, Copy)]
+struct Token<'a> {
+    line: usize,
+    text: &'a str,
+}
+
+/// How a forward reference is patched once its label is defined
+#[derive(Clone, Copy)]
+enum FixupKind {
+    /// The low 12 bits of the instruction at the address
+    Address,
+    /// `:unpack`'s high byte: the nibble given, then the address's top 4 bits
+    UnpackHigh(u8),
+    UnpackLow,
+}
+
+struct Fixup {
+    at: u16,
+    name: String,
+    line: usize,
+    kind: FixupKind,
+}
+
+/// The right-hand side of a comparison or assignment
+#[derive(Clone, Copy)]
+enum Operand {
+    Register(u8),
+    Value(u8),
+}
+
+/// A condition, compiled to the instruction that skips when it is false
+/// (`then`) or true (`begin` and `while`)
+enum Condition {
+    Equal(u8, Operand),
+    NotEqual(u8, Operand),
+    Key(u8),
+    NotKey(u8),
+    /// Compared through vf: `vf := rhs`, then `subtract_vx` picks `vf -= vx`
+    /// or `vf =- vx`, leaving vf's flag set when the condition `holds_when_flag`
+    Compare { register: u8, rhs: Operand, subtract_vx: bool, holds_when_flag: bool },
+}
+
+/// Compile Octo source into a program to be loaded at 0x200
+pub fn compile(source: &str) -> Result<Vec<u8>, OctoError> {
+    let tokens = source
+        .lines()
+        .enumerate()
+        .flat_map(|(index, line)| {
+            let code = line.split('#').next().unwrap_or("");
+            code.split_whitespace().map(move |text| Token { line: index + 1, text })
+        })
+        .collect();
+
+    let mut compiler = Compiler {
+        tokens,
+        position: 0,
+        rom: Vec::new(),
+        here: PROGRAM_START_ADDRESS,
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        fixups: Vec::new(),
+        loops: Vec::new(),
+        branches: Vec::new(),
+    };
+
+    // The jump to main, patched like any other forward reference
+    compiler.fixups.push(Fixup { at: compiler.here, name: "main".to_string(), line: 1, kind: FixupKind::Address });
+    compiler.emit(0x1000);
+    while compiler.position < compiler.tokens.len() {
+        compiler.statement()?;
+    }
+    compiler.finish()
+}
+
+struct OpenLoop {
+    start: u16,
+    exits: Vec<u16>,
+    line: usize,
+}
+
+struct Compiler<'a> {
+    tokens: Vec<Token<'a>>,
+    position: usize,
+    rom: Vec<u8>,
+    here: u16,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, i32>,
+    aliases: HashMap<String, u8>,
+    fixups: Vec<Fixup>,
+    /// Start address of each open `loop`, with the jumps its `while`s exit through
+    loops: Vec<OpenLoop>,
+    /// Jumps waiting for the `else` or `end` of an open `begin`, with its line
+    branches: Vec<(u16, usize)>,
+}
+
+impl<'a> Compiler<'a> {
+    fn line(&self) -> usize {
+        let index = self.position.min(self.tokens.len()).saturating_sub(1);
+        self.tokens.get(index).map_or(1, |token| token.line)
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, OctoError> {
+        Err(OctoError { line: self.line(), message })
+    }
+
+    fn next(&mut self) -> Result<&'a str, OctoError> {
+        match self.tokens.get(self.position) {
+            Some(token) => {
+                self.position += 1;
+                Ok(token.text)
+            }
+            None => self.error("unexpected end of program".to_string()),
+        }
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.position).map(|token| token.text)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), OctoError> {
+        let token = self.next()?;
+        if token != expected {
+            return self.error(format!("expected '{}', found '{}'", expected, token));
+        }
+        Ok(())
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let offset = (self.here - PROGRAM_START_ADDRESS) as usize;
+        if offset >= self.rom.len() {
+            self.rom.resize(offset + 1, 0);
+        }
+        self.rom[offset] = byte;
+        self.here += 1;
+    }
+
+    fn emit(&mut self, word: u16) {
+        let [high, low] = word.to_be_bytes();
+        self.emit_byte(high);
+        self.emit_byte(low);
+    }
+
+    fn patch(&mut self, at: u16, kind: FixupKind, address: u16) {
+        let offset = (at - PROGRAM_START_ADDRESS) as usize;
+        match kind {
+            FixupKind::Address => {
+                self.rom[offset] = (self.rom[offset] & 0xF0) | (address >> 8) as u8;
+                self.rom[offset + 1] = address as u8;
+            }
+            FixupKind::UnpackHigh(nibble) => self.rom[offset] = (nibble << 4) | (address >> 8) as u8,
+            FixupKind::UnpackLow => self.rom[offset] = address as u8,
+        }
+    }
+
+    fn define_label(&mut self, name: &str, address: u16) -> Result<(), OctoError> {
+        if self.labels.insert(name.to_string(), address).is_some() {
+            return self.error(format!("the name '{}' is already defined", name));
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self) -> Result<(), OctoError> {
+        let token = self.next()?;
+        match token {
+            ":" => {
+                let name = self.next()?;
+                self.define_label(name, self.here)?;
+            }
+            ":const" => {
+                let name = self.next()?;
+                let value = self.value(i32::MIN, i32::MAX)?;
+                self.constants.insert(name.to_string(), value);
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let register = self.register()?;
+                self.aliases.insert(name.to_string(), register);
+            }
+            ":unpack" => {
+                // v0 := nibble and the address's top 4 bits, v1 := its low byte
+                let nibble = self.value(0, NIBBLE_MAX)? as u8;
+                let at = self.here;
+                self.emit(0x6000);
+                self.emit(0x6100);
+                self.reference(&[(at + 1, FixupKind::UnpackHigh(nibble)), (at + 3, FixupKind::UnpackLow)])?;
+            }
+            ":next" => {
+                let name = self.next()?;
+                self.define_label(name, self.here + 1)?;
+            }
+            ":org" => {
+                let address = self.value(PROGRAM_START_ADDRESS as i32, ADDRESS_MAX)?;
+                self.here = address as u16;
+            }
+            ":byte" => {
+                let value = self.value(BYTE_MIN, BYTE_MAX)?;
+                self.emit_byte(value as u8);
+            }
+            // Debugger hints, which don't affect the program
+            ":breakpoint" => {
+                self.next()?;
+            }
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            }
+            ";" | "return" => self.emit(0x00EE),
+            "clear" => self.emit(0x00E0),
+            "bcd" => self.register_instruction(0xF033)?,
+            "save" => self.register_instruction(0xF055)?,
+            "load" => self.register_instruction(0xF065)?,
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let height = self.value(0, NIBBLE_MAX)?;
+                self.emit(0xD000 | (x as u16) << 8 | (y as u16) << 4 | height as u16);
+            }
+            "jump" => self.address_instruction(0x1000)?,
+            "jump0" => self.address_instruction(0xB000)?,
+            "native" => self.address_instruction(0x0000)?,
+            "delay" | "buzzer" => {
+                self.expect(":=")?;
+                let register = self.register()? as u16;
+                self.emit(if token == "delay" { 0xF015 } else { 0xF018 } | register << 8);
+            }
+            "i" => self.index_statement()?,
+            "if" => self.if_statement()?,
+            "else" => {
+                let Some((branch, line)) = self.branches.pop() else {
+                    return self.error("'else' without 'begin'".to_string());
+                };
+                self.branches.push((self.here, line));
+                self.emit(0x1000);
+                self.patch(branch, FixupKind::Address, self.here);
+            }
+            "end" => {
+                let Some((branch, _)) = self.branches.pop() else {
+                    return self.error("'end' without 'begin'".to_string());
+                };
+                self.patch(branch, FixupKind::Address, self.here);
+            }
+            "loop" => self.loops.push(OpenLoop { start: self.here, exits: Vec::new(), line: self.line() }),
+            "while" => {
+                if self.loops.is_empty() {
+                    return self.error("'while' outside a loop".to_string());
+                }
+                let condition = self.condition()?;
+                self.skip_when(&condition, true);
+                let exit = self.here;
+                self.emit(0x1000);
+                if let Some(open) = self.loops.last_mut() {
+                    open.exits.push(exit);
+                }
+            }
+            "again" => {
+                let Some(open) = self.loops.pop() else {
+                    return self.error("'again' without 'loop'".to_string());
+                };
+                self.emit(0x1000 | open.start);
+                for exit in open.exits {
+                    self.patch(exit, FixupKind::Address, self.here);
+                }
+            }
+            _ if self.is_register(token) => {
+                self.position -= 1;
+                self.register_statement()?;
+            }
+            _ if EXTENDED_STATEMENTS.contains(&token) => {
+                return self.error(format!("'{}' is a SUPER-CHIP/XO-CHIP statement, which this emulator doesn't run", token));
+            }
+            _ if UNSUPPORTED_DIRECTIVES.contains(&token) => {
+                return self.error(format!("'{}' isn't supported", token));
+            }
+            _ if token.starts_with(':') => return self.error(format!("unknown directive '{}'", token)),
+            _ => {
+                if let Some(value) = self.number_or_constant(token) {
+                    // Bare numbers are data
+                    if !(BYTE_MIN..=BYTE_MAX).contains(&value) {
+                        return self.error(format!("byte {} out of range", token));
+                    }
+                    self.emit_byte(value as u8);
+                } else {
+                    // Naming a label calls it
+                    self.position -= 1;
+                    self.address_instruction(0x2000)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn register_instruction(&mut self, opcode: u16) -> Result<(), OctoError> {
+        let register = self.register()? as u16;
+        self.emit(opcode | register << 8);
+        Ok(())
+    }
+
+    /// Emit an instruction taking an address, resolved now or once the label is defined
+    fn address_instruction(&mut self, opcode: u16) -> Result<(), OctoError> {
+        let at = self.here;
+        self.emit(opcode);
+        self.reference(&[(at, FixupKind::Address)])
+    }
+
+    /// Read an address and patch it into each place, now or once its label is defined
+    fn reference(&mut self, places: &[(u16, FixupKind)]) -> Result<(), OctoError> {
+        let token = self.next()?;
+        let address = match self.number_or_constant(token) {
+            Some(value) if (0..=ADDRESS_MAX).contains(&value) => value as u16,
+            Some(value) => return self.error(format!("address {:#X} out of range", value)),
+            None => match self.labels.get(token) {
+                Some(&address) => address,
+                None => {
+                    for &(at, kind) in places {
+                        self.fixups.push(Fixup { at, name: token.to_string(), line: self.line(), kind });
+                    }
+                    return Ok(());
+                }
+            },
+        };
+        for &(at, kind) in places {
+            self.patch(at, kind, address);
+        }
+        Ok(())
+    }
+
+    fn index_statement(&mut self) -> Result<(), OctoError> {
+        match self.next()? {
+            ":=" if self.peek() == Some("hex") => {
+                self.next()?;
+                self.register_instruction(0xF029)
+            }
+            ":=" => self.address_instruction(0xA000),
+            "+=" => self.register_instruction(0xF01E),
+            other => self.error(format!("unknown operator 'i {}'", other)),
+        }
+    }
+
+    fn register_statement(&mut self) -> Result<(), OctoError> {
+        let x = self.register()? as u16;
+        let operator = self.next()?;
+        if operator == ":=" {
+            match self.peek() {
+                Some("random") => {
+                    self.next()?;
+                    let mask = self.value(BYTE_MIN, BYTE_MAX)? as u8;
+                    self.emit(0xC000 | x << 8 | mask as u16);
+                    return Ok(());
+                }
+                Some("key") => {
+                    self.next()?;
+                    self.emit(0xF00A | x << 8);
+                    return Ok(());
+                }
+                Some("delay") => {
+                    self.next()?;
+                    self.emit(0xF007 | x << 8);
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+
+        let operand = self.operand()?;
+        let word = match (operator, operand) {
+            (":=", Operand::Value(n)) => 0x6000 | x << 8 | n as u16,
+            (":=", Operand::Register(y)) => 0x8000 | x << 8 | (y as u16) << 4,
+            ("+=", Operand::Value(n)) => 0x7000 | x << 8 | n as u16,
+            ("-=", Operand::Value(n)) => 0x7000 | x << 8 | n.wrapping_neg() as u16,
+            (_, Operand::Register(y)) => {
+                let function = match operator {
+                    "|=" => 0x1,
+                    "&=" => 0x2,
+                    "^=" => 0x3,
+                    "+=" => 0x4,
+                    "-=" => 0x5,
+                    ">>=" => 0x6,
+                    "=-" => 0x7,
+                    "<<=" => 0xE,
+                    _ => return self.error(format!("unknown operator '{}'", operator)),
+                };
+                0x8000 | x << 8 | (y as u16) << 4 | function
+            }
+            _ => return self.error(format!("'{}' needs a register on the right", operator)),
+        };
+        self.emit(word);
+        Ok(())
+    }
+
+    fn if_statement(&mut self) -> Result<(), OctoError> {
+        let condition = self.condition()?;
+        match self.next()? {
+            "then" => self.skip_when(&condition, false),
+            "begin" => {
+                self.skip_when(&condition, true);
+                self.branches.push((self.here, self.line()));
+                self.emit(0x1000);
+            }
+            other => return self.error(format!("expected 'then' or 'begin', found '{}'", other)),
+        }
+        Ok(())
+    }
+
+    fn condition(&mut self) -> Result<Condition, OctoError> {
+        let register = self.register()?;
+        let operator = self.next()?;
+        let condition = match operator {
+            "key" => Condition::Key(register),
+            "-key" => Condition::NotKey(register),
+            "==" => Condition::Equal(register, self.operand()?),
+            "!=" => Condition::NotEqual(register, self.operand()?),
+            "<" | ">=" => Condition::Compare {
+                register,
+                rhs: self.operand()?,
+                subtract_vx: false,
+                holds_when_flag: operator == ">=",
+            },
+            ">" | "<=" => Condition::Compare {
+                register,
+                rhs: self.operand()?,
+                subtract_vx: true,
+                holds_when_flag: operator == "<=",
+            },
+            other => return self.error(format!("unknown comparison '{}'", other)),
+        };
+        Ok(condition)
+    }
+
+    /// Emit the instruction that skips the next one when `condition` is
+    /// `when_true`, after any set-up it needs
+    fn skip_when(&mut self, condition: &Condition, when_true: bool) {
+        let x = |register: u8| (register as u16) << 8;
+        let (skip_if_equal, skip_if_not_equal) = match *condition {
+            Condition::Equal(register, rhs) | Condition::NotEqual(register, rhs) => {
+                let (equal, not_equal) = match rhs {
+                    Operand::Value(n) => (0x3000 | x(register) | n as u16, 0x4000 | x(register) | n as u16),
+                    Operand::Register(y) => (0x5000 | x(register) | (y as u16) << 4, 0x9000 | x(register) | (y as u16) << 4),
+                };
+                match condition {
+                    Condition::Equal(..) => (equal, not_equal),
+                    _ => (not_equal, equal),
+                }
+            }
+            Condition::Key(register) => (0xE09E | x(register), 0xE0A1 | x(register)),
+            Condition::NotKey(register) => (0xE0A1 | x(register), 0xE09E | x(register)),
+            Condition::Compare { register, rhs, subtract_vx, holds_when_flag } => {
+                let vf = x(FLAG_REGISTER);
+                self.emit(match rhs {
+                    Operand::Value(n) => 0x6000 | vf | n as u16,
+                    Operand::Register(y) => 0x8000 | vf | (y as u16) << 4,
+                });
+                let function = if subtract_vx { 0x5 } else { 0x7 };
+                self.emit(0x8000 | vf | (register as u16) << 4 | function);
+                // vf is now 1 or 0, so compare it with 1
+                let skip_if_flag = 0x3001 | vf;
+                let skip_unless_flag = 0x4001 | vf;
+                if holds_when_flag { (skip_if_flag, skip_unless_flag) } else { (skip_unless_flag, skip_if_flag) }
+            }
+        };
+        // "Skip when true" is the skip-if-equal form of the condition
+        self.emit(if when_true { skip_if_equal } else { skip_if_not_equal });
+    }
+
+    fn operand(&mut self) -> Result<Operand, OctoError> {
+        match self.peek() {
+            Some(token) if self.is_register(token) => Ok(Operand::Register(self.register()?)),
+            _ => Ok(Operand::Value(self.value(BYTE_MIN, BYTE_MAX)? as u8)),
+        }
+    }
+
+    fn is_register(&self, token: &str) -> bool {
+        self.aliases.contains_key(token) || parse_register(token).is_some()
+    }
+
+    fn register(&mut self) -> Result<u8, OctoError> {
+        let token = self.next()?;
+        match self.aliases.get(token).copied().or_else(|| parse_register(token)) {
+            Some(register) => Ok(register),
+            None => self.error(format!("expected a register, found '{}'", token)),
+        }
+    }
+
+    /// A number, constant or already-defined label within `min..=max`
+    fn value(&mut self, min: i32, max: i32) -> Result<i32, OctoError> {
+        let token = self.next()?;
+        let value = self
+            .number_or_constant(token)
+            .or_else(|| self.labels.get(token).map(|&address| address as i32));
+        match value {
+            Some(value) if (min..=max).contains(&value) => Ok(value),
+            Some(value) => self.error(format!("value {} out of range ({} to {})", value, min, max)),
+            None => self.error(format!("unknown name '{}'", token)),
+        }
+    }
+
+    fn number_or_constant(&self, token: &str) -> Option<i32> {
+        parse_number(token).or_else(|| self.constants.get(token).copied())
+    }
+
+    fn finish(mut self) -> Result<Vec<u8>, OctoError> {
+        if let Some(open) = self.loops.last() {
+            return Err(OctoError { line: open.line, message: "'loop' without 'again'".to_string() });
+        }
+        if let Some(&(_, line)) = self.branches.last() {
+            return Err(OctoError { line, message: "'begin' without 'end'".to_string() });
+        }
+        for fixup in std::mem::take(&mut self.fixups) {
+            let Some(&address) = self.labels.get(&fixup.name) else {
+                let message = if fixup.name == "main" {
+                    "the program has no ': main' label".to_string()
+                } else {
+                    format!("unknown name '{}'", fixup.name)
+                };
+                return Err(OctoError { line: fixup.line, message });
+            };
+            self.patch(fixup.at, fixup.kind, address);
+        }
+        Ok(self.rom)
+    }
+}
+
+fn parse_register(token: &str) -> Option<u8> {
+    let digit = token.strip_prefix(['v', 'V'])?;
+    (digit.len() == 1).then(|| u8::from_str_radix(digit, 16).ok()).flatten()
+}
+
+fn parse_number(token: &str) -> Option<i32> {
+    let (negative, digits) = match token.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, token),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i32::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+        i32::from_str_radix(binary, 2).ok()?
+    } else {
+        digits.parse().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::SilentBackend;
+    use crate::cpu::CPU;
+    use crate::settings::Settings;
+
+    /// The compiled program as instruction words, after the jump to main
+    fn words(source: &str) -> Vec<u16> {
+        let rom = compile(source).unwrap();
+        assert_eq!(rom.len() % 2, 0, "the program ends mid-word");
+        rom.chunks(2).skip(1).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect()
+    }
+
+    fn error(source: &str) -> (usize, String) {
+        let error = compile(source).unwrap_err();
+        (error.line, error.message)
+    }
+
+    #[test]
+    fn program_starts_with_a_jump_to_main() {
+        let rom = compile("clear : main return").unwrap();
+        assert_eq!(rom, [0x12, 0x04, 0x00, 0xE0, 0x00, 0xEE]);
+    }
+
+    #[test]
+    fn simple_statements() {
+        let source = "
+            : main
+            clear bcd v1 save v2 load v3 sprite v4 v5 6
+            jump main jump0 0x300 native 0x400
+            delay := v6 buzzer := v7
+            i := hex v8 i := 0x123 i += v9
+            return ;
+        ";
+        assert_eq!(
+            words(source),
+            [
+                0x00E0, 0xF133, 0xF255, 0xF365, 0xD456, 0x1202, 0xB300, 0x0400, 0xF615, 0xF718, 0xF829, 0xA123,
+                0xF91E, 0x00EE, 0x00EE,
+            ]
+        );
+    }
+
+    #[test]
+    fn register_statements() {
+        let source = "
+            : main
+            v1 := 0x12 v2 := v3 v4 += 5 v5 -= 1
+            v6 |= v7 v6 &= v7 v6 ^= v7 v6 += v7 v6 -= v7 v6 >>= v7 v6 =- v7 v6 <<= v7
+            v8 := random 0x0F v9 := key va := delay
+        ";
+        assert_eq!(
+            words(source),
+            [
+                0x6112, 0x8230, 0x7405, 0x75FF, 0x8671, 0x8672, 0x8673, 0x8674, 0x8675, 0x8676, 0x8677, 0x867E,
+                0xC80F, 0xF90A, 0xFA07,
+            ]
+        );
+    }
+
+    #[test]
+    fn constants_and_aliases() {
+        assert_eq!(words(":const SPEED 3 :alias x v4 : main x := SPEED x += x"), [0x6403, 0x8444]);
+    }
+
+    #[test]
+    fn if_then_skips_when_the_condition_is_false() {
+        let source = ": main if v1 key then if v1 -key then if v1 == v2 then if v1 != 3 then";
+        assert_eq!(words(source), [0xE1A1, 0xE19E, 0x9120, 0x3103]);
+    }
+
+    #[test]
+    fn if_begin_else_end() {
+        let source = ": main if v0 == 1 begin v1 := 2 else v1 := 3 end";
+        // The skip jumps over the branch to else when the condition holds
+        assert_eq!(words(source), [0x3001, 0x120A, 0x6102, 0x120C, 0x6103]);
+        assert_eq!(words(": main if v0 != v2 begin v1 := 2 end"), [0x9020, 0x1208, 0x6102]);
+    }
+
+    #[test]
+    fn loop_while_again() {
+        let source = ": main loop v0 += 1 while v0 != 5 again";
+        assert_eq!(words(source), [0x7001, 0x4005, 0x120A, 0x1202]);
+    }
+
+    #[test]
+    fn comparisons_go_through_vf() {
+        // `<` and `>=` compute vf =- vx, whose flag is set when vx >= rhs
+        assert_eq!(words(": main if v1 < 5 then"), [0x6F05, 0x8F17, 0x3F01]);
+        assert_eq!(words(": main if v1 >= 5 then"), [0x6F05, 0x8F17, 0x4F01]);
+        // `>` and `<=` compute vf -= vx, whose flag is set when vx <= rhs
+        assert_eq!(words(": main if v1 > v3 then"), [0x8F30, 0x8F15, 0x3F01]);
+        assert_eq!(words(": main if v1 <= v3 then"), [0x8F30, 0x8F15, 0x4F01]);
+        assert_eq!(words(": main loop while v1 < 5 again"), [0x6F05, 0x8F17, 0x4F01, 0x120C, 0x1202]);
+    }
+
+    #[test]
+    fn comparisons_hold_when_run() {
+        let cases = [
+            ("<", [true, false, false]),
+            (">=", [false, true, true]),
+            (">", [false, false, true]),
+            ("<=", [true, true, false]),
+        ];
+        for (operator, holds) in cases {
+            for (value, holds) in [4, 5, 6].into_iter().zip(holds) {
+                let source = format!(": main v1 := {} v2 := 0 if v1 {} 5 then v2 := 1 loop again", value, operator);
+                let mut cpu = CPU::with_audio_backend(Settings::default(), Box::new(SilentBackend));
+                cpu.load_program(&compile(&source).unwrap());
+                for _ in 0..8 {
+                    cpu.tick().unwrap();
+                }
+                assert_eq!(cpu.get_register(2) == 1, holds, "{} {} 5", value, operator);
+            }
+        }
+    }
+
+    #[test]
+    fn forward_references_are_patched() {
+        let source = ": main draw i := data ; : draw clear ; : data 0xF0 0x90";
+        assert_eq!(words(source), [0x2208, 0xA20C, 0x00EE, 0x00E0, 0x00EE, 0xF090]);
+    }
+
+    #[test]
+    fn unpack_splits_an_address_into_v0_and_v1() {
+        let source = ": main :unpack 0xA data ; : data 0x12 0x34";
+        assert_eq!(words(source), [0x60A2, 0x6108, 0x00EE, 0x1234]);
+        let backward = ": data 0x12 0x34 : main :unpack 0 data";
+        assert_eq!(words(backward), [0x1234, 0x6002, 0x6102]);
+    }
+
+    #[test]
+    fn next_labels_the_second_byte_of_an_instruction() {
+        let source = ": main :next counter v0 := 0 i := counter ;";
+        assert_eq!(words(source), [0x6000, 0xA203, 0x00EE]);
+    }
+
+    #[test]
+    fn org_and_byte() {
+        let rom = compile(": main ; :org 0x210 :byte 0xAB :byte -1").unwrap();
+        assert_eq!(rom.len(), 0x12);
+        assert_eq!(&rom[0x10..], [0xAB, 0xFF]);
+    }
+
+    #[test]
+    fn comments_and_debugger_hints_are_skipped() {
+        let source = ": main # a comment\n:breakpoint here :monitor v0 4\nclear";
+        assert_eq!(words(source), [0x00E0]);
+    }
+
+    #[test]
+    fn unbalanced_blocks() {
+        assert_eq!(error(": main\nelse"), (2, "'else' without 'begin'".to_string()));
+        assert_eq!(error(": main\nend"), (2, "'end' without 'begin'".to_string()));
+        assert_eq!(error(": main\nwhile v0 == 1"), (2, "'while' outside a loop".to_string()));
+        assert_eq!(error(": main\nagain"), (2, "'again' without 'loop'".to_string()));
+        // The unclosed block is reported where it opened
+        assert_eq!(error(": main\nloop\nv0 += 1"), (2, "'loop' without 'again'".to_string()));
+        assert_eq!(error(": main\nif v0 == 1 begin\nv1 := 2"), (2, "'begin' without 'end'".to_string()));
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(error("clear"), (1, "the program has no ': main' label".to_string()));
+        assert_eq!(error(": main\nmissing"), (2, "unknown name 'missing'".to_string()));
+        assert_eq!(error(": main\n: main"), (2, "the name 'main' is already defined".to_string()));
+        assert_eq!(error(": main\nv0 := nothing"), (2, "unknown name 'nothing'".to_string()));
+    }
+
+    #[test]
+    fn unsupported_statements() {
+        let (line, message) = error(": main\nhires");
+        assert_eq!(line, 2);
+        assert!(message.starts_with("'hires' is a SUPER-CHIP/XO-CHIP statement"), "{}", message);
+        assert_eq!(error(":macro"), (1, "':macro' isn't supported".to_string()));
+        assert_eq!(error(":bogus"), (1, "unknown directive ':bogus'".to_string()));
+    }
+
+    #[test]
+    fn values_out_of_range() {
+        assert_eq!(error(": main 256"), (1, "byte 256 out of range".to_string()));
+        assert_eq!(error(": main v0 := 256"), (1, "value 256 out of range (-128 to 255)".to_string()));
+        assert_eq!(error(": main i := 0x1000"), (1, "address 0x1000 out of range".to_string()));
+        assert_eq!(error(": main sprite v0 v1 16"), (1, "value 16 out of range (0 to 15)".to_string()));
+    }
+
+    #[test]
+    fn malformed_statements() {
+        assert_eq!(error(": main sprite v0 x 1"), (1, "expected a register, found 'x'".to_string()));
+        assert_eq!(error(": main v0 |= 1"), (1, "'|=' needs a register on the right".to_string()));
+        assert_eq!(error(": main v0 ** v1"), (1, "unknown operator '**'".to_string()));
+        assert_eq!(error(": main i -= v0"), (1, "unknown operator 'i -='".to_string()));
+        assert_eq!(error(": main delay v0"), (1, "expected ':=', found 'v0'".to_string()));
+        assert_eq!(error(": main if v0 == 1 v1"), (1, "expected 'then' or 'begin', found 'v1'".to_string()));
+        assert_eq!(error(": main if v0 ~ 1 then"), (1, "unknown comparison '~'".to_string()));
+        assert_eq!(error(": main\nv0 :="), (2, "unexpected end of program".to_string()));
+    }
+}
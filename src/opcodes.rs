@@ -52,19 +52,22 @@ FX55 	MEM 	reg_dump(Vx, &I) 	Stores from V0 to VX (including VX) in memory, star
 FX65 	MEM 	reg_load(Vx, &I) 	Fills from V0 to VX (including VX) with values from memory, starting at address I. The offset from I is increased by 1 for each value read, but I itself is left unmodified.[d][24]
  */
 
-use crate::constants::{INSTRUCTION_SIZE, FONT_START_ADDRESS};
+use crate::constants::{ADDRESS_MAX, INSTRUCTION_SIZE, FONT_START_ADDRESS};
+use crate::cpu::CpuFault;
+use std::fmt;
 
 // Opcode-specific constants
-const VF_REGISTER_INDEX: u8 = 0xF;        // Index of VF register (flags)
+pub const VF_REGISTER_INDEX: u8 = 0xF;        // Index of VF register (flags)
 const V0_REGISTER_INDEX: u8 = 0;          // Index of V0 register
 const NIBBLE_MASK: u8 = 0xF;              // Mask for single nibble
 const FONT_CHAR_SIZE: u16 = 5;            // Size of each font character in bytes
 const BCD_HUNDREDS: u8 = 100;             // BCD hundreds divisor
 const BCD_TENS: u8 = 10;                  // BCD tens divisor
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Opcode {
     // opcode: u16, // 2 bytes, 16 bits total, big endian...
-    CallRoutine, // 0NNN
+    CallRoutine { address: u16 }, // 0NNN
     ClearDisplay {}, // 00E0
     Return {}, // 00EE
     Goto { address: u16 }, // 1NNN
@@ -80,9 +83,9 @@ pub enum Opcode {
     BitwiseXor { reg_x: u8, reg_y: u8 }, // 8XY3
     AddRegisters { reg_x: u8, reg_y: u8 }, // 8XY4
     SubtractRegisters { reg_x: u8, reg_y: u8 }, // 8XY5
-    ShiftRight { reg_x: u8 }, // 8XY6
+    ShiftRight { reg_x: u8, reg_y: u8 }, // 8XY6
     SubtractReverse { reg_x: u8, reg_y: u8 }, // 8XY7
-    ShiftLeft { reg_x: u8 }, // 8XYE
+    ShiftLeft { reg_x: u8, reg_y: u8 }, // 8XYE
     SkipIfRegNotEqual { reg_x: u8, reg_y: u8 }, // 9XY0
     SetAddress { address: u16 }, // ANNN
     JumpWithOffset { address: u16 }, // BNNN
@@ -102,7 +105,8 @@ pub enum Opcode {
 }
 
 impl Opcode {
-    pub fn from_raw(opcode: u16) -> Self {
+    /// Decode a raw instruction, returning `None` for words that aren't valid CHIP-8 opcodes
+    pub fn decode(opcode: u16) -> Option<Self> {
         let nibbles = (
             (opcode & 0xF000) >> 12,
             (opcode & 0x0F00) >> 8,
@@ -116,10 +120,10 @@ impl Opcode {
         let y = nibbles.2 as u8;
         let n = nibbles.3 as u8;
         
-        match nibbles {
+        let decoded = match nibbles {
             (0x0, 0x0, 0xE, 0x0) => Opcode::ClearDisplay {},
             (0x0, 0x0, 0xE, 0xE) => Opcode::Return {},
-            (0x0, _, _, _) => Opcode::CallRoutine,
+            (0x0, _, _, _) => Opcode::CallRoutine { address: nnn },
             (0x1, _, _, _) => Opcode::Goto { address: nnn },
             (0x2, _, _, _) => Opcode::CallSubroutine { address: nnn },
             (0x3, _, _, _) => Opcode::SkipIfEqual { register: x, value: nn },
@@ -133,9 +137,9 @@ impl Opcode {
             (0x8, _, _, 0x3) => Opcode::BitwiseXor { reg_x: x, reg_y: y },
             (0x8, _, _, 0x4) => Opcode::AddRegisters { reg_x: x, reg_y: y },
             (0x8, _, _, 0x5) => Opcode::SubtractRegisters { reg_x: x, reg_y: y },
-            (0x8, _, _, 0x6) => Opcode::ShiftRight { reg_x: x },
+            (0x8, _, _, 0x6) => Opcode::ShiftRight { reg_x: x, reg_y: y },
             (0x8, _, _, 0x7) => Opcode::SubtractReverse { reg_x: x, reg_y: y },
-            (0x8, _, _, 0xE) => Opcode::ShiftLeft { reg_x: x },
+            (0x8, _, _, 0xE) => Opcode::ShiftLeft { reg_x: x, reg_y: y },
             (0x9, _, _, 0x0) => Opcode::SkipIfRegNotEqual { reg_x: x, reg_y: y },
             (0xA, _, _, _) => Opcode::SetAddress { address: nnn },
             (0xB, _, _, _) => Opcode::JumpWithOffset { address: nnn },
@@ -152,30 +156,167 @@ impl Opcode {
             (0xF, _, 0x3, 0x3) => Opcode::StoreBCD { register: x },
             (0xF, _, 0x5, 0x5) => Opcode::StoreRegisters { reg_x: x },
             (0xF, _, 0x6, 0x5) => Opcode::LoadRegisters { reg_x: x },
-            _ => panic!("Unknown opcode: {:#06X}", opcode),
+            _ => return None,
+        };
+        Some(decoded)
+    }
+
+    /// The instruction's pattern in the usual notation, e.g. `8XY4`
+    pub fn pattern(&self) -> &'static str {
+        match self {
+            Opcode::CallRoutine { .. } => "0NNN",
+            Opcode::ClearDisplay { .. } => "00E0",
+            Opcode::Return { .. } => "00EE",
+            Opcode::Goto { .. } => "1NNN",
+            Opcode::CallSubroutine { .. } => "2NNN",
+            Opcode::SkipIfEqual { .. } => "3XNN",
+            Opcode::SkipIfNotEqual { .. } => "4XNN",
+            Opcode::SkipIfRegistersEqual { .. } => "5XY0",
+            Opcode::SetRegister { .. } => "6XNN",
+            Opcode::AddToRegister { .. } => "7XNN",
+            Opcode::AssignRegister { .. } => "8XY0",
+            Opcode::BitwiseOr { .. } => "8XY1",
+            Opcode::BitwiseAnd { .. } => "8XY2",
+            Opcode::BitwiseXor { .. } => "8XY3",
+            Opcode::AddRegisters { .. } => "8XY4",
+            Opcode::SubtractRegisters { .. } => "8XY5",
+            Opcode::ShiftRight { .. } => "8XY6",
+            Opcode::SubtractReverse { .. } => "8XY7",
+            Opcode::ShiftLeft { .. } => "8XYE",
+            Opcode::SkipIfRegNotEqual { .. } => "9XY0",
+            Opcode::SetAddress { .. } => "ANNN",
+            Opcode::JumpWithOffset { .. } => "BNNN",
+            Opcode::Random { .. } => "CXNN",
+            Opcode::Draw { .. } => "DXYN",
+            Opcode::SkipIfKeyPressed { .. } => "EX9E",
+            Opcode::SkipIfKeyNotPressed { .. } => "EXA1",
+            Opcode::GetDelayTimer { .. } => "FX07",
+            Opcode::WaitForKey { .. } => "FX0A",
+            Opcode::SetDelayTimer { .. } => "FX15",
+            Opcode::SetSoundTimer { .. } => "FX18",
+            Opcode::AddToAddress { .. } => "FX1E",
+            Opcode::SetSpriteAddress { .. } => "FX29",
+            Opcode::StoreBCD { .. } => "FX33",
+            Opcode::StoreRegisters { .. } => "FX55",
+            Opcode::LoadRegisters { .. } => "FX65",
+        }
+    }
+
+    /// Opcode classes, following the groups in the table above
+    pub const CLASSES: [&'static str; 9] = ["flow", "cond", "const", "math", "mem", "display", "rand", "key", "timer"];
+
+    /// Which of `CLASSES` the instruction belongs to
+    pub fn class(&self) -> &'static str {
+        match self {
+            Opcode::CallRoutine { .. }
+            | Opcode::Return { .. }
+            | Opcode::Goto { .. }
+            | Opcode::CallSubroutine { .. }
+            | Opcode::JumpWithOffset { .. } => "flow",
+            Opcode::SkipIfEqual { .. }
+            | Opcode::SkipIfNotEqual { .. }
+            | Opcode::SkipIfRegistersEqual { .. }
+            | Opcode::SkipIfRegNotEqual { .. } => "cond",
+            Opcode::SetRegister { .. } | Opcode::AddToRegister { .. } => "const",
+            Opcode::AssignRegister { .. }
+            | Opcode::BitwiseOr { .. }
+            | Opcode::BitwiseAnd { .. }
+            | Opcode::BitwiseXor { .. }
+            | Opcode::AddRegisters { .. }
+            | Opcode::SubtractRegisters { .. }
+            | Opcode::ShiftRight { .. }
+            | Opcode::SubtractReverse { .. }
+            | Opcode::ShiftLeft { .. } => "math",
+            Opcode::SetAddress { .. }
+            | Opcode::AddToAddress { .. }
+            | Opcode::SetSpriteAddress { .. }
+            | Opcode::StoreBCD { .. }
+            | Opcode::StoreRegisters { .. }
+            | Opcode::LoadRegisters { .. } => "mem",
+            Opcode::ClearDisplay { .. } | Opcode::Draw { .. } => "display",
+            Opcode::Random { .. } => "rand",
+            Opcode::SkipIfKeyPressed { .. } | Opcode::SkipIfKeyNotPressed { .. } | Opcode::WaitForKey { .. } => "key",
+            Opcode::GetDelayTimer { .. } | Opcode::SetDelayTimer { .. } | Opcode::SetSoundTimer { .. } => "timer",
+        }
+    }
+
+    /// Whether the instruction belongs to a class or has a pattern, ignoring case
+    pub fn matches(&self, class_or_pattern: &str) -> bool {
+        self.class().eq_ignore_ascii_case(class_or_pattern) || self.pattern().eq_ignore_ascii_case(class_or_pattern)
+    }
+
+    /// Whether `text` names one of `CLASSES` or an instruction pattern
+    pub fn is_class_or_pattern(text: &str) -> bool {
+        (0..=u16::MAX).filter_map(Opcode::decode).any(|opcode| opcode.matches(text))
+    }
+
+    /// Encode back into the raw 16-bit instruction; the inverse of `decode`
+    pub fn encode(&self) -> u16 {
+        let xy = |op: u16, x: u8, y: u8, n: u16| op << 12 | (x as u16) << 8 | (y as u16) << 4 | n;
+        let xnn = |op: u16, x: u8, nn: u8| op << 12 | (x as u16) << 8 | nn as u16;
+        let fx = |x: u8, low: u16| 0xF000 | (x as u16) << 8 | low;
+
+        match *self {
+            Opcode::CallRoutine { address } => address,
+            Opcode::ClearDisplay {} => 0x00E0,
+            Opcode::Return {} => 0x00EE,
+            Opcode::Goto { address } => 0x1000 | address,
+            Opcode::CallSubroutine { address } => 0x2000 | address,
+            Opcode::SkipIfEqual { register, value } => xnn(0x3, register, value),
+            Opcode::SkipIfNotEqual { register, value } => xnn(0x4, register, value),
+            Opcode::SkipIfRegistersEqual { reg_x, reg_y } => xy(0x5, reg_x, reg_y, 0x0),
+            Opcode::SetRegister { register, value } => xnn(0x6, register, value),
+            Opcode::AddToRegister { register, value } => xnn(0x7, register, value),
+            Opcode::AssignRegister { reg_x, reg_y } => xy(0x8, reg_x, reg_y, 0x0),
+            Opcode::BitwiseOr { reg_x, reg_y } => xy(0x8, reg_x, reg_y, 0x1),
+            Opcode::BitwiseAnd { reg_x, reg_y } => xy(0x8, reg_x, reg_y, 0x2),
+            Opcode::BitwiseXor { reg_x, reg_y } => xy(0x8, reg_x, reg_y, 0x3),
+            Opcode::AddRegisters { reg_x, reg_y } => xy(0x8, reg_x, reg_y, 0x4),
+            Opcode::SubtractRegisters { reg_x, reg_y } => xy(0x8, reg_x, reg_y, 0x5),
+            Opcode::ShiftRight { reg_x, reg_y } => xy(0x8, reg_x, reg_y, 0x6),
+            Opcode::SubtractReverse { reg_x, reg_y } => xy(0x8, reg_x, reg_y, 0x7),
+            Opcode::ShiftLeft { reg_x, reg_y } => xy(0x8, reg_x, reg_y, 0xE),
+            Opcode::SkipIfRegNotEqual { reg_x, reg_y } => xy(0x9, reg_x, reg_y, 0x0),
+            Opcode::SetAddress { address } => 0xA000 | address,
+            Opcode::JumpWithOffset { address } => 0xB000 | address,
+            Opcode::Random { register, value } => xnn(0xC, register, value),
+            Opcode::Draw { reg_x, reg_y, height } => xy(0xD, reg_x, reg_y, height as u16),
+            Opcode::SkipIfKeyPressed { register } => xnn(0xE, register, 0x9E),
+            Opcode::SkipIfKeyNotPressed { register } => xnn(0xE, register, 0xA1),
+            Opcode::GetDelayTimer { register } => fx(register, 0x07),
+            Opcode::WaitForKey { register } => fx(register, 0x0A),
+            Opcode::SetDelayTimer { register } => fx(register, 0x15),
+            Opcode::SetSoundTimer { register } => fx(register, 0x18),
+            Opcode::AddToAddress { register } => fx(register, 0x1E),
+            Opcode::SetSpriteAddress { register } => fx(register, 0x29),
+            Opcode::StoreBCD { register } => fx(register, 0x33),
+            Opcode::StoreRegisters { reg_x } => fx(reg_x, 0x55),
+            Opcode::LoadRegisters { reg_x } => fx(reg_x, 0x65),
         }
     }
 
-    pub fn execute(&self, cpu: &mut crate::cpu::CPU) {
-        use rand::Rng;
+    /// Carry out the instruction, which has already been fetched and the
+    /// program counter moved past it
+    pub fn execute(&self, cpu: &mut crate::cpu::CPU) -> Result<(), CpuFault> {
+        let quirks = cpu.quirks();
         
         match self {
-            Opcode::CallRoutine => {
-                unimplemented!("CallRoutine not implemented for modern CHIP-8")
+            Opcode::CallRoutine { .. } => {
+                // Machine code for the original interpreter's CPU can't be run
+                return Err(CpuFault::MachineCodeCall { address: cpu.instruction_address(), opcode: self.encode() });
             }
             Opcode::ClearDisplay {} => {
                 cpu.clear_display();
             }
             Opcode::Return {} => {
-                if let Some(addr) = cpu.pop_stack() {
-                    cpu.set_program_counter(addr);
-                }
+                let addr = cpu.pop_stack().ok_or(CpuFault::StackUnderflow { address: cpu.instruction_address() })?;
+                cpu.set_program_counter(addr);
             }
             Opcode::Goto { address } => {
                 cpu.set_program_counter(*address);
             }
             Opcode::CallSubroutine { address } => {
-                cpu.push_stack(cpu.get_program_counter());
+                cpu.push_stack(cpu.get_program_counter())?;
                 cpu.set_program_counter(*address);
             }
             Opcode::SkipIfEqual { register, value } => {
@@ -208,16 +349,25 @@ impl Opcode {
                 let x_val = cpu.get_register(*reg_x);
                 let y_val = cpu.get_register(*reg_y);
                 cpu.set_register(*reg_x, x_val | y_val);
+                if quirks.vf_reset {
+                    cpu.set_register(VF_REGISTER_INDEX, 0);
+                }
             }
             Opcode::BitwiseAnd { reg_x, reg_y } => {
                 let x_val = cpu.get_register(*reg_x);
                 let y_val = cpu.get_register(*reg_y);
                 cpu.set_register(*reg_x, x_val & y_val);
+                if quirks.vf_reset {
+                    cpu.set_register(VF_REGISTER_INDEX, 0);
+                }
             }
             Opcode::BitwiseXor { reg_x, reg_y } => {
                 let x_val = cpu.get_register(*reg_x);
                 let y_val = cpu.get_register(*reg_y);
                 cpu.set_register(*reg_x, x_val ^ y_val);
+                if quirks.vf_reset {
+                    cpu.set_register(VF_REGISTER_INDEX, 0);
+                }
             }
             Opcode::AddRegisters { reg_x, reg_y } => {
                 let x_val = cpu.get_register(*reg_x);
@@ -233,10 +383,11 @@ impl Opcode {
                 cpu.set_register(*reg_x, result);
                 cpu.set_register(VF_REGISTER_INDEX, if borrow { 0 } else { 1 });
             }
-            Opcode::ShiftRight { reg_x } => {
-                let value = cpu.get_register(*reg_x);
-                cpu.set_register(VF_REGISTER_INDEX, value & 0x1);
+            Opcode::ShiftRight { reg_x, reg_y } => {
+                let value = cpu.get_register(if quirks.shift_uses_vy { *reg_y } else { *reg_x });
                 cpu.set_register(*reg_x, value >> 1);
+                // The flag is written last, so it wins when X is F
+                cpu.set_register(VF_REGISTER_INDEX, value & 0x1);
             }
             Opcode::SubtractReverse { reg_x, reg_y } => {
                 let x_val = cpu.get_register(*reg_x);
@@ -245,10 +396,10 @@ impl Opcode {
                 cpu.set_register(*reg_x, result);
                 cpu.set_register(VF_REGISTER_INDEX, if borrow { 0 } else { 1 });
             }
-            Opcode::ShiftLeft { reg_x } => {
-                let value = cpu.get_register(*reg_x);
-                cpu.set_register(VF_REGISTER_INDEX, (value >> 7) & 0x1);
+            Opcode::ShiftLeft { reg_x, reg_y } => {
+                let value = cpu.get_register(if quirks.shift_uses_vy { *reg_y } else { *reg_x });
                 cpu.set_register(*reg_x, value << 1);
+                cpu.set_register(VF_REGISTER_INDEX, (value >> 7) & 0x1);
             }
             Opcode::SkipIfRegNotEqual { reg_x, reg_y } => {
                 if cpu.get_register(*reg_x) != cpu.get_register(*reg_y) {
@@ -259,14 +410,15 @@ impl Opcode {
                 cpu.set_address_register(*address);
             }
             Opcode::JumpWithOffset { address } => {
-                cpu.set_program_counter(*address + cpu.get_register(V0_REGISTER_INDEX) as u16);
+                let offset_register = if quirks.jump_uses_vx { (*address >> 8) as u8 } else { V0_REGISTER_INDEX };
+                cpu.set_program_counter(*address + cpu.get_register(offset_register) as u16);
             }
             Opcode::Random { register, value } => {
-                let mut rng = rand::rng();
-                let random: u8 = rng.random();
+                let random = cpu.random_byte();
                 cpu.set_register(*register, random & *value);
             }
             Opcode::Draw { reg_x, reg_y, height } => {
+                cpu.check_memory_range(cpu.get_address_register(), *height as u16)?;
                 let x = cpu.get_register(*reg_x);
                 let y = cpu.get_register(*reg_y);
                 let collision = cpu.draw_sprite(x, y, *height);
@@ -304,7 +456,8 @@ impl Opcode {
             Opcode::AddToAddress { register } => {
                 let current = cpu.get_address_register();
                 let value = cpu.get_register(*register) as u16;
-                cpu.set_address_register(current.wrapping_add(value));
+                // I is 12 bits wide and wraps around
+                cpu.set_address_register(current.wrapping_add(value) & ADDRESS_MAX);
             }
             Opcode::SetSpriteAddress { register } => {
                 let sprite_idx = cpu.get_register(*register) & NIBBLE_MASK;
@@ -313,25 +466,611 @@ impl Opcode {
             Opcode::StoreBCD { register } => {
                 let value = cpu.get_register(*register);
                 let i = cpu.get_address_register();
+                cpu.check_memory_range(i, 3)?;
                 cpu.write_memory(i, value / BCD_HUNDREDS);
                 cpu.write_memory(i + 1, (value % BCD_HUNDREDS) / BCD_TENS);
                 cpu.write_memory(i + 2, value % 10);
             }
             Opcode::StoreRegisters { reg_x } => {
                 let i = cpu.get_address_register();
+                cpu.check_memory_range(i, *reg_x as u16 + 1)?;
                 for idx in 0..=*reg_x {
                     let value = cpu.get_register(idx);
                     cpu.write_memory(i + idx as u16, value);
                 }
+                if quirks.load_store_increment_i {
+                    cpu.set_address_register(i.wrapping_add(*reg_x as u16 + 1) & ADDRESS_MAX);
+                }
             }
             Opcode::LoadRegisters { reg_x } => {
                 let i = cpu.get_address_register();
+                cpu.check_memory_range(i, *reg_x as u16 + 1)?;
                 for idx in 0..=*reg_x {
                     let value = cpu.read_memory(i + idx as u16);
                     cpu.set_register(idx, value);
                 }
+                if quirks.load_store_increment_i {
+                    cpu.set_address_register(i.wrapping_add(*reg_x as u16 + 1) & ADDRESS_MAX);
+                }
             }
         }
+        Ok(())
+    }
+}
+
+/// Disassembles to the common "Cowgod" mnemonic syntax, which `asm` reads back
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Opcode::CallRoutine { address } => write!(f, "SYS {:#05X}", address),
+            Opcode::ClearDisplay {} => write!(f, "CLS"),
+            Opcode::Return {} => write!(f, "RET"),
+            Opcode::Goto { address } => write!(f, "JP {:#05X}", address),
+            Opcode::CallSubroutine { address } => write!(f, "CALL {:#05X}", address),
+            Opcode::SkipIfEqual { register, value } => write!(f, "SE V{:X}, {:#04X}", register, value),
+            Opcode::SkipIfNotEqual { register, value } => write!(f, "SNE V{:X}, {:#04X}", register, value),
+            Opcode::SkipIfRegistersEqual { reg_x, reg_y } => write!(f, "SE V{:X}, V{:X}", reg_x, reg_y),
+            Opcode::SetRegister { register, value } => write!(f, "LD V{:X}, {:#04X}", register, value),
+            Opcode::AddToRegister { register, value } => write!(f, "ADD V{:X}, {:#04X}", register, value),
+            Opcode::AssignRegister { reg_x, reg_y } => write!(f, "LD V{:X}, V{:X}", reg_x, reg_y),
+            Opcode::BitwiseOr { reg_x, reg_y } => write!(f, "OR V{:X}, V{:X}", reg_x, reg_y),
+            Opcode::BitwiseAnd { reg_x, reg_y } => write!(f, "AND V{:X}, V{:X}", reg_x, reg_y),
+            Opcode::BitwiseXor { reg_x, reg_y } => write!(f, "XOR V{:X}, V{:X}", reg_x, reg_y),
+            Opcode::AddRegisters { reg_x, reg_y } => write!(f, "ADD V{:X}, V{:X}", reg_x, reg_y),
+            Opcode::SubtractRegisters { reg_x, reg_y } => write!(f, "SUB V{:X}, V{:X}", reg_x, reg_y),
+            Opcode::ShiftRight { reg_x, reg_y } => write!(f, "SHR V{:X}, V{:X}", reg_x, reg_y),
+            Opcode::SubtractReverse { reg_x, reg_y } => write!(f, "SUBN V{:X}, V{:X}", reg_x, reg_y),
+            Opcode::ShiftLeft { reg_x, reg_y } => write!(f, "SHL V{:X}, V{:X}", reg_x, reg_y),
+            Opcode::SkipIfRegNotEqual { reg_x, reg_y } => write!(f, "SNE V{:X}, V{:X}", reg_x, reg_y),
+            Opcode::SetAddress { address } => write!(f, "LD I, {:#05X}", address),
+            Opcode::JumpWithOffset { address } => write!(f, "JP V0, {:#05X}", address),
+            Opcode::Random { register, value } => write!(f, "RND V{:X}, {:#04X}", register, value),
+            Opcode::Draw { reg_x, reg_y, height } => write!(f, "DRW V{:X}, V{:X}, {}", reg_x, reg_y, height),
+            Opcode::SkipIfKeyPressed { register } => write!(f, "SKP V{:X}", register),
+            Opcode::SkipIfKeyNotPressed { register } => write!(f, "SKNP V{:X}", register),
+            Opcode::GetDelayTimer { register } => write!(f, "LD V{:X}, DT", register),
+            Opcode::WaitForKey { register } => write!(f, "LD V{:X}, K", register),
+            Opcode::SetDelayTimer { register } => write!(f, "LD DT, V{:X}", register),
+            Opcode::SetSoundTimer { register } => write!(f, "LD ST, V{:X}", register),
+            Opcode::AddToAddress { register } => write!(f, "ADD I, V{:X}", register),
+            Opcode::SetSpriteAddress { register } => write!(f, "LD F, V{:X}", register),
+            Opcode::StoreBCD { register } => write!(f, "LD B, V{:X}", register),
+            Opcode::StoreRegisters { reg_x } => write!(f, "LD [I], V{:X}", reg_x),
+            Opcode::LoadRegisters { reg_x } => write!(f, "LD V{:X}, [I]", reg_x),
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::SilentBackend;
+    use crate::constants::PROGRAM_START_ADDRESS;
+    use crate::cpu::CPU;
+    use crate::memory::STACK_SIZE;
+    use crate::settings::{QuirkSettings, Settings};
+    use proptest::prelude::*;
+
+    const VF: u8 = VF_REGISTER_INDEX;
+    const NEXT: u16 = PROGRAM_START_ADDRESS + INSTRUCTION_SIZE;   // PC once the instruction is fetched
+    const SKIPPED: u16 = NEXT + INSTRUCTION_SIZE;
+
+    fn cpu_with_quirks(quirks: QuirkSettings) -> CPU {
+        let settings = Settings { quirks, ..Settings::default() };
+        let mut cpu = CPU::with_audio_backend(settings, Box::new(SilentBackend));
+        cpu.set_program_counter(NEXT);
+        cpu
+    }
+
+    fn cpu() -> CPU {
+        cpu_with_quirks(QuirkSettings::default())
+    }
+
+    fn chip8_quirks() -> QuirkSettings {
+        QuirkSettings::profile("chip8").unwrap()
+    }
+
+    /// Execute a raw instruction as if it had just been fetched from 0x200
+    fn run(cpu: &mut CPU, opcode: u16) -> Result<(), CpuFault> {
+        Opcode::decode(opcode).expect("valid opcode").execute(cpu)
+    }
+
+    fn set(cpu: &mut CPU, registers: &[(u8, u8)]) {
+        for &(register, value) in registers {
+            cpu.set_register(register, value);
+        }
+    }
+
+    #[test]
+    fn decode_rejects_invalid_words() {
+        for word in [0x5001, 0x800F, 0x9AB1, 0xE000, 0xE19F, 0xF000, 0xF1FF] {
+            assert_eq!(Opcode::decode(word), None, "{:04X}", word);
+        }
+    }
+
+    #[test]
+    fn decode_picks_special_zero_opcodes_before_machine_code_calls() {
+        assert_eq!(Opcode::decode(0x00E0), Some(Opcode::ClearDisplay {}));
+        assert_eq!(Opcode::decode(0x00EE), Some(Opcode::Return {}));
+        assert_eq!(Opcode::decode(0x0123), Some(Opcode::CallRoutine { address: 0x123 }));
+    }
+
+    #[test]
+    fn machine_code_call_faults() {
+        let mut cpu = cpu();
+        assert!(matches!(run(&mut cpu, 0x0123), Err(CpuFault::MachineCodeCall { opcode: 0x0123, .. })));
+    }
+
+    #[test]
+    fn clear_display() {
+        let mut cpu = cpu();
+        cpu.set_address_register(FONT_START_ADDRESS);
+        run(&mut cpu, 0xD005).unwrap();
+        assert!(cpu.display_snapshot().iter().any(|&row| row != 0));
+        run(&mut cpu, 0x00E0).unwrap();
+        assert!(cpu.display_snapshot().iter().all(|&row| row == 0));
+    }
+
+    #[test]
+    fn call_and_return() {
+        let mut cpu = cpu();
+        run(&mut cpu, 0x2345).unwrap();
+        assert_eq!(cpu.get_program_counter(), 0x345);
+        assert_eq!(cpu.stack_depth(), 1);
+        run(&mut cpu, 0x00EE).unwrap();
+        assert_eq!(cpu.get_program_counter(), NEXT);
+        assert_eq!(cpu.stack_depth(), 0);
+    }
+
+    #[test]
+    fn return_with_empty_stack_faults() {
+        let mut cpu = cpu();
+        assert!(matches!(run(&mut cpu, 0x00EE), Err(CpuFault::StackUnderflow { .. })));
+    }
+
+    #[test]
+    fn calls_nested_past_the_stack_fault() {
+        let mut cpu = cpu();
+        for _ in 0..STACK_SIZE {
+            run(&mut cpu, 0x2300).unwrap();
+        }
+        assert!(matches!(run(&mut cpu, 0x2300), Err(CpuFault::StackOverflow { .. })));
+    }
+
+    #[test]
+    fn goto() {
+        let mut cpu = cpu();
+        run(&mut cpu, 0x1ABC).unwrap();
+        assert_eq!(cpu.get_program_counter(), 0xABC);
+    }
+
+    #[test]
+    fn skips() {
+        let cases = [
+            (0x3142, true),  // V1 == 0x42
+            (0x3143, false),
+            (0x4142, false), // V1 != 0x42
+            (0x4143, true),
+            (0x5120, true),  // V1 == V2
+            (0x5130, false),
+            (0x9120, false), // V1 != V2
+            (0x9130, true),
+        ];
+        for (opcode, skips) in cases {
+            let mut cpu = cpu();
+            set(&mut cpu, &[(0x1, 0x42), (0x2, 0x42), (0x3, 0x07)]);
+            run(&mut cpu, opcode).unwrap();
+            let expected = if skips { SKIPPED } else { NEXT };
+            assert_eq!(cpu.get_program_counter(), expected, "{:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn set_and_add_constant() {
+        let mut cpu = cpu();
+        set(&mut cpu, &[(VF, 0x55)]);
+        run(&mut cpu, 0x63F0).unwrap();
+        assert_eq!(cpu.get_register(0x3), 0xF0);
+        run(&mut cpu, 0x7320).unwrap();
+        assert_eq!(cpu.get_register(0x3), 0x10, "7XNN wraps around");
+        assert_eq!(cpu.get_register(VF), 0x55, "7XNN leaves VF alone");
+    }
+
+    #[test]
+    fn assign_and_bitwise_operations() {
+        let cases = [(0x8120, 0x3C), (0x8121, 0xFC), (0x8122, 0x30), (0x8123, 0xCC)];
+        for (opcode, expected) in cases {
+            let mut cpu = cpu();
+            set(&mut cpu, &[(0x1, 0xF0), (0x2, 0x3C), (VF, 0x55)]);
+            run(&mut cpu, opcode).unwrap();
+            assert_eq!(cpu.get_register(0x1), expected, "{:04X}", opcode);
+            assert_eq!(cpu.get_register(VF), 0x55, "{:04X} leaves VF alone", opcode);
+        }
+    }
+
+    #[test]
+    fn bitwise_operations_reset_vf_with_the_quirk() {
+        for opcode in [0x8121, 0x8122, 0x8123] {
+            let mut cpu = cpu_with_quirks(chip8_quirks());
+            set(&mut cpu, &[(0x1, 0xF0), (0x2, 0x3C), (VF, 0x55)]);
+            run(&mut cpu, opcode).unwrap();
+            assert_eq!(cpu.get_register(VF), 0, "{:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn add_registers_sets_carry() {
+        let mut cpu = cpu();
+        set(&mut cpu, &[(0x1, 0x10), (0x2, 0x20)]);
+        run(&mut cpu, 0x8124).unwrap();
+        assert_eq!((cpu.get_register(0x1), cpu.get_register(VF)), (0x30, 0));
+
+        set(&mut cpu, &[(0x1, 0xFF), (0x2, 0x02)]);
+        run(&mut cpu, 0x8124).unwrap();
+        assert_eq!((cpu.get_register(0x1), cpu.get_register(VF)), (0x01, 1));
+    }
+
+    #[test]
+    fn subtractions_set_not_borrow() {
+        // (opcode, V1, V2, V1 afterwards, VF afterwards)
+        let cases = [
+            (0x8125, 0x30, 0x10, 0x20, 1),
+            (0x8125, 0x10, 0x30, 0xE0, 0),
+            (0x8125, 0x42, 0x42, 0x00, 1), // No borrow when equal
+            (0x8127, 0x10, 0x30, 0x20, 1),
+            (0x8127, 0x30, 0x10, 0xE0, 0),
+            (0x8127, 0x42, 0x42, 0x00, 1),
+        ];
+        for (opcode, x, y, result, flag) in cases {
+            let mut cpu = cpu();
+            set(&mut cpu, &[(0x1, x), (0x2, y)]);
+            run(&mut cpu, opcode).unwrap();
+            assert_eq!((cpu.get_register(0x1), cpu.get_register(VF)), (result, flag), "{:04X} {:02X} {:02X}", opcode, x, y);
+        }
+    }
+
+    #[test]
+    fn arithmetic_flag_wins_when_vf_is_the_destination() {
+        let mut cpu = cpu();
+        set(&mut cpu, &[(VF, 0xFF), (0x2, 0x02)]);
+        run(&mut cpu, 0x8F24).unwrap();
+        assert_eq!(cpu.get_register(VF), 1);
+
+        set(&mut cpu, &[(VF, 0x10), (0x2, 0x30)]);
+        run(&mut cpu, 0x8F25).unwrap();
+        assert_eq!(cpu.get_register(VF), 0);
+
+        set(&mut cpu, &[(VF, 0x30), (0x2, 0x10)]);
+        run(&mut cpu, 0x8F27).unwrap();
+        assert_eq!(cpu.get_register(VF), 0);
+    }
+
+    #[test]
+    fn shift_flag_wins_when_vf_is_the_destination() {
+        let mut cpu = cpu();
+        set(&mut cpu, &[(VF, 0x06)]);
+        run(&mut cpu, 0x8F06).unwrap();
+        assert_eq!(cpu.get_register(VF), 0);
+
+        set(&mut cpu, &[(VF, 0x81)]);
+        run(&mut cpu, 0x8F0E).unwrap();
+        assert_eq!(cpu.get_register(VF), 1);
+    }
+
+    #[test]
+    fn shifts_work_in_place_by_default() {
+        let mut cpu = cpu();
+        set(&mut cpu, &[(0x1, 0x81), (0x2, 0x00)]);
+        run(&mut cpu, 0x8126).unwrap();
+        assert_eq!((cpu.get_register(0x1), cpu.get_register(VF)), (0x40, 1));
+
+        set(&mut cpu, &[(0x1, 0x81)]);
+        run(&mut cpu, 0x812E).unwrap();
+        assert_eq!((cpu.get_register(0x1), cpu.get_register(VF)), (0x02, 1));
+
+        set(&mut cpu, &[(0x1, 0x40)]);
+        run(&mut cpu, 0x812E).unwrap();
+        assert_eq!((cpu.get_register(0x1), cpu.get_register(VF)), (0x80, 0));
+    }
+
+    #[test]
+    fn shifts_use_vy_with_the_quirk() {
+        let mut cpu = cpu_with_quirks(chip8_quirks());
+        set(&mut cpu, &[(0x1, 0x00), (0x2, 0x03)]);
+        run(&mut cpu, 0x8126).unwrap();
+        assert_eq!((cpu.get_register(0x1), cpu.get_register(VF)), (0x01, 1));
+        assert_eq!(cpu.get_register(0x2), 0x03, "VY is left alone");
+
+        set(&mut cpu, &[(0x1, 0x00), (0x2, 0x81)]);
+        run(&mut cpu, 0x812E).unwrap();
+        assert_eq!((cpu.get_register(0x1), cpu.get_register(VF)), (0x02, 1));
+    }
+
+    #[test]
+    fn set_address() {
+        let mut cpu = cpu();
+        run(&mut cpu, 0xA123).unwrap();
+        assert_eq!(cpu.get_address_register(), 0x123);
+    }
+
+    #[test]
+    fn jump_with_offset() {
+        let mut cpu = cpu();
+        set(&mut cpu, &[(0x0, 0x10), (0x3, 0x20)]);
+        run(&mut cpu, 0xB300).unwrap();
+        assert_eq!(cpu.get_program_counter(), 0x310, "BNNN adds V0");
+
+        let mut cpu = cpu_with_quirks(QuirkSettings::profile("schip").unwrap());
+        set(&mut cpu, &[(0x0, 0x10), (0x3, 0x20)]);
+        run(&mut cpu, 0xB300).unwrap();
+        assert_eq!(cpu.get_program_counter(), 0x320, "BXNN adds VX with the quirk");
+    }
+
+    #[test]
+    fn random_is_masked() {
+        let mut cpu = cpu();
+        for _ in 0..100 {
+            run(&mut cpu, 0xC10F).unwrap();
+            assert!(cpu.get_register(0x1) <= 0x0F);
+        }
+        run(&mut cpu, 0xC100).unwrap();
+        assert_eq!(cpu.get_register(0x1), 0);
+    }
+
+    #[test]
+    fn random_repeats_with_a_seed() {
+        let sequence = || {
+            let mut settings = Settings::default();
+            settings.cpu.seed = 7;
+            let mut cpu = CPU::with_audio_backend(settings, Box::new(SilentBackend));
+            (0..16).map(|_| cpu.random_byte()).collect::<Vec<_>>()
+        };
+        assert_eq!(sequence(), sequence());
+    }
+
+    #[test]
+    fn draw_sets_vf_on_collision() {
+        let mut cpu = cpu();
+        cpu.set_address_register(FONT_START_ADDRESS); // "0": F0 90 90 90 F0
+        set(&mut cpu, &[(0x1, 2), (0x2, 3)]);
+        run(&mut cpu, 0xD125).unwrap();
+        assert_eq!(cpu.get_register(VF), 0);
+        assert_eq!(cpu.display_snapshot()[3] >> (64 - 2 - 4), 0b1111);
+        assert_eq!(cpu.display_snapshot()[4] >> (64 - 2 - 4), 0b1001);
+
+        run(&mut cpu, 0xD125).unwrap();
+        assert_eq!(cpu.get_register(VF), 1, "drawing again erases and collides");
+        assert!(cpu.display_snapshot().iter().all(|&row| row == 0));
+    }
+
+    #[test]
+    fn draw_wraps_or_clips_at_the_edge() {
+        for (quirks, wraps) in [(QuirkSettings::default(), true), (chip8_quirks(), false)] {
+            let mut cpu = cpu_with_quirks(quirks);
+            cpu.set_address_register(FONT_START_ADDRESS);
+            set(&mut cpu, &[(0x1, 62), (0x2, 30)]);
+            run(&mut cpu, 0xD125).unwrap();
+            let snapshot = cpu.display_snapshot();
+            assert_eq!(snapshot[30] & 0b11, 0b11, "the visible part is drawn");
+            assert_eq!(snapshot[30] >> 62 == 0b11, wraps, "columns wrap");
+            assert_eq!(snapshot[0] != 0, wraps, "rows wrap");
+        }
+    }
+
+    #[test]
+    fn draw_start_position_always_wraps() {
+        let mut cpu = cpu_with_quirks(chip8_quirks());
+        cpu.set_address_register(FONT_START_ADDRESS);
+        set(&mut cpu, &[(0x1, 64 + 1), (0x2, 32 + 2)]);
+        run(&mut cpu, 0xD121).unwrap();
+        assert_eq!(cpu.display_snapshot()[2], 0xF0 << (64 - 8 - 1));
+    }
+
+    #[test]
+    fn draw_past_the_end_of_memory_faults() {
+        let mut cpu = cpu();
+        cpu.set_address_register(0xFFE);
+        assert!(matches!(run(&mut cpu, 0xD125), Err(CpuFault::MemoryOutOfRange { .. })));
+    }
+
+    #[test]
+    fn key_skips_use_the_low_nibble() {
+        let mut cpu = cpu();
+        cpu.press_key(0xA);
+        set(&mut cpu, &[(0x1, 0xFA), (0x2, 0x0B)]);
+        let cases = [(0xE19E, true), (0xE29E, false), (0xE1A1, false), (0xE2A1, true)];
+        for (opcode, skips) in cases {
+            cpu.set_program_counter(NEXT);
+            run(&mut cpu, opcode).unwrap();
+            let expected = if skips { SKIPPED } else { NEXT };
+            assert_eq!(cpu.get_program_counter(), expected, "{:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn wait_for_key_repeats_until_the_key_is_released() {
+        let mut cpu = cpu();
+        run(&mut cpu, 0xF30A).unwrap();
+        assert_eq!(cpu.get_program_counter(), PROGRAM_START_ADDRESS, "no key yet");
+
+        cpu.press_key(0x7);
+        cpu.set_program_counter(NEXT);
+        run(&mut cpu, 0xF30A).unwrap();
+        assert_eq!(cpu.get_program_counter(), PROGRAM_START_ADDRESS, "still held");
+
+        cpu.release_key(0x7);
+        cpu.set_program_counter(NEXT);
+        run(&mut cpu, 0xF30A).unwrap();
+        assert_eq!(cpu.get_program_counter(), NEXT);
+        assert_eq!(cpu.get_register(0x3), 0x7);
+    }
+
+    #[test]
+    fn timers() {
+        let mut cpu = cpu();
+        set(&mut cpu, &[(0x1, 0x20), (0x2, 0x30)]);
+        run(&mut cpu, 0xF115).unwrap();
+        run(&mut cpu, 0xF218).unwrap();
+        assert_eq!((cpu.get_delay_timer(), cpu.get_sound_timer()), (0x20, 0x30));
+        cpu.update_timers();
+        run(&mut cpu, 0xF307).unwrap();
+        assert_eq!(cpu.get_register(0x3), 0x1F);
+    }
+
+    #[test]
+    fn add_to_address_leaves_vf_alone() {
+        let mut cpu = cpu();
+        cpu.set_address_register(0x300);
+        set(&mut cpu, &[(0x1, 0xFF), (VF, 0x55)]);
+        run(&mut cpu, 0xF11E).unwrap();
+        assert_eq!(cpu.get_address_register(), 0x3FF);
+        assert_eq!(cpu.get_register(VF), 0x55);
+    }
+
+    #[test]
+    fn add_to_address_wraps_at_12_bits() {
+        let mut cpu = cpu();
+        cpu.set_address_register(0xFF0);
+        set(&mut cpu, &[(0x1, 0x20)]);
+        run(&mut cpu, 0xF11E).unwrap();
+        assert_eq!(cpu.get_address_register(), 0x010);
+
+        // The largest sum, from the top of memory
+        cpu.set_address_register(ADDRESS_MAX);
+        set(&mut cpu, &[(0x1, 0xFF)]);
+        run(&mut cpu, 0xF11E).unwrap();
+        assert_eq!(cpu.get_address_register(), 0x0FE);
+    }
+
+    #[test]
+    fn load_store_increment_wraps_at_12_bits() {
+        let mut cpu = cpu_with_quirks(chip8_quirks());
+        cpu.set_address_register(0xFFE);
+        run(&mut cpu, 0xF155).unwrap();
+        assert_eq!(cpu.get_address_register(), 0x000);
+    }
+
+    #[test]
+    fn sprite_address_uses_the_low_nibble() {
+        let mut cpu = cpu();
+        set(&mut cpu, &[(0x1, 0x3A)]);
+        run(&mut cpu, 0xF129).unwrap();
+        assert_eq!(cpu.get_address_register(), FONT_START_ADDRESS + 0xA * FONT_CHAR_SIZE);
+    }
+
+    #[test]
+    fn store_bcd() {
+        for (value, digits) in [(234, [2, 3, 4]), (7, [0, 0, 7]), (0, [0, 0, 0]), (255, [2, 5, 5]), (90, [0, 9, 0])] {
+            let mut cpu = cpu();
+            cpu.set_address_register(0x300);
+            set(&mut cpu, &[(0x1, value)]);
+            run(&mut cpu, 0xF133).unwrap();
+            let stored: Vec<u8> = (0..3).map(|offset| cpu.read_memory(0x300 + offset)).collect();
+            assert_eq!(stored, digits, "{}", value);
+            assert_eq!(cpu.get_address_register(), 0x300);
+        }
+    }
+
+    #[test]
+    fn store_bcd_past_the_end_of_memory_faults() {
+        let mut cpu = cpu();
+        cpu.set_address_register(0xFFE);
+        assert!(matches!(run(&mut cpu, 0xF133), Err(CpuFault::MemoryOutOfRange { .. })));
+    }
+
+    #[test]
+    fn store_and_load_registers() {
+        let mut cpu = cpu();
+        cpu.set_address_register(0x300);
+        set(&mut cpu, &[(0x0, 0x11), (0x1, 0x22), (0x2, 0x33), (0x3, 0x44)]);
+        run(&mut cpu, 0xF255).unwrap();
+        let stored: Vec<u8> = (0..4).map(|offset| cpu.read_memory(0x300 + offset)).collect();
+        assert_eq!(stored, [0x11, 0x22, 0x33, 0x00], "V0 to V2 only");
+        assert_eq!(cpu.get_address_register(), 0x300);
+
+        set(&mut cpu, &[(0x0, 0), (0x1, 0), (0x2, 0), (0x3, 0x44)]);
+        run(&mut cpu, 0xF265).unwrap();
+        let loaded: Vec<u8> = (0..4).map(|register| cpu.get_register(register)).collect();
+        assert_eq!(loaded, [0x11, 0x22, 0x33, 0x44]);
+        assert_eq!(cpu.get_address_register(), 0x300);
+    }
+
+    #[test]
+    fn store_and_load_increment_i_with_the_quirk() {
+        let mut cpu = cpu_with_quirks(chip8_quirks());
+        cpu.set_address_register(0x300);
+        run(&mut cpu, 0xF255).unwrap();
+        assert_eq!(cpu.get_address_register(), 0x303);
+        run(&mut cpu, 0xF065).unwrap();
+        assert_eq!(cpu.get_address_register(), 0x304);
+    }
+
+    #[test]
+    fn store_and_load_past_the_end_of_memory_fault() {
+        for opcode in [0xF355, 0xF365] {
+            let mut cpu = cpu();
+            cpu.set_address_register(0xFFE);
+            assert!(matches!(run(&mut cpu, opcode), Err(CpuFault::MemoryOutOfRange { target: 0x1001, .. })), "{:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn display_round_trips_through_the_assembler() {
+        for word in (0..=u16::MAX).filter(|word| Opcode::decode(*word).is_some()).step_by(97) {
+            let text = Opcode::decode(word).unwrap().to_string();
+            assert_eq!(crate::asm::assemble(&text).unwrap(), word.to_be_bytes(), "{}", text);
+        }
+    }
+
+    /// Two different registers other than VF
+    fn register_pair() -> impl Strategy<Value = (u8, u8)> {
+        (0..VF, 0..VF).prop_filter("distinct registers", |(x, y)| x != y)
+    }
+
+    proptest! {
+        #[test]
+        fn encode_inverts_decode(word in any::<u16>()) {
+            if let Some(opcode) = Opcode::decode(word) {
+                prop_assert_eq!(opcode.encode(), word);
+                prop_assert_eq!(Opcode::decode(opcode.encode()), Some(opcode));
+            }
+        }
+
+        #[test]
+        fn add_carry_completes_the_sum((x, y) in register_pair(), a in any::<u8>(), b in any::<u8>()) {
+            let mut cpu = cpu();
+            set(&mut cpu, &[(x, a), (y, b)]);
+            Opcode::AddRegisters { reg_x: x, reg_y: y }.execute(&mut cpu).unwrap();
+            let total = cpu.get_register(x) as u16 + ((cpu.get_register(VF) as u16) << 8);
+            prop_assert_eq!(total, a as u16 + b as u16);
+            prop_assert!(cpu.get_register(VF) <= 1);
+        }
+
+        #[test]
+        fn subtract_flag_is_not_borrow((x, y) in register_pair(), a in any::<u8>(), b in any::<u8>()) {
+            let mut cpu = cpu();
+            set(&mut cpu, &[(x, a), (y, b)]);
+            Opcode::SubtractRegisters { reg_x: x, reg_y: y }.execute(&mut cpu).unwrap();
+            prop_assert_eq!(cpu.get_register(x), a.wrapping_sub(b));
+            prop_assert_eq!(cpu.get_register(VF), (a >= b) as u8);
+
+            set(&mut cpu, &[(x, a), (y, b)]);
+            Opcode::SubtractReverse { reg_x: x, reg_y: y }.execute(&mut cpu).unwrap();
+            prop_assert_eq!(cpu.get_register(x), b.wrapping_sub(a));
+            prop_assert_eq!(cpu.get_register(VF), (b >= a) as u8);
+        }
+
+        #[test]
+        fn shift_flag_is_the_bit_shifted_out((x, y) in register_pair(), a in any::<u8>(), shift_uses_vy in any::<bool>()) {
+            let mut cpu = cpu_with_quirks(QuirkSettings { shift_uses_vy, ..QuirkSettings::default() });
+            let source = if shift_uses_vy { y } else { x };
+            set(&mut cpu, &[(source, a)]);
+            Opcode::ShiftRight { reg_x: x, reg_y: y }.execute(&mut cpu).unwrap();
+            prop_assert_eq!(cpu.get_register(x) << 1 | cpu.get_register(VF), a);
+
+            set(&mut cpu, &[(source, a)]);
+            Opcode::ShiftLeft { reg_x: x, reg_y: y }.execute(&mut cpu).unwrap();
+            prop_assert_eq!(cpu.get_register(x) >> 1 | cpu.get_register(VF) << 7, a);
+        }
+    }
+}
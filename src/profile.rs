@@ -0,0 +1,391 @@
+//! Instruction profiling.
+//!
+//! With `profile.enabled`, the CPU counts every instruction it executes by
+//! address and by opcode, follows `CALL`/`RET` to charge cycles to subroutines,
+//! and counts draws and collisions in each frame. When the CPU is dropped the
+//! profiler writes three files next to `profile.output`:
+//!
+//! - `.txt`, a readable report of the hotspots, opcodes, subroutines and drawing
+//! - `.json`, the same numbers for scripts
+//! - `.folded`, call stacks in the folded format read by flamegraph tools
+//!
+//! The window drops the CPU whenever another ROM is opened, so there the ROM's
+//! name is added to `profile.output` and each ROM keeps its own reports.
+//!
+//! Time is measured in instructions executed, which is what the emulated
+//! machine spends: a frame runs `cpu.cycles_per_frame` of them.
+
+use crate::constants::PROGRAM_START_ADDRESS;
+use crate::memory::MEMORY_SIZE;
+use crate::opcodes::Opcode;
+use crate::settings::ProfileSettings;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+
+const HOTSPOT_COUNT: usize = 20;            // Addresses listed in the text report
+const ROOT_NAME: &str = "main";             // Stack frame for code outside any subroutine
+
+/// One entry in the tree of call stacks seen so far
+struct CallNode {
+    parent: usize,
+    address: u16, // Subroutine entry point, or the program start for the root
+    cycles: u64,  // Instructions executed with this exact stack
+}
+
+struct OpenCall {
+    address: u16,
+    start_cycle: u64,
+}
+
+#[derive(Default, Clone, Copy, Serialize)]
+struct FrameStats {
+    draws: u32,
+    collisions: u32,
+}
+
+#[derive(Default, Clone, Serialize)]
+struct SubroutineStats {
+    calls: u64,
+    /// Cycles from the call to the matching return, including nested calls
+    inclusive_cycles: u64,
+    /// Cycles spent in the subroutine's own instructions
+    self_cycles: u64,
+}
+
+pub struct Profiler {
+    settings: ProfileSettings,
+    cycles: u64,
+    addresses: Vec<(u64, u16)>, // Executions and opcode at each address
+    opcodes: BTreeMap<&'static str, u64>,
+    nodes: Vec<CallNode>,
+    children: HashMap<(usize, u16), usize>,
+    current: usize, // Node of the current call stack
+    open_calls: Vec<OpenCall>,
+    subroutines: BTreeMap<u16, SubroutineStats>,
+    frame: FrameStats,
+    frames: Vec<FrameStats>,
+}
+
+impl Profiler {
+    /// A profiler for `settings`, or `None` when profiling is off
+    pub fn new(settings: &ProfileSettings) -> Option<Self> {
+        settings.enabled.then(|| Self {
+            settings: settings.clone(),
+            cycles: 0,
+            addresses: vec![(0, 0); MEMORY_SIZE],
+            opcodes: BTreeMap::new(),
+            nodes: vec![CallNode { parent: 0, address: PROGRAM_START_ADDRESS, cycles: 0 }],
+            children: HashMap::new(),
+            current: 0,
+            open_calls: Vec::new(),
+            subroutines: BTreeMap::new(),
+            frame: FrameStats::default(),
+            frames: Vec::new(),
+        })
+    }
+
+    pub fn settings(&self) -> &ProfileSettings {
+        &self.settings
+    }
+
+    /// Count an executed instruction. `vf` is VF afterwards, which holds the
+    /// collision flag after a draw.
+    pub fn record(&mut self, address: u16, opcode: &Opcode, vf: u8) {
+        self.cycles += 1;
+        let entry = &mut self.addresses[address as usize];
+        entry.0 += 1;
+        entry.1 = opcode.encode();
+        *self.opcodes.entry(opcode.pattern()).or_default() += 1;
+        self.nodes[self.current].cycles += 1;
+        if let Some(call) = self.open_calls.last() {
+            self.subroutines.entry(call.address).or_default().self_cycles += 1;
+        }
+
+        match opcode {
+            Opcode::CallSubroutine { address } => self.enter(*address),
+            Opcode::Return {} => self.leave(),
+            Opcode::Draw { .. } => {
+                self.frame.draws += 1;
+                self.frame.collisions += (vf == 1) as u32;
+            }
+            _ => {}
+        }
+    }
+
+    fn enter(&mut self, address: u16) {
+        let next = self.nodes.len();
+        let node = *self.children.entry((self.current, address)).or_insert(next);
+        if node == next {
+            self.nodes.push(CallNode { parent: self.current, address, cycles: 0 });
+        }
+        self.current = node;
+        self.subroutines.entry(address).or_default().calls += 1;
+        self.open_calls.push(OpenCall { address, start_cycle: self.cycles });
+    }
+
+    fn leave(&mut self) {
+        // A return without a call, e.g. after a reset, has nothing to close
+        let Some(call) = self.open_calls.pop() else {
+            return;
+        };
+        self.subroutines.entry(call.address).or_default().inclusive_cycles += self.cycles - call.start_cycle;
+        self.current = self.nodes[self.current].parent;
+    }
+
+    /// Forget the open calls when the CPU's stack is cleared
+    pub fn reset_stack(&mut self) {
+        while !self.open_calls.is_empty() {
+            self.leave();
+        }
+    }
+
+    pub fn end_frame(&mut self) {
+        self.frames.push(std::mem::take(&mut self.frame));
+    }
+
+    /// Write the text, JSON and folded stack reports
+    pub fn write_reports(&self) -> std::io::Result<()> {
+        let base = &self.settings.output;
+        fs::write(format!("{}.txt", base), self.text_report())?;
+        fs::write(format!("{}.json", base), self.json_report())?;
+        fs::write(format!("{}.folded", base), self.folded_stacks())?;
+        println!("Wrote profile to {0}.txt, {0}.json and {0}.folded", base);
+        Ok(())
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        if self.cycles == 0 { 0.0 } else { count as f64 * 100.0 / self.cycles as f64 }
+    }
+
+    /// Addresses by executions, most executed first
+    fn hotspots(&self) -> Vec<(u16, u64, u16)> {
+        let mut hotspots: Vec<(u16, u64, u16)> = self
+            .addresses
+            .iter()
+            .enumerate()
+            .filter(|(_, (count, _))| *count > 0)
+            .map(|(address, &(count, opcode))| (address as u16, count, opcode))
+            .collect();
+        hotspots.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hotspots
+    }
+
+    fn drawing_summary(&self, select: fn(&FrameStats) -> u32) -> (f64, u32) {
+        let total: u64 = self.frames.iter().map(|frame| select(frame) as u64).sum();
+        let max = self.frames.iter().map(select).max().unwrap_or(0);
+        let average = if self.frames.is_empty() { 0.0 } else { total as f64 / self.frames.len() as f64 };
+        (average, max)
+    }
+
+    fn text_report(&self) -> String {
+        let mut text = String::new();
+        let _ = writeln!(text, "Profile: {} instructions over {} frames", self.cycles, self.frames.len());
+
+        let _ = writeln!(text, "\nHotspots (top {} addresses):", HOTSPOT_COUNT);
+        for (address, count, opcode) in self.hotspots().into_iter().take(HOTSPOT_COUNT) {
+            let mnemonic = Opcode::decode(opcode).map(|opcode| opcode.to_string()).unwrap_or_default();
+            let _ = writeln!(text, "  {:#05X}  {:<18} {:>12} {:>6.2}%", address, mnemonic, count, self.percent(count));
+        }
+
+        let _ = writeln!(text, "\nOpcodes:");
+        let mut opcodes: Vec<_> = self.opcodes.iter().collect();
+        opcodes.sort_by(|a, b| b.1.cmp(a.1));
+        for (pattern, count) in opcodes {
+            let _ = writeln!(text, "  {:<6} {:>12} {:>6.2}%", pattern, count, self.percent(*count));
+        }
+
+        let _ = writeln!(text, "\nSubroutines (cycles):");
+        if self.subroutines.is_empty() {
+            let _ = writeln!(text, "  none called");
+        } else {
+            let _ = writeln!(text, "  {:<8} {:>8} {:>21} {:>21}", "address", "calls", "inclusive", "self");
+            let mut subroutines: Vec<_> = self.subroutines.iter().collect();
+            subroutines.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.inclusive_cycles));
+            for (address, stats) in subroutines {
+                let _ = writeln!(
+                    text,
+                    "  {:<8} {:>8} {:>12} {:>7.2}% {:>12} {:>7.2}%",
+                    format!("{:#05X}", address),
+                    stats.calls,
+                    stats.inclusive_cycles,
+                    self.percent(stats.inclusive_cycles),
+                    stats.self_cycles,
+                    self.percent(stats.self_cycles)
+                );
+            }
+        }
+
+        let (draws, max_draws) = self.drawing_summary(|frame| frame.draws);
+        let (collisions, max_collisions) = self.drawing_summary(|frame| frame.collisions);
+        let _ = writeln!(text, "\nDrawing per frame:");
+        let _ = writeln!(text, "  draws       average {:.2}, max {}", draws, max_draws);
+        let _ = writeln!(text, "  collisions  average {:.2}, max {}", collisions, max_collisions);
+        text
+    }
+
+    fn json_report(&self) -> String {
+        #[derive(Serialize)]
+        struct Hotspot {
+            address: u16,
+            instruction: String,
+            count: u64,
+        }
+        #[derive(Serialize)]
+        struct Report<'a> {
+            instructions: u64,
+            frames: &'a [FrameStats],
+            addresses: Vec<Hotspot>,
+            opcodes: &'a BTreeMap<&'static str, u64>,
+            subroutines: BTreeMap<String, &'a SubroutineStats>,
+        }
+
+        let report = Report {
+            instructions: self.cycles,
+            frames: &self.frames,
+            addresses: self
+                .hotspots()
+                .into_iter()
+                .map(|(address, count, opcode)| Hotspot {
+                    address,
+                    instruction: Opcode::decode(opcode).map(|opcode| opcode.to_string()).unwrap_or_default(),
+                    count,
+                })
+                .collect(),
+            opcodes: &self.opcodes,
+            subroutines: self.subroutines.iter().map(|(address, stats)| (format!("{:#05X}", address), stats)).collect(),
+        };
+        serde_json::to_string_pretty(&report).unwrap_or_default()
+    }
+
+    /// One line per call stack: the frames from the outermost, separated by
+    /// semicolons, then the instructions executed with exactly that stack
+    fn folded_stacks(&self) -> String {
+        let mut text = String::new();
+        for (index, node) in self.nodes.iter().enumerate().filter(|(_, node)| node.cycles > 0) {
+            let mut names = Vec::new();
+            let mut current = index;
+            while current != 0 {
+                names.push(format!("sub_{:03X}", self.nodes[current].address));
+                current = self.nodes[current].parent;
+            }
+            names.push(ROOT_NAME.to_string());
+            names.reverse();
+            let _ = writeln!(text, "{} {}", names.join(";"), node.cycles);
+        }
+        text
+    }
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        if let Err(e) = self.write_reports() {
+            eprintln!("Failed to write the profile to {}: {}", self.settings.output, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    const LOAD: Opcode = Opcode::SetRegister { register: 0, value: 1 };
+    const DRAW: Opcode = Opcode::Draw { reg_x: 0, reg_y: 0, height: 1 };
+
+    fn profiler(dir: &Path) -> Profiler {
+        let output = dir.join("profile").to_string_lossy().into_owned();
+        Profiler::new(&ProfileSettings { enabled: true, output }).unwrap()
+    }
+
+    /// main calls 0x300, which calls 0x400, then returns once more than it called
+    fn run_nested_calls(profiler: &mut Profiler) {
+        profiler.record(0x200, &Opcode::CallSubroutine { address: 0x300 }, 0);
+        profiler.record(0x300, &LOAD, 0);
+        profiler.record(0x302, &Opcode::CallSubroutine { address: 0x400 }, 0);
+        profiler.record(0x400, &LOAD, 0);
+        profiler.record(0x402, &Opcode::Return {}, 0);
+        profiler.record(0x304, &Opcode::Return {}, 0);
+        profiler.record(0x202, &Opcode::Return {}, 0);
+    }
+
+    #[test]
+    fn off_without_enabled() {
+        assert!(Profiler::new(&ProfileSettings::default()).is_none());
+    }
+
+    #[test]
+    fn calls_charge_inclusive_and_self_cycles() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut profiler = profiler(dir.path());
+        run_nested_calls(&mut profiler);
+
+        assert_eq!(profiler.cycles, 7);
+        let outer = &profiler.subroutines[&0x300];
+        assert_eq!((outer.calls, outer.inclusive_cycles, outer.self_cycles), (1, 5, 3));
+        let inner = &profiler.subroutines[&0x400];
+        assert_eq!((inner.calls, inner.inclusive_cycles, inner.self_cycles), (1, 2, 2));
+        // The unmatched return is counted in main and leaves the stack at the root
+        assert_eq!(profiler.current, 0);
+        assert_eq!(profiler.subroutines.len(), 2);
+    }
+
+    #[test]
+    fn folded_stacks_list_each_stack_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut profiler = profiler(dir.path());
+        run_nested_calls(&mut profiler);
+        run_nested_calls(&mut profiler);
+
+        assert_eq!(profiler.folded_stacks(), "main 4\nmain;sub_300 6\nmain;sub_300;sub_400 4\n");
+        assert_eq!(profiler.subroutines[&0x300].calls, 2);
+    }
+
+    #[test]
+    fn reset_stack_closes_open_calls() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut profiler = profiler(dir.path());
+        profiler.record(0x200, &Opcode::CallSubroutine { address: 0x300 }, 0);
+        profiler.record(0x300, &LOAD, 0);
+        profiler.reset_stack();
+        profiler.record(0x200, &LOAD, 0);
+
+        assert_eq!(profiler.subroutines[&0x300].inclusive_cycles, 1);
+        assert_eq!(profiler.folded_stacks(), "main 2\nmain;sub_300 1\n");
+    }
+
+    #[test]
+    fn draws_and_collisions_are_counted_per_frame() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut profiler = profiler(dir.path());
+        profiler.record(0x200, &DRAW, 1);
+        profiler.record(0x202, &DRAW, 0);
+        profiler.record(0x204, &LOAD, 1); // VF set by something other than a draw
+        profiler.end_frame();
+        profiler.record(0x200, &DRAW, 0);
+        profiler.end_frame();
+
+        let frames: Vec<_> = profiler.frames.iter().map(|frame| (frame.draws, frame.collisions)).collect();
+        assert_eq!(frames, [(2, 1), (1, 0)]);
+        let text = profiler.text_report();
+        assert!(text.contains("draws       average 1.50, max 2"), "{}", text);
+        assert!(text.contains("collisions  average 0.50, max 1"), "{}", text);
+    }
+
+    #[test]
+    fn reports_are_written_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut profiler = profiler(dir.path());
+        run_nested_calls(&mut profiler);
+        drop(profiler);
+
+        let text = fs::read_to_string(dir.path().join("profile.txt")).unwrap();
+        assert!(text.starts_with("Profile: 7 instructions over 0 frames"), "{}", text);
+        let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(dir.path().join("profile.json")).unwrap()).unwrap();
+        assert_eq!(json["instructions"], 7);
+        assert_eq!(json["subroutines"]["0x300"]["inclusive_cycles"], 5);
+        let folded = fs::read_to_string(dir.path().join("profile.folded")).unwrap();
+        assert_eq!(folded, "main 2\nmain;sub_300 3\nmain;sub_300;sub_400 2\n");
+    }
+}
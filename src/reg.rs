@@ -12,6 +12,12 @@ pub struct Registers {
     address_register: u16, // 12 bits wide, can we enforce this?
 }
 
+impl Default for Registers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Registers {
     pub fn new() -> Self {
         Self { data_registers: [0; DATA_REGISTERS_SIZE], address_register: 0 } // initialize registers to 0!
@@ -30,6 +36,11 @@ impl Registers {
         
     }
 
+    /// V0 to VF
+    pub fn data(&self) -> [u8; DATA_REGISTERS_SIZE] {
+        self.data_registers
+    }
+
     pub fn get_v(&self, index: u8) -> u8 {
         if (index as usize) < DATA_REGISTERS_SIZE {
             self.data_registers[index as usize]
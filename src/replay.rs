@@ -0,0 +1,143 @@
+//! Rolling buffer of recent display states for "instant replay" recordings.
+//!
+//! Only the 1-bit framebuffer and the beeper state are kept (257 bytes per
+//! frame), so holding several seconds of history is cheap. The phosphor effect is rebuilt when the buffer
+//! is dumped by replaying the snapshots through a fresh `Display`.
+
+use crate::constants::{DISPLAY_WIDTH, DISPLAY_HEIGHT, RGBA_PIXEL_SIZE};
+use crate::display::{Display, DisplaySnapshot};
+use crate::settings::{DisplaySettings, Shared};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+
+pub struct ReplayBuffer {
+    frames: VecDeque<(Duration, bool, DisplaySnapshot)>, // Timestamp, beeper on, display
+    capacity: usize,
+}
+
+impl ReplayBuffer {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            frames: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Record a frame shown at `timestamp` of emulated time, and whether the
+    /// beeper was on, discarding the oldest frame once the buffer is full
+    pub fn push(&mut self, timestamp: Duration, sound_on: bool, snapshot: DisplaySnapshot) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.frames.len() == self.capacity {
+            self.frames.pop_front();
+        }
+        self.frames.push_back((timestamp, sound_on, snapshot));
+    }
+
+    /// Change how many frames are kept, dropping the oldest if it shrinks
+    pub fn set_capacity(&mut self, capacity: usize) {
+        while self.frames.len() > capacity {
+            self.frames.pop_front();
+        }
+        self.capacity = capacity;
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Render every buffered frame to RGBA with its timestamp, oldest first
+    pub fn render_frames(&self, settings: Shared<DisplaySettings>) -> Vec<(Duration, Vec<u8>)> {
+        let mut display = Display::with_settings(settings);
+        self.frames
+            .iter()
+            .map(|(timestamp, _, snapshot)| {
+                let mut buffer = vec![0u8; DISPLAY_WIDTH * DISPLAY_HEIGHT * RGBA_PIXEL_SIZE];
+                display.load_snapshot(snapshot);
+                display.render_to_buffer(&mut buffer);
+                (*timestamp, buffer)
+            })
+            .collect()
+    }
+
+    /// Every buffered frame's timestamp and beeper state, oldest first
+    pub fn sound(&self) -> Vec<(Duration, bool)> {
+        self.frames.iter().map(|&(timestamp, sound_on, _)| (timestamp, sound_on)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings;
+
+    fn push_seconds(buffer: &mut ReplayBuffer, seconds: std::ops::RangeInclusive<u64>) {
+        for second in seconds {
+            buffer.push(Duration::from_secs(second), second % 2 == 0, [second; DISPLAY_HEIGHT]);
+        }
+    }
+
+    fn timestamps(buffer: &ReplayBuffer) -> Vec<u64> {
+        buffer.frames.iter().map(|(timestamp, _, _)| timestamp.as_secs()).collect()
+    }
+
+    #[test]
+    fn keeps_at_most_its_capacity() {
+        let mut buffer = ReplayBuffer::with_capacity(3);
+        push_seconds(&mut buffer, 1..=2);
+        assert_eq!(timestamps(&buffer), [1, 2]);
+
+        // Wraps around, dropping the oldest frames
+        push_seconds(&mut buffer, 3..=5);
+        assert_eq!(timestamps(&buffer), [3, 4, 5]);
+        assert!(buffer.frames.iter().all(|(timestamp, _, snapshot)| snapshot[0] == timestamp.as_secs()));
+        let sound: Vec<(u64, bool)> = buffer.sound().iter().map(|(timestamp, on)| (timestamp.as_secs(), *on)).collect();
+        assert_eq!(sound, [(3, false), (4, true), (5, false)]);
+    }
+
+    #[test]
+    fn zero_capacity_keeps_nothing() {
+        let mut buffer = ReplayBuffer::with_capacity(0);
+        push_seconds(&mut buffer, 1..=3);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn shrinking_drops_the_oldest_frames() {
+        let mut buffer = ReplayBuffer::with_capacity(5);
+        push_seconds(&mut buffer, 1..=5);
+        buffer.set_capacity(2);
+        assert_eq!(timestamps(&buffer), [4, 5]);
+        push_seconds(&mut buffer, 6..=6);
+        assert_eq!(timestamps(&buffer), [5, 6]);
+
+        // Growing keeps what is there and makes room for more
+        buffer.set_capacity(4);
+        push_seconds(&mut buffer, 7..=8);
+        assert_eq!(timestamps(&buffer), [5, 6, 7, 8]);
+
+        buffer.clear();
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn renders_every_frame_oldest_first() {
+        let mut buffer = ReplayBuffer::with_capacity(2);
+        buffer.push(Duration::from_secs(1), false, [0; DISPLAY_HEIGHT]);
+        let mut lit = [0; DISPLAY_HEIGHT];
+        lit[0] = 1 << (DISPLAY_WIDTH - 1); // Top left pixel
+        buffer.push(Duration::from_secs(2), false, lit);
+
+        let frames = buffer.render_frames(settings::shared(DisplaySettings::default()));
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].0, Duration::from_secs(1));
+        assert_eq!(frames[1].1.len(), DISPLAY_WIDTH * DISPLAY_HEIGHT * RGBA_PIXEL_SIZE);
+        assert_ne!(frames[0].1[..RGBA_PIXEL_SIZE], frames[1].1[..RGBA_PIXEL_SIZE], "the lit pixel differs");
+    }
+}
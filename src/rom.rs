@@ -0,0 +1,757 @@
+//! ROM files and what can be told about them before running.
+//!
+//! Loading checks that a program fits in memory, and `analyze` follows the
+//! program's control flow from 0x200 to find the instructions it really uses.
+//! That tells CHIP-8 programs from SUPER-CHIP and XO-CHIP ones far more
+//! reliably than scanning every word, since sprite data decodes as garbage.
+//!
+//! A ROM can be a plain file, an entry in a ZIP archive (`games.zip:PONG.ch8`,
+//! or just `games.zip` when it holds a single ROM), or `-` for standard input.
+//! An Octo cartridge GIF from any of these is compiled into its program.
+
+use crate::cartridge;
+use crate::constants::PROGRAM_START_ADDRESS;
+use crate::memory::MEMORY_SIZE;
+use crate::opcodes::Opcode;
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Largest program that fits in memory above 0x200
+pub const MAX_PROGRAM_SIZE: usize = MEMORY_SIZE - PROGRAM_START_ADDRESS as usize;
+const XO_CHIP_MEMORY_SIZE: usize = 0x10000;  // XO-CHIP extends memory to 64K
+
+const MAX_READ_SIZE: u64 = 1 << 20;        // Refuse archive entries and piped input beyond this
+
+/// File extensions recognised as CHIP-8 family ROMs
+pub const ROM_EXTENSIONS: [&str; 4] = ["ch8", "c8", "sc8", "xo8"];
+const ARCHIVE_EXTENSION: &str = "zip";
+const STDIN_NAME: &str = "stdin";
+
+/// The machine a ROM was written for, in order of how much they add to CHIP-8
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Platform {
+    Chip8,
+    SuperChip,
+    XoChip,
+}
+
+impl Platform {
+    /// Guess from the file extension, the convention most ROM archives follow
+    pub fn from_extension(path: &Path) -> Self {
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_ascii_lowercase();
+        match extension.as_str() {
+            "sc8" => Platform::SuperChip,
+            "xo8" => Platform::XoChip,
+            _ => Platform::Chip8,
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Platform::Chip8 => "CHIP-8",
+            Platform::SuperChip => "SUPER-CHIP",
+            Platform::XoChip => "XO-CHIP",
+        })
+    }
+}
+
+/// Where a ROM is read from, parsed from a command-line style path
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RomSource<'a> {
+    Stdin,
+    File(&'a Path),
+    /// An archive and the entry to use, or `None` to pick its only ROM
+    Archive { archive: &'a Path, entry: Option<&'a str> },
+}
+
+impl<'a> RomSource<'a> {
+    pub fn parse(spec: &'a str) -> Self {
+        if spec == "-" {
+            return RomSource::Stdin;
+        }
+        // Split at the first ':' that follows an existing archive, so Windows
+        // drive letters and ':' in directory names are left alone
+        for (index, _) in spec.match_indices(':') {
+            let archive = Path::new(&spec[..index]);
+            if is_archive(archive) && archive.is_file() {
+                return RomSource::Archive { archive, entry: Some(&spec[index + 1..]) };
+            }
+        }
+        let path = Path::new(spec);
+        if is_archive(path) {
+            RomSource::Archive { archive: path, entry: None }
+        } else {
+            RomSource::File(path)
+        }
+    }
+
+    /// The path that names the ROM itself, for titles and output file names
+    pub fn name_path(&self) -> &'a Path {
+        match *self {
+            RomSource::Stdin => Path::new(STDIN_NAME),
+            RomSource::File(path) => path,
+            RomSource::Archive { entry: Some(entry), .. } => Path::new(entry),
+            RomSource::Archive { archive, entry: None } => archive,
+        }
+    }
+
+    /// The file on disk holding the ROM, if any
+    pub fn file(&self) -> Option<&'a Path> {
+        match *self {
+            RomSource::Stdin => None,
+            RomSource::File(path) | RomSource::Archive { archive: path, .. } => Some(path),
+        }
+    }
+}
+
+pub struct RomFile {
+    pub data: Vec<u8>,
+    /// The ROM's own name: its path, the archive entry used, or "stdin"
+    pub name: String,
+    /// Settings embedded in an Octo cartridge, as a partial settings table
+    pub cartridge_settings: Option<toml::Table>,
+}
+
+impl RomFile {
+    /// Read a ROM, refusing programs that don't fit in memory. Errors name the file.
+    pub fn load_from_file(file_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let rom = Self::read(file_path)?;
+        check_size(rom.data.len()).map_err(|e| format!("{}: {}", file_path, e))?;
+        Ok(rom)
+    }
+
+    /// Read a ROM without checking it, for tools that inspect any file
+    pub fn read(file_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let source = RomSource::parse(file_path);
+        let result = match source {
+            RomSource::Stdin => read_limited(io::stdin().lock()).map(|data| (STDIN_NAME.to_string(), data)),
+            RomSource::File(path) => fs::read(path)
+                .map(|data| (file_path.to_string(), data))
+                .map_err(|e| e.to_string()),
+            RomSource::Archive { archive, entry } => read_archive(archive, entry),
+        };
+        let (name, data) = result.map_err(|e| format!("{}: {}", file_path, e))?;
+        if cartridge::is_cartridge(&data) {
+            let cartridge = cartridge::decode(&data).map_err(|e| format!("{}: {}", file_path, e))?;
+            return Ok(Self { data: cartridge.program, name, cartridge_settings: Some(cartridge.settings) });
+        }
+        Ok(Self { data, name, cartridge_settings: None })
+    }
+
+    /// Print anything about the program that suggests it won't run correctly
+    pub fn print_warnings(&self, file_path: &str) {
+        for warning in analyze(&self.data).warnings() {
+            eprintln!("warning: {}: {}", file_path, warning);
+        }
+    }
+}
+
+/// Read everything from `reader`, refusing more than any ROM could need
+fn read_limited(reader: impl Read) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    reader.take(MAX_READ_SIZE + 1).read_to_end(&mut data).map_err(|e| e.to_string())?;
+    if data.len() as u64 > MAX_READ_SIZE {
+        return Err(format!("more than {} bytes, too large to be a ROM", MAX_READ_SIZE));
+    }
+    Ok(data)
+}
+
+/// Read one entry of a ZIP archive, returning its name and contents
+fn read_archive(archive: &Path, entry: Option<&str>) -> Result<(String, Vec<u8>), String> {
+    let (mut zip, names) = open_archive(archive)?;
+    let name = match entry {
+        Some(entry) => find_entry(&names, entry)?,
+        None => only_rom(archive, &names)?,
+    };
+    let file = zip.by_name(&name).map_err(|e| e.to_string())?;
+    let data = read_limited(file).map_err(|e| format!("{}: {}", name, e))?;
+    Ok((name, data))
+}
+
+/// The entry read from an archive named without one, which must be its only ROM
+pub fn archive_rom(archive: &Path) -> Result<String, String> {
+    let (_, names) = open_archive(archive)?;
+    only_rom(archive, &names)
+}
+
+/// An archive and the names of the files in it
+fn open_archive(archive: &Path) -> Result<(zip::ZipArchive<fs::File>, Vec<String>), String> {
+    let file = fs::File::open(archive).map_err(|e| e.to_string())?;
+    let zip = zip::ZipArchive::new(file).map_err(|e| format!("not a readable ZIP archive ({})", e))?;
+    let names = zip.file_names().filter(|name| !name.ends_with('/')).map(String::from).collect();
+    Ok((zip, names))
+}
+
+fn only_rom(archive: &Path, names: &[String]) -> Result<String, String> {
+    let roms: Vec<&String> = names.iter().filter(|name| is_rom_file(Path::new(name))).collect();
+    match roms.as_slice() {
+        [rom] => Ok(rom.to_string()),
+        [] => Err("the archive contains no ROM files".to_string()),
+        _ => {
+            let list: Vec<&str> = roms.iter().map(|name| name.as_str()).collect();
+            Err(format!(
+                "the archive contains {} ROMs, pick one with {}:NAME ({})",
+                roms.len(),
+                archive.display(),
+                list.join(", ")
+            ))
+        }
+    }
+}
+
+/// An entry by its full name, or else by file name alone, ignoring case
+fn find_entry(names: &[String], entry: &str) -> Result<String, String> {
+    if let Some(name) = names.iter().find(|name| *name == entry) {
+        return Ok(name.clone());
+    }
+    let wanted = entry.to_ascii_lowercase();
+    let matches: Vec<&String> = names
+        .iter()
+        .filter(|name| {
+            let name = name.to_ascii_lowercase();
+            name == wanted || Path::new(&name).file_name().is_some_and(|file| file.to_str() == Some(wanted.as_str()))
+        })
+        .collect();
+    match matches.as_slice() {
+        [name] => Ok(name.to_string()),
+        [] => Err(format!("the archive has no entry named '{}'", entry)),
+        _ => {
+            let list: Vec<&str> = matches.iter().map(|name| name.as_str()).collect();
+            Err(format!("'{}' matches several entries ({}), use the full name", entry, list.join(", ")))
+        }
+    }
+}
+
+/// Whether a ROM path still points at something readable, for the recent list
+pub fn source_exists(spec: &str) -> bool {
+    RomSource::parse(spec).file().is_some_and(Path::is_file)
+}
+
+/// Whether a program of `size` bytes can be loaded at 0x200
+pub fn check_size(size: usize) -> Result<(), String> {
+    if size == 0 {
+        return Err("the ROM is empty".to_string());
+    }
+    if size > MAX_PROGRAM_SIZE {
+        let mut message = format!(
+            "the ROM is {} bytes, but only {} bytes fit in memory from {:#05X}",
+            size, MAX_PROGRAM_SIZE, PROGRAM_START_ADDRESS
+        );
+        if size <= XO_CHIP_MEMORY_SIZE - PROGRAM_START_ADDRESS as usize {
+            message.push_str(" (XO-CHIP programs this large need 64K of memory, which isn't emulated)");
+        }
+        return Err(message);
+    }
+    Ok(())
+}
+
+/// Summary of a ROM file for listings
+#[derive(Debug, Clone)]
+pub struct RomInfo {
+    pub title: String,
+    pub size: usize,
+    pub crc32: u32,
+    pub platform: Platform,
+}
+
+impl RomInfo {
+    /// Read any ROM path `RomFile` accepts, titled after the ROM rather than its archive
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let rom = RomFile::read(&path.to_string_lossy())?;
+        Ok(Self::from_data(Path::new(&rom.name), &rom.data))
+    }
+
+    pub fn from_data(path: &Path, data: &[u8]) -> Self {
+        Self {
+            title: title_from_path(path),
+            size: data.len(),
+            crc32: crc32fast::hash(data),
+            platform: Platform::from_extension(path),
+        }
+    }
+}
+
+/// A readable title from a file name, e.g. `space_invaders.ch8` -> `space invaders`
+pub fn title_from_path(path: &Path) -> String {
+    path.file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("unknown")
+        .replace(['_', '-'], " ")
+}
+
+pub fn is_rom_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| ROM_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+}
+
+pub fn is_archive(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case(ARCHIVE_EXTENSION))
+}
+
+/// Every ROM file and archive directly inside `dir`, sorted by name
+pub fn list_roms(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut roms: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && (is_rom_file(path) || is_archive(path)))
+        .collect();
+    roms.sort_by_key(|path| path.file_name().map(|name| name.to_string_lossy().to_ascii_lowercase()));
+    Ok(roms)
+}
+
+const HISTOGRAM_BAR_WIDTH: usize = 30;   // Length of the bar for the most used instruction
+
+/// The `info` report: size, hash, detected platform, instruction histogram and warnings
+pub fn report(file_path: &str, rom: &RomFile) -> String {
+    let data = &rom.data;
+    let info = RomInfo::from_data(Path::new(&rom.name), data);
+    let analysis = analyze(data);
+    let mut text = String::new();
+
+    let _ = writeln!(text, "File:         {}", file_path);
+    if rom.name != file_path {
+        let _ = writeln!(text, "ROM:          {}", rom.name);
+    }
+    let _ = writeln!(text, "Title:        {}", info.title);
+    let _ = writeln!(
+        text,
+        "Size:         {} bytes ({:.1}% of {} bytes available)",
+        info.size,
+        info.size as f64 * 100.0 / MAX_PROGRAM_SIZE as f64,
+        MAX_PROGRAM_SIZE
+    );
+    let _ = writeln!(text, "CRC32:        {:08X}", info.crc32);
+    let _ = write!(text, "Platform:     {}", analysis.platform);
+    let extension = extension(&rom.name);
+    if analysis.platform != info.platform && !extension.is_empty() {
+        let _ = write!(text, " (the .{} extension suggests {})", extension, info.platform);
+    }
+    let _ = writeln!(text);
+    let _ = writeln!(
+        text,
+        "Instructions: {} reachable from {:#05X}, {} not valid",
+        analysis.reachable, PROGRAM_START_ADDRESS, analysis.unknown
+    );
+
+    let mut counts: Vec<(&str, usize, Option<Platform>)> = analysis
+        .histogram
+        .iter()
+        .map(|(pattern, count)| (*pattern, *count, None))
+        .chain(analysis.extensions.iter().map(|(pattern, (platform, count))| (*pattern, *count, Some(*platform))))
+        .collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    if let Some(&(_, most, _)) = counts.first() {
+        let _ = writeln!(text, "\nInstruction histogram:");
+        for (pattern, count, platform) in counts {
+            let bar = "#".repeat((count * HISTOGRAM_BAR_WIDTH).div_ceil(most));
+            let platform = platform.map(|p| p.to_string()).unwrap_or_default();
+            let line = format!("  {}  {:>5}  {:<width$}  {}", pattern, count, bar, platform, width = HISTOGRAM_BAR_WIDTH);
+            let _ = writeln!(text, "{}", line.trim_end());
+        }
+    }
+
+    let mut warnings = analysis.warnings();
+    if let Err(e) = check_size(data.len()) {
+        warnings.insert(0, e);
+    }
+    if !warnings.is_empty() {
+        let _ = writeln!(text, "\nWarnings:");
+        for warning in warnings {
+            let _ = writeln!(text, "  {}", warning);
+        }
+    }
+    text
+}
+
+fn extension(file_path: &str) -> &str {
+    Path::new(file_path).extension().and_then(|e| e.to_str()).unwrap_or("")
+}
+
+/// What following a program's control flow found
+pub struct RomAnalysis {
+    /// Instructions reached from 0x200
+    pub reachable: usize,
+    /// How often each CHIP-8 instruction pattern was reached
+    pub histogram: BTreeMap<&'static str, usize>,
+    /// Reached instructions from later extensions, with the platform that added them
+    pub extensions: BTreeMap<&'static str, (Platform, usize)>,
+    /// Reached words that aren't instructions on any platform
+    pub unknown: usize,
+    /// The least capable platform that has every instruction reached
+    pub platform: Platform,
+}
+
+impl RomAnalysis {
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.reachable == 0 {
+            warnings.push("no instructions found at 0x200; this may not be a CHIP-8 program".to_string());
+        }
+        if self.platform > Platform::Chip8 {
+            let patterns: Vec<&str> = self.extensions.keys().copied().collect();
+            warnings.push(format!(
+                "uses {} instructions ({}) that this emulator doesn't implement, so it will fail at the first one",
+                self.platform,
+                patterns.join(", ")
+            ));
+        }
+        if self.unknown > 0 {
+            warnings.push(format!("{} reachable word(s) are not valid instructions", self.unknown));
+        }
+        warnings
+    }
+}
+
+/// Follow jumps, calls and skips from 0x200, classifying every instruction reached.
+/// Computed jumps (BNNN) can't be followed, so code only reached through them is missed.
+pub fn analyze(program: &[u8]) -> RomAnalysis {
+    let mut analysis = RomAnalysis {
+        reachable: 0,
+        histogram: BTreeMap::new(),
+        extensions: BTreeMap::new(),
+        unknown: 0,
+        platform: Platform::Chip8,
+    };
+    let mut visited = vec![false; program.len()];
+    let mut pending = vec![PROGRAM_START_ADDRESS as usize];
+
+    while let Some(address) = pending.pop() {
+        let Some(offset) = address.checked_sub(PROGRAM_START_ADDRESS as usize) else { continue };
+        if offset + 1 >= program.len() || visited[offset] {
+            continue;
+        }
+        visited[offset] = true;
+        let word = u16::from_be_bytes([program[offset], program[offset + 1]]);
+        let next = address + 2;
+
+        if let Some((platform, pattern)) = extension_instruction(word) {
+            analysis.reachable += 1;
+            analysis.extensions.entry(pattern).or_insert((platform, 0)).1 += 1;
+            analysis.platform = analysis.platform.max(platform);
+            match pattern {
+                "00FD" => {}                       // Exit
+                "F000" => pending.push(next + 2),  // Followed by a 16-bit address
+                _ => pending.push(next),
+            }
+            continue;
+        }
+
+        let Some(opcode) = Opcode::decode(word) else {
+            analysis.unknown += 1;
+            continue;
+        };
+        analysis.reachable += 1;
+        *analysis.histogram.entry(opcode.pattern()).or_insert(0) += 1;
+        match opcode {
+            Opcode::Goto { address } => pending.push(address as usize),
+            Opcode::CallSubroutine { address } => pending.extend([address as usize, next]),
+            Opcode::Return {} | Opcode::JumpWithOffset { .. } => {}
+            Opcode::SkipIfEqual { .. }
+            | Opcode::SkipIfNotEqual { .. }
+            | Opcode::SkipIfRegistersEqual { .. }
+            | Opcode::SkipIfRegNotEqual { .. }
+            | Opcode::SkipIfKeyPressed { .. }
+            | Opcode::SkipIfKeyNotPressed { .. } => pending.extend([next, next + 2]),
+            _ => pending.push(next),
+        }
+    }
+    analysis
+}
+
+/// Instructions added by SUPER-CHIP and XO-CHIP. Several overlap CHIP-8's 0NNN
+/// machine code calls and DXY0 (a zero-height sprite), which no CHIP-8 program
+/// relies on, so they're treated as the extension.
+fn extension_instruction(word: u16) -> Option<(Platform, &'static str)> {
+    use Platform::{SuperChip, XoChip};
+    let nibbles = (word >> 12, (word >> 8) & 0xF, (word >> 4) & 0xF, word & 0xF);
+    let found = match nibbles {
+        (0x0, 0x0, 0xC, n) if n != 0 => (SuperChip, "00CN"),
+        (0x0, 0x0, 0xD, n) if n != 0 => (XoChip, "00DN"),
+        (0x0, 0x0, 0xF, 0xB) => (SuperChip, "00FB"),
+        (0x0, 0x0, 0xF, 0xC) => (SuperChip, "00FC"),
+        (0x0, 0x0, 0xF, 0xD) => (SuperChip, "00FD"),
+        (0x0, 0x0, 0xF, 0xE) => (SuperChip, "00FE"),
+        (0x0, 0x0, 0xF, 0xF) => (SuperChip, "00FF"),
+        (0x5, _, _, 0x2) => (XoChip, "5XY2"),
+        (0x5, _, _, 0x3) => (XoChip, "5XY3"),
+        (0xD, _, _, 0x0) => (SuperChip, "DXY0"),
+        (0xF, 0x0, 0x0, 0x0) => (XoChip, "F000"),
+        (0xF, _, 0x0, 0x1) => (XoChip, "FN01"),
+        (0xF, 0x0, 0x0, 0x2) => (XoChip, "F002"),
+        (0xF, _, 0x3, 0x0) => (SuperChip, "FX30"),
+        (0xF, _, 0x3, 0xA) => (XoChip, "FX3A"),
+        (0xF, _, 0x7, 0x5) => (SuperChip, "FX75"),
+        (0xF, _, 0x8, 0x5) => (SuperChip, "FX85"),
+        _ => return None,
+    };
+    Some(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write as _};
+    use zip::write::SimpleFileOptions;
+
+    /// A ZIP archive of `entries`, built in memory and written to `dir/name`
+    fn write_zip(dir: &Path, name: &str, entries: &[(&str, &[u8])]) -> PathBuf {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (entry, data) in entries {
+            zip.start_file(*entry, SimpleFileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        let path = dir.join(name);
+        fs::write(&path, zip.finish().unwrap().into_inner()).unwrap();
+        path
+    }
+
+    fn read(spec: &Path) -> Result<RomFile, String> {
+        RomFile::read(&spec.to_string_lossy()).map_err(|e| e.to_string())
+    }
+
+    fn read_error(spec: &Path) -> String {
+        match read(spec) {
+            Ok(_) => panic!("{} was read", spec.display()),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn rom_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = write_zip(dir.path(), "games.zip", &[]);
+        let archive_spec = format!("{}:PONG.ch8", archive.display());
+
+        assert_eq!(RomSource::parse("-"), RomSource::Stdin);
+        assert_eq!(RomSource::parse("pong.ch8"), RomSource::File(Path::new("pong.ch8")));
+        assert_eq!(RomSource::parse("games.ZIP"), RomSource::Archive { archive: Path::new("games.ZIP"), entry: None });
+        assert_eq!(RomSource::parse(&archive_spec), RomSource::Archive { archive: &archive, entry: Some("PONG.ch8") });
+        // A ':' only splits off an entry after an archive that exists
+        assert_eq!(RomSource::parse("missing.zip:PONG.ch8"), RomSource::File(Path::new("missing.zip:PONG.ch8")));
+        assert_eq!(RomSource::parse("C:\\roms\\pong.ch8"), RomSource::File(Path::new("C:\\roms\\pong.ch8")));
+    }
+
+    #[test]
+    fn entries_are_found_by_full_name_or_file_name() {
+        let names = ["PONG.ch8", "games/Tetris.ch8", "a/maze.ch8", "b/maze.ch8"].map(String::from);
+        assert_eq!(find_entry(&names, "PONG.ch8"), Ok("PONG.ch8".to_string()));
+        assert_eq!(find_entry(&names, "pong.CH8"), Ok("PONG.ch8".to_string()));
+        assert_eq!(find_entry(&names, "tetris.ch8"), Ok("games/Tetris.ch8".to_string()));
+        assert_eq!(find_entry(&names, "a/maze.ch8"), Ok("a/maze.ch8".to_string()));
+        assert_eq!(find_entry(&names, "ufo.ch8"), Err("the archive has no entry named 'ufo.ch8'".to_string()));
+        assert_eq!(
+            find_entry(&names, "maze.ch8"),
+            Err("'maze.ch8' matches several entries (a/maze.ch8, b/maze.ch8), use the full name".to_string())
+        );
+    }
+
+    #[test]
+    fn archives_are_read_by_entry_or_by_their_only_rom() {
+        let dir = tempfile::tempdir().unwrap();
+        let single = write_zip(dir.path(), "single.zip", &[("readme.txt", b"hello"), ("games/Pong.ch8", &[0x12, 0x00])]);
+        let rom = read(&single).unwrap();
+        assert_eq!((rom.name.as_str(), rom.data.as_slice()), ("games/Pong.ch8", [0x12, 0x00].as_slice()));
+        assert_eq!(archive_rom(&single), Ok("games/Pong.ch8".to_string()));
+
+        let several = write_zip(dir.path(), "several.zip", &[("a.ch8", &[0x00, 0xE0]), ("b.sc8", &[0x00, 0xFF])]);
+        let rom = read(Path::new(&format!("{}:B.SC8", several.display()))).unwrap();
+        assert_eq!((rom.name.as_str(), rom.data.as_slice()), ("b.sc8", [0x00, 0xFF].as_slice()));
+        assert!(read_error(&several).contains("the archive contains 2 ROMs, pick one with"));
+
+        let none = write_zip(dir.path(), "none.zip", &[("readme.txt", b"hello")]);
+        assert!(read_error(&none).ends_with("the archive contains no ROM files"));
+
+        let not_zip = dir.path().join("broken.zip");
+        fs::write(&not_zip, b"not a zip").unwrap();
+        assert!(read_error(&not_zip).contains("not a readable ZIP archive"));
+    }
+
+    #[test]
+    fn per_rom_config_is_named_after_the_rom() {
+        use crate::config_layers::rom_config_file;
+        let dir = tempfile::tempdir().unwrap();
+        let archive = write_zip(dir.path(), "games.zip", &[("roms/pong.v2.ch8", &[0x12, 0x00])]);
+        let config = |spec: &str| rom_config_file(spec).map(|path| path.file_name().unwrap().to_string_lossy().into_owned());
+
+        assert_eq!(config("roms/pong.v2.ch8").as_deref(), Some("pong.v2.toml"));
+        assert_eq!(config(&format!("{}:pong.v2.ch8", archive.display())).as_deref(), Some("pong.v2.toml"));
+        // The archive alone means its only ROM, so it shares that ROM's config
+        assert_eq!(config(&archive.to_string_lossy()).as_deref(), Some("pong.v2.toml"));
+        assert_eq!(rom_config_file(&format!("{}:pong.v2.ch8", archive.display())), Some(dir.path().join("pong.v2.toml")));
+        assert_eq!(config("-"), None);
+    }
+
+    #[test]
+    fn reads_are_capped_at_a_mebibyte() {
+        let data = read_limited(io::repeat(0xAB).take(MAX_READ_SIZE)).unwrap();
+        assert_eq!(data.len() as u64, MAX_READ_SIZE);
+        let error = read_limited(io::repeat(0xAB).take(MAX_READ_SIZE + 1)).unwrap_err();
+        assert_eq!(error, format!("more than {} bytes, too large to be a ROM", MAX_READ_SIZE));
+
+        // Zeros compress to almost nothing, so the cap is on the entry's contents
+        let dir = tempfile::tempdir().unwrap();
+        let huge = vec![0; MAX_READ_SIZE as usize + 1];
+        let archive = write_zip(dir.path(), "bomb.zip", &[("bomb.ch8", &huge)]);
+        assert!(fs::metadata(&archive).unwrap().len() < MAX_READ_SIZE / 100);
+        assert!(read_error(&archive).ends_with("bomb.ch8: more than 1048576 bytes, too large to be a ROM"));
+    }
+
+    /// Big-endian bytes of `words`, a program loaded at 0x200
+    fn program(words: &[u16]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_be_bytes()).collect()
+    }
+
+    #[test]
+    fn sizes_must_fit_above_0x200() {
+        assert_eq!(check_size(0).unwrap_err(), "the ROM is empty");
+        assert!(check_size(1).is_ok());
+        assert!(check_size(MAX_PROGRAM_SIZE).is_ok());
+
+        let too_big = check_size(MAX_PROGRAM_SIZE + 1).unwrap_err();
+        assert!(too_big.starts_with("the ROM is 3585 bytes, but only 3584 bytes fit"), "{}", too_big);
+        assert!(too_big.contains("XO-CHIP"), "{}", too_big);
+        let too_big_for_xo_chip = check_size(XO_CHIP_MEMORY_SIZE).unwrap_err();
+        assert!(!too_big_for_xo_chip.contains("XO-CHIP"), "{}", too_big_for_xo_chip);
+    }
+
+    #[test]
+    fn analysis_follows_calls_jumps_and_skips() {
+        let analysis = analyze(&program(&[
+            0x2208, // 0x200  CALL 0x208
+            0x3000, // 0x202  SE V0, 0
+            0x1204, // 0x204  JP 0x204
+            0x00E0, // 0x206  CLS, reached by the skip
+            0x6001, // 0x208  LD V0, 1
+            0x00EE, // 0x20A  RET
+            0xFFFF, // 0x20C  data after the return, never reached
+        ]));
+        assert_eq!(analysis.reachable, 6);
+        assert_eq!(analysis.unknown, 0);
+        assert_eq!(analysis.platform, Platform::Chip8);
+        let patterns: Vec<&str> = analysis.histogram.keys().copied().collect();
+        assert_eq!(patterns, ["00E0", "00EE", "1NNN", "2NNN", "3XNN", "6XNN"]);
+        assert!(analysis.warnings().is_empty());
+    }
+
+    #[test]
+    fn extensions_reached_only_through_a_jump_set_the_platform() {
+        let analysis = analyze(&program(&[
+            0x1204, // 0x200  JP 0x204
+            0x00FF, // 0x202  SUPER-CHIP HIGH, jumped over
+            0x00FE, // 0x204  SUPER-CHIP LOW
+            0x1206, // 0x206  JP 0x206
+        ]));
+        assert_eq!(analysis.reachable, 3);
+        assert_eq!(analysis.platform, Platform::SuperChip);
+        assert_eq!(analysis.extensions.keys().copied().collect::<Vec<_>>(), ["00FE"]);
+        assert!(analysis.warnings()[0].contains("SUPER-CHIP instructions (00FE)"), "{:?}", analysis.warnings());
+
+        // Sprite data that happens to decode as an extension doesn't count
+        let data_only = analyze(&program(&[0x1204, 0x00FF, 0x1204]));
+        assert_eq!(data_only.platform, Platform::Chip8);
+    }
+
+    #[test]
+    fn xo_chip_long_loads_skip_their_address() {
+        let analysis = analyze(&program(&[
+            0xF000, // 0x200  LD I, long
+            0xFFFF, // 0x202  the address, not an instruction
+            0x5012, // 0x204  XO-CHIP SAVE V0-V1
+            0x1206, // 0x206  JP 0x206
+        ]));
+        assert_eq!(analysis.reachable, 3);
+        assert_eq!(analysis.unknown, 0);
+        assert_eq!(analysis.platform, Platform::XoChip);
+        assert_eq!(analysis.extensions["5XY2"], (Platform::XoChip, 1));
+    }
+
+    #[test]
+    fn unknown_and_missing_instructions_are_warned_about() {
+        let analysis = analyze(&program(&[0xFFFF]));
+        assert_eq!((analysis.reachable, analysis.unknown), (0, 1));
+        let warnings = analysis.warnings();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].starts_with("no instructions found at 0x200"));
+        assert_eq!(warnings[1], "1 reachable word(s) are not valid instructions");
+    }
+
+    #[test]
+    fn report_describes_the_rom() {
+        let rom = RomFile {
+            data: program(&[0x1204, 0x00FF, 0x00FE, 0x1206]),
+            name: "space_race.ch8".to_string(),
+            cartridge_settings: None,
+        };
+        let text = report("games.zip", &rom);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines[..4],
+            [
+                "File:         games.zip",
+                "ROM:          space_race.ch8",
+                "Title:        space race",
+                "Size:         8 bytes (0.2% of 3584 bytes available)",
+            ]
+        );
+        assert_eq!(lines[4], format!("CRC32:        {:08X}", crc32fast::hash(&rom.data)));
+        assert_eq!(lines[5], "Platform:     SUPER-CHIP (the .ch8 extension suggests CHIP-8)");
+        assert_eq!(lines[6], "Instructions: 3 reachable from 0x200, 0 not valid");
+        assert!(text.contains("\nInstruction histogram:\n  1NNN      2  ##############################\n"), "{}", text);
+        assert!(text.contains("  00FE      1  ###############                 SUPER-CHIP\n"), "{}", text);
+        assert!(text.contains("\nWarnings:\n  uses SUPER-CHIP instructions (00FE)"), "{}", text);
+    }
+
+    #[test]
+    fn platform_follows_the_extension() {
+        assert_eq!(Platform::from_extension(Path::new("a.ch8")), Platform::Chip8);
+        assert_eq!(Platform::from_extension(Path::new("a.SC8")), Platform::SuperChip);
+        assert_eq!(Platform::from_extension(Path::new("a.xo8")), Platform::XoChip);
+        assert_eq!(Platform::from_extension(Path::new("a")), Platform::Chip8);
+    }
+
+    #[test]
+    fn listing_holds_roms_and_archives_sorted_by_name() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["b.SC8", "a.ch8", "notes.txt", "C.xo8"] {
+            fs::write(dir.path().join(name), [0x12, 0x00]).unwrap();
+        }
+        write_zip(dir.path(), "d.zip", &[("x.ch8", &[0x12, 0x00])]);
+        fs::create_dir(dir.path().join("folder.ch8")).unwrap();
+
+        let names: Vec<String> = list_roms(dir.path())
+            .unwrap()
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, ["a.ch8", "b.SC8", "C.xo8", "d.zip"]);
+        assert!(list_roms(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn rom_info_describes_files_and_archive_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = [0x00, 0xE0, 0x12, 0x00];
+        let file = dir.path().join("space_invaders.sc8");
+        fs::write(&file, data).unwrap();
+        let info = RomInfo::from_file(&file).unwrap();
+        assert_eq!(info.title, "space invaders");
+        assert_eq!(info.size, data.len());
+        assert_eq!(info.crc32, crc32fast::hash(&data));
+        assert_eq!(info.platform, Platform::SuperChip);
+
+        // Named after the entry, not the archive
+        let archive = write_zip(dir.path(), "games.zip", &[("roms/tic-tac-toe.xo8", &data)]);
+        let info = RomInfo::from_file(&archive).unwrap();
+        assert_eq!((info.title.as_str(), info.platform), ("tic tac toe", Platform::XoChip));
+        assert_eq!(info.crc32, crc32fast::hash(&data));
+
+        assert!(RomInfo::from_file(&dir.path().join("missing.ch8")).is_err());
+    }
+}
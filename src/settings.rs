@@ -3,9 +3,61 @@
 //! These settings control various aspects of the emulator that users might want
 //! to customize for their experience, including display effects, audio, and timing.
 
+use crate::constants::{ADDRESS_MAX, DISPLAY_WIDTH};
+use crate::opcodes::Opcode;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::fs;
 use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+/// A settings section shared with the components that use it, so it can be
+/// changed while the emulator is running
+pub type Shared<T> = Arc<RwLock<T>>;
+
+pub fn shared<T>(value: T) -> Shared<T> {
+    Arc::new(RwLock::new(value))
+}
+
+/// A problem with one setting, identified by its TOML path (e.g. `cpu.target_fps`)
+#[derive(Debug, Clone)]
+pub struct ConfigIssue {
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            f.write_str(&self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+/// Settings that can't be used, with every problem found
+#[derive(Debug)]
+pub struct ConfigError {
+    pub file: String,
+    pub issues: Vec<ConfigIssue>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.file.is_empty() {
+            write!(f, "invalid settings")?;
+        } else {
+            write!(f, "invalid config file {}", self.file)?;
+        }
+        for issue in &self.issues {
+            write!(f, "\n  {}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(default)]
@@ -13,7 +65,12 @@ pub struct Settings {
     pub display: DisplaySettings,
     pub audio: AudioSettings,
     pub cpu: CpuSettings,
+    pub quirks: QuirkSettings,
     pub recording: RecordingSettings,
+    pub launcher: LauncherSettings,
+    pub trace: TraceSettings,
+    pub profile: ProfileSettings,
+    pub coverage: CoverageSettings,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -56,6 +113,25 @@ pub struct AudioSettings {
     
     /// Audio volume/amplitude (0.0 to 1.0)
     pub beep_volume: f32,
+    
+    /// Shape of the beep waveform
+    pub waveform: Waveform,
+    
+    /// Time for the beep to fade in when the sound timer starts, in milliseconds
+    pub attack_ms: f32,
+    
+    /// Time for the beep to fade out when the sound timer stops, in milliseconds
+    pub release_ms: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+    Sawtooth,
+    Noise,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -66,6 +142,31 @@ pub struct CpuSettings {
     
     /// Number of CPU cycles to execute per frame
     pub cycles_per_frame: u32,
+    
+    /// Seed for the CXNN random number generator (0 = different every run)
+    pub seed: u64,
+}
+
+/// Behaviours that differ between CHIP-8 interpreters. The defaults match how
+/// this emulator has always behaved; `QuirkSettings::profile` gives the
+/// settings for well-known platforms.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct QuirkSettings {
+    /// 8XY6/8XYE shift VY into VX instead of shifting VX in place
+    pub shift_uses_vy: bool,
+    
+    /// FX55/FX65 leave I pointing past the last register stored or loaded
+    pub load_store_increment_i: bool,
+    
+    /// BNNN jumps to XNN + VX instead of NNN + V0
+    pub jump_uses_vx: bool,
+    
+    /// 8XY1/8XY2/8XY3 reset VF to 0
+    pub vf_reset: bool,
+    
+    /// Sprites are clipped at the screen edge instead of wrapping around
+    pub clip_sprites: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -74,7 +175,8 @@ pub struct RecordingSettings {
     /// Scale factor for GIF recordings
     pub gif_scale_factor: u16,
     
-    /// Delay between GIF frames in centiseconds
+    /// How long the final frame of a GIF is shown, in centiseconds. Other frame
+    /// delays follow emulated time.
     pub gif_frame_delay: u16,
     
     /// Record every Nth frame
@@ -82,6 +184,62 @@ pub struct RecordingSettings {
     
     /// Default output directory
     pub output_dir: String,
+    
+    /// Seconds of recent gameplay kept in memory for instant replay (0 = disabled)
+    pub replay_seconds: u32,
+    
+    /// Also write the beeper to a WAV file next to each GIF recording
+    pub record_audio: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LauncherSettings {
+    /// Directory the in-window ROM launcher lists
+    pub rom_dir: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TraceSettings {
+    /// Log every instruction that passes the filters below
+    pub enabled: bool,
+
+    /// Keep the last N instructions that pass the filters and dump them when
+    /// the CPU faults (0 = off). Works with or without `enabled`.
+    pub ring_size: u32,
+
+    /// File to write the trace to; empty sends it through the logger
+    pub output: String,
+
+    /// Only trace instructions whose address is in this inclusive range
+    pub address_range: [u16; 2],
+
+    /// Only trace these opcode classes or patterns, e.g. "display" or "8XY4" (empty = all)
+    pub classes: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProfileSettings {
+    /// Count where the cycles go and write a report when the ROM stops
+    pub enabled: bool,
+
+    /// Report path without extension; `.txt`, `.json` and `.folded` are added
+    pub output: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CoverageSettings {
+    /// Record which bytes run as code, are read as data or are written
+    pub enabled: bool,
+
+    /// Report path without extension; `.cov`, `.asm` and `.png` are added
+    pub output: String,
+
+    /// Add to the coverage already in `<output>.cov` instead of replacing it
+    pub merge: bool,
 }
 
 // Default implementations
@@ -114,6 +272,9 @@ impl Default for AudioSettings {
         Self {
             beep_frequency_hz: 440.0,
             beep_volume: 0.05,
+            waveform: Waveform::Sine,
+            attack_ms: 2.0,
+            release_ms: 5.0,
         }
     }
 }
@@ -123,6 +284,7 @@ impl Default for CpuSettings {
         Self {
             target_fps: 60,
             cycles_per_frame: 10,
+            seed: 0,
         }
     }
 }
@@ -134,27 +296,281 @@ impl Default for RecordingSettings {
             gif_frame_delay: 4,
             gif_frame_skip: 3,
             output_dir: ".".to_string(),
+            replay_seconds: 10,
+            record_audio: true,
+        }
+    }
+}
+
+impl Default for LauncherSettings {
+    fn default() -> Self {
+        Self {
+            rom_dir: ".".to_string(),
+        }
+    }
+}
+
+impl Default for CoverageSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            output: "chip8_coverage".to_string(),
+            merge: false,
+        }
+    }
+}
+
+impl Default for ProfileSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            output: "chip8_profile".to_string(),
+        }
+    }
+}
+
+impl Default for TraceSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ring_size: 0,
+            output: String::new(),
+            address_range: [0x000, 0xFFF],
+            classes: Vec::new(),
+        }
+    }
+}
+
+/// Live handles to the settings sections used outside the main loop. Components
+/// hold the section they need, and `apply` updates them in place when the
+/// config file changes. CPU and quirk settings are read by the main loop itself.
+#[derive(Clone)]
+pub struct LiveSettings {
+    pub display: Shared<DisplaySettings>,
+    pub audio: Shared<AudioSettings>,
+    pub recording: Shared<RecordingSettings>,
+}
+
+impl LiveSettings {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            display: shared(settings.display.clone()),
+            audio: shared(settings.audio.clone()),
+            recording: shared(settings.recording.clone()),
+        }
+    }
+
+    pub fn apply(&self, settings: &Settings) {
+        *self.display.write().unwrap() = settings.display.clone();
+        *self.audio.write().unwrap() = settings.audio.clone();
+        *self.recording.write().unwrap() = settings.recording.clone();
+    }
+}
+
+impl ColorSettings {
+    /// RGB colour of a phosphor at the given brightness
+    pub fn phosphor_color(&self, brightness: u8) -> [u8; 3] {
+        [
+            brightness / self.red_divisor,
+            brightness / self.green_divisor,
+            brightness / self.blue_divisor,
+        ]
+    }
+}
+
+impl QuirkSettings {
+    /// Names accepted by `QuirkSettings::profile`
+    pub const PROFILES: [&'static str; 4] = ["default", "chip8", "schip", "xo-chip"];
+
+    /// Quirks of a well-known platform, by name
+    pub fn profile(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "default" | "chiper" => Some(Self::default()),
+            // Original COSMAC VIP interpreter
+            "chip8" | "chip-8" | "vip" => Some(Self {
+                shift_uses_vy: true,
+                load_store_increment_i: true,
+                jump_uses_vx: false,
+                vf_reset: true,
+                clip_sprites: true,
+            }),
+            // SUPER-CHIP 1.1 on the HP48
+            "schip" | "superchip" => Some(Self {
+                shift_uses_vy: false,
+                load_store_increment_i: false,
+                jump_uses_vx: true,
+                vf_reset: false,
+                clip_sprites: true,
+            }),
+            "xo-chip" | "xochip" => Some(Self {
+                shift_uses_vy: true,
+                load_store_increment_i: true,
+                jump_uses_vx: false,
+                vf_reset: false,
+                clip_sprites: false,
+            }),
+            _ => None,
         }
     }
 }
 
 impl Settings {
-    /// Load settings from a TOML file, falling back to defaults if the file doesn't exist
-    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
+    /// Read one config file as a table of the settings it sets. The file must
+    /// parse and every value must have the right type; unknown keys are returned
+    /// as warnings. Ranges are checked later, once every layer has been merged.
+    pub fn read_layer<P: AsRef<Path>>(path: P) -> Result<(toml::Table, Vec<ConfigIssue>), ConfigError> {
         let path = path.as_ref();
-        
-        if path.exists() {
-            let contents = fs::read_to_string(path)?;
-            let settings: Settings = toml::from_str(&contents)?;
-            Ok(settings)
+        let fail = |message: String| ConfigError {
+            file: path.display().to_string(),
+            issues: vec![ConfigIssue { path: String::new(), message }],
+        };
+
+        let contents = fs::read_to_string(path).map_err(|e| fail(e.to_string()))?;
+        // toml's own errors carry the line, column and offending key
+        toml::from_str::<Settings>(&contents).map_err(|e| fail(e.to_string().trim_end().to_string()))?;
+        let table: toml::Table = toml::from_str(&contents).map_err(|e| fail(e.to_string().trim_end().to_string()))?;
+        let warnings = Self::unknown_keys(&table);
+        Ok((table, warnings))
+    }
+
+    /// Keys in a config table that don't correspond to any setting
+    fn unknown_keys(table: &toml::Table) -> Vec<ConfigIssue> {
+        fn walk(table: &toml::Table, known: &toml::Table, prefix: &str, issues: &mut Vec<ConfigIssue>) {
+            for (key, value) in table {
+                let path = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+                match (value, known.get(key)) {
+                    (toml::Value::Table(inner), Some(toml::Value::Table(known_inner))) => {
+                        walk(inner, known_inner, &path, issues)
+                    }
+                    (_, Some(_)) => {}
+                    (_, None) => issues.push(ConfigIssue { path, message: "unknown key, ignored".to_string() }),
+                }
+            }
+        }
+
+        let mut issues = Vec::new();
+        if let Ok(toml::Value::Table(known)) = toml::Value::try_from(Settings::default()) {
+            walk(table, &known, "", &mut issues);
+        }
+        issues
+    }
+
+    /// Every setting that is outside its allowed range
+    pub fn validate(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        let mut check = |path: &str, value: f64, min: f64, max: f64| {
+            if !(min..=max).contains(&value) {
+                issues.push(ConfigIssue {
+                    path: path.to_string(),
+                    message: format!("{} is out of range, allowed {} to {}", value, min, max),
+                });
+            }
+        };
+
+        let display = &self.display;
+        check("display.default_scale_factor", display.default_scale_factor as f64, 1.0, 64.0);
+        // Brightness is divided by these, so zero would crash the renderer
+        check("display.color.red_divisor", display.color.red_divisor as f64, 1.0, 255.0);
+        check("display.color.green_divisor", display.color.green_divisor as f64, 1.0, 255.0);
+        check("display.color.blue_divisor", display.color.blue_divisor as f64, 1.0, 255.0);
+
+        let audio = &self.audio;
+        check("audio.beep_frequency_hz", audio.beep_frequency_hz as f64, 20.0, 20_000.0);
+        check("audio.beep_volume", audio.beep_volume as f64, 0.0, 1.0);
+        check("audio.attack_ms", audio.attack_ms as f64, 0.0, 1000.0);
+        check("audio.release_ms", audio.release_ms as f64, 0.0, 1000.0);
+
+        check("cpu.target_fps", self.cpu.target_fps as f64, 1.0, 1000.0);
+        check("cpu.cycles_per_frame", self.cpu.cycles_per_frame as f64, 1.0, 1_000_000.0);
+
+        let recording = &self.recording;
+        // 64 pixels times the scale factor must still fit the GIF's 16-bit width
+        check("recording.gif_scale_factor", recording.gif_scale_factor as f64, 1.0, (u16::MAX as usize / DISPLAY_WIDTH) as f64);
+        check("recording.gif_frame_delay", recording.gif_frame_delay as f64, 0.0, 1000.0);
+        check("recording.gif_frame_skip", recording.gif_frame_skip as f64, 0.0, 1000.0);
+        check("recording.replay_seconds", recording.replay_seconds as f64, 0.0, 3600.0);
+
+        let trace = &self.trace;
+        check("trace.ring_size", trace.ring_size as f64, 0.0, 1_000_000.0);
+        let [start, end] = trace.address_range;
+        if start > end || end > ADDRESS_MAX {
+            issues.push(ConfigIssue {
+                path: "trace.address_range".to_string(),
+                message: format!("[{:#05X}, {:#05X}] is not a range within memory", start, end),
+            });
+        }
+        for class in &trace.classes {
+            if !Opcode::is_class_or_pattern(class) {
+                issues.push(ConfigIssue {
+                    path: "trace.classes".to_string(),
+                    message: format!(
+                        "unknown opcode class '{}', expected a pattern such as \"8XY4\" or one of: {}",
+                        class,
+                        Opcode::CLASSES.join(", ")
+                    ),
+                });
+            }
+        }
+
+        let reports = [
+            ("profile.output", self.profile.enabled, &self.profile.output),
+            ("coverage.output", self.coverage.enabled, &self.coverage.output),
+        ];
+        for (path, enabled, output) in reports {
+            if enabled && output.is_empty() {
+                issues.push(ConfigIssue {
+                    path: path.to_string(),
+                    message: "is empty, so the report has nowhere to go".to_string(),
+                });
+            }
+        }
+
+        issues
+    }
+
+    /// `validate` as a `Result`, for callers that refuse to run with invalid settings
+    pub fn check(&self) -> Result<(), ConfigError> {
+        let issues = self.validate();
+        if issues.is_empty() {
+            Ok(())
         } else {
-            // Return default settings if file doesn't exist
-            Ok(Settings::default())
+            Err(ConfigError { file: String::new(), issues })
         }
     }
     
+    /// Override a single setting by its dotted TOML path, e.g. `cpu.cycles_per_frame`.
+    /// Dashes in the path are accepted in place of underscores, and the value is
+    /// parsed according to the type of the setting it replaces.
+    pub fn set_by_path(&mut self, path: &str, value: &str) -> Result<(), String> {
+        let path = path.replace('-', "_");
+        let mut root = toml::Value::try_from(&*self).map_err(|e| e.to_string())?;
+
+        let mut slot = &mut root;
+        for key in path.split('.') {
+            slot = slot
+                .as_table_mut()
+                .and_then(|table| table.get_mut(key))
+                .ok_or_else(|| format!("unknown setting '{}'", path))?;
+        }
+
+        *slot = match slot {
+            toml::Value::Table(_) => return Err(format!("'{}' is a section, not a setting", path)),
+            toml::Value::String(_) => toml::Value::String(value.to_string()),
+            _ => {
+                // Parse the value as TOML so numbers, booleans and arrays all work
+                let parsed: toml::Table = toml::from_str(&format!("value = {}", value))
+                    .map_err(|_| format!("invalid value '{}' for '{}'", value, path))?;
+                parsed["value"].clone()
+            }
+        };
+
+        *self = root
+            .try_into()
+            .map_err(|e: toml::de::Error| format!("invalid value '{}' for '{}': {}", value, path, e.message()))?;
+        Ok(())
+    }
+    
     /// Save current settings to a TOML file
-    #[allow(dead_code)]
     pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn std::error::Error>> {
         let toml_string = toml::to_string_pretty(self)?;
         fs::write(path, toml_string)?;
@@ -162,3 +578,97 @@ impl Settings {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(Settings::default().validate().is_empty());
+        assert!(Settings::default().check().is_ok());
+    }
+
+    #[test]
+    fn out_of_range_values_name_their_setting() {
+        let cases = [
+            ("display.default_scale_factor", "0"),
+            ("display.color.red_divisor", "0"),
+            ("display.color.green_divisor", "0"),
+            ("display.color.blue_divisor", "0"),
+            ("audio.beep_frequency_hz", "10"),
+            ("audio.beep_volume", "1.5"),
+            ("audio.attack_ms", "1001"),
+            ("audio.release_ms", "5000"),
+            ("cpu.target_fps", "0"),
+            ("cpu.cycles_per_frame", "0"),
+            ("recording.gif_scale_factor", "1024"),
+            ("recording.gif_frame_delay", "1001"),
+            ("recording.gif_frame_skip", "1001"),
+            ("recording.replay_seconds", "3601"),
+            ("trace.ring_size", "1000001"),
+            ("trace.address_range", "[0x300, 0x200]"),
+            ("trace.address_range", "[0x200, 0x1000]"),
+            ("trace.classes", "[\"display\", \"sound\"]"),
+        ];
+        for (path, value) in cases {
+            let mut settings = Settings::default();
+            settings.set_by_path(path, value).unwrap();
+            let issues = settings.validate();
+            assert_eq!(issues.len(), 1, "{} = {}: {:?}", path, value, issues);
+            assert_eq!(issues[0].path, path, "{} = {}", path, value);
+        }
+    }
+
+    #[test]
+    fn range_errors_give_the_allowed_range() {
+        let mut settings = Settings::default();
+        settings.cpu.target_fps = 0;
+        settings.audio.beep_volume = 2.0;
+        let error = settings.check().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid settings\n  audio.beep_volume: 2 is out of range, allowed 0 to 1\n  cpu.target_fps: 0 is out of range, allowed 1 to 1000"
+        );
+    }
+
+    #[test]
+    fn reports_need_an_output_when_enabled() {
+        let mut settings = Settings::default();
+        settings.profile.output.clear();
+        assert!(settings.validate().is_empty(), "an unused output may be empty");
+        settings.profile.enabled = true;
+        let issues = settings.validate();
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].path, "profile.output");
+    }
+
+    #[test]
+    fn misspelled_keys_are_reported_by_path() {
+        let table: toml::Table = toml::from_str(
+            "[cpu]\ncycles_per_frame = 20\ncycles_per_frmae = 30\n\n[dispaly]\ndefault_scale_factor = 4\n",
+        )
+        .unwrap();
+        let issues: Vec<String> = Settings::unknown_keys(&table).iter().map(ToString::to_string).collect();
+        assert_eq!(issues, ["cpu.cycles_per_frmae: unknown key, ignored", "dispaly: unknown key, ignored"]);
+    }
+
+    #[test]
+    fn read_layer_warns_of_unknown_keys_and_fails_on_wrong_types() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chip8_config.toml");
+
+        fs::write(&path, "[cpu]\ncycles_per_frame = 20\nspeed = 2\n").unwrap();
+        let (table, warnings) = Settings::read_layer(&path).unwrap();
+        assert_eq!(table["cpu"]["cycles_per_frame"].as_integer(), Some(20));
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].path, "cpu.speed");
+
+        fs::write(&path, "[cpu]\ncycles_per_frame = \"fast\"\n").unwrap();
+        let error = Settings::read_layer(&path).unwrap_err();
+        assert_eq!(error.file, path.display().to_string());
+        assert_eq!(error.issues.len(), 1);
+        assert!(error.to_string().starts_with(&format!("invalid config file {}", path.display())), "{}", error);
+        assert!(error.issues[0].message.contains("cycles_per_frame"), "{}", error);
+    }
+}
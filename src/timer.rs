@@ -1,32 +1,27 @@
-
-use rodio::{OutputStreamBuilder, Sink, Source};
-use std::time::Duration;
-use crate::settings::AudioSettings;
-use std::sync::Arc;
+use crate::audio::{self, AudioBackend};
+use crate::settings::{self, AudioSettings, Shared};
 
 pub struct Timers{
     delay_timer: u8,
     sound_timer: u8,
-    _stream: rodio::OutputStream,
-    sink: Sink,
-    settings: Arc<AudioSettings>,
+    audio: Box<dyn AudioBackend>,
 }
 
 impl Timers {
-    #[allow(dead_code)]
+    #[allow(clippy::new_without_default)] // Opens the audio device, like `CPU::new`
     pub fn new() -> Self {
-        Self::with_settings(Arc::new(AudioSettings::default()))
+        Self::with_settings(settings::shared(AudioSettings::default()))
+    }
+
+    pub fn with_settings(settings: Shared<AudioSettings>) -> Self {
+        Self::with_backend(audio::open_default_backend(settings))
     }
-    
-    pub fn with_settings(settings: Arc<AudioSettings>) -> Self {
-        let _stream = OutputStreamBuilder::open_default_stream().unwrap();
-        let sink = Sink::connect_new(_stream.mixer());
+
+    pub fn with_backend(audio: Box<dyn AudioBackend>) -> Self {
         Self {
             delay_timer: 0,
             sound_timer: 0,
-            _stream,
-            sink,
-            settings,
+            audio,
         }
     }
 
@@ -36,17 +31,9 @@ impl Timers {
         }
         if self.sound_timer > 0 {
             self.sound_timer -= 1;
-            // Play beep sound while timer is active
-            if self.sink.empty() {
-                let source = rodio::source::SineWave::new(self.settings.beep_frequency_hz)
-                    .take_duration(Duration::from_millis(100))
-                    .amplify(self.settings.beep_volume);
-                self.sink.append(source);
-                self.sink.play();
-            }
+            self.audio.set_tone(true);
         } else {
-            // Stop sound when timer reaches 0
-            self.sink.stop();
+            self.audio.set_tone(false);
         }
     }
 
@@ -58,8 +45,43 @@ impl Timers {
         self.delay_timer = value;
     }
 
+    pub fn is_sound_active(&self) -> bool {
+        self.sound_timer > 0
+    }
+
+    pub fn get_sound(&self) -> u8 {
+        self.sound_timer
+    }
+
     pub fn set_sound(&mut self, value: u8) {
         self.sound_timer = value;
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::{RecordingBackend, ToneEvent};
+
+    #[test]
+    fn beeper_sounds_while_the_sound_timer_runs() {
+        let backend = RecordingBackend::new();
+        let events = backend.events();
+        let mut timers = Timers::with_backend(Box::new(backend));
+
+        timers.tick();
+        timers.set_sound(3);
+        for _ in 0..5 {
+            timers.tick();
+        }
+        timers.set_sound(1);
+        timers.tick();
+        timers.tick();
+
+        let on = |tick| ToneEvent { tick, active: true };
+        let off = |tick| ToneEvent { tick, active: false };
+        // On for the three ticks that count 3 down to 0, then for the one that counts 1 down
+        assert_eq!(*events.lock().unwrap(), [on(1), off(4), on(6), off(7)]);
+        assert!(!timers.is_sound_active());
+    }
+}
@@ -0,0 +1,266 @@
+//! Instruction tracing.
+//!
+//! With `trace.enabled`, every executed instruction that passes the address
+//! and class filters is written out as one line: the cycle count, address, raw
+//! opcode, mnemonic, I, the timers and any registers it changed. Lines go to
+//! `trace.output`, or through the logger under the `chip8::trace` target.
+//!
+//! With `trace.ring_size`, the last instructions are kept in memory instead and
+//! only written out when the CPU faults, which shows how a ROM went wrong
+//! without the cost of logging a whole run.
+
+use crate::cpu::CpuFault;
+use crate::opcodes::Opcode;
+use crate::settings::TraceSettings;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+pub const TRACE_TARGET: &str = "chip8::trace";
+const MNEMONIC_COLUMN_WIDTH: usize = 18;   // Pad mnemonics so the state columns line up
+const REGISTER_COUNT: usize = 16;
+
+/// The machine state around one executed instruction
+#[derive(Debug, Clone, Copy)]
+pub struct TraceEntry {
+    pub cycle: u64,
+    pub address: u16,
+    pub opcode: u16,
+    pub before: [u8; REGISTER_COUNT],
+    pub after: [u8; REGISTER_COUNT],
+    pub i: u16,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mnemonic = Opcode::decode(self.opcode).map(|opcode| opcode.to_string()).unwrap_or_default();
+        write!(
+            f,
+            "{:>10} {:#05X}  {:04X}  {:<width$} I={:03X} DT={:02X} ST={:02X}",
+            self.cycle, self.address, self.opcode, mnemonic, self.i, self.delay_timer, self.sound_timer,
+            width = MNEMONIC_COLUMN_WIDTH
+        )?;
+        for (register, (before, after)) in self.before.iter().zip(self.after).enumerate() {
+            if *before != after {
+                write!(f, " V{:X}:{:02X}->{:02X}", register, before, after)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+enum TraceOutput {
+    Log,
+    File(BufWriter<File>),
+}
+
+pub struct Tracer {
+    settings: TraceSettings,
+    output: TraceOutput,
+    ring: VecDeque<TraceEntry>,
+}
+
+impl Tracer {
+    /// A tracer for `settings`, or `None` when tracing is off
+    pub fn new(settings: &TraceSettings) -> io::Result<Option<Self>> {
+        if !settings.enabled && settings.ring_size == 0 {
+            return Ok(None);
+        }
+        let output = if settings.output.is_empty() {
+            TraceOutput::Log
+        } else {
+            TraceOutput::File(BufWriter::new(File::create(&settings.output)?))
+        };
+        Ok(Some(Self {
+            settings: settings.clone(),
+            output,
+            ring: VecDeque::with_capacity(settings.ring_size as usize),
+        }))
+    }
+
+    pub fn settings(&self) -> &TraceSettings {
+        &self.settings
+    }
+
+    /// Whether the instruction at `address` passes the filters
+    pub fn wants(&self, address: u16, opcode: &Opcode) -> bool {
+        let [start, end] = self.settings.address_range;
+        (start..=end).contains(&address)
+            && (self.settings.classes.is_empty() || self.settings.classes.iter().any(|class| opcode.matches(class)))
+    }
+
+    pub fn record(&mut self, entry: TraceEntry) {
+        if self.settings.enabled {
+            self.write_line(&entry.to_string());
+        }
+        if self.settings.ring_size > 0 {
+            if self.ring.len() == self.settings.ring_size as usize {
+                self.ring.pop_front();
+            }
+            self.ring.push_back(entry);
+        }
+    }
+
+    /// Write out the ring buffer, if kept, followed by the fault
+    pub fn dump(&mut self, fault: &CpuFault) {
+        if !self.ring.is_empty() {
+            self.write_line(&format!("Last {} instructions before the fault:", self.ring.len()));
+            let ring = std::mem::take(&mut self.ring);
+            for entry in &ring {
+                self.write_line(&entry.to_string());
+            }
+        }
+        self.write_line(&format!("CPU fault: {}", fault));
+        self.flush();
+    }
+
+    pub fn flush(&mut self) {
+        if let TraceOutput::File(file) = &mut self.output
+            && let Err(e) = file.flush()
+        {
+            eprintln!("Failed to write the trace to {}: {}", self.settings.output, e);
+        }
+    }
+
+    fn write_line(&mut self, line: &str) {
+        match &mut self.output {
+            TraceOutput::Log => log::trace!(target: TRACE_TARGET, "{}", line),
+            TraceOutput::File(file) => {
+                if let Err(e) = writeln!(file, "{}", line) {
+                    eprintln!("Failed to write the trace to {}: {}", self.settings.output, e);
+                    self.output = TraceOutput::Log;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn entry(cycle: u64) -> TraceEntry {
+        TraceEntry {
+            cycle,
+            address: 0x200,
+            opcode: 0x00E0,
+            before: [0; REGISTER_COUNT],
+            after: [0; REGISTER_COUNT],
+            i: 0,
+            delay_timer: 0,
+            sound_timer: 0,
+        }
+    }
+
+    fn file_settings(path: &Path) -> TraceSettings {
+        TraceSettings { output: path.to_string_lossy().into_owned(), ..TraceSettings::default() }
+    }
+
+    fn lines(path: &Path) -> Vec<String> {
+        std::fs::read_to_string(path).unwrap().lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn off_without_enabled_or_a_ring() {
+        assert!(Tracer::new(&TraceSettings::default()).unwrap().is_none());
+        let ring_only = TraceSettings { ring_size: 4, ..TraceSettings::default() };
+        assert!(Tracer::new(&ring_only).unwrap().is_some());
+    }
+
+    #[test]
+    fn filters_by_address_range_and_class() {
+        let settings = TraceSettings {
+            enabled: true,
+            address_range: [0x300, 0x3FF],
+            classes: vec!["display".to_string(), "8xy4".to_string()],
+            ..TraceSettings::default()
+        };
+        let tracer = Tracer::new(&settings).unwrap().unwrap();
+        let draw = Opcode::Draw { reg_x: 0, reg_y: 1, height: 5 };
+        let add = Opcode::AddRegisters { reg_x: 0, reg_y: 1 };
+        let load = Opcode::SetRegister { register: 0, value: 1 };
+
+        assert!(tracer.wants(0x300, &draw));
+        assert!(tracer.wants(0x3FF, &add), "the range is inclusive");
+        assert!(!tracer.wants(0x300, &load), "neither class");
+        assert!(!tracer.wants(0x2FE, &draw), "before the range");
+        assert!(!tracer.wants(0x400, &draw), "after the range");
+
+        let everything = Tracer::new(&TraceSettings { enabled: true, ..TraceSettings::default() }).unwrap().unwrap();
+        assert!(everything.wants(0x200, &load));
+    }
+
+    #[test]
+    fn entries_show_only_the_registers_that_changed() {
+        let mut add = TraceEntry {
+            cycle: 42,
+            address: 0x204,
+            opcode: 0x8014,
+            i: 0x123,
+            delay_timer: 3,
+            sound_timer: 0,
+            ..entry(0)
+        };
+        add.before[0] = 0xFF;
+        add.before[1] = 0x02;
+        add.after = add.before;
+        add.after[0] = 0x01;
+        add.after[0xF] = 0x01;
+        assert_eq!(
+            add.to_string(),
+            "        42 0x204  8014  ADD V0, V1         I=123 DT=03 ST=00 V0:FF->01 VF:00->01"
+        );
+
+        let unchanged = TraceEntry { opcode: 0xFFFF, ..entry(7) };
+        assert_eq!(unchanged.to_string(), "         7 0x200  FFFF                     I=000 DT=00 ST=00");
+    }
+
+    #[test]
+    fn records_every_entry_to_the_output_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.txt");
+        let mut tracer = Tracer::new(&TraceSettings { enabled: true, ..file_settings(&path) }).unwrap().unwrap();
+        tracer.record(entry(1));
+        tracer.record(entry(2));
+        drop(tracer);
+
+        assert_eq!(lines(&path), [entry(1).to_string(), entry(2).to_string()]);
+    }
+
+    #[test]
+    fn ring_keeps_the_last_entries_until_a_fault() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.txt");
+        let mut tracer = Tracer::new(&TraceSettings { ring_size: 3, ..file_settings(&path) }).unwrap().unwrap();
+        for cycle in 1..=5 {
+            tracer.record(entry(cycle));
+        }
+        tracer.flush();
+        assert!(lines(&path).is_empty(), "nothing is written before a fault");
+
+        let fault = CpuFault::StackUnderflow { address: 0x206 };
+        tracer.dump(&fault);
+        let expected = [
+            "Last 3 instructions before the fault:".to_string(),
+            entry(3).to_string(),
+            entry(4).to_string(),
+            entry(5).to_string(),
+            format!("CPU fault: {}", fault),
+        ];
+        assert_eq!(lines(&path), expected);
+
+        // The dump empties the ring
+        tracer.dump(&fault);
+        assert_eq!(lines(&path).len(), expected.len() + 1);
+    }
+}
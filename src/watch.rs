@@ -0,0 +1,606 @@
+//! Watchpoints and breakpoint conditions.
+//!
+//! Memory watches fire when an instruction reads or writes an address in their
+//! range. Register watches fire when a write changes a register and, if they
+//! have a condition, the condition goes from false to true, so `vf == 1` means
+//! "when VF becomes 1" and `!(i in 0x200..0x400)` means "when I leaves
+//! 0x200..0x400". The CPU checks them in `read_memory`, `write_memory`,
+//! `set_register`, `set_address_register`, the stack and timer setters and the
+//! 60 Hz timer tick, and checks `pc` once each instruction has run. It keeps
+//! the first hit of each instruction for the debugger to collect.
+//!
+//! Conditions are small expressions over the registers (`v0`-`vf`, `i`, `pc`,
+//! `sp`, `dt`, `st`) and memory (`[address]` is the byte there), with decimal
+//! or `0x` numbers and, loosest binding first: `||`, `&&`, comparisons and
+//! `x in a..b`, `|`, `^`, `&`, `+ -`, and the prefixes `!` and `-`.
+
+use crate::cpu::CPU;
+use std::cell::Cell;
+use std::fmt;
+
+/// A register a watch or condition can name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    V(u8),
+    I,
+    Pc,
+    Sp,
+    Dt,
+    St,
+}
+
+impl Register {
+    pub fn parse(name: &str) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+        Some(match name.as_str() {
+            "i" => Register::I,
+            "pc" => Register::Pc,
+            "sp" => Register::Sp,
+            "dt" => Register::Dt,
+            "st" => Register::St,
+            _ => {
+                let index = name.strip_prefix('v')?;
+                if index.len() != 1 {
+                    return None;
+                }
+                Register::V(u8::from_str_radix(index, 16).ok()?)
+            }
+        })
+    }
+
+    pub fn read(self, cpu: &CPU) -> u16 {
+        match self {
+            Register::V(index) => cpu.get_register(index) as u16,
+            Register::I => cpu.get_address_register(),
+            Register::Pc => cpu.get_program_counter(),
+            Register::Sp => cpu.stack_depth() as u16,
+            Register::Dt => cpu.get_delay_timer() as u16,
+            Register::St => cpu.get_sound_timer() as u16,
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Register::V(index) => write!(f, "v{:x}", index),
+            Register::I => f.write_str("i"),
+            Register::Pc => f.write_str("pc"),
+            Register::Sp => f.write_str("sp"),
+            Register::Dt => f.write_str("dt"),
+            Register::St => f.write_str("st"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Any,
+}
+
+impl Access {
+    fn covers(self, access: Access) -> bool {
+        self == Access::Any || self == access
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Any => "access",
+        })
+    }
+}
+
+/// A watch on the addresses `start..end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryWatch {
+    pub start: u16,
+    pub end: u16,
+    pub access: Access,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisterWatch {
+    pub register: Register,
+    pub condition: Option<Expr>,
+}
+
+/// What stopped the program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchHit {
+    Memory { address: u16, access: Access, watch: MemoryWatch, old: u8, new: u8 },
+    Register { register: Register, old: u16, new: u16 },
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            WatchHit::Memory { address, access: Access::Read, new, .. } => {
+                write!(f, "read of {:#05X} (value {:#04X})", address, new)
+            }
+            WatchHit::Memory { address, old, new, .. } => {
+                write!(f, "write to {:#05X} ({:#04X} -> {:#04X})", address, old, new)
+            }
+            WatchHit::Register { register, old, new } => {
+                write!(f, "{} changed ({:#X} -> {:#X})", register, old, new)
+            }
+        }
+    }
+}
+
+/// The watches set on a CPU
+#[derive(Default)]
+pub struct Watches {
+    memory: Vec<MemoryWatch>,
+    registers: Vec<RegisterWatch>,
+    hit: Cell<Option<WatchHit>>, // The first hit since the debugger last looked
+}
+
+impl Watches {
+    pub fn add_memory(&mut self, watch: MemoryWatch) {
+        if !self.memory.contains(&watch) {
+            self.memory.push(watch);
+        }
+    }
+
+    /// Remove a memory watch, returning whether it was set
+    pub fn remove_memory(&mut self, watch: MemoryWatch) -> bool {
+        let count = self.memory.len();
+        self.memory.retain(|existing| *existing != watch);
+        self.memory.len() != count
+    }
+
+    pub fn add_register(&mut self, watch: RegisterWatch) {
+        self.registers.push(watch);
+    }
+
+    pub fn clear(&mut self) {
+        self.memory.clear();
+        self.registers.clear();
+        self.hit.set(None);
+    }
+
+    pub fn memory_watches(&self) -> &[MemoryWatch] {
+        &self.memory
+    }
+
+    pub fn register_watches(&self) -> &[RegisterWatch] {
+        &self.registers
+    }
+
+    pub fn take_hit(&self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+
+    fn record(&self, hit: WatchHit) {
+        if self.hit.get().is_none() {
+            self.hit.set(Some(hit));
+        }
+    }
+
+    pub fn check_memory(&self, address: u16, access: Access, old: u8, new: u8) {
+        if self.memory.is_empty() {
+            return;
+        }
+        let watch = self
+            .memory
+            .iter()
+            .find(|watch| watch.access.covers(access) && (watch.start..watch.end).contains(&address));
+        if let Some(&watch) = watch {
+            self.record(WatchHit::Memory { address, access, watch, old, new });
+        }
+    }
+
+    /// Check the register watches after `register` changed from `old`; `cpu`
+    /// already holds the new value
+    pub fn check_register(&self, register: Register, old: u16, cpu: &CPU) {
+        if self.registers.is_empty() {
+            return;
+        }
+        let new = register.read(cpu);
+        if old == new {
+            return;
+        }
+        let before = State { cpu, replaced: Some((register, old)) };
+        let after = State { cpu, replaced: None };
+        let fired = self.registers.iter().any(|watch| {
+            watch.register == register
+                && watch.condition.as_ref().is_none_or(|condition| condition.holds(&after) && !condition.holds(&before))
+        });
+        if fired {
+            self.record(WatchHit::Register { register, old, new });
+        }
+    }
+}
+
+/// The machine as a condition sees it, optionally with one register showing
+/// the value it had before a write
+struct State<'a> {
+    cpu: &'a CPU,
+    replaced: Option<(Register, u16)>,
+}
+
+impl State<'_> {
+    fn register(&self, register: Register) -> u16 {
+        match self.replaced {
+            Some((replaced, value)) if replaced == register => value,
+            _ => register.read(self.cpu),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Add,
+    Subtract,
+}
+
+/// A parsed condition
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(i64),
+    Register(Register),
+    Memory(Box<Expr>),
+    Not(Box<Expr>),
+    Negate(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    /// `value in start..end`
+    InRange(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens: &tokens, position: 0 };
+        let expr = parser.or()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected '{}'", token)),
+        }
+    }
+
+    /// Whether the condition holds for the CPU's current state
+    pub fn evaluate(&self, cpu: &CPU) -> bool {
+        self.holds(&State { cpu, replaced: None })
+    }
+
+    fn holds(&self, state: &State) -> bool {
+        self.value(state) != 0
+    }
+
+    fn value(&self, state: &State) -> i64 {
+        match self {
+            Expr::Number(value) => *value,
+            Expr::Register(register) => state.register(*register) as i64,
+            Expr::Memory(address) => match u16::try_from(address.value(state)) {
+                Ok(address) => state.cpu.peek_memory(address).unwrap_or(0) as i64,
+                Err(_) => 0,
+            },
+            Expr::Not(operand) => (!operand.holds(state)) as i64,
+            Expr::Negate(operand) => operand.value(state).wrapping_neg(),
+            Expr::InRange(value, start, end) => {
+                (start.value(state)..end.value(state)).contains(&value.value(state)) as i64
+            }
+            Expr::Binary(op, left, right) => {
+                let left = left.value(state);
+                // Short-circuit so `[i] == 0 || ...` style conditions stay cheap
+                match op {
+                    BinaryOp::Or if left != 0 => return 1,
+                    BinaryOp::And if left == 0 => return 0,
+                    _ => {}
+                }
+                let right = right.value(state);
+                match op {
+                    BinaryOp::Or | BinaryOp::And => (right != 0) as i64,
+                    BinaryOp::Equal => (left == right) as i64,
+                    BinaryOp::NotEqual => (left != right) as i64,
+                    BinaryOp::Less => (left < right) as i64,
+                    BinaryOp::LessEqual => (left <= right) as i64,
+                    BinaryOp::Greater => (left > right) as i64,
+                    BinaryOp::GreaterEqual => (left >= right) as i64,
+                    BinaryOp::BitOr => left | right,
+                    BinaryOp::BitXor => left ^ right,
+                    BinaryOp::BitAnd => left & right,
+                    BinaryOp::Add => left.wrapping_add(right),
+                    BinaryOp::Subtract => left.wrapping_sub(right),
+                }
+            }
+        }
+    }
+}
+
+impl BinaryOp {
+    fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Or => "||",
+            BinaryOp::And => "&&",
+            BinaryOp::Equal => "==",
+            BinaryOp::NotEqual => "!=",
+            BinaryOp::Less => "<",
+            BinaryOp::LessEqual => "<=",
+            BinaryOp::Greater => ">",
+            BinaryOp::GreaterEqual => ">=",
+            BinaryOp::BitOr => "|",
+            BinaryOp::BitXor => "^",
+            BinaryOp::BitAnd => "&",
+            BinaryOp::Add => "+",
+            BinaryOp::Subtract => "-",
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    /// Nested operations are parenthesized, so the text parses back to the same expression
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let operand = |f: &mut fmt::Formatter<'_>, expr: &Expr| match expr {
+            Expr::Binary(..) | Expr::InRange(..) => write!(f, "({})", expr),
+            _ => write!(f, "{}", expr),
+        };
+        match self {
+            Expr::Number(value) if *value > 9 => write!(f, "{:#X}", value),
+            Expr::Number(value) => write!(f, "{}", value),
+            Expr::Register(register) => write!(f, "{}", register),
+            Expr::Memory(address) => write!(f, "[{}]", address),
+            Expr::Not(inner) => {
+                f.write_str("!")?;
+                operand(f, inner)
+            }
+            Expr::Negate(inner) => {
+                f.write_str("-")?;
+                operand(f, inner)
+            }
+            Expr::InRange(value, start, end) => {
+                operand(f, value)?;
+                f.write_str(" in ")?;
+                operand(f, start)?;
+                f.write_str("..")?;
+                operand(f, end)
+            }
+            Expr::Binary(op, left, right) => {
+                operand(f, left)?;
+                write!(f, " {} ", op.symbol())?;
+                operand(f, right)
+            }
+        }
+    }
+}
+
+const OPERATORS: [&str; 20] = [
+    "||", "&&", "==", "!=", "<=", ">=", "..", "<", ">", "|", "^", "&", "+", "-", "!", "(", ")", "[", "]", "in",
+];
+
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let word_length = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+        let length = if word_length > 0 {
+            word_length
+        } else {
+            OPERATORS
+                .iter()
+                .find(|operator| rest.starts_with(**operator))
+                .map(|operator| operator.len())
+                .ok_or_else(|| format!("unexpected '{}'", rest.chars().next().unwrap_or_default()))?
+        };
+        tokens.push(rest[..length].to_string());
+        rest = rest[length..].trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [String],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Result<&str, String> {
+        let token = self.tokens.get(self.position).ok_or("unexpected end of condition")?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => Err(format!("expected '{}' but found '{}'", expected, token)),
+        }
+    }
+
+    /// Parse one precedence level of left-associative binary operators
+    fn binary(
+        &mut self,
+        operators: &[(&str, BinaryOp)],
+        operand: fn(&mut Self) -> Result<Expr, String>,
+    ) -> Result<Expr, String> {
+        let mut left = operand(self)?;
+        while let Some(&(_, op)) = operators.iter().find(|(token, _)| self.peek() == Some(*token)) {
+            self.position += 1;
+            left = Expr::Binary(op, Box::new(left), Box::new(operand(self)?));
+        }
+        Ok(left)
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        self.binary(&[("||", BinaryOp::Or)], Self::and)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        self.binary(&[("&&", BinaryOp::And)], Self::comparison)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let left = self.bit_or()?;
+        if self.peek() == Some("in") {
+            self.position += 1;
+            let start = self.bit_or()?;
+            self.expect("..")?;
+            let end = self.bit_or()?;
+            return Ok(Expr::InRange(Box::new(left), Box::new(start), Box::new(end)));
+        }
+        let operators = [
+            ("==", BinaryOp::Equal),
+            ("!=", BinaryOp::NotEqual),
+            ("<", BinaryOp::Less),
+            ("<=", BinaryOp::LessEqual),
+            (">", BinaryOp::Greater),
+            (">=", BinaryOp::GreaterEqual),
+        ];
+        match operators.iter().find(|(token, _)| self.peek() == Some(*token)) {
+            Some(&(_, op)) => {
+                self.position += 1;
+                Ok(Expr::Binary(op, Box::new(left), Box::new(self.bit_or()?)))
+            }
+            None => Ok(left),
+        }
+    }
+
+    fn bit_or(&mut self) -> Result<Expr, String> {
+        self.binary(&[("|", BinaryOp::BitOr)], Self::bit_xor)
+    }
+
+    fn bit_xor(&mut self) -> Result<Expr, String> {
+        self.binary(&[("^", BinaryOp::BitXor)], Self::bit_and)
+    }
+
+    fn bit_and(&mut self) -> Result<Expr, String> {
+        self.binary(&[("&", BinaryOp::BitAnd)], Self::sum)
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        self.binary(&[("+", BinaryOp::Add), ("-", BinaryOp::Subtract)], Self::unary)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some("!") => {
+                self.position += 1;
+                Ok(Expr::Not(Box::new(self.unary()?)))
+            }
+            Some("-") => {
+                self.position += 1;
+                Ok(Expr::Negate(Box::new(self.unary()?)))
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let token = self.next()?.to_string();
+        match token.as_str() {
+            "(" => {
+                let expr = self.or()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            "[" => {
+                let address = self.or()?;
+                self.expect("]")?;
+                Ok(Expr::Memory(Box::new(address)))
+            }
+            _ => {
+                if let Some(register) = Register::parse(&token) {
+                    return Ok(Expr::Register(register));
+                }
+                parse_number(&token)
+                    .map(Expr::Number)
+                    .ok_or_else(|| format!("'{}' is not a register or number", token))
+            }
+        }
+    }
+}
+
+/// A decimal or `0x` hexadecimal number
+pub fn parse_number(text: &str) -> Option<i64> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::SilentBackend;
+    use crate::settings::Settings;
+
+    fn cpu_with(program: &[u8], watch: &str) -> CPU {
+        let mut cpu = CPU::with_audio_backend(Settings::default(), Box::new(SilentBackend));
+        cpu.load_program(program);
+        let (register, condition) = match watch.split_once(" if ") {
+            Some((register, condition)) => (register, Some(Expr::parse(condition).unwrap())),
+            None => (watch, None),
+        };
+        let register = Register::parse(register).unwrap();
+        cpu.watches_mut().add_register(RegisterWatch { register, condition });
+        cpu
+    }
+
+    /// Run instructions until a watch fires, returning the hit and how many ran
+    fn run_to_hit(cpu: &mut CPU, limit: usize) -> Option<(WatchHit, usize)> {
+        (1..=limit).find_map(|count| {
+            cpu.tick().unwrap();
+            cpu.watches().take_hit().map(|hit| (hit, count))
+        })
+    }
+
+    #[test]
+    fn pc_watch_fires_when_its_condition_becomes_true() {
+        // 0x200: LD V0, 1   0x202: JP 0x206   0x204: CLS   0x206: JP 0x206
+        let mut cpu = cpu_with(&[0x60, 0x01, 0x12, 0x06, 0x00, 0xE0, 0x12, 0x06], "pc if pc == 0x206");
+        let (hit, count) = run_to_hit(&mut cpu, 4).expect("no hit");
+        assert_eq!(count, 2);
+        assert_eq!(hit, WatchHit::Register { register: Register::Pc, old: 0x202, new: 0x206 });
+        // Jumping to itself doesn't change the program counter
+        assert_eq!(run_to_hit(&mut cpu, 4), None);
+    }
+
+    #[test]
+    fn sp_watch_fires_on_call_and_return() {
+        // 0x200: CALL 0x204   0x202: JP 0x202   0x204: RET
+        let mut cpu = cpu_with(&[0x22, 0x04, 0x12, 0x02, 0x00, 0xEE], "sp");
+        let (hit, _) = run_to_hit(&mut cpu, 1).expect("no hit on call");
+        assert_eq!(hit, WatchHit::Register { register: Register::Sp, old: 0, new: 1 });
+        let (hit, _) = run_to_hit(&mut cpu, 1).expect("no hit on return");
+        assert_eq!(hit, WatchHit::Register { register: Register::Sp, old: 1, new: 0 });
+    }
+
+    #[test]
+    fn timer_watches_fire_on_writes_and_ticks() {
+        // 0x200: LD V0, 2   0x202: LD DT, V0   0x204: LD ST, V0   0x206: JP 0x206
+        let program = [0x60, 0x02, 0xF0, 0x15, 0xF0, 0x18, 0x12, 0x06];
+        let mut cpu = cpu_with(&program, "dt");
+        let (hit, count) = run_to_hit(&mut cpu, 3).expect("no hit on write");
+        assert_eq!((hit, count), (WatchHit::Register { register: Register::Dt, old: 0, new: 2 }, 2));
+        cpu.update_timers();
+        assert_eq!(cpu.watches().take_hit(), Some(WatchHit::Register { register: Register::Dt, old: 2, new: 1 }));
+
+        let mut cpu = cpu_with(&program, "st if st == 0");
+        run_to_hit(&mut cpu, 3);
+        cpu.update_timers();
+        assert_eq!(cpu.watches().take_hit(), None);
+        cpu.update_timers();
+        assert_eq!(cpu.watches().take_hit(), Some(WatchHit::Register { register: Register::St, old: 1, new: 0 }));
+    }
+}
@@ -0,0 +1,233 @@
+//! Writes the beeper to a WAV file alongside GIF recordings and instant replays.
+//!
+//! Audio is synthesized from emulated time rather than captured from the sound
+//! device, so the result is deterministic, works without any audio hardware and
+//! lines up sample-for-sample with the GIF frame timestamps. The same
+//! `ToneGenerator` as live playback is used, so recordings sound identical.
+//! Only the sound timer beep is produced; this core has no XO-CHIP pattern
+//! audio to capture.
+
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::thread;
+use std::time::Duration;
+use crossbeam_channel::{Sender, Receiver, bounded, unbounded};
+use crate::audio::{ToneGenerator, SAMPLE_RATE};
+use crate::settings::{AudioSettings, Shared};
+
+// WAV format constants that don't change
+const BITS_PER_SAMPLE: u16 = 16;             // Signed 16-bit PCM
+const CHANNELS: u16 = 1;                     // Mono
+const HEADER_SIZE: u32 = 44;                 // Size of a canonical PCM WAV header
+
+pub struct WavRecorder {
+    sender: Option<Sender<AudioCommand>>,
+    thread_handle: Option<thread::JoinHandle<()>>,
+    settings: Shared<AudioSettings>,
+}
+
+enum AudioCommand {
+    /// The beeper was `sound_on` from the previous frame until `until` of emulated time
+    Frame { until: Duration, sound_on: bool },
+    Stop,
+}
+
+impl WavRecorder {
+    pub fn with_settings(settings: Shared<AudioSettings>) -> Self {
+        Self {
+            sender: None,
+            thread_handle: None,
+            settings,
+        }
+    }
+
+    /// Start writing to `filename`; emulated time `start` maps to the first sample
+    pub fn start_recording(&mut self, filename: &str, start: Duration) -> Result<(), Box<dyn std::error::Error>> {
+        if self.is_recording() {
+            return Err("Already recording audio".into());
+        }
+
+        let (sender, receiver) = unbounded();
+        self.sender = Some(sender);
+
+        let filename = filename.to_string();
+        // A recording keeps the tone it started with, even across a config reload
+        let settings = self.settings.read().unwrap().clone();
+        self.thread_handle = Some(thread::spawn(move || {
+            if let Err(e) = recording_thread(receiver, filename, settings, start) {
+                eprintln!("Audio recording thread error: {}", e);
+            }
+        }));
+        Ok(())
+    }
+
+    /// Record the beeper state for the emulated frame ending at `until`
+    pub fn add_frame(&mut self, until: Duration, sound_on: bool) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(ref sender) = self.sender {
+            sender.send(AudioCommand::Frame { until, sound_on })?;
+        }
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(sender) = self.sender.take() {
+            sender.send(AudioCommand::Stop)?;
+
+            if let Some(handle) = self.thread_handle.take() {
+                handle.join().map_err(|_| "Audio recording thread panicked")?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.sender.is_some()
+    }
+
+    /// Write already captured frames (e.g. an instant replay), each the emulated
+    /// time it ended and the beeper state, starting at the first frame. A few
+    /// seconds of audio take milliseconds to synthesize, so this runs on the
+    /// caller's thread.
+    pub fn save_frames(&self, filename: &str, frames: &[(Duration, bool)]) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(&(start, _)) = frames.first() else {
+            return Err("No frames to save".into());
+        };
+        let (sender, receiver) = bounded(frames.len() + 1);
+        for &(until, sound_on) in frames {
+            sender.send(AudioCommand::Frame { until, sound_on })?;
+        }
+        sender.send(AudioCommand::Stop)?;
+        let settings = self.settings.read().unwrap().clone();
+        recording_thread(receiver, filename.to_string(), settings, start)
+    }
+}
+
+impl Drop for WavRecorder {
+    fn drop(&mut self) {
+        if self.is_recording() {
+            let _ = self.stop_recording();
+        }
+    }
+}
+
+fn recording_thread(receiver: Receiver<AudioCommand>, filename: String, settings: AudioSettings, start: Duration) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = BufWriter::new(File::create(&filename)?);
+    write_header(&mut writer, 0)?; // Sizes are patched once the length is known
+
+    let mut generator = ToneGenerator::new(&settings, SAMPLE_RATE);
+    let mut samples_written: u64 = 0;
+
+    while let Ok(AudioCommand::Frame { until, sound_on }) = receiver.recv() {
+        // Derive the sample count from absolute time so rounding never accumulates
+        let elapsed = until.saturating_sub(start);
+        let target = (elapsed.as_secs_f64() * SAMPLE_RATE as f64).round() as u64;
+
+        while samples_written < target {
+            let sample = (generator.next_sample(sound_on) * i16::MAX as f32) as i16;
+            writer.write_all(&sample.to_le_bytes())?;
+            samples_written += 1;
+        }
+    }
+
+    let data_size = (samples_written * (BITS_PER_SAMPLE / 8) as u64) as u32;
+    writer.seek(SeekFrom::Start(0))?;
+    write_header(&mut writer, data_size)?;
+    writer.flush()?;
+
+    println!("WAV saved: {}", filename);
+    Ok(())
+}
+
+fn write_header<W: Write>(writer: &mut W, data_size: u32) -> std::io::Result<()> {
+    let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+    let byte_rate = SAMPLE_RATE * block_align as u32;
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?; // fmt chunk size
+    writer.write_all(&1u16.to_le_bytes())?;  // PCM
+    writer.write_all(&CHANNELS.to_le_bytes())?;
+    writer.write_all(&SAMPLE_RATE.to_le_bytes())?;
+    writer.write_all(&byte_rate.to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+    writer.write_all(b"data")?;
+    writer.write_all(&data_size.to_le_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants;
+    use crate::settings;
+
+    const FPS: u32 = 60;
+    const SAMPLES_PER_FRAME: usize = SAMPLE_RATE as usize / FPS as usize;
+
+    fn field<const N: usize>(wav: &[u8], offset: usize) -> [u8; N] {
+        wav[offset..offset + N].try_into().unwrap()
+    }
+
+    /// Save the beeper states in `sound`, one per frame after a start at one
+    /// second, and return the file's contents
+    fn save(sound: &[bool]) -> Vec<u8> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.wav");
+        let start = Duration::from_secs(1);
+        let frame = constants::frame_duration(FPS);
+        let frames: Vec<(Duration, bool)> = std::iter::once((start, false))
+            .chain(sound.iter().enumerate().map(|(index, &on)| (start + frame * (index as u32 + 1), on)))
+            .collect();
+        let recorder = WavRecorder::with_settings(settings::shared(AudioSettings::default()));
+        recorder.save_frames(&path.to_string_lossy(), &frames).unwrap();
+        std::fs::read(path).unwrap()
+    }
+
+    fn samples(wav: &[u8]) -> Vec<i16> {
+        wav[HEADER_SIZE as usize..].chunks_exact(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])).collect()
+    }
+
+    #[test]
+    fn header_describes_16_bit_mono_pcm() {
+        let wav = save(&[false; 60]);
+        let data_size = SAMPLE_RATE * 2; // One second of 16-bit samples
+        assert_eq!(wav.len(), (HEADER_SIZE + data_size) as usize);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(field(&wav, 4)), HEADER_SIZE - 8 + data_size);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u32::from_le_bytes(field(&wav, 16)), 16);
+        assert_eq!(u16::from_le_bytes(field(&wav, 20)), 1); // PCM
+        assert_eq!(u16::from_le_bytes(field(&wav, 22)), CHANNELS);
+        assert_eq!(u32::from_le_bytes(field(&wav, 24)), SAMPLE_RATE);
+        assert_eq!(u32::from_le_bytes(field(&wav, 28)), SAMPLE_RATE * 2); // Byte rate
+        assert_eq!(u16::from_le_bytes(field(&wav, 32)), 2); // Block align
+        assert_eq!(u16::from_le_bytes(field(&wav, 34)), BITS_PER_SAMPLE);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(u32::from_le_bytes(field(&wav, 40)), data_size);
+    }
+
+    #[test]
+    fn each_frame_adds_its_share_of_samples() {
+        for frames in [0, 1, 2, 7, 60] {
+            let samples = samples(&save(&vec![false; frames]));
+            assert_eq!(samples.len(), frames * SAMPLES_PER_FRAME, "{} frames", frames);
+        }
+    }
+
+    #[test]
+    fn beeper_frames_are_audible() {
+        let samples = samples(&save(&[false, false, true, true]));
+        let (silent, beeping) = samples.split_at(2 * SAMPLES_PER_FRAME);
+        assert!(silent.iter().all(|&sample| sample == 0));
+        assert!(beeping.iter().any(|&sample| sample != 0));
+    }
+
+    #[test]
+    fn saving_no_frames_fails() {
+        let recorder = WavRecorder::with_settings(settings::shared(AudioSettings::default()));
+        assert!(recorder.save_frames("unused.wav", &[]).is_err());
+    }
+}
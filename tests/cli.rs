@@ -0,0 +1,76 @@
+//! The program run as a user would, for behaviour that depends on the process:
+//! what goes to stdout and stderr, and reading a ROM from stdin.
+
+use chip8::settings::Settings;
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// `--print-config` output is the merged settings as TOML and nothing else, so
+/// it can be redirected to a file
+
+#[test]
+fn print_config_output_parses_as_settings() {
+    let dir = tempfile::tempdir().unwrap();
+    // Where `config_layers::user_config_dir` looks with the variables set below
+    let user_base = if cfg!(target_os = "macos") { "Library/Application Support" } else { "user" };
+    let user_dir = dir.path().join(user_base).join("chip8");
+    fs::create_dir_all(&user_dir).unwrap();
+    fs::write(user_dir.join("config.toml"), "[audio]\nbeep_volume = 0.25\n").unwrap();
+    fs::write(dir.path().join("chip8_config.toml"), "[cpu]\ncycles_per_frame = 20\n").unwrap();
+    fs::write(dir.path().join("Maze.ch8"), [0x12, 0x00]).unwrap();
+    fs::write(dir.path().join("Maze.toml"), "[quirks]\nclip_sprites = false\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_Chip8"))
+        .args(["--print-config", "Maze.ch8", "--cpu.seed", "7"])
+        .current_dir(dir.path())
+        .env("HOME", dir.path())
+        .env("XDG_CONFIG_HOME", dir.path().join("user"))
+        .env("APPDATA", dir.path().join("user"))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let settings: Settings = toml::from_str(&stdout).unwrap_or_else(|e| panic!("{}\n{}", e, stdout));
+    assert_eq!(settings.audio.beep_volume, 0.25);
+    assert_eq!(settings.cpu.cycles_per_frame, 20);
+    assert!(!settings.quirks.clip_sprites);
+    assert_eq!(settings.cpu.seed, 7);
+    // The progress messages went to stderr instead
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Loading config from"));
+}
+
+#[test]
+fn rom_is_read_from_stdin() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_Chip8"))
+        .args(["info", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // CLS, then JP 0x200
+    child.stdin.take().unwrap().write_all(&[0x00, 0xE0, 0x12, 0x00]).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("ROM:          stdin"), "{}", stdout);
+    assert!(stdout.contains("Size:         4 bytes"), "{}", stdout);
+    assert!(stdout.contains("Instructions: 2 reachable"), "{}", stdout);
+}
+
+#[test]
+fn oversized_stdin_is_refused() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_Chip8"))
+        .args(["info", "-"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // The reader stops at the cap, so the pipe may close before all of this is written
+    let _ = child.stdin.take().unwrap().write_all(&vec![0; (1 << 20) + 1]);
+    let output = child.wait_with_output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("too large to be a ROM"));
+}
@@ -0,0 +1,221 @@
+//! Conformance tests against test ROMs.
+//!
+//! Every case in `tests/conformance/cases.toml` runs a ROM headlessly with
+//! scripted key presses, then compares the display with a golden image. The
+//! same ROM and input also run on the reference interpreter in
+//! `tests/reference`, which has to match the golden too, so a golden can't
+//! just record a bug in the emulator. On a mismatch the actual display and a
+//! diff are written under Cargo's test temporary directory: pixels lit only in
+//! the golden are red, pixels lit only in the run are green.
+//!
+//! `UPDATE_GOLDENS=1 cargo test --test conformance` writes the goldens from
+//! the reference interpreter, then checks the emulator against them.
+
+mod reference;
+
+use chip8::constants::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use chip8::display::DisplaySnapshot;
+use chip8::headless::Headless;
+use chip8::settings::{QuirkSettings, Settings};
+use chip8::{asm, octo};
+use image::{GrayImage, Luma, Rgb, RgbImage};
+use reference::Reference;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const CASES_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/conformance");
+const UPDATE_VARIABLE: &str = "UPDATE_GOLDENS";
+const DIFF_SCALE: u32 = 8;                  // Pixels per display pixel in diff images
+
+const LIT: Luma<u8> = Luma([255]);
+const DARK: Luma<u8> = Luma([0]);
+const BOTH_COLOR: Rgb<u8> = Rgb([255, 255, 255]);
+const GOLDEN_ONLY_COLOR: Rgb<u8> = Rgb([230, 40, 40]);
+const ACTUAL_ONLY_COLOR: Rgb<u8> = Rgb([40, 200, 40]);
+const NEITHER_COLOR: Rgb<u8> = Rgb([0, 0, 0]);
+
+#[derive(Deserialize)]
+struct Manifest {
+    case: Vec<Case>,
+}
+
+#[derive(Deserialize)]
+struct Case {
+    name: String,
+    rom: String,
+    frames: u32,
+    #[serde(default = "default_quirks")]
+    quirks: String,
+    #[serde(default = "default_seed")]
+    seed: u64,
+    cycles_per_frame: Option<u32>,
+    #[serde(default)]
+    keys: Vec<KeyPress>,
+}
+
+#[derive(Deserialize)]
+struct KeyPress {
+    key: u8,
+    frame: u32,
+    #[serde(default = "default_press_frames")]
+    frames: u32,
+}
+
+fn default_quirks() -> String {
+    "default".to_string()
+}
+
+fn default_seed() -> u64 {
+    1
+}
+
+fn default_press_frames() -> u32 {
+    1
+}
+
+enum Outcome {
+    Passed,
+    Updated,
+}
+
+#[test]
+fn conformance() {
+    let manifest_path = Path::new(CASES_DIR).join("cases.toml");
+    let text = fs::read_to_string(&manifest_path).expect("read cases.toml");
+    let manifest: Manifest = toml::from_str(&text).expect("parse cases.toml");
+    let update = std::env::var_os(UPDATE_VARIABLE).is_some();
+
+    let mut failures = Vec::new();
+    for case in &manifest.case {
+        match run_case(case, update) {
+            Ok(Outcome::Passed) => println!("{}: ok", case.name),
+            Ok(Outcome::Updated) => println!("{}: golden written", case.name),
+            Err(e) => {
+                println!("{}: FAILED, {}", case.name, e);
+                failures.push(format!("{}: {}", case.name, e));
+            }
+        }
+    }
+    assert!(failures.is_empty(), "{} conformance case(s) failed:\n{}", failures.len(), failures.join("\n"));
+}
+
+fn run_case(case: &Case, update: bool) -> Result<Outcome, String> {
+    let program = load_program(&Path::new(CASES_DIR).join(&case.rom))?;
+
+    let quirks = QuirkSettings::profile(&case.quirks).ok_or_else(|| format!("unknown quirk profile '{}'", case.quirks))?;
+    let mut settings = Settings { quirks, ..Settings::default() };
+    settings.cpu.seed = case.seed;
+    if let Some(cycles_per_frame) = case.cycles_per_frame {
+        settings.cpu.cycles_per_frame = cycles_per_frame;
+    }
+    let cycles_per_frame = settings.cpu.cycles_per_frame;
+
+    let mut headless = Headless::new(settings, &program);
+    let mut reference = Reference::new(quirks, case.seed, &program);
+    for frame in 0..case.frames {
+        for press in &case.keys {
+            if press.frame == frame {
+                headless.press_key(press.key);
+                reference.press_key(press.key);
+            }
+            if press.frame + press.frames == frame {
+                headless.release_key(press.key);
+                reference.release_key(press.key);
+            }
+        }
+        headless
+            .run_frame()
+            .map_err(|e| format!("CPU fault in frame {}: {}\n{}", frame, e, headless.screen_text()))?;
+        reference
+            .run_frame(cycles_per_frame)
+            .map_err(|e| format!("reference interpreter faulted in frame {}: {:?}", frame, e))?;
+    }
+    let actual = display_image(&headless.display_snapshot());
+    let expected = display_image(&reference.display());
+
+    let golden_path = Path::new(CASES_DIR).join("goldens").join(format!("{}.png", case.name));
+    if update {
+        expected.save(&golden_path).map_err(|e| format!("{}: {}", golden_path.display(), e))?;
+    }
+    let golden = image::open(&golden_path)
+        .map_err(|e| format!("{}: {} (run with {}=1 to create it)", golden_path.display(), e, UPDATE_VARIABLE))?
+        .into_luma8();
+    if golden.dimensions() != actual.dimensions() {
+        return Err(format!("{} is {:?}, not the display size", golden_path.display(), golden.dimensions()));
+    }
+    if count_differences(&expected, &golden) != 0 {
+        return Err(format!(
+            "{} doesn't match the reference interpreter; run with {}=1 to regenerate it",
+            golden_path.display(),
+            UPDATE_VARIABLE
+        ));
+    }
+
+    let differences = count_differences(&actual, &golden);
+    if differences == 0 {
+        return Ok(if update { Outcome::Updated } else { Outcome::Passed });
+    }
+    let (actual_path, diff_path) = write_mismatch(&case.name, &actual, &golden)?;
+    Err(format!(
+        "{} pixels differ from {}; the display is in {} and the diff in {}",
+        differences,
+        golden_path.display(),
+        actual_path.display(),
+        diff_path.display()
+    ))
+}
+
+fn load_program(path: &Path) -> Result<Vec<u8>, String> {
+    let fail = |e: String| format!("{}: {}", path.display(), e);
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    match extension {
+        "asm" => {
+            let source = fs::read_to_string(path).map_err(|e| fail(e.to_string()))?;
+            asm::assemble(&source).map_err(|e| fail(e.to_string()))
+        }
+        "8o" => {
+            let source = fs::read_to_string(path).map_err(|e| fail(e.to_string()))?;
+            octo::compile(&source).map_err(|e| fail(e.to_string()))
+        }
+        _ => fs::read(path).map_err(|e| fail(e.to_string())),
+    }
+}
+
+fn display_image(snapshot: &DisplaySnapshot) -> GrayImage {
+    GrayImage::from_fn(DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32, |x, y| {
+        let lit = (snapshot[y as usize] >> (DISPLAY_WIDTH as u32 - 1 - x)) & 1 == 1;
+        if lit { LIT } else { DARK }
+    })
+}
+
+fn is_lit(pixel: &Luma<u8>) -> bool {
+    pixel.0[0] >= 128
+}
+
+fn count_differences(a: &GrayImage, b: &GrayImage) -> usize {
+    a.pixels().zip(b.pixels()).filter(|(a, b)| is_lit(a) != is_lit(b)).count()
+}
+
+/// Write the actual display and a scaled-up diff against the golden
+fn write_mismatch(name: &str, actual: &GrayImage, golden: &GrayImage) -> Result<(PathBuf, PathBuf), String> {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("conformance");
+    fs::create_dir_all(&dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+
+    let (width, height) = actual.dimensions();
+    let diff = RgbImage::from_fn(width * DIFF_SCALE, height * DIFF_SCALE, |x, y| {
+        let (x, y) = (x / DIFF_SCALE, y / DIFF_SCALE);
+        match (is_lit(actual.get_pixel(x, y)), is_lit(golden.get_pixel(x, y))) {
+            (true, true) => BOTH_COLOR,
+            (false, true) => GOLDEN_ONLY_COLOR,
+            (true, false) => ACTUAL_ONLY_COLOR,
+            (false, false) => NEITHER_COLOR,
+        }
+    });
+
+    let actual_path = dir.join(format!("{}.actual.png", name));
+    let diff_path = dir.join(format!("{}.diff.png", name));
+    actual.save(&actual_path).map_err(|e| format!("{}: {}", actual_path.display(), e))?;
+    diff.save(&diff_path).map_err(|e| format!("{}: {}", diff_path.display(), e))?;
+    Ok((actual_path, diff_path))
+}
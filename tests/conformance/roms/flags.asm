@@ -0,0 +1,125 @@
+; Flag behaviour of the 8XYN arithmetic instructions.
+;
+; Each row shows the result in hex and then VF, in columns of five rows:
+;
+;   ADD   0x10 + 0x20       30 0        SUBN  0x30 - 0x10   20 1
+;   ADD   0xFF + 0x02       01 1        SHR   0x03          01 1
+;   SUB   0x30 - 0x10       20 1        SHL   0x81          02 1
+;   SUB   0x10 - 0x30       E0 0        ...
+;
+; The last four rows use VF itself as the destination, where the flag has to
+; win over the result: ADD VF (01 1), SUB VF (00 0), SHR VF (00 0) and
+; SHL VF (01 1).
+
+    LD VA, 0                ; Cursor x
+    LD VB, 0                ; Cursor y
+    LD VC, 0                ; Left edge of the current column
+
+    LD V2, 0x10
+    LD V3, 0x20
+    ADD V2, V3
+    LD V6, VF
+    CALL row
+
+    LD V2, 0xFF
+    LD V3, 0x02
+    ADD V2, V3
+    LD V6, VF
+    CALL row
+
+    LD V2, 0x30
+    LD V3, 0x10
+    SUB V2, V3
+    LD V6, VF
+    CALL row
+
+    LD V2, 0x10
+    LD V3, 0x30
+    SUB V2, V3
+    LD V6, VF
+    CALL row
+
+    LD V2, 0x10
+    LD V3, 0x30
+    SUBN V2, V3
+    LD V6, VF
+    CALL row
+
+    LD V2, 0x03             ; VY holds the same value, so both shift quirks agree
+    LD V3, 0x03
+    SHR V2, V3
+    LD V6, VF
+    CALL row
+
+    LD V2, 0x81
+    LD V3, 0x81
+    SHL V2, V3
+    LD V6, VF
+    CALL row
+
+    LD VF, 0xFF
+    LD V3, 0x02
+    ADD VF, V3
+    LD V2, VF
+    LD V6, VF
+    CALL row
+
+    LD VF, 0x10
+    LD V3, 0x30
+    SUB VF, V3
+    LD V2, VF
+    LD V6, VF
+    CALL row
+
+    LD VF, 0x06             ; VY holds the same value, so both shift quirks agree
+    LD V3, 0x06
+    SHR VF, V3
+    LD V2, VF
+    LD V6, VF
+    CALL row
+
+    LD VF, 0x81
+    LD V3, 0x81
+    SHL VF, V3
+    LD V2, VF
+    LD V6, VF
+    CALL row
+
+done:
+    JP done
+
+; Draw V2 as a byte and V6 as a digit, then move to the next row
+row:
+    LD V4, V2
+    CALL byte
+    ADD VA, 2
+    LD V4, V6
+    CALL digit
+    LD VA, VC
+    ADD VB, 6
+    SE VB, 30
+    RET
+    LD VB, 0
+    ADD VC, 21
+    LD VA, VC
+    RET
+
+; Draw V4 as two hex digits at the cursor
+byte:
+    LD V5, V4
+    SHR V5, V5
+    SHR V5, V5
+    SHR V5, V5
+    SHR V5, V5
+    LD F, V5
+    DRW VA, VB, 5
+    ADD VA, 5
+
+; Draw the low digit of V4 at the cursor
+digit:
+    LD V5, 0x0F
+    AND V5, V4
+    LD F, V5
+    DRW VA, VB, 5
+    ADD VA, 5
+    RET
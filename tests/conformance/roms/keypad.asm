@@ -0,0 +1,34 @@
+; Keypad input. Each key press is shown as a digit once the key is released,
+; as FX0A waits for the release. While key E is held, a bar is shown in the
+; bottom right corner by a SKP/SKNP pair.
+
+    LD VA, 0                ; Cursor x
+    LD VB, 0                ; Cursor y
+    LD VE, 0x0E
+
+loop:
+    LD V4, K
+    LD F, V4
+    DRW VA, VB, 5
+    ADD VA, 5
+    SE V4, 0x0E
+    JP loop
+
+; After E, watch it being held and released
+held:
+    LD I, bar
+    LD V0, 56
+    LD V1, 30
+    SKNP VE
+    JP pressed
+    JP held
+pressed:
+    DRW V0, V1, 1
+wait_release:
+    SKNP VE
+    JP wait_release
+    DRW V0, V1, 1           ; Erase the bar
+    JP held
+
+bar:
+    DB 0xFF
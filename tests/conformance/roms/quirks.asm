@@ -0,0 +1,77 @@
+; Which interpreter quirks are in effect. One row per quirk:
+;
+;   vf_reset                 VF after OR with VF = 0x05: 05 off, 00 on
+;   shift_uses_vy            SHR of 0x02 with VY = 0x08: 01 off, 04 on
+;   load_store_increment_i   LD V0, [I] after LD V1, [I]: 11 off, 33 on
+;   jump_uses_vx             JP V0 with V0 = 0, V2 = 2: B0 off, B2 on
+;
+; Finally an 8 is drawn across the right edge, which wraps around to the left
+; unless clip_sprites is on.
+
+    LD VA, 0                ; Cursor x
+    LD VB, 0                ; Cursor y
+
+    LD VF, 0x05
+    LD V2, 0x0F
+    LD V3, 0xF0
+    OR V2, V3
+    LD V4, VF
+    CALL row
+
+    LD V2, 0x02
+    LD V3, 0x08
+    SHR V2, V3
+    LD V4, V2
+    CALL row
+
+    LD I, data
+    LD V1, [I]
+    LD V0, [I]
+    LD V4, V0
+    CALL row
+
+    LD V0, 0
+    LD V2, 2
+    JP V0, jumps            ; BNNN with X = 2, as the program sits at 0x2NN
+jumped:
+    CALL row
+
+    LD V0, 8
+    LD F, V0
+    LD V0, 61
+    LD V1, 26
+    DRW V0, V1, 5
+
+done:
+    JP done
+
+jumps:
+    JP jump_b0
+    JP jump_b2
+jump_b0:
+    LD V4, 0xB0
+    JP jumped
+jump_b2:
+    LD V4, 0xB2
+    JP jumped
+
+data:
+    DB 0x11, 0x22, 0x33
+
+; Draw V4 as two hex digits, then move to the next row
+row:
+    LD V5, V4
+    SHR V5, V5
+    SHR V5, V5
+    SHR V5, V5
+    SHR V5, V5
+    LD F, V5
+    DRW VA, VB, 5
+    ADD VA, 5
+    LD V5, 0x0F
+    AND V5, V4
+    LD F, V5
+    DRW VA, VB, 5
+    LD VA, 0
+    ADD VB, 6
+    RET
@@ -0,0 +1,200 @@
+//! Differential testing of the CPU against a reference model.
+//!
+//! Random programs of valid instructions run on the emulator and on
+//! `Reference` (in `tests/reference`), one instruction at a time, and the
+//! registers, stack depth, timers, memory and display are compared after every
+//! step. A divergence fails the test and proptest shrinks the program to a
+//! minimal reproducer, which the failure message disassembles.
+//!
+//! Every quirk profile gets its own run. No keys are pressed, so FX0A waits
+//! forever and the key skips are only checked with the keypad idle.
+
+mod reference;
+
+use chip8::audio::SilentBackend;
+use chip8::constants::PROGRAM_START_ADDRESS;
+use chip8::cpu::CPU;
+use chip8::disasm;
+use chip8::opcodes::Opcode;
+use chip8::settings::{QuirkSettings, Settings};
+use proptest::prelude::*;
+use reference::{Fault, Reference, State, MEMORY_SIZE};
+
+const SEED: u64 = 1;
+const CASES: u32 = 128;
+const MAX_STEPS: usize = 256;
+const STEPS_PER_FRAME: usize = 10;          // Timers tick after this many instructions
+const BODY_LENGTH: usize = 48;              // Random instructions after the register setup
+const PROGRAM_WORDS: u16 = 16 + 1 + BODY_LENGTH as u16;
+
+fn cpu_state(cpu: &CPU) -> State {
+    let mut v = [0; 16];
+    for (register, value) in v.iter_mut().enumerate() {
+        *value = cpu.get_register(register as u8);
+    }
+    State {
+        v,
+        i: cpu.get_address_register(),
+        pc: cpu.get_program_counter(),
+        stack_depth: cpu.stack_depth() as usize,
+        delay: cpu.get_delay_timer(),
+        sound: cpu.get_sound_timer(),
+        memory: (0..MEMORY_SIZE as u16).map(|address| cpu.peek_memory(address).unwrap_or(0)).collect(),
+        display: cpu.display_snapshot(),
+    }
+}
+
+/// The first difference between two states, for the failure message
+fn describe_difference(cpu: &State, reference: &State) -> String {
+    if cpu.memory != reference.memory {
+        let address = cpu.memory.iter().zip(&reference.memory).position(|(a, b)| a != b).unwrap_or(0);
+        return format!(
+            "memory at {:#05X}: emulator {:02X}, reference {:02X}",
+            address, cpu.memory[address], reference.memory[address]
+        );
+    }
+    if cpu.display != reference.display {
+        let row = cpu.display.iter().zip(&reference.display).position(|(a, b)| a != b).unwrap_or(0);
+        return format!(
+            "display row {}:\n  emulator  {:064b}\n  reference {:064b}",
+            row, cpu.display[row], reference.display[row]
+        );
+    }
+    let strip = |state: &State| format!(
+        "V={:02X?} I={:03X} PC={:03X} depth={} DT={} ST={}",
+        state.v, state.i, state.pc, state.stack_depth, state.delay, state.sound
+    );
+    format!("\n  emulator  {}\n  reference {}", strip(cpu), strip(reference))
+}
+
+/// Run a program on both interpreters, stopping at the first difference
+fn compare(quirks: QuirkSettings, program: &[u8]) -> Result<(), String> {
+    let mut settings = Settings { quirks, ..Settings::default() };
+    settings.cpu.seed = SEED;
+    let mut cpu = CPU::with_audio_backend(settings, Box::new(SilentBackend));
+    cpu.load_program(program);
+    let mut reference = Reference::new(quirks, SEED, program);
+
+    for step in 0..MAX_STEPS {
+        let address = reference.pc;
+        let instruction = reference.memory.get(address as usize..address as usize + 2).map_or(String::new(), |word| {
+            let word = u16::from_be_bytes([word[0], word[1]]);
+            Opcode::decode(word).map_or(format!("{:04X}", word), |opcode| opcode.to_string())
+        });
+        let fail = |message: String| {
+            Err(format!(
+                "step {}, {} at {:#05X}: {}\nprogram:\n{}",
+                step,
+                instruction,
+                address,
+                message,
+                disasm::disassemble(program, PROGRAM_START_ADDRESS)
+            ))
+        };
+
+        let expected = reference.step();
+        let actual = cpu.tick().map_err(Fault::from);
+        if expected != actual {
+            return fail(format!("emulator gave {:?}, reference {:?}", actual, expected));
+        }
+        if expected.is_err() {
+            return Ok(());
+        }
+        if (step + 1) % STEPS_PER_FRAME == 0 {
+            cpu.update_timers();
+            reference.tick_timers();
+        }
+        let (actual, expected) = (cpu_state(&cpu), reference.state());
+        if actual != expected {
+            return fail(describe_difference(&actual, &expected));
+        }
+    }
+    Ok(())
+}
+
+/// A valid instruction; jumps and calls land on instructions of the program
+fn instruction() -> impl Strategy<Value = u16> {
+    const FAMILIES: u8 = 36;
+    (0..FAMILIES, 0u16..16, 0u16..16, any::<u8>(), 0..PROGRAM_WORDS, 0u16..0x1000, 0u16..16).prop_map(
+        |(family, x, y, nn, word, address, n)| {
+            let nn = nn as u16;
+            let target = PROGRAM_START_ADDRESS + 2 * word;
+            let xy = |op: u16, low: u16| op << 12 | x << 8 | y << 4 | low;
+            let xnn = |op: u16| op << 12 | x << 8 | nn;
+            let fx = |low: u16| 0xF000 | x << 8 | low;
+            match family {
+                0 => 0x00E0,
+                1 => 0x00EE,
+                2 => 0x1000 | target,
+                3 => 0x2000 | target,
+                4 => xnn(0x3),
+                5 => xnn(0x4),
+                6 => xy(0x5, 0x0),
+                7 | 8 => xnn(0x6),
+                9 => xnn(0x7),
+                10..=17 => xy(0x8, family as u16 - 10),
+                18 => xy(0x8, 0xE),
+                19 => xy(0x9, 0x0),
+                20 => 0xA000 | address,
+                21 => 0xB000 | target,
+                22 => xnn(0xC),
+                23 => xy(0xD, n),
+                24 => xnn(0xE) & 0xFF00 | 0x9E,
+                25 => xnn(0xE) & 0xFF00 | 0xA1,
+                26 => fx(0x07),
+                27 => fx(0x0A),
+                28 => fx(0x15),
+                29 => fx(0x18),
+                30 => fx(0x1E),
+                31 => fx(0x29),
+                32 => fx(0x33),
+                33 => fx(0x55),
+                34 => fx(0x65),
+                _ => 0xA000 | (0x300 + address % 0x100), // Point I at spare memory
+            }
+        },
+    )
+}
+
+/// Set every register and I to something interesting, then run random instructions
+fn program() -> impl Strategy<Value = Vec<u8>> {
+    (prop::array::uniform16(any::<u8>()), 0u16..0x1000, prop::collection::vec(instruction(), 1..=BODY_LENGTH)).prop_map(
+        |(registers, i, body)| {
+            let setup = registers.iter().enumerate().map(|(x, &value)| 0x6000 | (x as u16) << 8 | value as u16);
+            setup
+                .chain([0xA000 | i])
+                .chain(body)
+                .flat_map(u16::to_be_bytes)
+                .collect()
+        },
+    )
+}
+
+fn check_profile(profile: &str, program: &[u8]) -> Result<(), TestCaseError> {
+    let quirks = QuirkSettings::profile(profile).unwrap();
+    compare(quirks, program).map_err(|message| TestCaseError::fail(format!("quirks {}: {}", profile, message)))
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(CASES))]
+
+    #[test]
+    fn matches_reference_with_default_quirks(program in program()) {
+        check_profile("default", &program)?;
+    }
+
+    #[test]
+    fn matches_reference_with_chip8_quirks(program in program()) {
+        check_profile("chip8", &program)?;
+    }
+
+    #[test]
+    fn matches_reference_with_schip_quirks(program in program()) {
+        check_profile("schip", &program)?;
+    }
+
+    #[test]
+    fn matches_reference_with_xo_chip_quirks(program in program()) {
+        check_profile("xo-chip", &program)?;
+    }
+}
@@ -0,0 +1,311 @@
+//! A reference CHIP-8 interpreter for the integration tests.
+//!
+//! `Reference` is a deliberately plain interpreter written from the CHIP-8
+//! specification, sharing nothing with the emulator but the font and the
+//! quirk settings. The differential test compares it with the emulator after
+//! every instruction; the conformance test writes its goldens from it, so they
+//! don't just record whatever the emulator did.
+
+// Each test uses only part of the model
+#![allow(dead_code)]
+
+use chip8::constants::{FONT_START_ADDRESS, PROGRAM_START_ADDRESS};
+use chip8::cpu::CpuFault;
+use chip8::font::FONT_SET;
+use chip8::settings::QuirkSettings;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+pub const MEMORY_SIZE: usize = 4096;
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 32;
+const STACK_LIMIT: usize = 12;
+const KEY_COUNT: usize = 16;
+
+/// Faults as the reference sees them; the emulator's carry addresses too
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    UnknownOpcode,
+    MachineCodeCall,
+    StackOverflow,
+    StackUnderflow,
+    MemoryOutOfRange,
+    ProgramCounterOutOfRange,
+}
+
+impl From<CpuFault> for Fault {
+    fn from(fault: CpuFault) -> Self {
+        match fault {
+            CpuFault::UnknownOpcode { .. } => Fault::UnknownOpcode,
+            CpuFault::MachineCodeCall { .. } => Fault::MachineCodeCall,
+            CpuFault::StackOverflow { .. } => Fault::StackOverflow,
+            CpuFault::StackUnderflow { .. } => Fault::StackUnderflow,
+            CpuFault::MemoryOutOfRange { .. } => Fault::MemoryOutOfRange,
+            CpuFault::ProgramCounterOutOfRange { .. } => Fault::ProgramCounterOutOfRange,
+        }
+    }
+}
+
+/// Everything that is compared between the two interpreters
+#[derive(Debug, PartialEq)]
+pub struct State {
+    pub v: [u8; 16],
+    pub i: u16,
+    pub pc: u16,
+    pub stack_depth: usize,
+    pub delay: u8,
+    pub sound: u8,
+    pub memory: Vec<u8>,
+    pub display: [u64; HEIGHT],
+}
+
+pub struct Reference {
+    pub v: [u8; 16],
+    pub i: u16,
+    pub pc: u16,
+    stack: Vec<u16>,
+    delay: u8,
+    sound: u8,
+    pub memory: Vec<u8>,
+    display: [[bool; WIDTH]; HEIGHT],
+    keys: [bool; KEY_COUNT],
+    /// Set by FX0A: `Some(None)` until a key goes down, then the key until it comes up
+    key_wait: Option<Option<u8>>,
+    quirks: QuirkSettings,
+    rng: StdRng,
+}
+
+impl Reference {
+    pub fn new(quirks: QuirkSettings, seed: u64, program: &[u8]) -> Self {
+        let mut memory = vec![0; MEMORY_SIZE];
+        let font = FONT_START_ADDRESS as usize;
+        memory[font..font + FONT_SET.len()].copy_from_slice(&FONT_SET);
+        let start = PROGRAM_START_ADDRESS as usize;
+        memory[start..start + program.len()].copy_from_slice(program);
+        Self {
+            v: [0; 16],
+            i: 0,
+            pc: PROGRAM_START_ADDRESS,
+            stack: Vec::new(),
+            delay: 0,
+            sound: 0,
+            memory,
+            display: [[false; WIDTH]; HEIGHT],
+            keys: [false; KEY_COUNT],
+            key_wait: None,
+            quirks,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// The display as one bit per pixel, leftmost pixel in the top bit
+    pub fn display(&self) -> [u64; HEIGHT] {
+        let mut display = [0; HEIGHT];
+        for (row, pixels) in display.iter_mut().zip(&self.display) {
+            for (x, &lit) in pixels.iter().enumerate() {
+                *row |= (lit as u64) << (WIDTH - 1 - x);
+            }
+        }
+        display
+    }
+
+    pub fn state(&self) -> State {
+        State {
+            v: self.v,
+            i: self.i,
+            pc: self.pc,
+            stack_depth: self.stack.len(),
+            delay: self.delay,
+            sound: self.sound,
+            memory: self.memory.clone(),
+            display: self.display(),
+        }
+    }
+
+    pub fn press_key(&mut self, key: u8) {
+        self.keys[key as usize] = true;
+        if self.key_wait == Some(None) {
+            self.key_wait = Some(Some(key));
+        }
+    }
+
+    pub fn release_key(&mut self, key: u8) {
+        self.keys[key as usize] = false;
+    }
+
+    pub fn tick_timers(&mut self) {
+        self.delay = self.delay.saturating_sub(1);
+        self.sound = self.sound.saturating_sub(1);
+    }
+
+    /// Run `cycles` instructions, then the 60 Hz timer tick
+    pub fn run_frame(&mut self, cycles: u32) -> Result<(), Fault> {
+        for _ in 0..cycles {
+            self.step()?;
+        }
+        self.tick_timers();
+        Ok(())
+    }
+
+    pub fn step(&mut self) -> Result<(), Fault> {
+        if self.pc as usize + 1 >= MEMORY_SIZE {
+            return Err(Fault::ProgramCounterOutOfRange);
+        }
+        let op = u16::from_be_bytes([self.memory[self.pc as usize], self.memory[self.pc as usize + 1]]);
+        self.pc += 2;
+
+        let x = ((op >> 8) & 0xF) as usize;
+        let y = ((op >> 4) & 0xF) as usize;
+        let n = (op & 0xF) as usize;
+        let nn = (op & 0xFF) as u8;
+        let nnn = op & 0xFFF;
+        let (vx, vy) = (self.v[x], self.v[y]);
+
+        match (op >> 12, n) {
+            (0x0, _) => match op {
+                0x00E0 => self.display = [[false; WIDTH]; HEIGHT],
+                0x00EE => self.pc = self.stack.pop().ok_or(Fault::StackUnderflow)?,
+                _ => return Err(Fault::MachineCodeCall),
+            },
+            (0x1, _) => self.pc = nnn,
+            (0x2, _) => {
+                if self.stack.len() == STACK_LIMIT {
+                    return Err(Fault::StackOverflow);
+                }
+                self.stack.push(self.pc);
+                self.pc = nnn;
+            }
+            (0x3, _) => self.skip_if(vx == nn),
+            (0x4, _) => self.skip_if(vx != nn),
+            (0x5, 0x0) => self.skip_if(vx == vy),
+            (0x6, _) => self.v[x] = nn,
+            (0x7, _) => self.v[x] = vx.wrapping_add(nn),
+            (0x8, 0x0) => self.v[x] = vy,
+            (0x8, 0x1..=0x3) => {
+                self.v[x] = match n {
+                    0x1 => vx | vy,
+                    0x2 => vx & vy,
+                    _ => vx ^ vy,
+                };
+                if self.quirks.vf_reset {
+                    self.v[0xF] = 0;
+                }
+            }
+            // The flag is written last, so it wins when X is F
+            (0x8, 0x4) => {
+                let sum = vx as u16 + vy as u16;
+                self.v[x] = sum as u8;
+                self.v[0xF] = (sum > 0xFF) as u8;
+            }
+            (0x8, 0x5) => {
+                self.v[x] = vx.wrapping_sub(vy);
+                self.v[0xF] = (vx >= vy) as u8;
+            }
+            (0x8, 0x7) => {
+                self.v[x] = vy.wrapping_sub(vx);
+                self.v[0xF] = (vy >= vx) as u8;
+            }
+            (0x8, 0x6) => {
+                let source = if self.quirks.shift_uses_vy { vy } else { vx };
+                self.v[x] = source >> 1;
+                self.v[0xF] = source & 1;
+            }
+            (0x8, 0xE) => {
+                let source = if self.quirks.shift_uses_vy { vy } else { vx };
+                self.v[x] = source << 1;
+                self.v[0xF] = source >> 7;
+            }
+            (0x9, 0x0) => self.skip_if(vx != vy),
+            (0xA, _) => self.i = nnn,
+            (0xB, _) => {
+                let offset = if self.quirks.jump_uses_vx { vx } else { self.v[0] };
+                self.pc = nnn + offset as u16;
+            }
+            (0xC, _) => self.v[x] = self.rng.random::<u8>() & nn,
+            (0xD, _) => {
+                self.check_range(n)?;
+                self.v[0xF] = self.draw(vx, vy, n) as u8;
+            }
+            (0xE, _) if nn == 0x9E => self.skip_if(self.keys[(vx & 0xF) as usize]),
+            (0xE, _) if nn == 0xA1 => self.skip_if(!self.keys[(vx & 0xF) as usize]),
+            (0xF, _) => match nn {
+                0x07 => self.v[x] = self.delay,
+                // Waits for a key to go down and come back up, as on the COSMAC VIP
+                0x0A => match self.key_wait {
+                    Some(Some(key)) if !self.keys[key as usize] => {
+                        self.v[x] = key;
+                        self.key_wait = None;
+                    }
+                    Some(_) => self.pc -= 2,
+                    None => {
+                        self.key_wait = Some(None);
+                        self.pc -= 2;
+                    }
+                },
+                0x15 => self.delay = vx,
+                0x18 => self.sound = vx,
+                // I is 12 bits wide and wraps around
+                0x1E => self.i = (self.i + vx as u16) & 0xFFF,
+                0x29 => self.i = FONT_START_ADDRESS + 5 * (vx & 0xF) as u16,
+                0x33 => {
+                    self.check_range(3)?;
+                    let i = self.i as usize;
+                    self.memory[i..i + 3].copy_from_slice(&[vx / 100, vx / 10 % 10, vx % 10]);
+                }
+                0x55 | 0x65 => {
+                    self.check_range(x + 1)?;
+                    let i = self.i as usize;
+                    if nn == 0x55 {
+                        self.memory[i..=i + x].copy_from_slice(&self.v[..=x]);
+                    } else {
+                        self.v[..=x].copy_from_slice(&self.memory[i..=i + x]);
+                    }
+                    if self.quirks.load_store_increment_i {
+                        self.i = (self.i + x as u16 + 1) & 0xFFF;
+                    }
+                }
+                _ => return Err(Fault::UnknownOpcode),
+            },
+            _ => return Err(Fault::UnknownOpcode),
+        }
+        Ok(())
+    }
+
+    fn skip_if(&mut self, condition: bool) {
+        if condition {
+            self.pc += 2;
+        }
+    }
+
+    fn check_range(&self, length: usize) -> Result<(), Fault> {
+        if self.i as usize + length > MEMORY_SIZE {
+            return Err(Fault::MemoryOutOfRange);
+        }
+        Ok(())
+    }
+
+    /// XOR a sprite from I onto the display, returning whether a lit pixel was erased
+    fn draw(&mut self, vx: u8, vy: u8, height: usize) -> bool {
+        let (left, top) = (vx as usize % WIDTH, vy as usize % HEIGHT);
+        let mut collision = false;
+        for row in 0..height {
+            let y = top + row;
+            if y >= HEIGHT && self.quirks.clip_sprites {
+                break;
+            }
+            let bits = self.memory[self.i as usize + row];
+            for column in 0..8 {
+                let x = left + column;
+                if x >= WIDTH && self.quirks.clip_sprites {
+                    break;
+                }
+                if bits & (0x80 >> column) != 0 {
+                    let pixel = &mut self.display[y % HEIGHT][x % WIDTH];
+                    collision |= *pixel;
+                    *pixel = !*pixel;
+                }
+            }
+        }
+        collision
+    }
+}